@@ -92,9 +92,20 @@ pub enum Method {
     FuturePublicKey {
         key: key::PublicKey,
     },
+    KeyboardInteractive {
+        submethods: String,
+    },
     // Hostbased,
 }
 
+/// The answer of a client handler to a request of the server during
+/// authentication, sent once the handler returns.
+#[derive(Debug)]
+pub(crate) enum Answer {
+    NewPassword(String),
+    Responses(Vec<String>),
+}
+
 impl encoding::Bytes for MethodSet {
     fn bytes(&self) -> &'static [u8] {
         match *self {
@@ -119,6 +130,26 @@ impl MethodSet {
             _ => None,
         }
     }
+
+    /// Parse a comma-separated list of method names, such as
+    /// `"publickey,password"`, into a set of methods.
+    pub fn from_names(names: &str) -> Result<MethodSet, crate::Error> {
+        let mut methods = MethodSet::empty();
+        for name in names.split(',') {
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            if let Some(m) = MethodSet::from_bytes(name.as_bytes()) {
+                methods |= m
+            } else {
+                return Err(crate::Error::UnknownAuthMethod {
+                    name: name.to_string(),
+                });
+            }
+        }
+        Ok(methods)
+    }
 }
 
 #[doc(hidden)]
@@ -126,8 +157,21 @@ impl MethodSet {
 pub struct AuthRequest {
     pub methods: MethodSet,
     pub partial_success: bool,
+    /// The user of the accepted steps of a multi-step
+    /// authentication. The remaining steps must be for that user.
+    pub partial_user: Option<String>,
+    /// The methods of the steps accepted so far, after their
+    /// signatures (if any) were verified.
+    pub completed: MethodSet,
+    /// The last request was accepted without completing the
+    /// authentication (partial success or `SSH_MSG_USERAUTH_PK_OK`):
+    /// its reply is delayed like successes.
+    pub step_accepted: bool,
     pub current: Option<CurrentRequest>,
     pub rejection_count: usize,
+    /// Number of `USERAUTH_INFO_REQUEST` sent in keyboard-interactive
+    /// authentication.
+    pub interactive_rounds: usize,
 }
 
 #[doc(hidden)]
@@ -137,8 +181,33 @@ pub enum CurrentRequest {
         key: CryptoVec,
         algo: CryptoVec,
         sent_pk_ok: bool,
+        /// Methods still required if the key was only accepted as
+        /// one step of the authentication.
+        remaining: Option<MethodSet>,
     },
     KeyboardInteractive {
         submethods: String,
     },
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use thrussh_keys::encoding::Bytes;
+
+    #[test]
+    fn method_set_from_names() {
+        let methods = MethodSet::from_names("publickey, password").unwrap();
+        assert_eq!(methods, MethodSet::PUBLICKEY | MethodSet::PASSWORD);
+        // The names of a set are parsed back into the same set.
+        let names: Vec<_> = MethodSet::all()
+            .map(|m| String::from_utf8_lossy(m.bytes()).into_owned())
+            .collect();
+        assert_eq!(MethodSet::from_names(&names.join(",")).unwrap(), MethodSet::all());
+
+        match MethodSet::from_names("publickey,gssapi-with-mic") {
+            Err(crate::Error::UnknownAuthMethod { name }) => assert_eq!(name, "gssapi-with-mic"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
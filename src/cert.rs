@@ -0,0 +1,170 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//! OpenSSH certificates, see
+//! [PROTOCOL.certkeys](https://cvsweb.openbsd.org/src/usr.bin/ssh/PROTOCOL.certkeys).
+
+use crate::Error;
+use cryptovec::CryptoVec;
+use thrussh_keys::encoding::{Encoding, Position, Reader};
+use thrussh_keys::key::{PublicKey, Verify};
+
+/// Certificate type of user certificates.
+pub const SSH_CERT_TYPE_USER: u32 = 1;
+/// Certificate type of host certificates.
+pub const SSH_CERT_TYPE_HOST: u32 = 2;
+
+/// An OpenSSH certificate, whose signature by its certificate
+/// authority has been checked.
+#[derive(Debug)]
+pub struct Certificate {
+    /// The certified public key.
+    pub key: PublicKey,
+    /// Serial number, chosen by the certificate authority.
+    pub serial: u64,
+    /// `SSH_CERT_TYPE_USER` or `SSH_CERT_TYPE_HOST`.
+    pub cert_type: u32,
+    /// Free-form identifier of the certificate, usually logged.
+    pub key_id: String,
+    /// The user names (or host names) this certificate is valid
+    /// for. An empty list means any name.
+    pub valid_principals: Vec<String>,
+    /// Start of the validity period, in seconds since the Unix epoch.
+    pub valid_after: u64,
+    /// End of the validity period, in seconds since the Unix epoch.
+    pub valid_before: u64,
+    /// Critical options, such as `force-command`, as pairs of a name
+    /// and its (encoded) data. Servers must refuse certificates with
+    /// critical options they don't understand.
+    pub critical_options: Vec<(String, Vec<u8>)>,
+    /// Extensions, such as `permit-pty`, as pairs of a name and its
+    /// (encoded) data.
+    pub extensions: Vec<(String, Vec<u8>)>,
+    /// The public key of the certificate authority.
+    pub signature_key: PublicKey,
+}
+
+/// The plain key algorithm for a certificate algorithm, or `None` if
+/// `algo` is not a certificate algorithm.
+pub(crate) fn key_algorithm(algo: &[u8]) -> Option<&'static [u8]> {
+    match algo {
+        b"ssh-ed25519-cert-v01@openssh.com" => Some(&b"ssh-ed25519"[..]),
+        b"ssh-rsa-cert-v01@openssh.com" => Some(&b"ssh-rsa"[..]),
+        b"rsa-sha2-256-cert-v01@openssh.com" => Some(&b"rsa-sha2-256"[..]),
+        b"rsa-sha2-512-cert-v01@openssh.com" => Some(&b"rsa-sha2-512"[..]),
+        _ => None,
+    }
+}
+
+impl Certificate {
+    /// Parse a certificate sent with algorithm `algo`, and check its
+    /// signature. The validity period, type and principals are not
+    /// checked.
+    pub fn parse(algo: &[u8], blob: &[u8]) -> Result<Certificate, anyhow::Error> {
+        let key_algo = key_algorithm(algo).ok_or(Error::InvalidCertificate)?;
+        let mut r = blob.reader(0);
+        let (plain_type, key_fields) = match r.read_string()? {
+            b"ssh-ed25519-cert-v01@openssh.com" => (&b"ssh-ed25519"[..], 1),
+            b"ssh-rsa-cert-v01@openssh.com" => (&b"ssh-rsa"[..], 2),
+            _ => return Err(Error::InvalidCertificate.into()),
+        };
+        r.read_string()?; // nonce
+        // Rebuild the plain public key from its fields.
+        let start = r.position;
+        for _ in 0..key_fields {
+            r.read_string()?;
+        }
+        let mut key_blob = CryptoVec::new();
+        key_blob.extend_ssh_string(plain_type);
+        key_blob.extend(&blob[start..r.position]);
+        let key = PublicKey::parse(key_algo, &key_blob)?;
+
+        let serial = read_u64(&mut r)?;
+        let cert_type = r.read_u32()?;
+        let key_id = std::str::from_utf8(r.read_string()?)?.to_string();
+        let mut valid_principals = Vec::new();
+        let mut p = r.read_string()?.reader(0);
+        while let Ok(principal) = p.read_string() {
+            valid_principals.push(std::str::from_utf8(principal)?.to_string())
+        }
+        let valid_after = read_u64(&mut r)?;
+        let valid_before = read_u64(&mut r)?;
+        let critical_options = read_options(r.read_string()?)?;
+        let extensions = read_options(r.read_string()?)?;
+        r.read_string()?; // reserved
+        let signature_key_blob = r.read_string()?;
+        let signed = &blob[..r.position];
+        let mut s = r.read_string()?.reader(0);
+        let signature_algo = s.read_string()?;
+        let signature = s.read_string()?;
+        let signature_key = PublicKey::parse(signature_algo, signature_key_blob)?;
+        if !signature_key.verify_client_auth(signed, signature) {
+            return Err(Error::InvalidCertificate.into());
+        }
+        Ok(Certificate {
+            key,
+            serial,
+            cert_type,
+            key_id,
+            valid_principals,
+            valid_after,
+            valid_before,
+            critical_options,
+            extensions,
+            signature_key,
+        })
+    }
+
+    /// Whether the current time is within the validity period of
+    /// this certificate.
+    pub fn is_valid_now(&self) -> bool {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.valid_after <= now && now < self.valid_before
+    }
+
+    /// The first critical option of this certificate other than
+    /// `force-command` and `source-address`, the only ones OpenSSH
+    /// defines, if any.
+    pub fn unknown_critical_option(&self) -> Option<&str> {
+        self.critical_options
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .find(|name| *name != "force-command" && *name != "source-address")
+    }
+
+    /// Whether this certificate is valid for principal `name`.
+    pub fn allows_principal(&self, name: &str) -> bool {
+        self.valid_principals.is_empty() || self.valid_principals.iter().any(|p| p == name)
+    }
+}
+
+fn read_u64(r: &mut Position) -> Result<u64, anyhow::Error> {
+    let hi = r.read_u32()? as u64;
+    let lo = r.read_u32()? as u64;
+    Ok((hi << 32) | lo)
+}
+
+fn read_options(buf: &[u8]) -> Result<Vec<(String, Vec<u8>)>, anyhow::Error> {
+    let mut r = buf.reader(0);
+    let mut options = Vec::new();
+    while r.position < buf.len() {
+        let name = std::str::from_utf8(r.read_string()?)?.to_string();
+        let data = r.read_string()?.to_vec();
+        options.push((name, data))
+    }
+    Ok(options)
+}
@@ -63,6 +63,11 @@ impl AsRef<str> for Name {
         self.0
     }
 }
+impl crate::Named for Name {
+    fn name(&self) -> &'static str {
+        self.0
+    }
+}
 
 pub struct CipherPair {
     pub local_to_remote: SealingCipher,
@@ -103,10 +108,13 @@ pub trait SealingKey {
     fn seal(&self, seqn: u32, plaintext_in_ciphertext_out: &mut [u8], tag_out: &mut [u8]);
 }
 
+/// Read a packet, refusing packets longer than `max_len` bytes
+/// before allocating them.
 pub async fn read<'a, R: AsyncRead + Unpin>(
     stream: &'a mut R,
     buffer: &'a mut SSHBuffer,
     pair: &'a CipherPair,
+    max_len: usize,
 ) -> Result<usize, anyhow::Error> {
     let mut len = [0; 4];
     stream.read_exact(&mut len).await?;
@@ -119,7 +127,11 @@ pub async fn read<'a, R: AsyncRead + Unpin>(
         let len = key.decrypt_packet_length(seqn, len);
         let len = BigEndian::read_u32(&len) as usize + key.tag_len();
         debug!("clear len = {:?}", len);
+        if len > max_len {
+            return Err(Error::PacketTooLarge.into());
+        }
         buffer.buffer.resize(len + 4);
+        buffer.bytes += len + 4;
     }
     stream.read_exact(&mut buffer.buffer[4..]).await?;
     let key = pair.remote_to_local.as_opening_key();
@@ -128,7 +140,7 @@ pub async fn read<'a, R: AsyncRead + Unpin>(
     let (ciphertext, tag) = buffer.buffer.split_at_mut(ciphertext_len);
     let plaintext = key.open(seqn, ciphertext, tag)?;
 
-    let padding_length = plaintext[0] as usize;
+    let padding_length = *plaintext.first().ok_or(Error::IndexOutOfBounds)? as usize;
     debug!("padding_length {:?}", padding_length);
     let plaintext_end = plaintext
         .len()
@@ -176,6 +188,7 @@ impl CipherPair {
             buffer.buffer[offset..].split_at_mut(PACKET_LENGTH_LEN + packet_length);
 
         key.seal(buffer.seqn.0, plaintext, tag);
+        buffer.bytes += buffer.buffer.len() - offset;
 
         // Sequence numbers are on 32 bits and wrap.
         // https://tools.ietf.org/html/rfc4253#section-6.4
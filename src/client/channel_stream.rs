@@ -0,0 +1,211 @@
+use super::{Channel, Msg, OpenChannelMsg};
+use crate::{ChannelId, ChannelMsg, Error};
+use cryptovec::CryptoVec;
+use futures::task::{Context, Poll};
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A channel seen as a byte stream, implementing `AsyncRead` and
+/// `AsyncWrite`, for instance to forward a TCP connection with
+/// `tokio::io::copy`.
+///
+/// Reads return the data sent by the server on this channel, and
+/// return 0 (end of file) once the server sends EOF or closes the
+/// channel. Extended data (such as the standard error of a command)
+/// and channel requests are discarded.
+///
+/// Writes complete as soon as the session's event loop accepts the
+/// data, which blocks the writer when the loop is busy. The loop
+/// then sends the data according to the window of the channel,
+/// keeping in memory what does not fit in the window yet, so the
+/// writer is not slowed down by a slow server. Shutting the stream
+/// down sends EOF, after any data still waiting for the window.
+pub struct ChannelStream {
+    channel: Channel,
+    /// Data received and not read yet, starting at `read_pos`.
+    read_buf: CryptoVec,
+    read_pos: usize,
+    eof_received: bool,
+    eof_sent: bool,
+}
+
+impl Channel {
+    /// Turn this channel into a byte stream.
+    pub fn into_stream(self) -> ChannelStream {
+        ChannelStream {
+            channel: self,
+            read_buf: CryptoVec::new(),
+            read_pos: 0,
+            eof_received: false,
+            eof_sent: false,
+        }
+    }
+}
+
+impl ChannelStream {
+    /// The identifier of the underlying channel.
+    pub fn id(&self) -> ChannelId {
+        self.channel.sender.id
+    }
+
+    /// Queue `msg` for the event loop, waiting for room in its queue.
+    fn poll_send(
+        &mut self,
+        cx: &mut Context,
+        msg: impl FnOnce(ChannelId) -> Msg,
+    ) -> Poll<std::io::Result<()>> {
+        let sender = &mut self.channel.sender;
+        match sender.sender.poll_ready(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(broken_pipe())),
+            Poll::Pending => return Poll::Pending,
+        }
+        let id = sender.id;
+        match sender.sender.try_send(msg(id)) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(_) => Poll::Ready(Err(broken_pipe())),
+        }
+    }
+}
+
+fn broken_pipe() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, Error::SendError)
+}
+
+impl AsyncRead for ChannelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = std::cmp::min(buf.len(), this.read_buf.len() - this.read_pos);
+                buf[..n].copy_from_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if this.eof_received {
+                return Poll::Ready(Ok(0));
+            }
+            match this.channel.receiver.poll_recv(cx) {
+                Poll::Ready(Some(OpenChannelMsg::Msg(ChannelMsg::Data { data }))) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                }
+                Poll::Ready(Some(OpenChannelMsg::Msg(ChannelMsg::Eof))) | Poll::Ready(None) => {
+                    this.eof_received = true;
+                }
+                Poll::Ready(Some(_)) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for ChannelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.eof_sent {
+            return Poll::Ready(Err(broken_pipe()));
+        }
+        let data = |id| Msg::Data {
+            id,
+            data: CryptoVec::from_slice(buf),
+        };
+        match this.poll_send(cx, data) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<std::io::Result<()>> {
+        // Written data is already in the hands of the event loop.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.eof_sent {
+            return Poll::Ready(Ok(()));
+        }
+        match this.poll_send(cx, |id| Msg::Eof { id }) {
+            Poll::Ready(Ok(())) => {
+                this.eof_sent = true;
+                Poll::Ready(Ok(()))
+            }
+            r => r,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::{self, Handler};
+    use crate::test_util;
+    use crate::ChannelId;
+    use tokio::io::AsyncWriteExt;
+
+    /// A server sending back everything it receives on a channel, and
+    /// closing the channel after the client's EOF.
+    struct Echo;
+
+    impl Handler for Echo {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn data(
+            self,
+            channel: ChannelId,
+            data: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            session.data_flow_controlled(channel, data);
+            self.finished(session)
+        }
+
+        fn channel_eof(self, channel: ChannelId, mut session: server::Session) -> Self::FutureUnit {
+            session.eof(channel);
+            session.close(channel);
+            self.finished(session)
+        }
+    }
+
+    /// Copy a buffer to the stream and the stream to another buffer
+    /// at the same time, as `tokio::io::copy_bidirectional` would.
+    #[tokio::test]
+    async fn copy_bidirectional() {
+        let (mut client, _server) = test_util::connect(
+            test_util::server_config(),
+            Echo,
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let stream = client.channel_open_session().await.unwrap().into_stream();
+        // Several times the window, so that both directions need the
+        // window to be adjusted.
+        let sent: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let write = async {
+            tokio::io::copy(&mut &sent[..], &mut writer).await?;
+            writer.shutdown().await
+        };
+        let mut received = Vec::new();
+        let read = tokio::io::copy(&mut reader, &mut received);
+        let (written, read) = futures::join!(write, read);
+        written.unwrap();
+        assert_eq!(read.unwrap(), sent.len() as u64);
+        assert!(received == sent);
+    }
+}
@@ -12,18 +12,37 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
-use super::{Msg, Reply};
+use super::{Msg, PendingRequest, Reply};
 use crate::auth;
 use crate::key::PubKey;
 use crate::msg;
 use crate::negotiation;
 use crate::negotiation::Named;
-use crate::negotiation::Select;
 use crate::session::*;
 use crate::{ChannelId, ChannelOpenFailure, Error, Sig};
 use cryptovec::CryptoVec;
 use std::cell::RefCell;
+use std::sync::Arc;
 use thrussh_keys::encoding::{Encoding, Reader};
+use thrussh_keys::key;
+
+/// The same RSA key, with the next signature algorithm to try after
+/// the server rejected it: `ssh-rsa` is retried with `rsa-sha2-512`,
+/// and `rsa-sha2-512` with `rsa-sha2-256`.
+fn retry_rsa_key(key: &key::KeyPair) -> Option<Arc<key::KeyPair>> {
+    if let key::KeyPair::RSA { ref key, hash } = *key {
+        let next = match hash {
+            key::SignatureHash::SHA1 => key::SignatureHash::SHA2_512,
+            key::SignatureHash::SHA2_512 => key::SignatureHash::SHA2_256,
+            _ => return None,
+        };
+        return Some(Arc::new(key::KeyPair::RSA {
+            key: key.clone(),
+            hash: next,
+        }));
+    }
+    None
+}
 
 thread_local! {
     static SIGNATURE_BUFFER: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
@@ -42,11 +61,7 @@ impl super::Session {
             if let Some(ref mut enc) = self.common.encrypted {
                 // If we're not currently rekeying, but buf is a rekey request
                 if let Some(exchange) = std::mem::replace(&mut enc.exchange, None) {
-                    let kexinit = KexInit::received_rekey(
-                        exchange,
-                        negotiation::Client::read_kex(buf, &self.common.config.as_ref().preferred)?,
-                        &enc.session_id,
-                    );
+                    let kexinit = KexInit::received_rekey(exchange, &enc.session_id);
                     self.common.kex = Some(Kex::KexDhDone(kexinit.client_parse(
                         self.common.config.as_ref(),
                         &mut self.common.cipher,
@@ -59,6 +74,29 @@ impl super::Session {
             }
             return Ok(self);
         }
+        if buf[0] == msg::EXT_INFO {
+            if let Some(ref mut enc) = self.common.encrypted {
+                negotiation::read_ext_info(buf, &mut enc.peer_extensions)?;
+            }
+            return Ok(self);
+        }
+        if buf[0] == msg::USERAUTH_BANNER {
+            // Banners can be sent at any time before authentication,
+            // including before the server accepts our service request.
+            let mut r = buf.reader(1);
+            let banner = String::from_utf8_lossy(r.read_string()?);
+            let language = String::from_utf8_lossy(r.read_string().unwrap_or(&b""[..]));
+            if let Some(ref mut enc) = self.common.encrypted {
+                enc.capture_language_tag(
+                    self.common.config.capture_language_tags,
+                    language.as_bytes(),
+                );
+            }
+            let c = client.take().unwrap();
+            let (c, s) = c.auth_banner_with_language(&banner, &language, self).await?;
+            *client = Some(c);
+            return Ok(s);
+        }
         // If we've successfully read a packet.
         debug!("buf = {:?}", buf);
         let mut is_authenticated = false;
@@ -74,18 +112,24 @@ impl super::Session {
                         let mut r = buf.reader(1);
                         if r.read_string()? == b"ssh-userauth" {
                             *accepted = true;
-                            if let Some(ref meth) = self.common.auth_method {
+                            if let Some(meth) = self.common.auth_method.take() {
+                                let meth = enc.with_server_sig_alg(meth);
                                 let auth_request = auth::AuthRequest {
                                     methods: auth::MethodSet::all(),
                                     partial_success: false,
+                                    partial_user: None,
+                                    completed: auth::MethodSet::empty(),
+                                    step_accepted: false,
                                     current: None,
                                     rejection_count: 0,
+                                    interactive_rounds: 0,
                                 };
                                 let len = enc.write.len();
-                                if enc.write_auth_request(&self.common.auth_user, meth) {
+                                if enc.write_auth_request(&self.common.auth_user, &meth) {
                                     debug!("enc: {:?}", &enc.write[len..]);
                                     enc.state = EncryptedState::WaitingAuthRequest(auth_request)
                                 }
+                                self.common.auth_method = Some(meth);
                             }
                         }
                     } else {
@@ -100,18 +144,13 @@ impl super::Session {
                             .send(Reply::AuthSuccess)
                             .map_err(|_| Error::SendError)?;
                         enc.state = EncryptedState::Authenticated;
-                        return Ok(self)
-                    } else if buf[0] == msg::USERAUTH_BANNER {
-                        let mut r = buf.reader(1);
-                        let banner = r.read_string()?;
-                        if let Ok(banner) = std::str::from_utf8(banner) {
-                            let c = client.take().unwrap();
-                            let (c, s) = c.auth_banner(banner, self).await?;
-                            *client = Some(c);
-                            return Ok(s)
-                        } else {
-                            return Ok(self)
+                        if self.common.config.request_connection_service {
+                            push_packet!(enc.write, {
+                                enc.write.push(msg::SERVICE_REQUEST);
+                                enc.write.extend_ssh_string(b"ssh-connection");
+                            })
                         }
+                        return Ok(self)
                     } else if buf[0] == msg::USERAUTH_FAILURE {
                         debug!("userauth_failure");
 
@@ -127,8 +166,50 @@ impl super::Session {
                                 auth_request.methods |= m
                             }
                         }
+                        auth_request.partial_success = r.read_byte()? != 0;
+                        auth_request.current = None;
                         let no_more_methods = auth_request.methods.is_empty();
+                        let partial_success = auth_request.partial_success;
+                        let remaining = auth_request.methods;
+                        // If the server rejected an RSA key with an
+                        // algorithm it didn't advertise in
+                        // `server-sig-algs`, it may have refused the
+                        // algorithm rather than the key: retry the key
+                        // with the next one. Then try the next key, if
+                        // any.
+                        if !partial_success && remaining.contains(auth::MethodSet::PUBLICKEY) {
+                            if let Some(auth::Method::PublicKey { ref key }) =
+                                self.common.auth_method
+                            {
+                                let mut next = None;
+                                let advertised = enc
+                                    .server_sig_algs()
+                                    .map(|algs| algs.contains(&key.name()))
+                                    .unwrap_or(false);
+                                if !advertised {
+                                    next = retry_rsa_key(key)
+                                }
+                                if next.is_none() {
+                                    next = self.remaining_keys.pop_front()
+                                }
+                                if let Some(key) = next {
+                                    debug!("retrying with {:?}", key.name());
+                                    let method =
+                                        enc.with_server_sig_alg(auth::Method::PublicKey { key });
+                                    enc.write_auth_request(&self.common.auth_user, &method);
+                                    self.common.auth_method = Some(method);
+                                    return Ok(self);
+                                }
+                            }
+                        }
                         self.common.auth_method = None;
+                        if partial_success {
+                            debug!("partial success, remaining {:?}", remaining);
+                            let c = client.take().unwrap();
+                            let (c, s) = c.auth_partial_success(remaining, self).await?;
+                            *client = Some(c);
+                            self = s;
+                        }
                         self.sender
                             .send(Reply::AuthFailure)
                             .map_err(|_| Error::SendError)?;
@@ -137,14 +218,102 @@ impl super::Session {
                         if no_more_methods {
                             return Err(Error::NoAuthMethod.into());
                         }
+                    } else if buf[0] == msg::USERAUTH_PASSWD_CHANGEREQ
+                        && matches!(self.common.auth_method, Some(auth::Method::Password { .. }))
+                    {
+                        debug!("userauth_passwd_changereq");
+                        let mut r = buf.reader(1);
+                        let prompt = String::from_utf8_lossy(r.read_string()?);
+                        let language = String::from_utf8_lossy(r.read_string()?);
+                        enc.capture_language_tag(
+                            self.common.config.capture_language_tags,
+                            language.as_bytes(),
+                        );
+                        self.auth_answer = None;
+                        let c = client.take().unwrap();
+                        let (c, s) = c.password_change_requested(&prompt, &language, self).await?;
+                        *client = Some(c);
+                        self = s;
+                        if let (
+                            Some(auth::Answer::NewPassword(new_password)),
+                            Some(auth::Method::Password { ref mut password }),
+                            Some(ref mut enc),
+                        ) = (
+                            self.auth_answer.take(),
+                            self.common.auth_method.as_mut(),
+                            self.common.encrypted.as_mut(),
+                        ) {
+                            let user = &self.common.auth_user;
+                            enc.write_password_change(user, password, &new_password);
+                            *password = new_password;
+                        } else {
+                            // The handler did not change the password.
+                            self.common.auth_method = None;
+                            self.sender
+                                .send(Reply::AuthFailure)
+                                .map_err(|_| Error::SendError)?;
+                        }
+                        return Ok(self);
+                    } else if buf[0] == msg::USERAUTH_INFO_REQUEST
+                        && matches!(
+                            self.common.auth_method,
+                            Some(auth::Method::KeyboardInteractive { .. })
+                        )
+                    {
+                        debug!("userauth_info_request");
+                        let mut r = buf.reader(1);
+                        let name = String::from_utf8_lossy(r.read_string()?);
+                        let instructions = String::from_utf8_lossy(r.read_string()?);
+                        let language = r.read_string()?; // deprecated.
+                        let capture = self.common.config.capture_language_tags;
+                        enc.capture_language_tag(capture, language);
+                        let n = r.read_u32()?;
+                        let mut prompts = Vec::new();
+                        for _ in 0..n {
+                            let prompt = String::from_utf8_lossy(r.read_string()?);
+                            let echo = r.read_byte()? != 0;
+                            prompts.push((prompt, echo));
+                        }
+                        let prompts: Vec<_> =
+                            prompts.iter().map(|(p, e)| (p.as_ref(), *e)).collect();
+                        self.auth_answer = None;
+                        let c = client.take().unwrap();
+                        let (c, s) = c
+                            .keyboard_interactive_prompts(&name, &instructions, &prompts, self)
+                            .await?;
+                        *client = Some(c);
+                        self = s;
+                        if let (
+                            Some(auth::Answer::Responses(responses)),
+                            Some(auth::Method::KeyboardInteractive { .. }),
+                            Some(ref mut enc),
+                        ) = (
+                            self.auth_answer.take(),
+                            self.common.auth_method.as_ref(),
+                            self.common.encrypted.as_mut(),
+                        ) {
+                            enc.write_info_response(&responses);
+                        } else {
+                            // The handler did not answer the prompts.
+                            self.common.auth_method = None;
+                            self.sender
+                                .send(Reply::AuthFailure)
+                                .map_err(|_| Error::SendError)?;
+                        }
+                        return Ok(self);
                     } else if buf[0] == msg::USERAUTH_PK_OK {
                         debug!("userauth_pk_ok");
-                        if let Some(auth::CurrentRequest::PublicKey {
-                            ref mut sent_pk_ok, ..
-                        }) = auth_request.current
-                        {
-                            *sent_pk_ok = true;
-                        }
+                        let mut r = buf.reader(1);
+                        let mut algo = CryptoVec::new();
+                        algo.extend(r.read_string()?);
+                        let mut key = CryptoVec::new();
+                        key.extend(r.read_string()?);
+                        auth_request.current = Some(auth::CurrentRequest::PublicKey {
+                            key,
+                            algo,
+                            sent_pk_ok: true,
+                            remaining: None,
+                        });
 
                         match self.common.auth_method.take() {
                             Some(auth_method @ auth::Method::PublicKey { .. }) => {
@@ -153,7 +322,10 @@ impl super::Session {
                                     &self.common.auth_user,
                                     &auth_method,
                                     &mut self.common.buffer,
-                                )?
+                                )?;
+                                // Keep the key, to retry it if the
+                                // server rejects the signature.
+                                self.common.auth_method = Some(auth_method);
                             }
                             Some(auth::Method::FuturePublicKey { key }) => {
                                 debug!("public key");
@@ -242,9 +414,17 @@ impl super::Session {
                 Ok(s)
             }
             msg::CHANNEL_EOF => {
-                debug!("channel_close");
+                debug!("channel_eof");
                 let mut r = buf.reader(1);
                 let channel_num = ChannelId(r.read_u32()?);
+                if let Some(ref mut enc) = self.common.encrypted {
+                    if let Some(channel) = enc.channels.get_mut(&channel_num) {
+                        // Keep the channel until CHANNEL_CLOSE, the
+                        // server may still send exit-status or
+                        // exit-signal requests.
+                        channel.eof_received = true;
+                    }
+                }
                 let c = client.take().unwrap();
                 let (c, s) = c.channel_eof(channel_num, self).await?;
                 *client = Some(c);
@@ -259,6 +439,10 @@ impl super::Session {
                 let language = std::str::from_utf8(r.read_string()?)?;
                 if let Some(ref mut enc) = self.common.encrypted {
                     enc.channels.remove(&channel_num);
+                    enc.capture_language_tag(
+                        self.common.config.capture_language_tags,
+                        language.as_bytes(),
+                    );
                 }
                 let c = client.take().unwrap();
                 let (c, s) = c
@@ -274,7 +458,7 @@ impl super::Session {
                 let data = r.read_string()?;
                 let target = self.common.config.window_size;
                 if let Some(ref mut enc) = self.common.encrypted {
-                    enc.adjust_window_size(channel_num, data, target);
+                    enc.adjust_window_size(channel_num, data, target)?;
                 }
                 let c = client.take().unwrap();
                 let (c, s) = c.data(channel_num, &data, self).await?;
@@ -289,7 +473,7 @@ impl super::Session {
                 let data = r.read_string()?;
                 let target = self.common.config.window_size;
                 if let Some(ref mut enc) = self.common.encrypted {
-                    enc.adjust_window_size(channel_num, data, target);
+                    enc.adjust_window_size(channel_num, data, target)?;
                 }
                 let c = client.take().unwrap();
                 let (c, s) = c
@@ -303,29 +487,18 @@ impl super::Session {
                 let mut r = buf.reader(1);
                 let channel_num = ChannelId(r.read_u32()?);
                 let req = r.read_string()?;
+                let want_reply = r.read_byte()? != 0;
                 let cl = client.take().unwrap();
                 let (c, s) = match req {
-                    b"forwarded_tcpip" => {
-                        let a = std::str::from_utf8(r.read_string()?)?;
-                        let b = r.read_u32()?;
-                        let c = std::str::from_utf8(r.read_string()?)?;
-                        let d = r.read_u32()?;
-                        cl
-                            .channel_open_forwarded_tcpip(channel_num, a, b, c, d, self)
-                            .await?
-                    }
                     b"xon-xoff" => {
-                        r.read_byte()?; // should be 0.
                         let client_can_do = r.read_byte()?;
                         cl.xon_xoff(channel_num, client_can_do != 0, self).await?
                     }
                     b"exit-status" => {
-                        r.read_byte()?; // should be 0.
                         let exit_status = r.read_u32()?;
                         cl.exit_status(channel_num, exit_status, self).await?
                     }
                     b"exit-signal" => {
-                        r.read_byte()?; // should be 0.
                         let signal_name = Sig::from_name(r.read_string()?)?;
                         let core_dumped = r.read_byte()?;
                         let error_message = std::str::from_utf8(r.read_string()?)?;
@@ -342,8 +515,25 @@ impl super::Session {
                             .await?
                     }
                     _ => {
-                        info!("Unknown channel request {:?}", std::str::from_utf8(req));
-                        (cl, self)
+                        debug!("channel request {:?}", std::str::from_utf8(req));
+                        if let Some(ref mut enc) = self.common.encrypted {
+                            if let Some(channel) = enc.channels.get_mut(&channel_num) {
+                                channel.wants_reply = want_reply;
+                            }
+                        }
+                        let data = &buf[r.position..];
+                        let (c, mut s) = cl
+                            .channel_request(
+                                channel_num,
+                                std::str::from_utf8(req)?,
+                                want_reply,
+                                data,
+                                self,
+                            )
+                            .await?;
+                        // If the handler did not reply, refuse the request.
+                        s.channel_failure(channel_num);
+                        (c, s)
                     }
                 };
                 *client = Some(c);
@@ -358,27 +548,215 @@ impl super::Session {
                 debug!("amount: {:?}", amount);
                 if let Some(ref mut enc) = self.common.encrypted {
                     if let Some(ref mut channel) = enc.channels.get_mut(&channel_num) {
-                        channel.recipient_window_size += amount;
+                        // The window may not exceed 2^32 - 1 bytes.
+                        // https://tools.ietf.org/html/rfc4254#section-5.2
+                        channel.recipient_window_size = channel
+                            .recipient_window_size
+                            .checked_add(amount)
+                            .ok_or(Error::WindowOverflow)?;
                         new_value = channel.recipient_window_size;
                     } else {
                         return Err(Error::WrongChannel.into());
                     }
+                    // Send the data that was waiting for this window.
+                    if enc.flush_pending(channel_num) > 0 {
+                        if let Some(channel) = enc.channels.get(&channel_num) {
+                            new_value = channel.recipient_window_size;
+                        }
+                    }
                 }
                 let c = client.take().unwrap();
                 let (c, s) = c.window_adjusted(channel_num, new_value as usize, self).await?;
                 *client = Some(c);
                 Ok(s)
             }
+            msg::SERVICE_ACCEPT => {
+                // Reply to our explicit ssh-connection service request.
+                debug!("service_accept");
+                Ok(self)
+            }
             msg::GLOBAL_REQUEST => {
                 let mut r = buf.reader(1);
                 let req = r.read_string()?;
+                let wants_reply = r.read_byte()?;
+                if req == b"hostkeys-00@openssh.com" {
+                    // https://github.com/openssh/openssh-portable/blob/master/PROTOCOL
+                    let mut keys = Vec::new();
+                    while r.position < buf.len() {
+                        let blob = r.read_string()?;
+                        match thrussh_keys::key::parse_public_key(blob) {
+                            Ok(key) => keys.push(key),
+                            Err(e) => debug!("hostkeys: skipping unknown key: {:?}", e),
+                        }
+                    }
+                    let c = client.take().unwrap();
+                    let (c, s) = c.hostkeys(&keys, self).await?;
+                    *client = Some(c);
+                    return Ok(s);
+                }
                 info!("Unhandled global request: {:?}", std::str::from_utf8(req));
+                if wants_reply != 0 {
+                    if let Some(ref mut enc) = self.common.encrypted {
+                        push_packet!(enc.write, {
+                            enc.write.push(msg::REQUEST_FAILURE);
+                        })
+                    }
+                }
                 Ok(self)
             }
-            _ => {
+            msg::REQUEST_SUCCESS | msg::REQUEST_FAILURE => {
+                // Replies to our global requests, including
+                // keepalives. Any reply shows the server is alive.
+                self.common.alive_timeouts = 0;
+                let success = buf[0] == msg::REQUEST_SUCCESS;
+                match self.pending_requests.pop_front() {
+                    Some(PendingRequest::TcpIpForward { address, port }) => {
+                        let bound_port = if !success {
+                            None
+                        } else if port == 0 {
+                            // The server sends the port it chose.
+                            buf.reader(1).read_u32().ok()
+                        } else {
+                            Some(port)
+                        };
+                        if let Some(i) =
+                            self.forwards.iter().position(|(a, p)| *a == address && *p == port)
+                        {
+                            match bound_port {
+                                Some(bound_port) => self.forwards[i].1 = bound_port,
+                                None => {
+                                    self.forwards.remove(i);
+                                }
+                            }
+                        }
+                        let c = client.take().unwrap();
+                        let (c, s) = c
+                            .tcpip_forward_reply(&address, port, bound_port, self)
+                            .await?;
+                        *client = Some(c);
+                        Ok(s)
+                    }
+                    Some(PendingRequest::CancelTcpIpForward { address, port }) => {
+                        let c = client.take().unwrap();
+                        let (c, s) = c
+                            .cancel_tcpip_forward_reply(&address, port, success, self)
+                            .await?;
+                        *client = Some(c);
+                        Ok(s)
+                    }
+                    Some(PendingRequest::Keepalive) | None => Ok(self),
+                }
+            }
+            msg::CHANNEL_OPEN => {
+                let mut r = buf.reader(1);
+                let typ = r.read_string()?;
+                let sender = r.read_u32()?;
+                let window = r.read_u32()?;
+                let maxpacket = r.read_u32()?;
+                if typ != b"forwarded-tcpip" {
+                    debug!("unknown channel type: {:?}", std::str::from_utf8(typ));
+                    self.refuse_channel_open(
+                        sender,
+                        ChannelOpenFailure::UnknownChannelType,
+                        "Unknown channel type",
+                    );
+                    return Ok(self);
+                }
+                let connected_address = std::str::from_utf8(r.read_string()?)?;
+                let connected_port = r.read_u32()?;
+                let originator_address = std::str::from_utf8(r.read_string()?)?;
+                let originator_port = r.read_u32()?;
+                let forwarded = self.forwards.iter().any(|(address, port)| {
+                    address == connected_address && (*port == connected_port || *port == 0)
+                });
+                if !forwarded {
+                    debug!(
+                        "forwarded channel for a port we did not forward: {:?}:{:?}",
+                        connected_address, connected_port
+                    );
+                    self.refuse_channel_open(
+                        sender,
+                        ChannelOpenFailure::AdministrativelyProhibited,
+                        "Port not forwarded",
+                    );
+                    return Ok(self);
+                }
+                let c = client.take().unwrap();
+                let (c, accept) = c
+                    .check_forwarded_tcpip(
+                        connected_address,
+                        connected_port,
+                        originator_address,
+                        originator_port,
+                    )
+                    .await?;
+                *client = Some(c);
+                if !accept {
+                    debug!("forwarded channel refused by the handler");
+                    self.refuse_channel_open(
+                        sender,
+                        ChannelOpenFailure::AdministrativelyProhibited,
+                        "Channel refused",
+                    );
+                    return Ok(self);
+                }
+                let window_size = self.common.config.window_size;
+                let maximum_packet_size = self.common.config.maximum_packet_size;
+                let id = if let Some(ref mut enc) = self.common.encrypted {
+                    let id = enc.new_channel(window_size, maximum_packet_size);
+                    if let Some(channel) = enc.channels.get_mut(&id) {
+                        channel.recipient_channel = sender;
+                        channel.recipient_window_size = window;
+                        channel.recipient_maximum_packet_size = maxpacket;
+                        channel.confirmed = true;
+                    }
+                    push_packet!(enc.write, {
+                        enc.write.push(msg::CHANNEL_OPEN_CONFIRMATION);
+                        enc.write.push_u32_be(sender);
+                        enc.write.push_u32_be(id.0);
+                        enc.write.push_u32_be(window_size);
+                        enc.write.push_u32_be(maximum_packet_size);
+                    });
+                    id
+                } else {
+                    return Err(Error::Inconsistent.into());
+                };
+                let c = client.take().unwrap();
+                let (c, s) = c
+                    .channel_open_forwarded_tcpip(
+                        id,
+                        connected_address,
+                        connected_port,
+                        originator_address,
+                        originator_port,
+                        self,
+                    )
+                    .await?;
+                *client = Some(c);
+                Ok(s)
+            }
+            msg::CHANNEL_SUCCESS | msg::CHANNEL_FAILURE => {
+                // Replies to our channel requests.
+                debug!("channel request reply: {:?}", buf[0]);
+                let channel = ChannelId(buf.reader(1).read_u32()?);
+                let c = client.take().unwrap();
+                let (c, s) = if buf[0] == msg::CHANNEL_SUCCESS {
+                    c.channel_success(channel, self).await?
+                } else {
+                    c.channel_failure(channel, self).await?
+                };
+                *client = Some(c);
+                Ok(s)
+            }
+            m if msg::is_assigned(m) => {
                 info!("Unhandled packet: {:?}", buf);
                 Ok(self)
             }
+            _ => {
+                info!("Unknown packet: {:?}", buf);
+                self.common.unimplemented();
+                Ok(self)
+            }
         }
     }
 
@@ -394,9 +772,14 @@ impl super::Session {
                 "write_auth_request_if_needed: is_waiting = {:?}",
                 is_waiting
             );
+            let meth = enc.with_server_sig_alg(meth);
             if is_waiting {
                 enc.write_auth_request(user, &meth);
             }
+            self.common.auth_user.clear();
+            self.common.auth_user.push_str(user);
+            self.common.auth_method = Some(meth);
+            return is_waiting;
         }
         self.common.auth_user.clear();
         self.common.auth_user.push_str(user);
@@ -406,6 +789,38 @@ impl super::Session {
 }
 
 impl Encrypted {
+    /// Sign with RSA keys using the strongest algorithm listed in the
+    /// server's `server-sig-algs` extension, so that the probe and the
+    /// signature use an algorithm the server accepts. Other methods,
+    /// and all methods if the server did not send that extension, are
+    /// left unchanged.
+    fn with_server_sig_alg(&self, method: auth::Method) -> auth::Method {
+        if let auth::Method::PublicKey { ref key } = method {
+            if let key::KeyPair::RSA { ref key, hash } = **key {
+                let algs = if let Some(algs) = self.server_sig_algs() {
+                    algs
+                } else {
+                    return method;
+                };
+                let best = [key::SignatureHash::SHA2_512, key::SignatureHash::SHA2_256]
+                    .iter()
+                    .cloned()
+                    .find(|h| algs.contains(&h.name().0));
+                if let Some(best) = best {
+                    if best != hash {
+                        return auth::Method::PublicKey {
+                            key: Arc::new(key::KeyPair::RSA {
+                                key: key.clone(),
+                                hash: best,
+                            }),
+                        };
+                    }
+                }
+            }
+        }
+        method
+    }
+
     fn write_auth_request(&mut self, user: &str, auth_method: &auth::Method) -> bool {
         // The server is waiting for our USERAUTH_REQUEST.
         push_packet!(self.write, {
@@ -416,7 +831,7 @@ impl Encrypted {
                     self.write.extend_ssh_string(user.as_bytes());
                     self.write.extend_ssh_string(b"ssh-connection");
                     self.write.extend_ssh_string(b"password");
-                    self.write.push(1);
+                    self.write.push(0);
                     self.write.extend_ssh_string(password.as_bytes());
                     true
                 }
@@ -441,6 +856,40 @@ impl Encrypted {
                     key.push_to(&mut self.write);
                     true
                 }
+                auth::Method::KeyboardInteractive { ref submethods } => {
+                    self.write.extend_ssh_string(user.as_bytes());
+                    self.write.extend_ssh_string(b"ssh-connection");
+                    self.write.extend_ssh_string(b"keyboard-interactive");
+                    self.write.extend_ssh_string(b""); // language tag, deprecated.
+                    self.write.extend_ssh_string(submethods.as_bytes());
+                    true
+                }
+            }
+        })
+    }
+
+    /// Reply to a password change request, see
+    /// [RFC4252](https://tools.ietf.org/html/rfc4252#section-8).
+    fn write_password_change(&mut self, user: &str, password: &str, new_password: &str) {
+        push_packet!(self.write, {
+            self.write.push(msg::USERAUTH_REQUEST);
+            self.write.extend_ssh_string(user.as_bytes());
+            self.write.extend_ssh_string(b"ssh-connection");
+            self.write.extend_ssh_string(b"password");
+            self.write.push(1);
+            self.write.extend_ssh_string(password.as_bytes());
+            self.write.extend_ssh_string(new_password.as_bytes());
+        })
+    }
+
+    /// Answer the prompts of a keyboard-interactive round, see
+    /// [RFC4256](https://tools.ietf.org/html/rfc4256#section-3.4).
+    fn write_info_response(&mut self, responses: &[String]) {
+        push_packet!(self.write, {
+            self.write.push(msg::USERAUTH_INFO_RESPONSE);
+            self.write.push_u32_be(responses.len() as u32);
+            for response in responses {
+                self.write.extend_ssh_string(response.as_bytes());
             }
         })
     }
@@ -485,3 +934,680 @@ impl Encrypted {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use thrussh_keys::encoding::Encoding;
+    use thrussh_keys::key;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    use crate::client;
+    use crate::server::{self, Handler, Prompt, Response};
+    use crate::test_util;
+    use crate::{auth, msg, ChannelId, Error};
+
+    /// A server adjusting the window of a channel by `u32::MAX` when
+    /// the client runs a command on it.
+    struct WindowOverflow;
+
+    impl Handler for WindowOverflow {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                let recipient_channel = enc.channels[&channel].recipient_channel;
+                push_packet!(enc.write, {
+                    enc.write.push(msg::CHANNEL_WINDOW_ADJUST);
+                    enc.write.push_u32_be(recipient_channel);
+                    enc.write.push_u32_be(u32::MAX);
+                });
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A server accepting RSA keys only with the `rsa-sha2-512`
+    /// signature algorithm.
+    struct RsaSha2_512Only;
+
+    impl Handler for RsaSha2_512Only {
+        server_futures!();
+
+        fn auth_publickey(self, _: &str, public_key: &key::PublicKey) -> Self::FutureAuth {
+            self.finished_auth(if public_key.name() == key::RSA_SHA2_512.0 {
+                server::Auth::Accept
+            } else {
+                server::Auth::Reject
+            })
+        }
+    }
+
+    /// A server accepting passwords as the first of two factors.
+    struct FirstFactor;
+
+    impl Handler for FirstFactor {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::AcceptPartial {
+                remaining: auth::MethodSet::PUBLICKEY,
+            })
+        }
+    }
+
+    /// A client reporting the methods remaining after a partial
+    /// success.
+    struct Partial(UnboundedSender<auth::MethodSet>);
+
+    impl client::Handler for Partial {
+        client_futures!();
+
+        fn auth_partial_success(
+            self,
+            remaining_methods: auth::MethodSet,
+            session: client::Session,
+        ) -> Self::FutureUnit {
+            self.0.send(remaining_methods).unwrap();
+            self.finished(session)
+        }
+    }
+
+    fn prompt(text: &'static str) -> server::Auth {
+        server::Auth::Partial {
+            name: "".into(),
+            instructions: "".into(),
+            prompts: vec![Prompt::hidden(text)].into(),
+        }
+    }
+
+    /// A server asking for a password and then for a code, in two
+    /// keyboard-interactive rounds.
+    struct TwoRounds;
+
+    impl Handler for TwoRounds {
+        server_futures!();
+
+        fn auth_keyboard_interactive(
+            self,
+            _: &str,
+            _: &str,
+            response: Option<Response>,
+        ) -> Self::FutureAuth {
+            let answer = response.and_then(|mut r| r.next()).map(|a| a.to_vec());
+            self.finished_auth(match answer.as_deref() {
+                None => prompt("Password: "),
+                Some(b"password") => prompt("Code: "),
+                Some(b"1234") => server::Auth::Accept,
+                Some(_) => server::Auth::Reject,
+            })
+        }
+    }
+
+    /// A client answering the prompts of `TwoRounds`.
+    struct Answering;
+
+    impl client::Handler for Answering {
+        client_futures!();
+
+        fn keyboard_interactive_prompts(
+            self,
+            _: &str,
+            _: &str,
+            prompts: &[(&str, bool)],
+            mut session: client::Session,
+        ) -> Self::FutureUnit {
+            let answers: Vec<&str> = prompts
+                .iter()
+                .map(|&(prompt, _)| {
+                    if prompt == "Code: " {
+                        "1234"
+                    } else {
+                        "password"
+                    }
+                })
+                .collect();
+            session.keyboard_interactive_responses(&answers);
+            self.finished(session)
+        }
+    }
+
+    /// A server rejecting keyboard-interactive requests, after sending
+    /// prompts that are not valid UTF-8.
+    struct RawPrompts;
+
+    impl Handler for RawPrompts {
+        server_futures!();
+
+        fn auth_banner_for(self, _: &str, mut session: server::Session) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::USERAUTH_INFO_REQUEST);
+                    enc.write.extend_ssh_string(b"n\xe9");
+                    enc.write.extend_ssh_string(b"i\xe9");
+                    enc.write.extend_ssh_string(b"");
+                    enc.write.push_u32_be(1);
+                    enc.write.extend_ssh_string(b"p\xe9");
+                    enc.write.push(1);
+                });
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A server rejecting keyboard-interactive requests, after sending
+    /// a prompt with language tag "fr".
+    struct TaggedPrompt;
+
+    impl Handler for TaggedPrompt {
+        server_futures!();
+
+        fn auth_banner_for(self, _: &str, mut session: server::Session) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::USERAUTH_INFO_REQUEST);
+                    enc.write.extend_ssh_string(b"");
+                    enc.write.extend_ssh_string(b"");
+                    enc.write.extend_ssh_string(b"fr");
+                    enc.write.push_u32_be(1);
+                    enc.write.extend_ssh_string(b"Mot de passe : ");
+                    enc.write.push(0);
+                });
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A client reporting the language tag captured when it receives
+    /// keyboard-interactive prompts, without answering them.
+    struct PromptLanguage(UnboundedSender<Option<String>>);
+
+    impl client::Handler for PromptLanguage {
+        client_futures!();
+
+        fn keyboard_interactive_prompts(
+            self,
+            _: &str,
+            _: &str,
+            _: &[(&str, bool)],
+            session: client::Session,
+        ) -> Self::FutureUnit {
+            let language = session.peer_language_tag().map(|l| l.to_string());
+            self.0.send(language).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A server asking for a login and a password, built with the
+    /// `Prompt` helpers.
+    struct MixedPrompts;
+
+    impl Handler for MixedPrompts {
+        server_futures!();
+
+        fn auth_keyboard_interactive(
+            self,
+            _: &str,
+            _: &str,
+            response: Option<Response>,
+        ) -> Self::FutureAuth {
+            self.finished_auth(if response.is_none() {
+                server::Auth::Partial {
+                    name: "Login".into(),
+                    instructions: "".into(),
+                    prompts: vec![Prompt::visible("User: "), Prompt::hidden("Password: ")].into(),
+                }
+            } else {
+                server::Auth::Reject
+            })
+        }
+    }
+
+    /// A client reporting keyboard-interactive prompts, without
+    /// answering them.
+    struct Silent(UnboundedSender<Vec<String>>);
+
+    impl client::Handler for Silent {
+        client_futures!();
+
+        fn keyboard_interactive_prompts(
+            self,
+            name: &str,
+            instructions: &str,
+            prompts: &[(&str, bool)],
+            session: client::Session,
+        ) -> Self::FutureUnit {
+            let mut received = vec![name.to_string(), instructions.to_string()];
+            received.extend(prompts.iter().map(|&(p, echo)| format!("{} {}", p, echo)));
+            self.0.send(received).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A server sending `0` bytes of data on a channel when the
+    /// client runs a command on it, whatever the maximum packet size
+    /// of the channel.
+    struct LargeData(usize);
+
+    impl Handler for LargeData {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                let recipient_channel = enc.channels[&channel].recipient_channel;
+                push_packet!(enc.write, {
+                    enc.write.push(msg::CHANNEL_DATA);
+                    enc.write.push_u32_be(recipient_channel);
+                    enc.write.extend_ssh_string(&vec![0; self.0]);
+                });
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A server accepting a single public key.
+    struct AuthorizedKey(key::PublicKey);
+
+    impl Handler for AuthorizedKey {
+        server_futures!();
+
+        fn auth_publickey(self, _: &str, public_key: &key::PublicKey) -> Self::FutureAuth {
+            let auth = if *public_key == self.0 {
+                server::Auth::Accept
+            } else {
+                server::Auth::Reject
+            };
+            self.finished_auth(auth)
+        }
+    }
+
+    /// A stream recording the bytes read from it.
+    struct Recorder {
+        stream: tokio::net::TcpStream,
+        read: Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    impl tokio::io::AsyncRead for Recorder {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let result = std::pin::Pin::new(&mut self.stream).poll_read(cx, buf);
+            if let std::task::Poll::Ready(Ok(n)) = result {
+                self.read.lock().unwrap().extend_from_slice(&buf[..n]);
+            }
+            result
+        }
+    }
+
+    impl tokio::io::AsyncWrite for Recorder {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::pin::Pin::new(&mut self.stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+
+    impl crate::tcp::Tcp for Recorder {}
+
+    /// A server sending a global request asking for a reply, followed
+    /// by data, on exec requests. When it then receives data, it
+    /// decrypts the packets it read since the global request, and
+    /// reports the type of the first reply to a global request (0 if
+    /// there is none).
+    struct GlobalRequester {
+        read: Arc<std::sync::Mutex<Vec<u8>>>,
+        /// The number of bytes read and the sequence number of the
+        /// next packet, when the request was sent.
+        sent: Option<(usize, u32)>,
+        replies: UnboundedSender<u8>,
+    }
+
+    impl Handler for GlobalRequester {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            mut self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::GLOBAL_REQUEST);
+                    enc.write.extend_ssh_string(b"test@example.com");
+                    enc.write.push(1);
+                });
+            }
+            let seqn = session.common.received_seqn.wrapping_add(1);
+            self.sent = Some((self.read.lock().unwrap().len(), seqn));
+            session.data(channel, b"sent");
+            self.finished(session)
+        }
+
+        fn data(self, _: ChannelId, _: &[u8], session: server::Session) -> Self::FutureUnit {
+            if let Some((offset, seqn)) = self.sent {
+                let read = self.read.lock().unwrap();
+                let mut stream = &read[offset..];
+                let mut buffer = crate::sshbuffer::SSHBuffer::new();
+                buffer.seqn = std::num::Wrapping(seqn);
+                let cipher = &session.common.cipher;
+                let mut reply = 0;
+                // Reading from memory never blocks.
+                while futures::executor::block_on(crate::cipher::read(
+                    &mut stream,
+                    &mut buffer,
+                    cipher,
+                    1 << 20,
+                ))
+                .is_ok()
+                {
+                    let typ = buffer.buffer[5];
+                    if typ == msg::REQUEST_SUCCESS || typ == msg::REQUEST_FAILURE {
+                        reply = typ;
+                        break;
+                    }
+                }
+                self.replies.send(reply).unwrap();
+            }
+            self.finished(session)
+        }
+    }
+
+    /// Global requests asking for a reply, such as the server's
+    /// keepalives, are answered with `SSH_MSG_REQUEST_FAILURE`.
+    #[tokio::test]
+    async fn answer_global_requests() {
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (replies, mut replies_receiver) = unbounded_channel();
+        let read = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler = GlobalRequester {
+            read: read.clone(),
+            sent: None,
+            replies,
+        };
+        let config = Arc::new(test_util::server_config());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            server::run_stream(config, Recorder { stream, read }, handler).await
+        });
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let config = Arc::new(client::Config::default());
+        let mut client = client::connect_stream(config, stream, test_util::Client)
+            .await
+            .unwrap();
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "request").await.unwrap();
+        // The request came before the data, so the reply is sent
+        // before our data.
+        while let Some(msg) = channel.wait().await {
+            if let crate::ChannelMsg::Data { .. } = msg {
+                break;
+            }
+        }
+        channel.data(&b"check"[..]).await.unwrap();
+        assert_eq!(replies_receiver.recv().await, Some(msg::REQUEST_FAILURE));
+    }
+
+    #[tokio::test]
+    async fn window_overflow() {
+        let (mut client, _server) = test_util::connect(
+            test_util::server_config(),
+            WindowOverflow,
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "true").await.unwrap();
+        let result = client.await;
+        let error = result
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<Error>());
+        assert!(matches!(error, Some(Error::WindowOverflow)), "{:?}", result);
+    }
+
+    /// An `ssh-rsa` key is signed with `rsa-sha2-512` when the server
+    /// does not accept SHA-1.
+    #[tokio::test]
+    async fn rsa_sha2_upgrade() {
+        let (mut client, _server) = test_util::connect(
+            test_util::server_config(),
+            RsaSha2_512Only,
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        let key = key::KeyPair::generate_rsa(2048, key::SignatureHash::SHA1).unwrap();
+        let auth = client.authenticate_publickey("user", Arc::new(key));
+        assert!(auth.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn partial_success() {
+        let (sender, mut remaining) = unbounded_channel();
+        let (mut client, _server) = test_util::connect(
+            test_util::server_config(),
+            FirstFactor,
+            Default::default(),
+            Partial(sender),
+        )
+        .await;
+        assert!(!client.authenticate_password("user", "").await.unwrap());
+        assert_eq!(remaining.recv().await, Some(auth::MethodSet::PUBLICKEY));
+    }
+
+    #[tokio::test]
+    async fn keyboard_interactive() {
+        let (mut client, _server) = test_util::connect(
+            test_util::server_config(),
+            TwoRounds,
+            Default::default(),
+            Answering,
+        )
+        .await;
+        let auth = client.authenticate_keyboard_interactive("user", "");
+        assert!(auth.await.unwrap());
+    }
+
+    /// Authentication fails if the handler doesn't answer the
+    /// prompts.
+    #[tokio::test]
+    async fn keyboard_interactive_unanswered() {
+        let (sender, mut prompts) = unbounded_channel();
+        let (mut client, _server) = test_util::connect(
+            test_util::server_config(),
+            RawPrompts,
+            Default::default(),
+            Silent(sender),
+        )
+        .await;
+        let auth = client.authenticate_keyboard_interactive("user", "");
+        assert!(!auth.await.unwrap());
+        let expected = vec!["n\u{fffd}", "i\u{fffd}", "p\u{fffd} true"];
+        assert_eq!(prompts.recv().await.unwrap(), expected);
+    }
+
+    /// The language tag of keyboard-interactive prompts is captured
+    /// only if `capture_language_tags` is set.
+    #[tokio::test]
+    async fn keyboard_interactive_language_tag() {
+        for &(capture, expected) in &[(false, None), (true, Some("fr"))] {
+            let (sender, mut languages) = unbounded_channel();
+            let config = client::Config {
+                capture_language_tags: capture,
+                ..Default::default()
+            };
+            let (mut client, _server) = test_util::connect(
+                test_util::server_config(),
+                TaggedPrompt,
+                config,
+                PromptLanguage(sender),
+            )
+            .await;
+            let auth = client.authenticate_keyboard_interactive("user", "");
+            assert!(!auth.await.unwrap());
+            let language = languages.recv().await.unwrap();
+            assert_eq!(language.as_deref(), expected);
+        }
+    }
+
+    /// Prompts built with `Prompt::visible` are echoed, and those
+    /// built with `Prompt::hidden` are not.
+    #[tokio::test]
+    async fn keyboard_interactive_echo() {
+        let (sender, mut prompts) = unbounded_channel();
+        let (mut client, _server) = test_util::connect(
+            test_util::server_config(),
+            MixedPrompts,
+            Default::default(),
+            Silent(sender),
+        )
+        .await;
+        let auth = client.authenticate_keyboard_interactive("user", "");
+        assert!(!auth.await.unwrap());
+        let expected = vec!["Login", "", "User:  true", "Password:  false"];
+        assert_eq!(prompts.recv().await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn several_keys() {
+        let keys: Vec<_> = (0..3)
+            .map(|_| Arc::new(key::KeyPair::generate_ed25519().unwrap()))
+            .collect();
+        let authorized = AuthorizedKey(keys[2].clone_public_key());
+        let (mut client, _server) = test_util::connect(
+            test_util::server_config(),
+            authorized,
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        // None of the first two keys is accepted.
+        let auth = client.authenticate_publickeys("user", keys[..2].to_vec());
+        assert!(!auth.await.unwrap());
+        let auth = client.authenticate_publickeys("user", keys);
+        assert!(auth.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn oversized_data() {
+        let config = client::Config {
+            maximum_packet_size: 1024,
+            ..Default::default()
+        };
+        let (mut client, _server) = test_util::connect(
+            test_util::server_config(),
+            LargeData(1100),
+            config,
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "true").await.unwrap();
+        // The data is rejected rather than passed on.
+        assert!(channel.wait().await.is_none());
+        let result = client.await;
+        let error = result
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<Error>());
+        assert!(matches!(error, Some(Error::PacketTooLarge)), "{:?}", result);
+    }
+
+    /// A client sending `len` bytes of data in a single packet on the
+    /// channels it opens, whatever the maximum packet size of the
+    /// server.
+    struct OversizedData(usize);
+
+    impl client::Handler for OversizedData {
+        client_futures!();
+
+        fn channel_open_confirmation(
+            self,
+            id: ChannelId,
+            mut session: client::Session,
+        ) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                let recipient_channel = enc.channels[&id].recipient_channel;
+                push_packet!(enc.write, {
+                    enc.write.push(msg::CHANNEL_DATA);
+                    enc.write.push_u32_be(recipient_channel);
+                    enc.write.extend_ssh_string(&vec![0; self.0]);
+                });
+            }
+            if let Some(channel) = session.channels.get(&id) {
+                channel.send(client::OpenChannelMsg::Open { id }).unwrap_or(());
+            }
+            self.finished(session)
+        }
+    }
+
+    /// The server rejects data packets larger than its maximum packet
+    /// size, including those too large to be read at all.
+    #[tokio::test]
+    async fn oversized_data_to_server() {
+        for &len in &[1100, 4096] {
+            let mut config = test_util::server_config();
+            config.maximum_packet_size = 1024;
+            let client = OversizedData(len);
+            let (mut client, server) =
+                test_util::connect(config, test_util::Server, Default::default(), client).await;
+            let auth = client.authenticate_password("user", test_util::PASSWORD);
+            assert!(auth.await.unwrap());
+            client.channel_open_session().await.unwrap();
+            let result = server.await.unwrap();
+            let error = result
+                .as_ref()
+                .err()
+                .and_then(|e| e.downcast_ref::<Error>());
+            assert!(matches!(error, Some(Error::PacketTooLarge)), "{}: {:?}", len, result);
+        }
+    }
+}
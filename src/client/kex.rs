@@ -61,7 +61,18 @@ impl KexInit {
         write_buffer: &mut SSHBuffer,
     ) -> Result<(), anyhow::Error> {
         self.exchange.client_kex_init.clear();
-        negotiation::write_kex(&config.preferred, &mut self.exchange.client_kex_init)?;
+        // Extension negotiation and strict key exchange are only
+        // offered in the first key exchange.
+        let extensions: &[&str] = if self.session_id.is_none() {
+            &[negotiation::EXT_INFO_C, negotiation::STRICT_KEX_C]
+        } else {
+            &[]
+        };
+        negotiation::write_kex(
+            &config.preferred,
+            extensions,
+            &mut self.exchange.client_kex_init,
+        )?;
         self.sent = true;
         cipher.write(&self.exchange.client_kex_init, write_buffer);
         Ok(())
@@ -14,12 +14,16 @@
 //
 
 use crate::auth;
+use crate::key::HostCertificate;
 use crate::negotiation;
 use crate::pty::Pty;
 use crate::session::*;
 use crate::ssh_read::SshRead;
 use crate::sshbuffer::*;
-use crate::{ChannelId, ChannelMsg, ChannelOpenFailure, Disconnect, Limits, Sig};
+use crate::{
+    ChannelId, ChannelInfo, ChannelMsg, ChannelOpenFailure, ConnectionStats, Disconnect, Limits,
+    NegotiatedAlgorithms, Sig,
+};
 use cryptovec::CryptoVec;
 use futures::task::{Context, Poll};
 use futures::Future;
@@ -43,11 +47,33 @@ mod session;
 
 use tokio::sync::mpsc::*;
 pub mod proxy;
+mod channel_stream;
+pub use self::channel_stream::ChannelStream;
 pub struct Session {
     common: CommonSession<Arc<Config>>,
     receiver: Receiver<Msg>,
     sender: UnboundedSender<Reply>,
     channels: HashMap<ChannelId, UnboundedSender<OpenChannelMsg>>,
+    /// Global requests sent with `want_reply`, waiting for a reply
+    /// (replies come in the same order as the requests).
+    pending_requests: std::collections::VecDeque<PendingRequest>,
+    /// The addresses and ports forwarded with `tcpip_forward` (port 0
+    /// until the server tells which port it bound). Forwarded
+    /// channels are refused for any other port.
+    forwards: Vec<(String, u32)>,
+    /// Keys to try after the current one, when authenticating with
+    /// `Handle::authenticate_publickeys`.
+    remaining_keys: std::collections::VecDeque<Arc<key::KeyPair>>,
+    /// The answer of the handler to the last request of the server
+    /// during authentication, if it answered.
+    auth_answer: Option<auth::Answer>,
+}
+
+/// A global request waiting for a reply from the server.
+enum PendingRequest {
+    Keepalive,
+    TcpIpForward { address: String, port: u32 },
+    CancelTcpIpForward { address: String, port: u32 },
 }
 
 impl Drop for Session {
@@ -70,6 +96,10 @@ enum Msg {
         user: String,
         method: auth::Method,
     },
+    AuthenticatePublicKeys {
+        user: String,
+        keys: Vec<Arc<key::KeyPair>>,
+    },
     Signed {
         data: CryptoVec,
     },
@@ -134,6 +164,12 @@ enum Msg {
         want_reply: bool,
         command: String,
     },
+    ExecWithEnv {
+        id: ChannelId,
+        want_reply: bool,
+        env: Vec<(String, String)>,
+        command: String,
+    },
     Signal {
         id: ChannelId,
         signal: Sig,
@@ -222,6 +258,36 @@ impl Handle {
         }
     }
 
+    /// Authenticate with the "keyboard-interactive" method, see
+    /// [RFC4256](https://tools.ietf.org/html/rfc4256). The prompts
+    /// sent by the server are passed to
+    /// `Handler::keyboard_interactive_prompts`, possibly over several
+    /// rounds. `submethods` is a hint for the server, usually empty.
+    pub async fn authenticate_keyboard_interactive<U: Into<String>, S: Into<String>>(
+        &mut self,
+        user: U,
+        submethods: S,
+    ) -> Result<bool, anyhow::Error> {
+        let user = user.into();
+        self.sender
+            .send(Msg::Authenticate {
+                user,
+                method: auth::Method::KeyboardInteractive {
+                    submethods: submethods.into(),
+                },
+            })
+            .await
+            .map_err(|_| Error::SendError)?;
+        loop {
+            match self.receiver.recv().await {
+                Some(Reply::AuthSuccess) => return Ok(true),
+                Some(Reply::AuthFailure) => return Ok(false),
+                None => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+
     pub async fn authenticate_publickey<U: Into<String>>(
         &mut self,
         user: U,
@@ -245,6 +311,32 @@ impl Handle {
         }
     }
 
+    /// Authenticate with the first of `keys` accepted by the server,
+    /// trying them in order. Each key is first offered to the server,
+    /// and only used to sign if the server accepts it.
+    pub async fn authenticate_publickeys<U: Into<String>>(
+        &mut self,
+        user: U,
+        keys: Vec<Arc<key::KeyPair>>,
+    ) -> Result<bool, anyhow::Error> {
+        if keys.is_empty() {
+            return Ok(false);
+        }
+        let user = user.into();
+        self.sender
+            .send(Msg::AuthenticatePublicKeys { user, keys })
+            .await
+            .map_err(|_| Error::SendError)?;
+        loop {
+            match self.receiver.recv().await {
+                Some(Reply::AuthSuccess) => return Ok(true),
+                Some(Reply::AuthFailure) => return Ok(false),
+                None => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+
     pub async fn authenticate_future<
         U: Into<String>,
         S: auth::Signer,
@@ -451,6 +543,30 @@ impl ChannelSender {
         Ok(())
     }
 
+    /// Set the given environment variables, then execute a remote
+    /// program. All these requests are sent together, in a single
+    /// write.
+    pub async fn exec_with_env<A: Into<String>>(
+        &mut self,
+        want_reply: bool,
+        env: &[(&str, &str)],
+        command: A,
+    ) -> Result<(), anyhow::Error> {
+        self.sender
+            .send(Msg::ExecWithEnv {
+                id: self.id,
+                want_reply,
+                env: env
+                    .iter()
+                    .map(|&(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                command: command.into(),
+            })
+            .await
+            .map_err(|_| Error::SendError)?;
+        Ok(())
+    }
+
     /// Signal a remote process.
     pub async fn signal(&mut self, signal: Sig) -> Result<(), anyhow::Error> {
         self.sender
@@ -584,9 +700,9 @@ impl ChannelSender {
         Ok(())
     }
 
-    /// Send data to a channel. The number of bytes added to the
-    /// "sending pipeline" (to be processed by the event loop) is
-    /// returned.
+    /// Send data to a channel. The data that does not fit in the
+    /// window of the channel is kept by the session, and sent as the
+    /// server adjusts the window.
     pub async fn data<B: AsRef<[u8]>>(&mut self, data: B) -> Result<(), anyhow::Error> {
         self.sender
             .send(Msg::Data {
@@ -633,6 +749,43 @@ impl Channel {
             }
         }
     }
+
+    /// Wait until the server closes this channel, and collect how the
+    /// remote command finished. Data received in the meantime is
+    /// discarded, use [`wait`](#method.wait) to read it first.
+    pub async fn wait_close(&mut self) -> CommandResult {
+        let mut result = CommandResult {
+            exit_status: None,
+            exit_signal: None,
+            eof: false,
+        };
+        while let Some(msg) = self.wait().await {
+            match msg {
+                ChannelMsg::ExitStatus { exit_status } => {
+                    result.exit_status = Some(exit_status);
+                }
+                ChannelMsg::ExitSignal { signal_name, .. } => {
+                    result.exit_signal = Some(signal_name);
+                }
+                ChannelMsg::Eof => result.eof = true,
+                _ => {}
+            }
+        }
+        result
+    }
+}
+
+/// How a remote command finished, as returned by
+/// [`Channel::wait_close`](struct.Channel.html#method.wait_close).
+#[derive(Debug)]
+pub struct CommandResult {
+    /// The exit status of the command, if the server sent one.
+    pub exit_status: Option<u32>,
+    /// The signal that killed the command, if any.
+    pub exit_signal: Option<Sig>,
+    /// Whether the server sent EOF before closing the channel, which
+    /// closes both the standard output and the standard error.
+    pub eof: bool,
 }
 
 impl std::ops::Deref for Channel {
@@ -692,6 +845,7 @@ where
     let sshid = stream.read_ssh_id().await?;
     let (sender, receiver) = channel(10);
     let (sender2, receiver2) = unbounded_channel();
+    let limits = config.limits.clone();
     let mut session = Session {
         common: CommonSession {
             write_buffer,
@@ -704,10 +858,23 @@ where
             wants_reply: false,
             disconnected: false,
             buffer: CryptoVec::new(),
+            peer_addr: None,
+            local_addr: None,
+            alive_timeouts: 0,
+            received_seqn: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            connected_at: std::time::Instant::now(),
+            limits,
+            strict_kex: false,
         },
         receiver,
         sender: sender2,
         channels: HashMap::new(),
+        pending_requests: std::collections::VecDeque::new(),
+        forwards: Vec::new(),
+        remaining_keys: std::collections::VecDeque::new(),
+        auth_answer: None,
     };
     session.read_ssh_id(sshid)?;
     Ok(Handle {
@@ -725,26 +892,74 @@ impl Session {
     ) -> Result<(), anyhow::Error> {
         self.flush()?;
         stream.write_all(&self.common.write_buffer.buffer).await?;
+        self.common.bytes_written += self.common.write_buffer.buffer.len() as u64;
         self.common.write_buffer.buffer.clear();
         let mut buffer = SSHBuffer::new();
         let mut handler = Some(handler);
+        let keepalive = self.common.config.keepalive_interval;
+        let keepalive_max = self.common.config.keepalive_max;
+        let mut keepalive_deadline = keepalive.map(|t| tokio::time::Instant::now() + t);
         while !self.common.disconnected {
+            let max_len = self.common.max_read_len(
+                self.common.config.max_handshake_packet_size,
+                self.common.config.maximum_packet_size,
+            );
             tokio::select! {
-                n = cipher::read(&mut stream, &mut buffer, &self.common.cipher) => {
+                n = cipher::read(&mut stream, &mut buffer, &self.common.cipher, max_len) => {
                     debug!("n = {:?}, {:?}", n, buffer.buffer.len());
-                    if buffer.buffer.len() < 5 || buffer.buffer[5] == crate::msg::DISCONNECT {
+                    keepalive_deadline = keepalive.map(|t| tokio::time::Instant::now() + t);
+                    self.common.bytes_read = buffer.bytes as u64;
+                    self.common.received_seqn = buffer.seqn.0.wrapping_sub(1);
+                    if buffer.buffer.len() < 5 {
                         break;
-                    } else if buffer.buffer[5] <= 4 {
+                    } else if buffer.buffer.get(5) == Some(&crate::msg::DISCONNECT) {
+                        self = read_disconnect(self, &mut handler, &buffer.buffer[5..]).await?;
+                        break;
+                    } else if buffer.buffer.len() > 5 && self.common.strict_kex_forbids(buffer.buffer[5]) {
+                        return Err(Error::StrictKex.into());
+                    } else if buffer.buffer.get(5) == Some(&crate::msg::DEBUG) {
+                        self = read_debug(self, &mut handler, &buffer.buffer[5..]).await?;
+                    } else if buffer.buffer.get(5) == Some(&crate::msg::IGNORE) {
+                        self = read_ignore(self, &mut handler, &buffer.buffer[5..]).await?;
+                    } else if buffer.buffer.get(5) == Some(&crate::msg::UNIMPLEMENTED) {
+                        self = read_unimplemented(self, &mut handler, &buffer.buffer[5..]).await?;
+                    } else if buffer.buffer.len() > 5 && buffer.buffer[5] <= 4 {
                         continue;
+                    } else {
+                        debug!("{:?}", buffer.buffer.get(5));
+                        self = reply(self, &mut handler, &buffer.buffer[5..]).await?;
+                        if buffer.buffer[5] == crate::msg::NEWKEYS && self.common.strict_kex {
+                            buffer.seqn = std::num::Wrapping(0);
+                        }
+                    }
+                }
+                _ = timeout_at(keepalive_deadline) => {
+                    keepalive_deadline = keepalive.map(|t| tokio::time::Instant::now() + t);
+                    let missed = self.common.alive_timeouts;
+                    if missed > 0 {
+                        let h = handler.take().unwrap();
+                        let (h, s) = h.keepalive_missed(missed, self).await?;
+                        handler = Some(h);
+                        self = s;
                     }
-                    debug!("{:?}", buffer.buffer[5]);
-                    self = reply(self, &mut handler, &buffer.buffer[5..]).await?;
+                    if missed >= keepalive_max {
+                        debug!("keepalive timeout");
+                        return Err(Error::KeepaliveTimeout.into());
+                    }
+                    self.keepalive_request();
                 }
                 msg = self.receiver.recv() => {
                     match msg {
                         Some(Msg::Authenticate { user, method }) => {
+                            self.remaining_keys.clear();
                             self.write_auth_request_if_needed(&user, method);
                         }
+                        Some(Msg::AuthenticatePublicKeys { user, keys }) => {
+                            self.remaining_keys = keys.into();
+                            if let Some(key) = self.remaining_keys.pop_front() {
+                                self.write_auth_request_if_needed(&user, auth::Method::PublicKey { key });
+                            }
+                        }
                         Some(Msg::Signed { .. }) => {},
                         Some(Msg::ChannelOpenSession { sender }) => {
                             debug!("msg::channelopensession");
@@ -768,7 +983,7 @@ impl Session {
                         Some(Msg::Disconnect { reason, description, language_tag }) => {
                             self.disconnect(reason, &description, &language_tag)
                         },
-                        Some(Msg::Data { data, id }) => { self.data(id, &data); },
+                        Some(Msg::Data { data, id }) => { self.data_flow_controlled(id, &data); },
                         Some(Msg::Eof { id }) => { self.eof(id); },
                         Some(Msg::ExtendedData { data, ext, id }) => { self.extended_data(id, ext, &data); },
                         Some(Msg::RequestPty { id, want_reply, term, col_width, row_height, pix_width, pix_height, terminal_modes }) => {
@@ -790,6 +1005,10 @@ impl Session {
                             debug!("exec {:?}", id);
                             self.exec(id, want_reply, &command)
                         },
+                        Some(Msg::ExecWithEnv { id, want_reply, env, command }) => {
+                            let env: Vec<_> = env.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+                            self.exec_with_env(id, want_reply, &env, &command)
+                        },
                         Some(Msg::Signal { id, signal }) => {
                             self.signal(id, signal)
                         },
@@ -806,6 +1025,7 @@ impl Session {
             }
             self.flush()?;
             stream.write_all(&self.common.write_buffer.buffer).await?;
+            self.common.bytes_written += self.common.write_buffer.buffer.len() as u64;
             buffer.buffer.clear();
             self.common.write_buffer.buffer.clear();
         }
@@ -815,7 +1035,11 @@ impl Session {
 
             // Shutdown
             buffer.buffer.clear();
-            while cipher::read(&mut stream, &mut buffer, &self.common.cipher).await? != 0 {
+            let max_len = self.common.max_read_len(
+                self.common.config.max_handshake_packet_size,
+                self.common.config.maximum_packet_size,
+            );
+            while cipher::read(&mut stream, &mut buffer, &self.common.cipher, max_len).await? != 0 {
                 buffer.buffer.clear();
             }
         }
@@ -823,6 +1047,15 @@ impl Session {
     }
 }
 
+/// Resolves at `deadline`, or never if `deadline` is `None`.
+async fn timeout_at(deadline: Option<tokio::time::Instant>) {
+    if let Some(deadline) = deadline {
+        tokio::time::delay_until(deadline).await
+    } else {
+        futures::future::pending().await
+    };
+}
+
 impl Session {
     fn read_ssh_id(&mut self, sshid: &[u8]) -> Result<(), anyhow::Error> {
         // self.read_buffer.bytes += sshid.bytes_read + 2;
@@ -852,19 +1085,26 @@ impl Session {
     /// buffer. This does *not* flush to the socket.
     fn flush(&mut self) -> Result<(), anyhow::Error> {
         if let Some(ref mut enc) = self.common.encrypted {
-            if enc.flush(
-                &self.common.config.as_ref().limits,
+            // Packets queued during a key exchange are sent with the
+            // new keys, once it is over.
+            if self.common.kex.is_some() {
+                return Ok(());
+            }
+            let limits_reached = enc.flush(
+                &self.common.limits,
                 &mut self.common.cipher,
                 &mut self.common.write_buffer,
-            ) {
+            );
+            if limits_reached || (enc.rekey_wanted && self.common.kex.is_none()) {
                 if let Some(exchange) = std::mem::replace(&mut enc.exchange, None) {
+                    enc.rekey_wanted = false;
                     let mut kexinit = KexInit::initiate_rekey(exchange, &enc.session_id);
                     kexinit.client_write(
                         &self.common.config.as_ref(),
                         &mut self.common.cipher,
                         &mut self.common.write_buffer,
                     )?;
-                    enc.rekey = Some(Kex::KexInit(kexinit))
+                    self.common.kex = Some(Kex::KexInit(kexinit))
                 }
             }
         }
@@ -883,12 +1123,30 @@ impl Session {
         buf: &[u8],
     ) -> Result<(), anyhow::Error> {
         let mut reader = buf.reader(1);
-        let pubkey = reader.read_string()?; // server public key.
-        let pubkey = parse_public_key(pubkey)?;
-        debug!("server_public_Key: {:?}", pubkey);
-        let h = handler.take().unwrap();
-        let (h, check) = h.check_server_key(&pubkey).await?;
-        *handler = Some(h);
+        let host_key = reader.read_string()?; // server public key or certificate.
+        let key_algo = kexdhdone.names.key.0;
+        let certified = negotiation::certificate_key(key_algo).is_some();
+        let (pubkey, check) = if certified {
+            let cert = crate::Certificate::parse(key_algo.as_bytes(), host_key)?;
+            debug!("server certificate: {:?}", cert);
+            if cert.cert_type != crate::SSH_CERT_TYPE_HOST
+                || !cert.is_valid_now()
+                || !cert.critical_options.is_empty()
+            {
+                return Err(Error::InvalidCertificate.into());
+            }
+            let h = handler.take().unwrap();
+            let (h, check) = h.check_server_certificate(&cert).await?;
+            *handler = Some(h);
+            (cert.key, check)
+        } else {
+            let pubkey = parse_public_key(host_key)?;
+            debug!("server_public_Key: {:?}", pubkey);
+            let h = handler.take().unwrap();
+            let (h, check) = h.check_server_key(&pubkey).await?;
+            *handler = Some(h);
+            (pubkey, check)
+        };
         if !check {
             return Err(Error::UnknownKey.into());
         }
@@ -904,11 +1162,20 @@ impl Session {
                     .kex
                     .compute_shared_secret(&kexdhdone.exchange.server_ephemeral)?;
                 debug!("kexdhdone.exchange = {:?}", kexdhdone.exchange);
-                let hash = kexdhdone.kex.compute_exchange_hash(
-                    &pubkey,
-                    &kexdhdone.exchange,
-                    &mut buffer,
-                )?;
+                // The exchange hash covers the host key as sent.
+                let hash = if certified {
+                    kexdhdone.kex.compute_exchange_hash(
+                        &HostCertificate(host_key),
+                        &kexdhdone.exchange,
+                        &mut buffer,
+                    )?
+                } else {
+                    kexdhdone.kex.compute_exchange_hash(
+                        &pubkey,
+                        &kexdhdone.exchange,
+                        &mut buffer,
+                    )?
+                };
                 debug!("exchange hash: {:?}", hash);
                 let signature = {
                     let mut sig_reader = signature.reader(0);
@@ -923,10 +1190,12 @@ impl Session {
                 }
                 hash
             };
+            crate::release_buffer(&mut buffer);
             let mut newkeys = kexdhdone.compute_keys(hash, false)?;
             self.common
                 .cipher
                 .write(&[msg::NEWKEYS], &mut self.common.write_buffer);
+            self.common.newkeys_sent();
             newkeys.sent = true;
             self.common.kex = Some(Kex::NewKeys(newkeys));
             Ok::<(), anyhow::Error>(())
@@ -935,27 +1204,115 @@ impl Session {
     }
 }
 
+/// Parse a disconnect message from the server, and pass it to the
+/// handler.
+async fn read_disconnect<H: Handler>(
+    mut session: Session,
+    handler: &mut Option<H>,
+    buf: &[u8],
+) -> Result<Session, anyhow::Error> {
+    let mut r = buf.reader(1);
+    let reason = r.read_u32()?;
+    let description = String::from_utf8_lossy(r.read_string()?);
+    let language = String::from_utf8_lossy(r.read_string()?);
+    debug!("disconnected: {:?} {:?}", reason, description);
+    if let Some(ref mut enc) = session.common.encrypted {
+        enc.capture_language_tag(
+            session.common.config.capture_language_tags,
+            language.as_bytes(),
+        );
+    }
+    // Unknown reason codes are reported as application disconnections.
+    let reason = Disconnect::from_u32(reason).unwrap_or(Disconnect::ByApplication);
+    let h = handler.take().unwrap();
+    let (h, session) = h.disconnected(reason, &description, &language, session).await?;
+    *handler = Some(h);
+    Ok(session)
+}
+
+/// Parse a debug message from the server, and pass it to the
+/// handler.
+async fn read_debug<H: Handler>(
+    mut session: Session,
+    handler: &mut Option<H>,
+    buf: &[u8],
+) -> Result<Session, anyhow::Error> {
+    let mut r = buf.reader(1);
+    let always_display = r.read_byte()? != 0;
+    let message = String::from_utf8_lossy(r.read_string()?);
+    let language = String::from_utf8_lossy(r.read_string()?);
+    if let Some(ref mut enc) = session.common.encrypted {
+        enc.capture_language_tag(
+            session.common.config.capture_language_tags,
+            language.as_bytes(),
+        );
+    }
+    let h = handler.take().unwrap();
+    let (h, session) = h.debug(always_display, &message, &language, session).await?;
+    *handler = Some(h);
+    Ok(session)
+}
+
+/// Pass the data of an ignore message from the server to the handler.
+/// A missing data string is read as empty.
+async fn read_ignore<H: Handler>(
+    session: Session,
+    handler: &mut Option<H>,
+    buf: &[u8],
+) -> Result<Session, anyhow::Error> {
+    let data = buf.reader(1).read_string().unwrap_or(&[]);
+    let h = handler.take().unwrap();
+    let (h, session) = h.ignore(data, session).await?;
+    *handler = Some(h);
+    Ok(session)
+}
+
+/// Pass the sequence number of an unimplemented message from the
+/// server to the handler.
+async fn read_unimplemented<H: Handler>(
+    session: Session,
+    handler: &mut Option<H>,
+    buf: &[u8],
+) -> Result<Session, anyhow::Error> {
+    let seqn = buf.reader(1).read_u32()?;
+    let h = handler.take().unwrap();
+    let (h, session) = h.unimplemented(seqn, session).await?;
+    *handler = Some(h);
+    Ok(session)
+}
+
 async fn reply<H: Handler>(
     mut session: Session,
     handler: &mut Option<H>,
     buf: &[u8],
 ) -> Result<Session, anyhow::Error> {
+    if buf.is_empty() {
+        return Err(Error::IndexOutOfBounds.into());
+    }
     match session.common.kex.take() {
         Some(Kex::KexInit(kexinit)) => {
             if kexinit.algo.is_some()
                 || buf[0] == msg::KEXINIT
                 || session.common.encrypted.is_none()
             {
-                session.common.kex = Some(Kex::KexDhDone(kexinit.client_parse(
+                let kexdhdone = kexinit.client_parse(
                     session.common.config.as_ref(),
                     &session.common.cipher,
                     buf,
                     &mut session.common.write_buffer,
-                )?));
+                )?;
+                session.common.start_strict_kex(&kexdhdone.names)?;
+                session.common.kex = Some(Kex::KexDhDone(kexdhdone));
                 session.flush()?;
+                debug!("reply {}", line!());
+                Ok(session)
+            } else {
+                // We've sent a KEXINIT to re-exchange keys, and the
+                // server has not answered yet: process its packets
+                // normally.
+                session.common.kex = Some(Kex::KexInit(kexinit));
+                session.client_read_encrypted(handler, buf).await
             }
-            debug!("reply {}", line!());
-            Ok(session)
         }
         Some(Kex::KexDhDone(mut kexdhdone)) => {
             if kexdhdone.names.ignore_guessed {
@@ -979,16 +1336,37 @@ async fn reply<H: Handler>(
             if buf[0] != msg::NEWKEYS {
                 return Err(Error::Kex.into());
             }
+            let first_kex = session.common.encrypted.is_none();
+            let send_ext_info = newkeys.names.ext_info
+                && first_kex
+                && !session.common.config.extensions.is_empty();
             session.common.encrypted(
                 EncryptedState::WaitingServiceRequest { accepted: false },
                 newkeys,
             );
-            // Ok, NEWKEYS received, now encrypted.
-            let p = b"\x05\0\0\0\x0Cssh-userauth";
-            session
-                .common
-                .cipher
-                .write(p, &mut session.common.write_buffer);
+            // EXT_INFO is the first packet after our first NEWKEYS.
+            if send_ext_info {
+                let mut ext_info = CryptoVec::new();
+                negotiation::write_ext_info(&session.common.config.extensions, &mut ext_info);
+                session
+                    .common
+                    .cipher
+                    .write(&ext_info, &mut session.common.write_buffer);
+            }
+            // Ok, NEWKEYS received, now encrypted. The service
+            // request is only sent after the first key exchange.
+            if first_kex {
+                let p = b"\x05\0\0\0\x0Cssh-userauth";
+                session
+                    .common
+                    .cipher
+                    .write(p, &mut session.common.write_buffer);
+            } else if let Some(algorithms) = session.common.algorithms() {
+                let h = handler.take().unwrap();
+                let (h, s) = h.rekey_completed(&algorithms, session).await?;
+                *handler = Some(h);
+                session = s;
+            }
                 debug!("reply {}", line!());
             Ok(session)
         }
@@ -1013,12 +1391,35 @@ pub struct Config {
     pub limits: Limits,
     /// The initial size of a channel (used for flow control).
     pub window_size: u32,
-    /// The maximal size of a single packet.
+    /// The maximal size of a single packet. Once the user is
+    /// authenticated, larger channel data packets close the
+    /// connection.
     pub maximum_packet_size: u32,
+    /// The maximal size of the packets received before the user is
+    /// authenticated. Larger packets close the connection.
+    pub max_handshake_packet_size: usize,
     /// Lists of preferred algorithms.
     pub preferred: negotiation::Preferred,
     /// Time after which the connection is garbage-collected.
     pub connection_timeout: Option<std::time::Duration>,
+    /// Send an explicit `ssh-connection` service request after
+    /// authentication, which some servers expect.
+    pub request_connection_service: bool,
+    /// Send a `keepalive@openssh.com` request to the server after
+    /// this much time without receiving anything.
+    pub keepalive_interval: Option<std::time::Duration>,
+    /// Give up on the connection, with `Error::KeepaliveTimeout`, after
+    /// this many unanswered keepalive requests.
+    pub keepalive_max: usize,
+    /// Extensions (name and value) sent to the server in
+    /// `SSH_MSG_EXT_INFO` ([RFC8308](https://tools.ietf.org/html/rfc8308))
+    /// after the first key exchange, if the server supports it.
+    pub extensions: Vec<(String, Vec<u8>)>,
+    /// Keep the language tags sent by the server (in banners,
+    /// password change and keyboard-interactive requests, channel
+    /// open failures and debug messages), see
+    /// `Session::peer_language_tag`. They are ignored by default.
+    pub capture_language_tags: bool,
 }
 
 impl Default for Config {
@@ -1032,8 +1433,14 @@ impl Default for Config {
             limits: Limits::default(),
             window_size: 200000,
             maximum_packet_size: 200000,
+            max_handshake_packet_size: 256 * 1024,
             preferred: Default::default(),
             connection_timeout: None,
+            request_connection_service: false,
+            keepalive_interval: None,
+            keepalive_max: 3,
+            extensions: Vec::new(),
+            capture_language_tags: false,
         }
     }
 }
@@ -1060,14 +1467,74 @@ pub trait Handler: Sized {
     /// Called when the server sends us an authentication banner. This
     /// is usually meant to be shown to the user, see
     /// [RFC4252](https://tools.ietf.org/html/rfc4252#section-5.4) for
-    /// more details.
-    ///
-    /// The returned Boolean is ignored.
+    /// more details. Invalid UTF-8 in the banner is replaced with
+    /// `U+FFFD`.
     #[allow(unused_variables)]
     fn auth_banner(self, banner: &str, session: Session) -> Self::FutureUnit {
         self.finished(session)
     }
 
+    /// Called when the server sends us an authentication banner,
+    /// along with its language tag (usually empty). The default
+    /// implementation calls `auth_banner`.
+    #[allow(unused_variables)]
+    fn auth_banner_with_language(
+        self,
+        banner: &str,
+        language: &str,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.auth_banner(banner, session)
+    }
+
+    /// Called when the server accepts our password, but requires us
+    /// to change it, see
+    /// [RFC4252](https://tools.ietf.org/html/rfc4252#section-8). The
+    /// new password is sent by calling `session.change_password`;
+    /// if this method doesn't, authentication fails.
+    #[allow(unused_variables)]
+    fn password_change_requested(
+        self,
+        prompt: &str,
+        language: &str,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the server sends prompts during
+    /// keyboard-interactive authentication, with a pair `(prompt,
+    /// echo)` for each of them, where `echo` tells whether the
+    /// answer may be shown as it is typed. The answers are sent by
+    /// calling `session.keyboard_interactive_responses`, even when
+    /// there are no prompts; if this method doesn't, authentication
+    /// fails. The server may send several rounds of prompts.
+    #[allow(unused_variables)]
+    fn keyboard_interactive_prompts(
+        self,
+        name: &str,
+        instructions: &str,
+        prompts: &[(&str, bool)],
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the server accepted the last authentication
+    /// method, but requires another one from `remaining_methods`
+    /// (multi-factor authentication). The pending `authenticate_*`
+    /// call of the `Handle` then returns `false`, and the client
+    /// should continue with the next method rather than retry this
+    /// one.
+    #[allow(unused_variables)]
+    fn auth_partial_success(
+        self,
+        remaining_methods: auth::MethodSet,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
     /// Called to check the server's public key. This is a very important
     /// step to help prevent man-in-the-middle attacks. The default
     /// implementation rejects all keys.
@@ -1076,6 +1543,30 @@ pub trait Handler: Sized {
         self.finished_bool(false)
     }
 
+    /// Called instead of `check_server_key` when the server presents
+    /// an OpenSSH host certificate, which only happens if a
+    /// certificate algorithm such as
+    /// [`ED25519_CERT`](../constant.ED25519_CERT.html) is listed in
+    /// `config.preferred.key`. This is only called for host
+    /// certificates signed by their certificate authority, currently
+    /// valid and without critical options: the handler must still
+    /// check that it trusts `cert.signature_key`, and that the name
+    /// of the server is in `cert.valid_principals`. The default
+    /// implementation rejects all certificates.
+    #[allow(unused_variables)]
+    fn check_server_certificate(self, cert: &crate::Certificate) -> Self::FutureBool {
+        self.finished_bool(false)
+    }
+
+    /// Called when the server advertises all its host keys, after
+    /// authentication (see the `hostkeys-00@openssh.com` extension of
+    /// OpenSSH). This can be used to learn the new keys of a server
+    /// before it rotates them.
+    #[allow(unused_variables)]
+    fn hostkeys(self, keys: &[key::PublicKey], session: Session) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
     /// Called when the server confirmed our request to open a
     /// channel. A channel can only be written to after receiving this
     /// message (this library panics otherwise).
@@ -1126,7 +1617,26 @@ pub trait Handler: Sized {
         self.finished(session)
     }
 
-    /// Called when a new channel is created.
+    /// Called when the server opens a channel for a connection to a
+    /// port forwarded with `tcpip_forward`, before the channel is
+    /// confirmed. The channel is refused if this returns `false`.
+    /// Channels for ports that were not forwarded are refused without
+    /// calling this.
+    #[allow(unused_variables)]
+    fn check_forwarded_tcpip(
+        self,
+        connected_address: &str,
+        connected_port: u32,
+        originator_address: &str,
+        originator_port: u32,
+    ) -> Self::FutureBool {
+        self.finished_bool(true)
+    }
+
+    /// Called when the server opens a channel for a connection to a
+    /// port forwarded with `tcpip_forward`, once
+    /// `check_forwarded_tcpip` accepted it. The confirmation of the
+    /// channel is already queued.
     #[allow(unused_variables)]
     fn channel_open_forwarded_tcpip(
         self,
@@ -1140,6 +1650,34 @@ pub trait Handler: Sized {
         self.finished(session)
     }
 
+    /// Called when the server replies to a `tcpip-forward` request
+    /// sent with `want_reply`. `bound_port` is the port the server
+    /// listens on (chosen by the server if `port` was 0), or `None` if
+    /// the server refused the request.
+    #[allow(unused_variables)]
+    fn tcpip_forward_reply(
+        self,
+        address: &str,
+        port: u32,
+        bound_port: Option<u32>,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the server replies to a `cancel-tcpip-forward`
+    /// request sent with `want_reply`.
+    #[allow(unused_variables)]
+    fn cancel_tcpip_forward_reply(
+        self,
+        address: &str,
+        port: u32,
+        success: bool,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
     /// Called when the server sends us data. The `extended_code`
     /// parameter is a stream identifier, `None` is usually the
     /// standard output, and `Some(1)` is the standard error. See
@@ -1194,6 +1732,104 @@ pub trait Handler: Sized {
         self.finished(session)
     }
 
+    /// Called when the server sends a channel request this library
+    /// does not handle, such as a protocol extension. `data` is the
+    /// rest of the request. If `want_reply` is set, reply with
+    /// `session.channel_success(channel)`; requests that were not
+    /// answered are refused.
+    #[allow(unused_variables)]
+    fn channel_request(
+        self,
+        channel: ChannelId,
+        request: &str,
+        want_reply: bool,
+        data: &[u8],
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the server accepts a channel request sent with
+    /// `want_reply`.
+    #[allow(unused_variables)]
+    fn channel_success(self, channel: ChannelId, session: Session) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the server refuses a channel request sent with
+    /// `want_reply`.
+    #[allow(unused_variables)]
+    fn channel_failure(self, channel: ChannelId, session: Session) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when a keepalive interval elapses while `count`
+    /// keepalive requests are still unanswered. The connection is
+    /// closed once `count` reaches `Config::keepalive_max`.
+    #[allow(unused_variables)]
+    fn keepalive_missed(self, count: usize, session: Session) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the server disconnects, with the reason code,
+    /// description and language tag of its disconnect message.
+    #[allow(unused_variables)]
+    fn disconnected(
+        self,
+        reason: Disconnect,
+        description: &str,
+        language: &str,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the server sends a debug message
+    /// (`SSH_MSG_DEBUG`, see
+    /// [RFC4253](https://tools.ietf.org/html/rfc4253#section-11.3)),
+    /// for instance to explain why authentication failed.
+    /// `always_display` asks for the message to be shown even when
+    /// debugging is not enabled.
+    #[allow(unused_variables)]
+    fn debug(
+        self,
+        always_display: bool,
+        message: &str,
+        language: &str,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the server sends an ignore message
+    /// (`SSH_MSG_IGNORE`, see
+    /// [RFC4253](https://tools.ietf.org/html/rfc4253#section-11.2)),
+    /// which is meant to be discarded, for instance cover traffic.
+    #[allow(unused_variables)]
+    fn ignore(self, data: &[u8], session: Session) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the server did not implement one of our packets
+    /// (`SSH_MSG_UNIMPLEMENTED`, see
+    /// [RFC4253](https://tools.ietf.org/html/rfc4253#section-11.4)),
+    /// with the sequence number of that packet.
+    #[allow(unused_variables)]
+    fn unimplemented(self, seqn: u32, session: Session) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when a key re-exchange has completed (but not after the
+    /// initial key exchange), with the newly negotiated algorithms.
+    #[allow(unused_variables)]
+    fn rekey_completed(
+        self,
+        algorithms: &NegotiatedAlgorithms,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
     /// The remote process has exited, with the given exit status.
     #[allow(unused_variables)]
     fn exit_status(
@@ -1247,3 +1883,2662 @@ pub trait Handler: Sized {
         self.finished(session)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::negotiation::{DEFAULT, ED25519_CERT};
+    use std::borrow::Cow;
+    use crate::server;
+    use crate::{test_util, Certificate, SSH_CERT_TYPE_HOST};
+    use crate::key::PubKey;
+
+    /// A client trusting the host certificates signed by a CA.
+    struct TrustCa(key::PublicKey);
+
+    impl Handler for TrustCa {
+        client_futures!();
+
+        fn check_server_certificate(self, cert: &Certificate) -> Self::FutureBool {
+            let trusted = cert.signature_key == self.0 && cert.allows_principal("localhost");
+            self.finished_bool(trusted)
+        }
+    }
+
+    #[tokio::test]
+    async fn host_certificate() {
+        let ca = key::KeyPair::generate_ed25519().unwrap();
+        let other_ca = key::KeyPair::generate_ed25519().unwrap();
+        for &(signer, trusted) in &[(&ca, true), (&other_ca, false)] {
+            let mut server_config = test_util::server_config();
+            server_config.preferred = negotiation::Preferred {
+                key: Cow::Borrowed(&[ED25519_CERT, key::ED25519]),
+                ..DEFAULT
+            };
+            let cert = test_util::certificate(
+                &server_config.keys[0],
+                signer,
+                SSH_CERT_TYPE_HOST,
+                &["localhost"],
+                0,
+                u64::MAX,
+            );
+            server_config.host_certificates.push(cert);
+            let client_config = Config {
+                preferred: negotiation::Preferred {
+                    key: Cow::Borrowed(&[ED25519_CERT]),
+                    ..DEFAULT
+                },
+                ..Default::default()
+            };
+            let handler = TrustCa(ca.clone_public_key());
+            let (mut client, _) =
+                test_util::connect(server_config, test_util::Server, client_config, handler).await;
+            let authenticated = client
+                .authenticate_password("user", test_util::PASSWORD)
+                .await
+                .unwrap_or(false);
+            assert_eq!(authenticated, trusted);
+        }
+    }
+
+    /// A server accepting forwardings of port 0 on port 2222, and
+    /// opening a forwarded channel right away.
+    struct Forward;
+
+    impl server::Handler for Forward {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn tcpip_forward(
+            self,
+            address: &str,
+            _: u32,
+            mut session: server::Session,
+        ) -> Self::FutureBool {
+            session.set_bound_port(2222);
+            session
+                .channel_open_forwarded_tcpip(address, 2222, "10.0.0.1", 4321)
+                .unwrap();
+            self.finished_bool(true, session)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Reply(Option<u32>),
+        Check(String),
+        Open(String, u32),
+    }
+
+    /// A client reporting the replies to its forwarding requests, and
+    /// the forwarded channels.
+    struct Forwarded(UnboundedSender<Event>);
+
+    impl Handler for Forwarded {
+        client_futures!();
+
+        fn tcpip_forward_reply(
+            self,
+            _: &str,
+            _: u32,
+            bound_port: Option<u32>,
+            session: Session,
+        ) -> Self::FutureUnit {
+            self.0.send(Event::Reply(bound_port)).unwrap();
+            self.finished(session)
+        }
+
+        fn channel_open_forwarded_tcpip(
+            self,
+            _: ChannelId,
+            connected_address: &str,
+            connected_port: u32,
+            _: &str,
+            _: u32,
+            session: Session,
+        ) -> Self::FutureUnit {
+            let event = Event::Open(connected_address.to_string(), connected_port);
+            self.0.send(event).unwrap();
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn tcpip_forward() {
+        let (sender, mut events) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            Forward,
+            Config::default(),
+            Forwarded(sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.tcpip_forward(true, "127.0.0.1", 0).await.unwrap();
+        let open = Event::Open("127.0.0.1".to_string(), 2222);
+        assert_eq!(events.recv().await, Some(open));
+        assert_eq!(events.recv().await, Some(Event::Reply(Some(2222))));
+    }
+
+    /// A server accepting forwardings, and opening forwarded channels
+    /// for another port, another address and the forwarded port (twice)
+    /// right away. It reports which of these channels are still open
+    /// when the client opens a session channel.
+    struct ForwardMany {
+        opened: Vec<ChannelId>,
+        states: UnboundedSender<Vec<Option<bool>>>,
+    }
+
+    impl server::Handler for ForwardMany {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn tcpip_forward(
+            mut self,
+            address: &str,
+            port: u32,
+            mut session: server::Session,
+        ) -> Self::FutureBool {
+            for &(address, port, originator) in &[
+                (address, 9999, "10.0.0.1"),
+                ("10.9.9.9", port, "10.0.0.1"),
+                (address, port, "10.0.0.66"),
+                (address, port, "10.0.0.1"),
+            ] {
+                let channel = session
+                    .channel_open_forwarded_tcpip(address, port, originator, 4321)
+                    .unwrap();
+                self.opened.push(channel);
+            }
+            self.finished_bool(true, session)
+        }
+
+        fn channel_open_session(
+            self,
+            _: ChannelId,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            let states = self
+                .opened
+                .iter()
+                .map(|&c| session.channel_info(c).map(|info| info.confirmed))
+                .collect();
+            self.states.send(states).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A client refusing the forwarded channels from 10.0.0.66, and
+    /// reporting the channels it checks and opens.
+    struct CheckForwarded(UnboundedSender<Event>);
+
+    impl Handler for CheckForwarded {
+        client_futures!();
+
+        fn check_forwarded_tcpip(
+            self,
+            _: &str,
+            _: u32,
+            originator_address: &str,
+            _: u32,
+        ) -> Self::FutureBool {
+            self.0.send(Event::Check(originator_address.to_string())).unwrap();
+            let accept = originator_address != "10.0.0.66";
+            self.finished_bool(accept)
+        }
+
+        fn channel_open_forwarded_tcpip(
+            self,
+            _: ChannelId,
+            connected_address: &str,
+            connected_port: u32,
+            _: &str,
+            _: u32,
+            session: Session,
+        ) -> Self::FutureUnit {
+            let event = Event::Open(connected_address.to_string(), connected_port);
+            self.0.send(event).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// Forwarded channels for ports that were not forwarded are
+    /// refused without asking the handler, and the handler can refuse
+    /// the others.
+    #[tokio::test]
+    async fn forwarded_tcpip_refused() {
+        let (sender, mut events) = unbounded_channel();
+        let (states, mut states_receiver) = unbounded_channel();
+        let server_handler = ForwardMany {
+            opened: Vec::new(),
+            states,
+        };
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            server_handler,
+            Config::default(),
+            CheckForwarded(sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.tcpip_forward(false, "127.0.0.1", 8022).await.unwrap();
+        for event in &[
+            Event::Check("10.0.0.66".to_string()),
+            Event::Check("10.0.0.1".to_string()),
+            Event::Open("127.0.0.1".to_string(), 8022),
+        ] {
+            assert_eq!(events.recv().await.as_ref(), Some(event));
+        }
+        // The server has read the replies to its channel opens when
+        // it gets the next session channel: only the last channel was
+        // confirmed, the others were refused.
+        assert_eq!(states_receiver.recv().await, Some(Vec::new()));
+        client.channel_open_session().await.unwrap();
+        let states = vec![None, None, None, Some(true)];
+        assert_eq!(states_receiver.recv().await, Some(states));
+    }
+
+    /// A server accepting all forwardings, binding port 0 to port
+    /// 2222 on "localhost" only.
+    struct BindPorts;
+
+    impl server::Handler for BindPorts {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn tcpip_forward(
+            self,
+            address: &str,
+            _: u32,
+            mut session: server::Session,
+        ) -> Self::FutureBool {
+            if address == "localhost" {
+                session.set_bound_port(2222);
+            }
+            self.finished_bool(true, session)
+        }
+    }
+
+    /// Replies to forwardings of port 0 carry the bound port, and
+    /// fail if the handler bound none.
+    #[tokio::test]
+    async fn tcpip_forward_bound_port() {
+        let (sender, mut events) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            BindPorts,
+            Config::default(),
+            Forwarded(sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        for &(address, port, reply) in &[
+            ("localhost", 0, Some(2222)),
+            ("127.0.0.1", 0, None),
+            ("127.0.0.1", 8022, Some(8022)),
+        ] {
+            channel.tcpip_forward(true, address, port).await.unwrap();
+            assert_eq!(events.recv().await, Some(Event::Reply(reply)));
+        }
+    }
+
+    /// A server finishing `ok` commands with EOF and exit status 0,
+    /// and `kill` commands with an exit signal only.
+    struct Commands;
+
+    impl server::Handler for Commands {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            data: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            if data == b"kill" {
+                session.exit_signal_request(channel, Sig::KILL, false, "killed", "");
+            } else {
+                session.eof(channel);
+                session.exit_status_request(channel, 0);
+            }
+            session.close(channel);
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn wait_close() {
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            Commands,
+            Config::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "ok").await.unwrap();
+        let result = channel.wait_close().await;
+        assert_eq!(result.exit_status, Some(0));
+        assert!(result.exit_signal.is_none());
+        assert!(result.eof);
+
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "kill").await.unwrap();
+        let result = channel.wait_close().await;
+        assert_eq!(result.exit_status, None);
+        assert!(matches!(result.exit_signal, Some(Sig::KILL)));
+        assert!(!result.eof);
+    }
+
+    /// Channel requests sent after EOF are still delivered, until the
+    /// channel is closed.
+    #[tokio::test]
+    async fn exit_status_after_eof() {
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            Commands,
+            Config::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "ok").await.unwrap();
+        assert!(matches!(channel.wait().await, Some(ChannelMsg::Eof)));
+        assert!(matches!(
+            channel.wait().await,
+            Some(ChannelMsg::ExitStatus { exit_status: 0 })
+        ));
+        assert!(channel.wait().await.is_none());
+    }
+
+    type Extensions = Vec<(String, Vec<u8>)>;
+
+    /// A server reporting the extensions of the client when it starts
+    /// authenticating.
+    struct ClientExtensions(UnboundedSender<Extensions>);
+
+    impl server::Handler for ClientExtensions {
+        server_futures!();
+
+        fn auth_banner_for(self, _: &str, session: server::Session) -> Self::FutureUnit {
+            self.0.send(session.peer_extensions().to_vec()).unwrap();
+            self.finished(session)
+        }
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+    }
+
+    /// A client reporting the extensions of the server when it
+    /// receives the authentication banner.
+    struct ServerExtensions(UnboundedSender<Extensions>);
+
+    impl Handler for ServerExtensions {
+        client_futures!();
+
+        fn auth_banner(self, _: &str, session: Session) -> Self::FutureUnit {
+            self.0.send(session.peer_extensions().to_vec()).unwrap();
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_extensions() {
+        let client_extension = ("client@example.com".to_string(), b"v1".to_vec());
+        let server_extension = ("server@example.com".to_string(), b"v2".to_vec());
+        let mut server_config = test_util::server_config();
+        server_config.auth_banner = Some("banner");
+        server_config.extensions.push(server_extension.clone());
+        let mut client_config = Config::default();
+        client_config.extensions.push(client_extension.clone());
+        let (server_sender, mut server_received) = unbounded_channel();
+        let (client_sender, mut client_received) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            server_config,
+            ClientExtensions(server_sender),
+            client_config,
+            ServerExtensions(client_sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let received = server_received.recv().await.unwrap();
+        assert!(received.contains(&client_extension));
+        let received = client_received.recv().await.unwrap();
+        assert!(received.contains(&server_extension));
+    }
+
+    /// A server re-exchanging keys on each command.
+    struct RekeyOnExec;
+
+    impl server::Handler for RekeyOnExec {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            session.request_rekey();
+            session.close(channel);
+            self.finished(session)
+        }
+    }
+
+    /// A client reporting (and then forgetting) the extensions of the
+    /// server received before the banner and before each channel
+    /// confirmation, and `None` when a key re-exchange completes.
+    struct ExtInfoCount(UnboundedSender<Option<Extensions>>);
+
+    impl ExtInfoCount {
+        fn report(&self, session: &mut Session) {
+            if let Some(ref mut enc) = session.common.encrypted {
+                let extensions = std::mem::take(&mut enc.peer_extensions);
+                self.0.send(Some(extensions)).unwrap();
+            }
+        }
+    }
+
+    impl Handler for ExtInfoCount {
+        client_futures!();
+
+        fn auth_banner(self, _: &str, mut session: Session) -> Self::FutureUnit {
+            self.report(&mut session);
+            self.finished(session)
+        }
+
+        fn channel_open_confirmation(
+            self,
+            id: ChannelId,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            self.report(&mut session);
+            if let Some(channel) = session.channels.get(&id) {
+                channel.send(OpenChannelMsg::Open { id }).unwrap_or(());
+            }
+            self.finished(session)
+        }
+
+        fn rekey_completed(self, _: &NegotiatedAlgorithms, session: Session) -> Self::FutureUnit {
+            self.0.send(None).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// The server sends EXT_INFO, with `server-sig-algs`, after its
+    /// first NEWKEYS only, even to clients offering extension
+    /// negotiation in every KEXINIT.
+    #[tokio::test]
+    async fn ext_info_once() {
+        let mut server_config = test_util::server_config();
+        server_config.auth_banner = Some("banner");
+        let client_config = Config {
+            preferred: negotiation::Preferred {
+                kex: Cow::Borrowed(&[crate::kex::CURVE25519, crate::kex::EXT_INFO_C]),
+                ..DEFAULT
+            },
+            ..Default::default()
+        };
+        let (sender, mut received) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            server_config,
+            RekeyOnExec,
+            client_config,
+            ExtInfoCount(sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let first = received.recv().await.unwrap().unwrap();
+        assert!(first.iter().any(|(name, _)| name == negotiation::SERVER_SIG_ALGS));
+
+        let mut channel = client.channel_open_session().await.unwrap();
+        assert_eq!(received.recv().await, Some(Some(Vec::new())));
+        channel.exec(false, "rekey").await.unwrap();
+        while channel.wait().await.is_some() {}
+        let timeout = std::time::Duration::from_secs(5);
+        let rekey = tokio::time::timeout(timeout, received.recv()).await.unwrap();
+        assert_eq!(rekey, Some(None));
+
+        // Anything sent by the server after its NEWKEYS is received
+        // before this confirmation.
+        client.channel_open_session().await.unwrap();
+        assert_eq!(received.recv().await, Some(Some(Vec::new())));
+    }
+
+    /// A server sending a banner that is not valid UTF-8, before
+    /// accepting the authentication service.
+    struct EarlyBanner;
+
+    impl server::Handler for EarlyBanner {
+        server_futures!();
+
+        fn service_request(self, _: &str, mut session: server::Session) -> Self::FutureBool {
+            if let Some(ref mut enc) = session.common.encrypted {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::USERAUTH_BANNER);
+                    enc.write.extend_ssh_string(b"caf\xe9");
+                    enc.write.extend_ssh_string(b"fr");
+                });
+            }
+            self.finished_bool(true, session)
+        }
+    }
+
+    /// A client reporting banners and their language tags.
+    struct Banners(UnboundedSender<(String, String)>);
+
+    impl Handler for Banners {
+        client_futures!();
+
+        fn auth_banner_with_language(
+            self,
+            banner: &str,
+            language: &str,
+            session: Session,
+        ) -> Self::FutureUnit {
+            self.0
+                .send((banner.to_string(), language.to_string()))
+                .unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A client reporting banners through `auth_banner`.
+    struct PlainBanners(UnboundedSender<String>);
+
+    impl Handler for PlainBanners {
+        client_futures!();
+
+        fn auth_banner(self, banner: &str, session: Session) -> Self::FutureUnit {
+            self.0.send(banner.to_string()).unwrap();
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn early_banner() {
+        let (sender, mut banners) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            EarlyBanner,
+            Config::default(),
+            Banners(sender),
+        )
+        .await;
+        assert!(!client.authenticate_password("user", "").await.unwrap());
+        let banner = ("caf\u{fffd}".to_string(), "fr".to_string());
+        assert_eq!(banners.recv().await, Some(banner));
+
+        let (sender, mut banners) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            EarlyBanner,
+            Config::default(),
+            PlainBanners(sender),
+        )
+        .await;
+        assert!(!client.authenticate_password("user", "").await.unwrap());
+        assert_eq!(banners.recv().await, Some("caf\u{fffd}".to_string()));
+    }
+
+    /// A server greeting each user with a banner, and accepting
+    /// `test_util::PASSWORD` only.
+    struct UserBanners;
+
+    impl server::Handler for UserBanners {
+        server_futures!();
+
+        fn auth_banner_for(self, user: &str, mut session: server::Session) -> Self::FutureUnit {
+            session.send_auth_banner(&format!("Hello {}", user), "en");
+            self.finished(session)
+        }
+
+        fn auth_password(self, _: &str, password: &str) -> Self::FutureAuth {
+            self.finished_auth(if password == test_util::PASSWORD {
+                server::Auth::Accept
+            } else {
+                server::Auth::Reject
+            })
+        }
+    }
+
+    /// Per-user banners are sent after the configured banner, and
+    /// again when the client changes user names.
+    #[tokio::test]
+    async fn user_banner() {
+        let mut server_config = test_util::server_config();
+        server_config.auth_banner = Some("Welcome");
+        let (sender, mut banners) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            server_config,
+            UserBanners,
+            Config::default(),
+            Banners(sender),
+        )
+        .await;
+        assert!(!client.authenticate_password("alice", "").await.unwrap());
+        assert!(!client.authenticate_password("alice", "").await.unwrap());
+        let password = test_util::PASSWORD;
+        assert!(client.authenticate_password("bob", password).await.unwrap());
+        let banner = |b: &str, l: &str| Some((b.to_string(), l.to_string()));
+        assert_eq!(banners.recv().await, banner("Welcome", ""));
+        assert_eq!(banners.recv().await, banner("Hello alice", "en"));
+        assert_eq!(banners.recv().await, banner("Hello bob", "en"));
+        assert!(banners.try_recv().is_err());
+    }
+
+    /// A client reporting banners and disconnections.
+    struct Disconnections(UnboundedSender<String>);
+
+    impl Handler for Disconnections {
+        client_futures!();
+
+        fn auth_banner(self, banner: &str, session: Session) -> Self::FutureUnit {
+            self.0.send(format!("banner {}", banner)).unwrap();
+            self.finished(session)
+        }
+
+        fn disconnected(
+            self,
+            reason: Disconnect,
+            description: &str,
+            _: &str,
+            session: Session,
+        ) -> Self::FutureUnit {
+            let disconnected = format!("disconnected {:?} {}", reason, description);
+            self.0.send(disconnected).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// In maintenance mode, the server sends its message as a banner,
+    /// and disconnects.
+    #[tokio::test]
+    async fn maintenance_mode() {
+        let mut server_config = test_util::server_config();
+        server_config.maintenance_mode = Some("maintenance".to_string());
+        let (sender, mut events) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            server_config,
+            test_util::Server,
+            Config::default(),
+            Disconnections(sender),
+        )
+        .await;
+        let password = test_util::PASSWORD;
+        assert!(!client.authenticate_password("user", password).await.unwrap_or(false));
+        assert_eq!(events.recv().await.unwrap(), "banner maintenance");
+        let disconnected = "disconnected ServiceNotAvailable maintenance";
+        assert_eq!(events.recv().await.unwrap(), disconnected);
+    }
+
+    /// A client sending a publickey authentication request for
+    /// `service` along with its first authentication request.
+    struct PublickeyService(&'static [u8]);
+
+    impl Handler for PublickeyService {
+        client_futures!();
+
+        fn auth_banner(self, _: &str, mut session: Session) -> Self::FutureUnit {
+            let key = key::KeyPair::generate_ed25519().unwrap();
+            if let Some(ref mut enc) = session.common.encrypted {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::USERAUTH_REQUEST);
+                    enc.write.extend_ssh_string(b"user");
+                    enc.write.extend_ssh_string(self.0);
+                    enc.write.extend_ssh_string(b"publickey");
+                    enc.write.push(0);
+                    enc.write.extend_ssh_string(key.name().as_bytes());
+                    key.push_to(&mut enc.write);
+                })
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A server accepting all public keys, reporting the keys it is
+    /// asked about, and accepting `test_util::PASSWORD`.
+    struct PublickeyQueries(UnboundedSender<()>);
+
+    impl server::Handler for PublickeyQueries {
+        server_futures!();
+
+        fn auth_publickey(self, _: &str, _: &key::PublicKey) -> Self::FutureAuth {
+            self.0.send(()).unwrap();
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn auth_password(self, _: &str, password: &str) -> Self::FutureAuth {
+            self.finished_auth(if password == test_util::PASSWORD {
+                server::Auth::Accept
+            } else {
+                server::Auth::Reject
+            })
+        }
+    }
+
+    /// Publickey requests for services other than `ssh-connection`
+    /// are rejected as inconsistent, before reaching the handler.
+    #[tokio::test]
+    async fn publickey_service() {
+        for &(service, valid) in &[(&b"bogus"[..], false), (&b"ssh-connection"[..], true)] {
+            let mut server_config = test_util::server_config();
+            server_config.auth_banner = Some("banner");
+            let (sender, mut queries) = unbounded_channel();
+            let (mut client, server) = test_util::connect(
+                server_config,
+                PublickeyQueries(sender),
+                Config::default(),
+                PublickeyService(service),
+            )
+            .await;
+            // The publickey request is sent right after this one.
+            let auth = client.authenticate_password("user", "wrong");
+            assert!(!auth.await.unwrap_or(false));
+            if valid {
+                // The client ignores the `SSH_MSG_USERAUTH_PK_OK`
+                // reply, and can then authenticate.
+                assert_eq!(queries.recv().await, Some(()));
+                let auth = client.authenticate_password("user", test_util::PASSWORD);
+                assert!(auth.await.unwrap());
+            } else {
+                let error = server.await.unwrap().unwrap_err();
+                assert!(
+                    matches!(error.downcast_ref(), Some(Error::Inconsistent)),
+                    "{:?}",
+                    error
+                );
+                assert!(queries.try_recv().is_err());
+            }
+        }
+    }
+
+    /// A server refusing all services, reporting their names.
+    struct RefuseService(UnboundedSender<String>);
+
+    impl server::Handler for RefuseService {
+        server_futures!();
+
+        fn service_request(self, name: &str, session: server::Session) -> Self::FutureBool {
+            self.0.send(format!("service {}", name)).unwrap();
+            self.finished_bool(false, session)
+        }
+    }
+
+    /// A refused `ssh-userauth` service gets the client disconnected
+    /// before the service is accepted, and thus before the banner.
+    #[tokio::test]
+    async fn service_refused() {
+        let mut server_config = test_util::server_config();
+        server_config.auth_banner = Some("welcome");
+        let (sender, mut events) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            server_config,
+            RefuseService(sender.clone()),
+            Config::default(),
+            Disconnections(sender),
+        )
+        .await;
+        let password = test_util::PASSWORD;
+        assert!(!client.authenticate_password("user", password).await.unwrap_or(false));
+        assert_eq!(events.recv().await.unwrap(), "service ssh-userauth");
+        let disconnected = "disconnected ServiceNotAvailable Service not available";
+        assert_eq!(events.recv().await.unwrap(), disconnected);
+    }
+
+    /// A client opening a channel as soon as it receives the
+    /// authentication banner, before authenticating.
+    struct EarlyChannelOpen(UnboundedSender<String>);
+
+    impl Handler for EarlyChannelOpen {
+        client_futures!();
+
+        fn auth_banner(self, _: &str, mut session: Session) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::CHANNEL_OPEN);
+                    enc.write.extend_ssh_string(b"session");
+                    enc.write.push_u32_be(0);
+                    enc.write.push_u32_be(200000);
+                    enc.write.push_u32_be(32768);
+                })
+            }
+            self.finished(session)
+        }
+
+        fn disconnected(
+            self,
+            reason: Disconnect,
+            _: &str,
+            _: &str,
+            session: Session,
+        ) -> Self::FutureUnit {
+            self.0.send(format!("disconnected {:?}", reason)).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// Channels opened before authentication get the client
+    /// disconnected with a protocol error.
+    #[tokio::test]
+    async fn channel_open_before_auth() {
+        let mut server_config = test_util::server_config();
+        server_config.auth_banner = Some("welcome");
+        let (sender, mut events) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            server_config,
+            test_util::Server,
+            Config::default(),
+            EarlyChannelOpen(sender),
+        )
+        .await;
+        // A wrong password keeps the session unauthenticated.
+        assert!(!client.authenticate_password("user", "wrong").await.unwrap_or(false));
+        assert_eq!(events.recv().await.unwrap(), "disconnected ProtocolError");
+        assert!(client.channel_open_session().await.is_err());
+    }
+
+    /// A server reporting whether password requests are password
+    /// changes.
+    struct PasswordKind(UnboundedSender<bool>);
+
+    impl server::Handler for PasswordKind {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.0.send(false).unwrap();
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn auth_password_change(self, _: &str, _: &str, _: &str) -> Self::FutureAuth {
+            self.0.send(true).unwrap();
+            self.finished_auth(server::Auth::Reject)
+        }
+    }
+
+    /// Plain password requests have their "change" flag unset.
+    #[tokio::test]
+    async fn password_request() {
+        let (sender, mut changes) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            PasswordKind(sender),
+            Config::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "pass").await.unwrap());
+        assert_eq!(changes.recv().await, Some(false));
+    }
+
+    /// A server whose password `old` has expired, and must be changed
+    /// to `new`.
+    struct Expired;
+
+    impl server::Handler for Expired {
+        server_futures!();
+
+        fn auth_password(self, _: &str, password: &str) -> Self::FutureAuth {
+            self.finished_auth(if password == "old" {
+                server::Auth::PasswordChangeRequired {
+                    prompt: "Password expired".into(),
+                    language: "".into(),
+                }
+            } else {
+                server::Auth::Reject
+            })
+        }
+
+        fn auth_password_change(self, _: &str, old: &str, new: &str) -> Self::FutureAuth {
+            self.finished_auth(if old == "old" && new == "new" {
+                server::Auth::Accept
+            } else {
+                server::Auth::Reject
+            })
+        }
+    }
+
+    /// A client changing its password to `new`, and reporting the
+    /// prompt of the server.
+    struct ChangePassword(UnboundedSender<String>);
+
+    impl Handler for ChangePassword {
+        client_futures!();
+
+        fn password_change_requested(
+            self,
+            prompt: &str,
+            _: &str,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            self.0.send(prompt.to_string()).unwrap();
+            session.change_password("new");
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn password_change() {
+        let (sender, mut prompts) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            Expired,
+            Config::default(),
+            ChangePassword(sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "old").await.unwrap());
+        assert_eq!(prompts.recv().await.unwrap(), "Password expired");
+    }
+
+    /// A server rejecting passwords, after asking to change them with
+    /// a prompt that is not valid UTF-8.
+    struct ExpiredRaw;
+
+    impl server::Handler for ExpiredRaw {
+        server_futures!();
+
+        fn auth_banner_for(self, _: &str, mut session: server::Session) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::USERAUTH_PASSWD_CHANGEREQ);
+                    enc.write.extend_ssh_string(b"caf\xe9");
+                    enc.write.extend_ssh_string(b"");
+                });
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A client reporting password change prompts, without changing
+    /// its password.
+    struct KeepPassword(UnboundedSender<String>);
+
+    impl Handler for KeepPassword {
+        client_futures!();
+
+        fn password_change_requested(
+            self,
+            prompt: &str,
+            _: &str,
+            session: Session,
+        ) -> Self::FutureUnit {
+            self.0.send(prompt.to_string()).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// Authentication fails if the handler doesn't change the
+    /// password.
+    #[tokio::test]
+    async fn password_change_refused() {
+        let (sender, mut prompts) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            ExpiredRaw,
+            Config::default(),
+            KeepPassword(sender),
+        )
+        .await;
+        assert!(!client.authenticate_password("user", "old").await.unwrap());
+        assert_eq!(prompts.recv().await.unwrap(), "caf\u{fffd}");
+    }
+
+    /// A server reporting the environment variables sent by the
+    /// client.
+    struct Env(UnboundedSender<(String, String)>);
+
+    impl server::Handler for Env {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn env_request(
+            self,
+            _: ChannelId,
+            variable_name: &str,
+            variable_value: &str,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            let variable = (variable_name.to_string(), variable_value.to_string());
+            self.0.send(variable).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A client sending an environment variable whose name is not
+    /// valid UTF-8 on the first channel it opens, which `set_env`
+    /// cannot do.
+    struct InvalidEnv {
+        sent: bool,
+    }
+
+    impl Handler for InvalidEnv {
+        client_futures!();
+
+        fn channel_open_confirmation(
+            mut self,
+            id: ChannelId,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                let recipient_channel = enc.channels[&id].recipient_channel;
+                if !self.sent {
+                    push_packet!(enc.write, {
+                        enc.write.push(msg::CHANNEL_REQUEST);
+                        enc.write.push_u32_be(recipient_channel);
+                        enc.write.extend_ssh_string(b"env");
+                        enc.write.push(0);
+                        enc.write.extend_ssh_string(b"LC_\xff");
+                        enc.write.extend_ssh_string(b"C");
+                    });
+                    self.sent = true;
+                }
+            }
+            if let Some(channel) = session.channels.get(&id) {
+                channel.send(OpenChannelMsg::Open { id }).unwrap_or(());
+            }
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_env() {
+        let (sender, mut variables) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            Env(sender),
+            Config::default(),
+            InvalidEnv { sent: false },
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        client.channel_open_session().await.unwrap();
+        let variable = ("LC_\u{FFFD}".to_string(), "C".to_string());
+        assert_eq!(variables.recv().await, Some(variable));
+        // The connection is still up.
+        assert!(client.channel_open_session().await.is_ok());
+    }
+
+    /// A client sending a signal, a window change and an environment
+    /// variable on the first channel it opens, and reporting the
+    /// packets it wrote along with the packets the server expects.
+    struct ChannelRequests {
+        sent: bool,
+        packets: UnboundedSender<(Vec<u8>, Vec<u8>)>,
+    }
+
+    impl ChannelRequests {
+        /// Check the packet written by `request` against `payload`,
+        /// which follows the recipient channel.
+        fn check<F: FnOnce(&mut Session)>(
+            &self,
+            session: &mut Session,
+            id: ChannelId,
+            payload: &[u8],
+            request: F,
+        ) {
+            let start = match session.common.encrypted {
+                Some(ref enc) => enc.write.len(),
+                None => return,
+            };
+            request(session);
+            if let Some(ref enc) = session.common.encrypted {
+                let mut expected = CryptoVec::new();
+                expected.push(msg::CHANNEL_REQUEST);
+                expected.push_u32_be(enc.channels[&id].recipient_channel);
+                expected.extend(payload);
+                let mut packet = CryptoVec::new();
+                packet.extend_ssh_string(&expected);
+                let written = enc.write[start..].to_vec();
+                self.packets.send((written, packet.to_vec())).unwrap();
+            }
+        }
+    }
+
+    impl Handler for ChannelRequests {
+        client_futures!();
+
+        fn channel_open_confirmation(
+            mut self,
+            id: ChannelId,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            if let Some(channel) = session.channels.get(&id) {
+                channel.send(OpenChannelMsg::Open { id }).unwrap_or(());
+            }
+            if !self.sent {
+                self.sent = true;
+                self.check(&mut session, id, b"\0\0\0\x06signal\0\0\0\0\x03INT", |s| {
+                    s.signal(id, Sig::INT)
+                });
+                let window_change = b"\0\0\0\x0dwindow-change\0\
+                    \0\0\0\x50\0\0\0\x18\0\0\x02\x80\0\0\x01\xe0";
+                self.check(&mut session, id, window_change, |s| {
+                    s.window_change(id, 80, 24, 640, 480)
+                });
+                self.check(&mut session, id, b"\0\0\0\x03env\x01\0\0\0\x04LANG\0\0\0\x01C", |s| {
+                    s.set_env(id, true, "LANG", "C")
+                });
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A server reporting the signal, window change and environment
+    /// requests it receives.
+    struct ReceivedRequests(UnboundedSender<String>);
+
+    impl server::Handler for ReceivedRequests {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn signal(self, _: ChannelId, signal: Sig, session: server::Session) -> Self::FutureUnit {
+            self.0.send(format!("signal {}", signal.name())).unwrap();
+            self.finished(session)
+        }
+
+        fn window_change_request(
+            self,
+            _: ChannelId,
+            col_width: u32,
+            row_height: u32,
+            pix_width: u32,
+            pix_height: u32,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            let size = (col_width, row_height, pix_width, pix_height);
+            self.0.send(format!("window-change {:?}", size)).unwrap();
+            self.finished(session)
+        }
+
+        fn env_request(
+            self,
+            _: ChannelId,
+            variable_name: &str,
+            variable_value: &str,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            let env = format!("env {}={}", variable_name, variable_value);
+            self.0.send(env).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// The signal, window change and environment requests are encoded
+    /// as the server expects them.
+    #[tokio::test]
+    async fn channel_requests() {
+        let (server_sender, mut received) = unbounded_channel();
+        let (client_sender, mut packets) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            ReceivedRequests(server_sender),
+            Config::default(),
+            ChannelRequests {
+                sent: false,
+                packets: client_sender,
+            },
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let _channel = client.channel_open_session().await.unwrap();
+        for _ in 0..3 {
+            let (written, expected) = packets.recv().await.unwrap();
+            assert_eq!(written, expected);
+        }
+        assert_eq!(received.recv().await.unwrap(), "signal INT");
+        assert_eq!(received.recv().await.unwrap(), "window-change (80, 24, 640, 480)");
+        assert_eq!(received.recv().await.unwrap(), "env LANG=C");
+    }
+
+    /// A client running a command with environment variables on the
+    /// first channel it opens, and reporting the requests it queued in
+    /// a single write.
+    struct ExecWithEnv {
+        sent: bool,
+        requests: UnboundedSender<Vec<String>>,
+    }
+
+    impl Handler for ExecWithEnv {
+        client_futures!();
+
+        fn channel_open_confirmation(
+            mut self,
+            id: ChannelId,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            if let Some(channel) = session.channels.get(&id) {
+                channel.send(OpenChannelMsg::Open { id }).unwrap_or(());
+            }
+            if self.sent {
+                return self.finished(session);
+            }
+            self.sent = true;
+            let start = session.common.encrypted.as_ref().unwrap().write.len();
+            session.exec_with_env(id, false, &[("A", "1"), ("B", "2")], "run");
+            let enc = session.common.encrypted.as_ref().unwrap();
+            let mut packets = enc.write.reader(start);
+            let mut requests = Vec::new();
+            while let Ok(packet) = packets.read_string() {
+                let mut r = packet.reader(5);
+                let mut request = String::from_utf8_lossy(r.read_string().unwrap()).into_owned();
+                r.read_byte().unwrap();
+                while let Ok(arg) = r.read_string() {
+                    request.push(' ');
+                    request.push_str(&String::from_utf8_lossy(arg))
+                }
+                requests.push(request)
+            }
+            self.requests.send(requests).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A server reporting environment and exec requests.
+    struct EnvAndExec(UnboundedSender<String>);
+
+    impl server::Handler for EnvAndExec {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn env_request(
+            self,
+            _: ChannelId,
+            variable_name: &str,
+            variable_value: &str,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            self.0.send(format!("env {} {}", variable_name, variable_value)).unwrap();
+            self.finished(session)
+        }
+
+        fn exec_request(
+            self,
+            _: ChannelId,
+            data: &[u8],
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            self.0.send(format!("exec {}", String::from_utf8_lossy(data))).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// The environment requests and the exec request are queued
+    /// together, in order.
+    #[tokio::test]
+    async fn exec_with_env() {
+        let (server_sender, mut received) = unbounded_channel();
+        let (client_sender, mut queued) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            EnvAndExec(server_sender),
+            Config::default(),
+            ExecWithEnv {
+                sent: false,
+                requests: client_sender,
+            },
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let _channel = client.channel_open_session().await.unwrap();
+        let expected = ["env A 1", "env B 2", "exec run"];
+        assert_eq!(queued.recv().await.unwrap(), expected);
+        for request in &expected {
+            assert_eq!(received.recv().await.unwrap(), *request);
+        }
+    }
+
+    /// A server sending a custom channel request on exec requests.
+    struct CustomRequestServer;
+
+    impl server::Handler for CustomRequestServer {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                let recipient = enc.channels.get(&channel).unwrap().recipient_channel;
+                push_packet!(enc.write, {
+                    enc.write.push(msg::CHANNEL_REQUEST);
+                    enc.write.push_u32_be(recipient);
+                    enc.write.extend_ssh_string(b"custom@example.com");
+                    enc.write.push(1);
+                    enc.write.extend_ssh_string(b"payload");
+                })
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A client accepting custom channel requests, reporting them
+    /// along with the messages queued once they are answered.
+    struct CustomRequestClient(UnboundedSender<(String, bool, Vec<u8>, Vec<u8>)>);
+
+    impl Handler for CustomRequestClient {
+        client_futures!();
+
+        fn channel_request(
+            self,
+            channel: ChannelId,
+            request: &str,
+            want_reply: bool,
+            data: &[u8],
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            session.channel_success(channel);
+            let mut queued = Vec::new();
+            if let Some(ref enc) = session.common.encrypted {
+                let mut r = enc.write.reader(0);
+                while let Ok(packet) = r.read_string() {
+                    queued.push(packet[0])
+                }
+            }
+            let request = (request.to_string(), want_reply, data.to_vec(), queued);
+            self.0.send(request).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// Unknown channel requests from the server reach the client
+    /// handler, which can answer them.
+    #[tokio::test]
+    async fn server_channel_request() {
+        let (sender, mut requests) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            CustomRequestServer,
+            Config::default(),
+            CustomRequestClient(sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "run").await.unwrap();
+        let (request, want_reply, data, queued) = requests.recv().await.unwrap();
+        assert_eq!(request, "custom@example.com");
+        assert!(want_reply);
+        assert_eq!(data, b"\0\0\0\x07payload");
+        assert_eq!(queued, [msg::CHANNEL_SUCCESS]);
+    }
+
+    /// A server sending 1 MB on exec requests, reporting how much
+    /// was sent and the lengths of the data packets queued.
+    struct BulkSender(UnboundedSender<(usize, Vec<usize>)>);
+
+    impl server::Handler for BulkSender {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            let start = session.common.encrypted.as_ref().unwrap().write.len();
+            let sent = session.data(channel, &vec![0; 1 << 20]);
+            let mut lengths = Vec::new();
+            if let Some(ref enc) = session.common.encrypted {
+                let mut r = enc.write.reader(start);
+                while let Ok(packet) = r.read_string() {
+                    assert_eq!(packet[0], msg::CHANNEL_DATA);
+                    // After the channel number and the data length.
+                    lengths.push(packet.len() - 9)
+                }
+            }
+            self.0.send((sent, lengths)).unwrap();
+            session.close(channel);
+            self.finished(session)
+        }
+    }
+
+    /// Channel data is split into packets no larger than the maximum
+    /// packet size of the peer.
+    #[tokio::test]
+    async fn data_chunks() {
+        let (sender, mut sent) = unbounded_channel();
+        let config = Config {
+            window_size: 2 << 20,
+            maximum_packet_size: 32768,
+            ..Default::default()
+        };
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            BulkSender(sender),
+            config,
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "send").await.unwrap();
+        let (total, lengths) = sent.recv().await.unwrap();
+        assert_eq!(total, 1 << 20);
+        assert_eq!(lengths, vec![32768; 32]);
+        let mut received = 0;
+        while let Some(msg) = channel.wait().await {
+            if let ChannelMsg::Data { data } = msg {
+                assert!(data.len() <= 32768);
+                received += data.len()
+            }
+        }
+        assert_eq!(received, 1 << 20);
+    }
+
+    /// A server sending three times the client's window with flow
+    /// control on exec requests, then closing the channel, and
+    /// reporting how much data was sent right away.
+    struct FlowControlledSender(UnboundedSender<usize>);
+
+    /// Test data, different at each position of a window.
+    fn flow_controlled_data() -> Vec<u8> {
+        (0..3000).map(|i| (i % 251) as u8).collect()
+    }
+
+    impl server::Handler for FlowControlledSender {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            let start = session.common.encrypted.as_ref().unwrap().write.len();
+            session.data_flow_controlled(channel, &flow_controlled_data());
+            session.close(channel);
+            let mut sent = 0;
+            if let Some(ref enc) = session.common.encrypted {
+                let mut r = enc.write.reader(start);
+                while let Ok(packet) = r.read_string() {
+                    // Neither the close nor anything else overtakes
+                    // the data.
+                    assert_eq!(packet[0], msg::CHANNEL_DATA);
+                    sent += packet.len() - 9
+                }
+            }
+            self.0.send(sent).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// Data sent beyond the window waits for window adjustments, and
+    /// the close waits for the data.
+    #[tokio::test]
+    async fn data_flow_controlled() {
+        let (sender, mut sent) = unbounded_channel();
+        let config = Config {
+            window_size: 1000,
+            maximum_packet_size: 1000,
+            ..Default::default()
+        };
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            FlowControlledSender(sender),
+            config,
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "send").await.unwrap();
+        assert_eq!(sent.recv().await.unwrap(), 1000);
+        // The channel is closed after all the data.
+        let mut received = Vec::new();
+        while let Some(msg) = channel.wait().await {
+            if let ChannelMsg::Data { data } = msg {
+                received.extend_from_slice(&data)
+            }
+        }
+        assert_eq!(received, flow_controlled_data());
+    }
+
+    /// A server sending the test data from its own buffer on exec
+    /// requests, as far as the client's window allows, then the rest
+    /// as the window is adjusted, and reporting the offsets reached.
+    struct SliceSender {
+        data: Vec<u8>,
+        sent: usize,
+        offsets: UnboundedSender<usize>,
+    }
+
+    impl SliceSender {
+        fn send(&mut self, channel: ChannelId, session: &mut server::Session) {
+            let rest = session.data_from_slice(channel, &self.data[self.sent..]);
+            // The rest is a part of our buffer, not a copy.
+            assert_eq!(rest.as_ptr_range().end, self.data.as_ptr_range().end);
+            self.sent = self.data.len() - rest.len();
+            self.offsets.send(self.sent).unwrap();
+            if rest.is_empty() {
+                session.close(channel)
+            }
+        }
+    }
+
+    impl server::Handler for SliceSender {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            mut self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            self.send(channel, &mut session);
+            self.finished(session)
+        }
+
+        fn window_adjusted(
+            mut self,
+            channel: ChannelId,
+            _: usize,
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            if self.sent < self.data.len() {
+                self.send(channel, &mut session);
+            }
+            self.finished(session)
+        }
+    }
+
+    /// Data sent from a borrowed slice stops at the window, and the
+    /// caller sends the rest after window adjustments.
+    #[tokio::test]
+    async fn data_from_slice() {
+        let (offsets, mut offsets_receiver) = unbounded_channel();
+        let handler = SliceSender {
+            data: flow_controlled_data(),
+            sent: 0,
+            offsets,
+        };
+        let config = Config {
+            window_size: 1000,
+            maximum_packet_size: 1000,
+            ..Default::default()
+        };
+        let (mut client, _) =
+            test_util::connect(test_util::server_config(), handler, config, test_util::Client)
+                .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "send").await.unwrap();
+        assert_eq!(offsets_receiver.recv().await, Some(1000));
+        let mut received = Vec::new();
+        while let Some(msg) = channel.wait().await {
+            if let ChannelMsg::Data { data } = msg {
+                received.extend_from_slice(&data)
+            }
+        }
+        assert_eq!(received, flow_controlled_data());
+        // The offsets were reported before the close was sent.
+        let mut last = 1000;
+        while let Ok(offset) = offsets_receiver.try_recv() {
+            assert!(offset > last);
+            last = offset
+        }
+        assert_eq!(last, 3000);
+    }
+
+    /// A server reporting the extended data it receives, and the
+    /// data along with the remaining window of the channel.
+    struct ExtendedDataReporter(UnboundedSender<String>);
+
+    impl server::Handler for ExtendedDataReporter {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn extended_data(
+            self,
+            _: ChannelId,
+            code: u32,
+            data: &[u8],
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            let data = String::from_utf8_lossy(data);
+            self.0.send(format!("ext {} {}", code, data)).unwrap();
+            self.finished(session)
+        }
+
+        fn data(
+            self,
+            channel: ChannelId,
+            data: &[u8],
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            let enc = session.common.encrypted.as_ref().unwrap();
+            let window = enc.channels.get(&channel).unwrap().sender_window_size;
+            let data = String::from_utf8_lossy(data);
+            self.0.send(format!("data {} {}", data, window)).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// Extended data with unknown codes is delivered by default, and
+    /// dropped with `drop_unknown_extended_data`, still counting
+    /// against the window.
+    #[tokio::test]
+    async fn unknown_extended_data() {
+        for &drop in &[false, true] {
+            let mut server_config = test_util::server_config();
+            server_config.drop_unknown_extended_data = drop;
+            let window = server_config.window_size;
+            let (sender, mut received) = unbounded_channel();
+            let (mut client, _) = test_util::connect(
+                server_config,
+                ExtendedDataReporter(sender),
+                Config::default(),
+                test_util::Client,
+            )
+            .await;
+            assert!(client.authenticate_password("user", "").await.unwrap());
+            let mut channel = client.channel_open_session().await.unwrap();
+            channel.extended_data(7, b"seven").await.unwrap();
+            channel.extended_data(1, b"stderr").await.unwrap();
+            channel.data(&b"end"[..]).await.unwrap();
+            if !drop {
+                assert_eq!(received.recv().await.unwrap(), "ext 7 seven");
+            }
+            assert_eq!(received.recv().await.unwrap(), "ext 1 stderr");
+            let expected = format!("data end {}", window - 14);
+            assert_eq!(received.recv().await.unwrap(), expected);
+        }
+    }
+
+    /// A server reporting channel openings and closings.
+    struct OpenClose(UnboundedSender<&'static str>);
+
+    impl server::Handler for OpenClose {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn channel_open_session(self, _: ChannelId, session: server::Session) -> Self::FutureUnit {
+            self.0.send("open").unwrap();
+            self.finished(session)
+        }
+
+        fn channel_close(self, _: ChannelId, session: server::Session) -> Self::FutureUnit {
+            self.0.send("close").unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A client closing the first channel it opens twice.
+    struct DoubleClose {
+        sent: bool,
+    }
+
+    impl Handler for DoubleClose {
+        client_futures!();
+
+        fn channel_open_confirmation(
+            mut self,
+            id: ChannelId,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            if let Some(channel) = session.channels.get(&id) {
+                channel.send(OpenChannelMsg::Open { id }).unwrap_or(());
+            }
+            if let Some(ref mut enc) = session.common.encrypted {
+                if !self.sent {
+                    self.sent = true;
+                    let recipient = enc.channels.get(&id).unwrap().recipient_channel;
+                    for _ in 0..2 {
+                        push_packet!(enc.write, {
+                            enc.write.push(msg::CHANNEL_CLOSE);
+                            enc.write.push_u32_be(recipient);
+                        })
+                    }
+                }
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A repeated `SSH_MSG_CHANNEL_CLOSE` is ignored.
+    #[tokio::test]
+    async fn double_close() {
+        let (sender, mut events) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            OpenClose(sender),
+            Config::default(),
+            DoubleClose { sent: false },
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let _first = client.channel_open_session().await.unwrap();
+        // The second channel is opened after both closes are sent,
+        // on the same connection.
+        let _second = client.channel_open_session().await.unwrap();
+        for &expected in &["open", "close", "open"] {
+            assert_eq!(events.recv().await.unwrap(), expected);
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    /// A server sending a debug and an ignore message on exec
+    /// requests.
+    struct NoisyServer;
+
+    impl server::Handler for NoisyServer {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            _: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                test_util::push_debug_and_ignore(&mut enc.write)
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A client reporting debug and ignore messages.
+    struct ListeningClient(UnboundedSender<String>);
+
+    impl Handler for ListeningClient {
+        client_futures!();
+
+        fn debug(
+            self,
+            always_display: bool,
+            message: &str,
+            language: &str,
+            session: Session,
+        ) -> Self::FutureUnit {
+            let debug = format!("debug {} {} {}", always_display, message, language);
+            self.0.send(debug).unwrap();
+            self.finished(session)
+        }
+
+        fn ignore(self, data: &[u8], session: Session) -> Self::FutureUnit {
+            let ignore = format!("ignore {}", String::from_utf8_lossy(data));
+            self.0.send(ignore).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A client sending a debug and an ignore message when it
+    /// receives data.
+    struct NoisyClient;
+
+    impl Handler for NoisyClient {
+        client_futures!();
+
+        fn data(self, _: ChannelId, _: &[u8], mut session: Session) -> Self::FutureUnit {
+            if let Some(ref mut enc) = session.common.encrypted {
+                test_util::push_debug_and_ignore(&mut enc.write)
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A server writing data on exec requests, and reporting debug
+    /// and ignore messages.
+    struct ListeningServer(UnboundedSender<String>);
+
+    impl server::Handler for ListeningServer {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            session.data(channel, b"data");
+            self.finished(session)
+        }
+
+        fn debug(
+            self,
+            always_display: bool,
+            message: &str,
+            language: &str,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            let debug = format!("debug {} {} {}", always_display, message, language);
+            self.0.send(debug).unwrap();
+            self.finished(session)
+        }
+
+        fn ignore(self, data: &[u8], session: server::Session) -> Self::FutureUnit {
+            let ignore = format!("ignore {}", String::from_utf8_lossy(data));
+            self.0.send(ignore).unwrap();
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn debug_and_ignore() {
+        let (sender, mut messages) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            NoisyServer,
+            Config::default(),
+            ListeningClient(sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "debug").await.unwrap();
+        for &expected in &test_util::DEBUG_AND_IGNORE {
+            assert_eq!(messages.recv().await.unwrap(), expected);
+        }
+
+        let (sender, mut messages) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            ListeningServer(sender),
+            Config::default(),
+            NoisyClient,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "data").await.unwrap();
+        for &expected in &test_util::DEBUG_AND_IGNORE {
+            assert_eq!(messages.recv().await.unwrap(), expected);
+        }
+    }
+
+    /// A client reporting the length of the ignore messages it
+    /// receives.
+    struct CoverTraffic(UnboundedSender<usize>);
+
+    impl Handler for CoverTraffic {
+        client_futures!();
+
+        fn ignore(self, data: &[u8], session: Session) -> Self::FutureUnit {
+            self.0.send(data.len()).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// Idle servers send ignore messages periodically, no longer than
+    /// their maximum packet size.
+    #[tokio::test]
+    async fn cover_traffic() {
+        let mut server_config = test_util::server_config();
+        server_config.cover_traffic_interval = Some(std::time::Duration::from_millis(20));
+        server_config.maximum_packet_size = 64;
+        let (sender, mut lengths) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            server_config,
+            test_util::Server,
+            Config::default(),
+            CoverTraffic(sender),
+        )
+        .await;
+        let password = test_util::PASSWORD;
+        assert!(client.authenticate_password("user", password).await.unwrap());
+        let timeout = std::time::Duration::from_secs(5);
+        for _ in 0..3 {
+            let len = tokio::time::timeout(timeout, lengths.recv()).await.unwrap();
+            assert!(len.unwrap() <= 64);
+        }
+    }
+
+    /// A server refusing all channels, with a specific reason for
+    /// `direct-tcpip` channels.
+    struct RefuseChannels;
+
+    impl server::Handler for RefuseChannels {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn check_channel_open(
+            self,
+            _: ChannelId,
+            channel_type: &str,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureBool {
+            if channel_type == "direct-tcpip" {
+                session.set_channel_open_failure(ChannelOpenFailure::ConnectFailed, "No route");
+            }
+            self.finished_bool(false, session)
+        }
+    }
+
+    /// A client reporting the reasons of channel open failures.
+    struct OpenFailures(UnboundedSender<(ChannelOpenFailure, String)>);
+
+    impl Handler for OpenFailures {
+        client_futures!();
+
+        fn channel_open_failure(
+            self,
+            channel: ChannelId,
+            reason: ChannelOpenFailure,
+            description: &str,
+            _: &str,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            self.0.send((reason, description.to_string())).unwrap();
+            session.channels.remove(&channel);
+            session.sender.send(Reply::ChannelOpenFailure).unwrap_or(());
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_open_refused() {
+        let (sender, mut failures) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            RefuseChannels,
+            Config::default(),
+            OpenFailures(sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        assert!(client.channel_open_session().await.is_err());
+        let failure = (
+            ChannelOpenFailure::AdministrativelyProhibited,
+            "Channel refused".to_string(),
+        );
+        assert_eq!(failures.recv().await, Some(failure));
+        let channel = client.channel_open_direct_tcpip("localhost", 22, "127.0.0.1", 1234);
+        assert!(channel.await.is_err());
+        let failure = (ChannelOpenFailure::ConnectFailed, "No route".to_string());
+        assert_eq!(failures.recv().await, Some(failure));
+    }
+
+    /// A client sending `no-more-sessions@openssh.com` once its first
+    /// channel is confirmed, and reporting channel open failures.
+    struct NoMoreSessions(UnboundedSender<(ChannelOpenFailure, String)>);
+
+    impl Handler for NoMoreSessions {
+        client_futures!();
+
+        fn channel_open_confirmation(
+            self,
+            id: ChannelId,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            if let Some(channel) = session.channels.get(&id) {
+                channel.send(OpenChannelMsg::Open { id }).unwrap_or(());
+            }
+            if let Some(ref mut enc) = session.common.encrypted {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::GLOBAL_REQUEST);
+                    enc.write.extend_ssh_string(b"no-more-sessions@openssh.com");
+                    enc.write.push(0);
+                })
+            }
+            self.finished(session)
+        }
+
+        fn channel_open_failure(
+            self,
+            channel: ChannelId,
+            reason: ChannelOpenFailure,
+            description: &str,
+            _: &str,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            self.0.send((reason, description.to_string())).unwrap();
+            session.channels.remove(&channel);
+            session.sender.send(Reply::ChannelOpenFailure).unwrap_or(());
+            self.finished(session)
+        }
+    }
+
+    /// After `no-more-sessions@openssh.com`, session channels are
+    /// refused, but other channel types are still allowed.
+    #[tokio::test]
+    async fn no_more_sessions() {
+        let (sender, mut failures) = unbounded_channel();
+        let (opened, _opened) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            OpenedChannels(opened),
+            Config::default(),
+            NoMoreSessions(sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let _channel = client.channel_open_session().await.unwrap();
+        assert!(client.channel_open_session().await.is_err());
+        let failure = (
+            ChannelOpenFailure::AdministrativelyProhibited,
+            "No more sessions".to_string(),
+        );
+        assert_eq!(failures.recv().await, Some(failure));
+        let channel = client.channel_open_direct_tcpip("localhost", 22, "127.0.0.1", 1234);
+        assert!(channel.await.is_ok());
+    }
+
+    /// A server reporting channel openings, through the generic
+    /// method and the type-specific ones.
+    struct OpenedChannels(UnboundedSender<(String, ChannelId)>);
+
+    impl server::Handler for OpenedChannels {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn channel_opened(
+            self,
+            channel: ChannelId,
+            channel_type: &str,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            self.0.send((format!("opened {}", channel_type), channel)).unwrap();
+            self.finished(session)
+        }
+
+        fn channel_open_session(
+            self,
+            channel: ChannelId,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            self.0.send(("session".to_string(), channel)).unwrap();
+            self.finished(session)
+        }
+
+        fn channel_open_direct_tcpip(
+            self,
+            channel: ChannelId,
+            host_to_connect: &str,
+            _: u32,
+            _: &str,
+            _: u32,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            self.0.send((format!("direct-tcpip {}", host_to_connect), channel)).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// `channel_opened` is called for all channel types, before the
+    /// type-specific methods.
+    #[tokio::test]
+    async fn channel_opened() {
+        let (sender, mut events) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            OpenedChannels(sender),
+            Config::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let _session = client.channel_open_session().await.unwrap();
+        let _direct = client
+            .channel_open_direct_tcpip("localhost", 22, "127.0.0.1", 1234)
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            received.push(events.recv().await.unwrap())
+        }
+        let kinds: Vec<_> = received.iter().map(|(kind, _)| kind.as_str()).collect();
+        let expected = [
+            "opened session",
+            "session",
+            "opened direct-tcpip",
+            "direct-tcpip localhost",
+        ];
+        assert_eq!(kinds, expected);
+        // Both methods are called with the same channel.
+        assert_eq!(received[0].1, received[1].1);
+        assert_eq!(received[2].1, received[3].1);
+        assert_ne!(received[0].1, received[2].1);
+    }
+
+    /// The encoding of `key`, as in host key advertisements.
+    fn key_blob<K: PubKey>(key: &K) -> Vec<u8> {
+        let mut blob = CryptoVec::new();
+        key.push_to(&mut blob);
+        blob.to_vec()
+    }
+
+    /// A server advertising its first host key along with a key of an
+    /// unknown type when a session channel is opened.
+    struct CraftedHostkeys;
+
+    impl server::Handler for CraftedHostkeys {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn channel_open_session(
+            self,
+            _: ChannelId,
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            let known = key_blob(&session.common.config.keys[0]);
+            if let Some(ref mut enc) = session.common.encrypted {
+                let mut unknown = CryptoVec::new();
+                unknown.extend_ssh_string(b"ssh-unknown");
+                unknown.extend_ssh_string(b"blob");
+                push_packet!(enc.write, {
+                    enc.write.push(msg::GLOBAL_REQUEST);
+                    enc.write.extend_ssh_string(b"hostkeys-00@openssh.com");
+                    enc.write.push(0);
+                    enc.write.extend_ssh_string(&unknown);
+                    enc.write.extend(&known);
+                })
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A client reporting the encoding of the host keys advertised by
+    /// the server.
+    struct Hostkeys(UnboundedSender<Vec<Vec<u8>>>);
+
+    impl Handler for Hostkeys {
+        client_futures!();
+
+        fn hostkeys(self, keys: &[key::PublicKey], session: Session) -> Self::FutureUnit {
+            self.0.send(keys.iter().map(key_blob).collect()).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// All the host keys of the server are advertised after
+    /// authentication, and keys of unknown types are skipped.
+    #[tokio::test]
+    async fn hostkeys() {
+        let mut config = test_util::server_config();
+        config.keys.push(key::KeyPair::generate_ed25519().unwrap());
+        let blobs: Vec<_> = config.keys.iter().map(key_blob).collect();
+        let (sender, mut hostkeys) = unbounded_channel();
+        let (mut client, _) =
+            test_util::connect(config, CraftedHostkeys, Config::default(), Hostkeys(sender)).await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        assert_eq!(hostkeys.recv().await.unwrap(), blobs);
+        let _channel = client.channel_open_session().await.unwrap();
+        assert_eq!(hostkeys.recv().await.unwrap(), vec![blobs[0].clone()]);
+    }
+
+    /// A server reporting channel openings and tunnel requests, which
+    /// it accepts if `accept` is set.
+    struct Tunnels {
+        accept: bool,
+        events: UnboundedSender<String>,
+    }
+
+    impl server::Handler for Tunnels {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn channel_open_tun(
+            self,
+            _: ChannelId,
+            mode: u32,
+            remote_unit: u32,
+            session: server::Session,
+        ) -> Self::FutureBool {
+            self.events.send(format!("tun {} {}", mode, remote_unit)).unwrap();
+            let accept = self.accept;
+            self.finished_bool(accept, session)
+        }
+
+        fn channel_opened(
+            self,
+            _: ChannelId,
+            channel_type: &str,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            self.events.send(format!("opened {}", channel_type)).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A client opening a layer 3 tunnel to unit 5 once its first
+    /// channel is confirmed, and reporting the outcome.
+    struct TunOpener {
+        sent: bool,
+        events: UnboundedSender<String>,
+    }
+
+    impl Handler for TunOpener {
+        client_futures!();
+
+        fn channel_open_confirmation(
+            mut self,
+            id: ChannelId,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            if let Some(channel) = session.channels.get(&id) {
+                channel.send(OpenChannelMsg::Open { id }).unwrap_or(());
+            }
+            let window = session.common.config.window_size;
+            let max_packet = session.common.config.maximum_packet_size;
+            if let Some(ref mut enc) = session.common.encrypted {
+                if self.sent {
+                    self.events.send("confirmed".to_string()).unwrap();
+                } else {
+                    self.sent = true;
+                    let tun = enc.new_channel(window, max_packet);
+                    push_packet!(enc.write, {
+                        enc.write.push(msg::CHANNEL_OPEN);
+                        enc.write.extend_ssh_string(b"tun@openssh.com");
+                        enc.write.push_u32_be(tun.0);
+                        enc.write.push_u32_be(window);
+                        enc.write.push_u32_be(max_packet);
+                        enc.write.push_u32_be(1);
+                        enc.write.push_u32_be(5);
+                    })
+                }
+            }
+            self.finished(session)
+        }
+
+        fn channel_open_failure(
+            self,
+            _: ChannelId,
+            _: ChannelOpenFailure,
+            _: &str,
+            _: &str,
+            session: Session,
+        ) -> Self::FutureUnit {
+            self.events.send("refused".to_string()).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// The tunnel mode and unit are passed to `channel_open_tun`,
+    /// which decides whether the channel is opened.
+    #[tokio::test]
+    async fn channel_open_tun() {
+        for &accept in &[false, true] {
+            let (sender, mut events) = unbounded_channel();
+            let (mut client, _) = test_util::connect(
+                test_util::server_config(),
+                Tunnels {
+                    accept,
+                    events: sender.clone(),
+                },
+                Config::default(),
+                TunOpener {
+                    sent: false,
+                    events: sender,
+                },
+            )
+            .await;
+            assert!(client.authenticate_password("user", "").await.unwrap());
+            let _channel = client.channel_open_session().await.unwrap();
+            let expected: &[&str] = if accept {
+                &["opened session", "tun 1 5", "opened tun@openssh.com", "confirmed"]
+            } else {
+                &["opened session", "tun 1 5", "refused"]
+            };
+            for event in expected {
+                assert_eq!(events.recv().await.unwrap(), *event);
+            }
+        }
+    }
+
+    /// A client reporting the language tags of channel open failures,
+    /// and opening a channel of an unknown type after the first one.
+    struct FailureLanguages {
+        sent: bool,
+        languages: UnboundedSender<String>,
+    }
+
+    impl Handler for FailureLanguages {
+        client_futures!();
+
+        fn channel_open_failure(
+            mut self,
+            channel: ChannelId,
+            _: ChannelOpenFailure,
+            _: &str,
+            language: &str,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            self.languages.send(language.to_string()).unwrap();
+            if let Some(ref mut enc) = session.common.encrypted {
+                if !self.sent {
+                    self.sent = true;
+                    push_packet!(enc.write, {
+                        enc.write.push(msg::CHANNEL_OPEN);
+                        enc.write.extend_ssh_string(b"unknown@example.com");
+                        enc.write.push_u32_be(99);
+                        enc.write.push_u32_be(200000);
+                        enc.write.push_u32_be(32768);
+                    })
+                }
+            }
+            session.channels.remove(&channel);
+            session.sender.send(Reply::ChannelOpenFailure).unwrap_or(());
+            self.finished(session)
+        }
+    }
+
+    /// Channel open failures carry the language tag of the server
+    /// configuration, for refused channels as well as unknown types.
+    #[tokio::test]
+    async fn channel_open_failure_language() {
+        for tag in &["", "fr"] {
+            let mut server_config = test_util::server_config();
+            server_config.language_tag = tag.to_string();
+            let (sender, mut languages) = unbounded_channel();
+            let (mut client, _) = test_util::connect(
+                server_config,
+                RefuseChannels,
+                Config::default(),
+                FailureLanguages {
+                    sent: false,
+                    languages: sender,
+                },
+            )
+            .await;
+            assert!(client.authenticate_password("user", "").await.unwrap());
+            assert!(client.channel_open_session().await.is_err());
+            assert_eq!(languages.recv().await.unwrap(), *tag);
+            assert_eq!(languages.recv().await.unwrap(), *tag);
+        }
+    }
+
+    /// A server sending a message of an unknown type when a session
+    /// channel is opened, and reporting the sequence number of that
+    /// packet, and then the sequence number the client did not
+    /// implement.
+    struct UnknownMessage(UnboundedSender<u32>);
+
+    impl server::Handler for UnknownMessage {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn channel_open_session(
+            self,
+            _: ChannelId,
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            let mut seqn = session.common.write_buffer.seqn.0;
+            if let Some(ref mut enc) = session.common.encrypted {
+                // Our packet is flushed after the pending ones.
+                let mut r = enc.write.reader(0);
+                while r.read_string().is_ok() {
+                    seqn += 1
+                }
+                push_packet!(enc.write, enc.write.push(200));
+                self.0.send(seqn).unwrap();
+            }
+            self.finished(session)
+        }
+
+        fn unimplemented(self, seqn: u32, session: server::Session) -> Self::FutureUnit {
+            self.0.send(seqn).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A client sending a message of an unknown type when its first
+    /// channel is confirmed, and reporting the sequence number of that
+    /// packet, and then the sequence number the server did not
+    /// implement.
+    struct ClientUnknownMessage {
+        sent: bool,
+        seqns: UnboundedSender<u32>,
+    }
+
+    impl Handler for ClientUnknownMessage {
+        client_futures!();
+
+        fn channel_open_confirmation(
+            mut self,
+            id: ChannelId,
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            if let Some(channel) = session.channels.get(&id) {
+                channel.send(OpenChannelMsg::Open { id }).unwrap_or(());
+            }
+            let mut seqn = session.common.write_buffer.seqn.0;
+            if let Some(ref mut enc) = session.common.encrypted {
+                if !self.sent {
+                    self.sent = true;
+                    // Our packet is flushed after the pending ones.
+                    let mut r = enc.write.reader(0);
+                    while r.read_string().is_ok() {
+                        seqn += 1
+                    }
+                    push_packet!(enc.write, enc.write.push(200));
+                    self.seqns.send(seqn).unwrap();
+                }
+            }
+            self.finished(session)
+        }
+
+        fn unimplemented(self, seqn: u32, session: Session) -> Self::FutureUnit {
+            self.seqns.send(seqn).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// The server replies `SSH_MSG_UNIMPLEMENTED` to unknown messages,
+    /// with their sequence number.
+    #[tokio::test]
+    async fn server_unimplemented() {
+        let (sender, mut seqns) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            test_util::Server,
+            Config::default(),
+            ClientUnknownMessage {
+                sent: false,
+                seqns: sender,
+            },
+        )
+        .await;
+        let password = test_util::PASSWORD;
+        assert!(client.authenticate_password("user", password).await.unwrap());
+        let _channel = client.channel_open_session().await.unwrap();
+        let sent = seqns.recv().await.unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        let unimplemented = tokio::time::timeout(timeout, seqns.recv()).await.unwrap();
+        assert_eq!(unimplemented, Some(sent));
+    }
+
+    /// The client replies `SSH_MSG_UNIMPLEMENTED` to unknown messages,
+    /// with their sequence number.
+    #[tokio::test]
+    async fn client_unimplemented() {
+        let (sender, mut seqns) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            UnknownMessage(sender),
+            Config::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let _channel = client.channel_open_session().await.unwrap();
+        let sent = seqns.recv().await.unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        let unimplemented = tokio::time::timeout(timeout, seqns.recv()).await.unwrap();
+        assert_eq!(unimplemented, Some(sent));
+    }
+
+    /// A server reporting the sequence number of the packet opening
+    /// each session channel.
+    struct OpenSeqn(UnboundedSender<u32>);
+
+    impl server::Handler for OpenSeqn {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn channel_open_session(self, _: ChannelId, session: server::Session) -> Self::FutureUnit {
+            self.0.send(session.common.received_seqn).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// Sequence number of the `SSH_MSG_CHANNEL_OPEN` received by the
+    /// server, once the client has authenticated and opened a session.
+    async fn channel_open_seqn(request_connection_service: bool) -> u32 {
+        let (sender, mut seqns) = unbounded_channel();
+        let config = Config {
+            request_connection_service,
+            ..Default::default()
+        };
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            OpenSeqn(sender),
+            config,
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.data(&b"still works"[..]).await.unwrap();
+        seqns.recv().await.unwrap()
+    }
+
+    /// With `request_connection_service`, exactly one extra packet,
+    /// the service request, is sent between authentication and the
+    /// first channel, and the server's accept doesn't disturb the
+    /// client.
+    #[tokio::test]
+    async fn connection_service_request() {
+        let without = channel_open_seqn(false).await;
+        let with = channel_open_seqn(true).await;
+        assert_eq!(with, without + 1);
+    }
+
+    /// A server reporting its session identifier when it sends the
+    /// authentication banner.
+    struct ServerSessionId(UnboundedSender<Option<Vec<u8>>>);
+
+    impl server::Handler for ServerSessionId {
+        server_futures!();
+
+        fn auth_banner_for(self, _: &str, session: server::Session) -> Self::FutureUnit {
+            self.0
+                .send(session.session_id().map(|id| id.to_vec()))
+                .unwrap();
+            self.finished(session)
+        }
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+    }
+
+    /// A client reporting its session identifier when it receives the
+    /// authentication banner.
+    struct ClientSessionId(UnboundedSender<Option<Vec<u8>>>);
+
+    impl Handler for ClientSessionId {
+        client_futures!();
+
+        fn auth_banner(self, _: &str, session: Session) -> Self::FutureUnit {
+            self.0
+                .send(session.session_id().map(|id| id.to_vec()))
+                .unwrap();
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn session_id() {
+        let mut server_config = test_util::server_config();
+        server_config.auth_banner = Some("banner");
+        let (server_sender, mut server_ids) = unbounded_channel();
+        let (client_sender, mut client_ids) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            server_config,
+            ServerSessionId(server_sender),
+            Config::default(),
+            ClientSessionId(client_sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let server_id = server_ids.recv().await.unwrap().unwrap();
+        let client_id = client_ids.recv().await.unwrap().unwrap();
+        assert!(!server_id.is_empty());
+        assert_eq!(server_id, client_id);
+    }
+}
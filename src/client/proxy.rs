@@ -80,4 +80,16 @@ impl tcp::Tcp for Stream {
             Stream::Tcp(ref mut t) => t.tcp_shutdown(),
         }
     }
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        match *self {
+            Stream::Child(_) => None,
+            Stream::Tcp(ref t) => tcp::Tcp::peer_addr(t),
+        }
+    }
+    fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        match *self {
+            Stream::Child(_) => None,
+            Stream::Tcp(ref t) => tcp::Tcp::local_addr(t),
+        }
+    }
 }
@@ -1,6 +1,22 @@
 use super::*;
 
 impl Session {
+    /// Answer the prompts of a keyboard-interactive round (see
+    /// `Handler::keyboard_interactive_prompts`), in the order of the
+    /// prompts. The responses are sent when the handler returns.
+    pub fn keyboard_interactive_responses(&mut self, responses: &[&str]) {
+        let responses = responses.iter().map(|r| r.to_string()).collect();
+        self.auth_answer = Some(auth::Answer::Responses(responses))
+    }
+
+    /// Send a new password in reply to a password change request
+    /// (see `Handler::password_change_requested`), along with the
+    /// password of the current authentication request. The request
+    /// is sent when the handler returns.
+    pub fn change_password(&mut self, new_password: &str) {
+        self.auth_answer = Some(auth::Answer::NewPassword(new_password.to_string()))
+    }
+
     pub fn channel_open_session(&mut self) -> Result<ChannelId, anyhow::Error> {
         let result = if let Some(ref mut enc) = self.common.encrypted {
             match enc.state {
@@ -76,6 +92,10 @@ impl Session {
         Ok(result)
     }
 
+    /// Ask the server to open a `direct-tcpip` channel to
+    /// `host_to_connect:port_to_connect`, for local port forwarding.
+    /// The channel is registered right away, and can be written to
+    /// once the server confirms it.
     pub fn channel_open_direct_tcpip(
         &mut self,
         host_to_connect: &str,
@@ -106,9 +126,9 @@ impl Session {
                             .push_u32_be(self.common.config.as_ref().maximum_packet_size);
 
                         enc.write.extend_ssh_string(host_to_connect.as_bytes());
-                        enc.write.push_u32_be(port_to_connect); // sender channel id.
+                        enc.write.push_u32_be(port_to_connect);
                         enc.write.extend_ssh_string(originator_address.as_bytes());
-                        enc.write.push_u32_be(originator_port); // sender channel id.
+                        enc.write.push_u32_be(originator_port);
                     });
                     sender_channel
                 }
@@ -186,6 +206,9 @@ impl Session {
         }
     }
 
+    /// Set the environment variable `variable_name` of the process
+    /// about to be started on this channel (`env` request). Servers
+    /// usually accept only a few names, such as `LANG`.
     pub fn set_env(
         &mut self,
         channel: ChannelId,
@@ -239,6 +262,24 @@ impl Session {
         error!("exec");
     }
 
+    /// Queue `env` requests for each of the given variables,
+    /// followed by an `exec` request, so that they are all flushed
+    /// together.
+    pub fn exec_with_env(
+        &mut self,
+        channel: ChannelId,
+        want_reply: bool,
+        env: &[(&str, &str)],
+        command: &str,
+    ) {
+        for &(name, value) in env {
+            self.set_env(channel, want_reply, name, value)
+        }
+        self.exec(channel, want_reply, command)
+    }
+
+    /// Send a signal to the process running on this channel
+    /// (`signal` request, which never wants a reply).
     pub fn signal(&mut self, channel: ChannelId, signal: Sig) {
         if let Some(ref mut enc) = self.common.encrypted {
             if let Some(channel) = enc.channels.get(&channel) {
@@ -269,6 +310,9 @@ impl Session {
         }
     }
 
+    /// Notify the server that the size of the terminal on this
+    /// channel changed (`window-change` request, which never wants a
+    /// reply), in characters and in pixels.
     pub fn window_change(
         &mut self,
         channel: ChannelId,
@@ -294,8 +338,20 @@ impl Session {
         }
     }
 
+    /// Ask the server to listen on `address:port` and to forward the
+    /// connections it receives there to us, see
+    /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-7.1). If
+    /// `want_reply` is set, the result is passed to
+    /// `Handler::tcpip_forward_reply`.
     pub fn tcpip_forward(&mut self, want_reply: bool, address: &str, port: u32) {
         if let Some(ref mut enc) = self.common.encrypted {
+            if want_reply {
+                self.pending_requests.push_back(PendingRequest::TcpIpForward {
+                    address: address.to_string(),
+                    port,
+                });
+            }
+            self.forwards.push((address.to_string(), port));
             push_packet!(enc.write, {
                 enc.write.push(msg::GLOBAL_REQUEST);
                 enc.write.extend_ssh_string(b"tcpip-forward");
@@ -306,8 +362,51 @@ impl Session {
         }
     }
 
+    /// Refuse a channel opened by the server.
+    pub(crate) fn refuse_channel_open(
+        &mut self,
+        sender: u32,
+        reason: ChannelOpenFailure,
+        description: &str,
+    ) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            push_packet!(enc.write, {
+                enc.write.push(msg::CHANNEL_OPEN_FAILURE);
+                enc.write.push_u32_be(sender);
+                enc.write.push_u32_be(reason as u32);
+                enc.write.extend_ssh_string(description.as_bytes());
+                enc.write.extend_ssh_string(b"");
+            });
+        }
+    }
+
+    /// Send a `keepalive@openssh.com` global request, and count it as
+    /// unanswered until a reply comes back.
+    pub(crate) fn keepalive_request(&mut self) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            push_packet!(enc.write, {
+                enc.write.push(msg::GLOBAL_REQUEST);
+                enc.write.extend_ssh_string(b"keepalive@openssh.com");
+                enc.write.push(1);
+            });
+            self.pending_requests.push_back(PendingRequest::Keepalive);
+            self.common.alive_timeouts += 1;
+        }
+    }
+
+    /// Cancel a port forwarding requested with `tcpip_forward`. If
+    /// `want_reply` is set, the result is passed to
+    /// `Handler::cancel_tcpip_forward_reply`.
     pub fn cancel_tcpip_forward(&mut self, want_reply: bool, address: &str, port: u32) {
         if let Some(ref mut enc) = self.common.encrypted {
+            if want_reply {
+                self.pending_requests
+                    .push_back(PendingRequest::CancelTcpIpForward {
+                        address: address.to_string(),
+                        port,
+                    });
+            }
+            self.forwards.retain(|(a, p)| a != address || *p != port);
             push_packet!(enc.write, {
                 enc.write.push(msg::GLOBAL_REQUEST);
                 enc.write.extend_ssh_string(b"cancel-tcpip-forward");
@@ -318,6 +417,38 @@ impl Session {
         }
     }
 
+    /// Send a "success" reply to a channel request received from the
+    /// server, if the server expects an answer.
+    pub fn channel_success(&mut self, channel: ChannelId) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            if let Some(channel) = enc.channels.get_mut(&channel) {
+                if channel.wants_reply {
+                    channel.wants_reply = false;
+                    push_packet!(enc.write, {
+                        enc.write.push(msg::CHANNEL_SUCCESS);
+                        enc.write.push_u32_be(channel.recipient_channel);
+                    })
+                }
+            }
+        }
+    }
+
+    /// Send a "failure" reply to a channel request received from the
+    /// server, if the server expects an answer.
+    pub fn channel_failure(&mut self, channel: ChannelId) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            if let Some(channel) = enc.channels.get_mut(&channel) {
+                if channel.wants_reply {
+                    channel.wants_reply = false;
+                    push_packet!(enc.write, {
+                        enc.write.push(msg::CHANNEL_FAILURE);
+                        enc.write.push_u32_be(channel.recipient_channel);
+                    })
+                }
+            }
+        }
+    }
+
     pub fn data(&mut self, channel: ChannelId, data: &[u8]) -> usize {
         if let Some(ref mut enc) = self.common.encrypted {
             enc.data(channel, data)
@@ -326,6 +457,15 @@ impl Session {
         }
     }
 
+    /// Send data to a channel, respecting flow control: the bytes
+    /// that do not fit in the window of the channel are kept and
+    /// sent automatically as the other side adjusts the window.
+    pub fn data_flow_controlled(&mut self, channel: ChannelId, data: &[u8]) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.data_flow_controlled(channel, data)
+        }
+    }
+
     pub fn eof(&mut self, channel: ChannelId) {
         if let Some(ref mut enc) = self.common.encrypted {
             enc.eof(channel)
@@ -345,4 +485,86 @@ impl Session {
     pub fn disconnect(&mut self, reason: Disconnect, description: &str, language_tag: &str) {
         self.common.disconnect(reason, description, language_tag);
     }
+
+    /// The identifiers of the channels currently open (or waiting
+    /// for confirmation) on this session, in no particular order.
+    pub fn channels(&self) -> impl Iterator<Item = ChannelId> + '_ {
+        self.common
+            .encrypted
+            .iter()
+            .flat_map(|enc| enc.channels.keys().cloned())
+    }
+
+    /// A snapshot of the window and packet size parameters of a
+    /// channel, or `None` if there is no such channel.
+    pub fn channel_info(&self, channel: ChannelId) -> Option<ChannelInfo> {
+        let enc = self.common.encrypted.as_ref()?;
+        Some(enc.channels.get(&channel)?.info())
+    }
+
+    /// A snapshot of the counters and negotiated parameters of this
+    /// connection.
+    pub fn stats(&self) -> ConnectionStats {
+        self.common.stats()
+    }
+
+    /// The session identifier, i.e. the exchange hash of the first
+    /// key exchange, or `None` before that exchange has
+    /// completed. It is the same on the server side, and does not
+    /// change when keys are re-exchanged.
+    pub fn session_id(&self) -> Option<&[u8]> {
+        self.common.encrypted.as_ref().map(|enc| &enc.session_id[..])
+    }
+
+    /// Replace the bytes and time limits before key re-exchange for
+    /// this connection only, for instance to re-exchange keys more
+    /// often with a weaker cipher. The bytes and time already
+    /// elapsed since the last key exchange count towards the new
+    /// limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.common.limits = limits
+    }
+
+    /// Start a new key exchange with the server, for instance after a
+    /// privilege change. This does nothing before the first key
+    /// exchange has completed, or if a key exchange is already in
+    /// progress.
+    pub fn request_rekey(&mut self) {
+        if self.common.kex.is_some() {
+            return;
+        }
+        if let Some(ref mut enc) = self.common.encrypted {
+            if enc.exchange.is_some() {
+                enc.rekey_wanted = true
+            }
+        }
+    }
+
+    /// The last language tag sent by the server, if
+    /// `Config::capture_language_tags` is set and the server sent
+    /// one.
+    pub fn peer_language_tag(&self) -> Option<&str> {
+        self.common
+            .encrypted
+            .as_ref()?
+            .peer_language_tag
+            .as_deref()
+    }
+
+    /// The extensions sent by the server in `SSH_MSG_EXT_INFO`, as
+    /// `(name, value)` pairs. Empty if the server did not send any.
+    pub fn peer_extensions(&self) -> &[(String, Vec<u8>)] {
+        if let Some(ref enc) = self.common.encrypted {
+            &enc.peer_extensions
+        } else {
+            &[]
+        }
+    }
+
+    /// The public key signature algorithms accepted by the server,
+    /// as advertised in its `server-sig-algs` extension, or `None` if
+    /// the server did not send that extension.
+    pub fn server_sig_algs(&self) -> Option<Vec<&str>> {
+        self.common.encrypted.as_ref()?.server_sig_algs()
+    }
 }
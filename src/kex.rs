@@ -44,8 +44,18 @@ impl AsRef<str> for Name {
         self.0
     }
 }
+impl crate::Named for Name {
+    fn name(&self) -> &'static str {
+        self.0
+    }
+}
 pub const CURVE25519: Name = Name("curve25519-sha256@libssh.org");
 
+/// Extension negotiation, listed as a key exchange algorithm by peers
+/// offering it in every KEXINIT.
+#[cfg(test)]
+pub(crate) const EXT_INFO_C: Name = Name(crate::negotiation::EXT_INFO_C);
+
 thread_local! {
     static KEY_BUF: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
     static BUFFER: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
@@ -62,3 +62,14 @@ impl PubKey for KeyPair {
         }
     }
 }
+
+/// A host certificate in wire format, sent (and included in the
+/// exchange hash) instead of the plain host key when a certificate
+/// algorithm was negotiated.
+pub(crate) struct HostCertificate<'a>(pub &'a [u8]);
+
+impl<'a> PubKey for HostCertificate<'a> {
+    fn push_to(&self, buffer: &mut CryptoVec) {
+        buffer.extend_ssh_string(self.0)
+    }
+}
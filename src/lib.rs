@@ -276,7 +276,11 @@ extern crate thrussh_libsodium as sodium;
 extern crate thiserror;
 
 pub use cryptovec::CryptoVec;
+#[cfg(test)]
+#[macro_use]
+mod test_util;
 mod auth;
+mod cert;
 mod cipher;
 mod kex;
 mod key;
@@ -286,9 +290,10 @@ mod ssh_read;
 mod sshbuffer;
 mod tcp;
 
-pub use negotiation::{Named, Preferred};
+pub use negotiation::{Named, Preferred, ED25519_CERT, RSA_SHA2_256_CERT, RSA_SHA2_512_CERT};
 mod pty;
-pub use pty::Pty;
+pub use pty::{Pty, PtyModes};
+pub use cert::{Certificate, SSH_CERT_TYPE_HOST, SSH_CERT_TYPE_USER};
 
 pub use tcp::Tcp;
 
@@ -372,6 +377,25 @@ pub enum Error {
     #[error("Channel not open")]
     WrongChannel,
 
+    /// Data received on a channel after its EOF.
+    #[error("Data received after EOF")]
+    DataAfterEof,
+
+    /// The peer adjusted a channel window beyond 2^32 - 1 bytes.
+    #[error("Channel window overflow")]
+    WindowOverflow,
+
+    /// The peer sent a packet larger than allowed, or channel data
+    /// larger than the maximum packet size of the channel.
+    #[error("Packet too large")]
+    PacketTooLarge,
+
+    /// The peer sent a message other than a key exchange message
+    /// during the initial strict key exchange, or did not start with
+    /// its KEXINIT.
+    #[error("Unexpected message during strict key exchange")]
+    StrictKex,
+
     /// Disconnected
     #[error("Disconnected")]
     Disconnect,
@@ -389,6 +413,33 @@ pub enum Error {
     #[error("Connection closed by the remote side")]
     HUP,
 
+    /// The connection was closed by the remote side before the end
+    /// of the key exchange.
+    #[error("Connection closed by the remote side during the handshake")]
+    PeerClosedDuringHandshake,
+
+    /// The PROXY protocol header expected at the beginning of the
+    /// connection is missing or malformed.
+    #[error("Invalid PROXY protocol header")]
+    InvalidProxyHeader,
+
+    /// An OpenSSH certificate is malformed or not properly signed.
+    #[error("Invalid certificate")]
+    InvalidCertificate,
+
+    /// The remote side did not answer our keepalive requests.
+    #[error("No reply to keepalive requests")]
+    KeepaliveTimeout,
+
+    /// Writing to the remote side failed, usually because it closed
+    /// the connection.
+    #[error("Connection closed by the remote side while writing")]
+    PeerWriteClosed,
+
+    /// The remote side did not read our data in time.
+    #[error("Timeout while sending data")]
+    SendTimeout,
+
     /// Connection timeout.
     #[error("Connection timeout")]
     ConnectionTimeout,
@@ -399,6 +450,35 @@ pub enum Error {
 
     #[error("Channel send error")]
     SendError,
+
+    /// Unknown or unsupported algorithm name.
+    #[error("Unknown algorithm: {}", name)]
+    UnknownAlgorithm { name: String },
+
+    /// Empty list of algorithms of the given category (such as
+    /// `"cipher"`), with which no algorithm could be negotiated.
+    #[error("Empty list of {} algorithms", category)]
+    EmptyAlgorithmList { category: &'static str },
+
+    /// Unknown authentication method name.
+    #[error("Unknown authentication method: {}", name)]
+    UnknownAuthMethod { name: String },
+}
+
+/// Thread-local buffers are reused from one packet to the next, but
+/// their allocation is dropped after use if they grew beyond this
+/// size, so that a single large packet does not inflate the memory
+/// of a worker thread forever.
+const MAX_RETAINED_BUFFER_LEN: usize = 1 << 16;
+
+/// Clear a thread-local buffer after use, releasing its memory if it
+/// grew beyond `MAX_RETAINED_BUFFER_LEN`.
+pub(crate) fn release_buffer(buffer: &mut CryptoVec) {
+    if buffer.len() > MAX_RETAINED_BUFFER_LEN {
+        *buffer = CryptoVec::new()
+    } else {
+        buffer.clear()
+    }
 }
 
 /// Since handlers are large, their associated future types must implement this trait to provide reasonable default implementations (basically, rejecting all requests).
@@ -461,7 +541,7 @@ pub use auth::MethodSet;
 
 /// A reason for disconnection.
 #[allow(missing_docs)] // This should be relatively self-explanatory.
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Disconnect {
     HostNotAllowedToConnect = 1,
     ProtocolError = 2,
@@ -481,6 +561,29 @@ pub enum Disconnect {
     IllegalUserName = 15,
 }
 
+impl Disconnect {
+    fn from_u32(x: u32) -> Option<Disconnect> {
+        match x {
+            1 => Some(Disconnect::HostNotAllowedToConnect),
+            2 => Some(Disconnect::ProtocolError),
+            3 => Some(Disconnect::KeyExchangeFailed),
+            4 => Some(Disconnect::Reserved),
+            5 => Some(Disconnect::MACError),
+            6 => Some(Disconnect::CompressionError),
+            7 => Some(Disconnect::ServiceNotAvailable),
+            8 => Some(Disconnect::ProtocolVersionNotSupported),
+            9 => Some(Disconnect::HostKeyNotVerifiable),
+            10 => Some(Disconnect::ConnectionLost),
+            11 => Some(Disconnect::ByApplication),
+            12 => Some(Disconnect::TooManyConnections),
+            13 => Some(Disconnect::AuthCancelledByUser),
+            14 => Some(Disconnect::NoMoreAuthMethodsAvailable),
+            15 => Some(Disconnect::IllegalUserName),
+            _ => None,
+        }
+    }
+}
+
 /// The type of signals that can be sent to a remote process. If you
 /// plan to use custom signals, read [the
 /// RFC](https://tools.ietf.org/html/rfc4254#section-6.10) to
@@ -567,6 +670,63 @@ impl ChannelOpenFailure {
 /// The identifier of a channel.
 pub struct ChannelId(u32);
 
+/// A snapshot of the flow control parameters of a channel, as
+/// returned by `Session::channel_info`. "Sender" refers to our side
+/// of the channel, "recipient" to the remote side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelInfo {
+    /// Number of bytes the remote side may still send us before we
+    /// adjust the window.
+    pub sender_window_size: u32,
+    /// Number of bytes we may still send before the remote side
+    /// adjusts the window.
+    pub recipient_window_size: u32,
+    /// Maximal size of the packets we accept on this channel.
+    pub sender_maximum_packet_size: u32,
+    /// Maximal size of the packets the remote side accepts.
+    pub recipient_maximum_packet_size: u32,
+    /// Whether the remote side has confirmed the channel.
+    pub confirmed: bool,
+}
+
+/// A snapshot of the state of a connection, as returned by
+/// `Session::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Bytes received on the connection, including packet headers,
+    /// padding and MACs, but not the identification string.
+    pub bytes_read: u64,
+    /// Bytes sent on the connection, counted the same way.
+    pub bytes_written: u64,
+    /// Number of channels currently open (or waiting for
+    /// confirmation).
+    pub channels: usize,
+    /// Number of key re-exchanges completed after the first key
+    /// exchange.
+    pub rekeys: usize,
+    /// The authenticated user, or `None` if authentication is not
+    /// finished yet.
+    pub user: Option<String>,
+    /// Time elapsed since the connection was established.
+    pub uptime: std::time::Duration,
+    /// The negotiated algorithms, or `None` before the end of the
+    /// first key exchange.
+    pub algorithms: Option<NegotiatedAlgorithms>,
+}
+
+/// The algorithms negotiated in the last key exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedAlgorithms {
+    /// Key exchange algorithm.
+    pub kex: &'static str,
+    /// Host key algorithm.
+    pub key: &'static str,
+    /// Symmetric cipher.
+    pub cipher: &'static str,
+    /// MAC algorithm, or `None` for ciphers with integrated MACs.
+    pub mac: Option<&'static str>,
+}
+
 /// The parameters of a channel.
 #[derive(Debug)]
 pub(crate) struct Channel {
@@ -579,6 +739,30 @@ pub(crate) struct Channel {
     /// Has the other side confirmed the channel?
     pub confirmed: bool,
     wants_reply: bool,
+    /// Has the other side sent EOF? The channel stays open (and
+    /// channel requests such as `exit-status` are still processed)
+    /// until it is closed, but any further data is an error.
+    eof_received: bool,
+    /// Data waiting for the window to be adjusted, with its extended
+    /// data code if any, see `data_flow_controlled`.
+    pending_data: std::collections::VecDeque<(Option<u32>, CryptoVec)>,
+    /// EOF was requested while data was pending: send it once
+    /// `pending_data` is flushed.
+    pending_eof: bool,
+    /// Same as `pending_eof`, for closing the channel.
+    pending_close: bool,
+}
+
+impl Channel {
+    fn info(&self) -> ChannelInfo {
+        ChannelInfo {
+            sender_window_size: self.sender_window_size,
+            recipient_window_size: self.recipient_window_size,
+            sender_maximum_packet_size: self.sender_maximum_packet_size,
+            recipient_maximum_packet_size: self.recipient_maximum_packet_size,
+            confirmed: self.confirmed,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -604,3 +788,24 @@ pub enum ChannelMsg {
         lang_tag: String,
     },
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Large buffers lose their allocation after use, others keep it.
+    #[test]
+    fn release_buffer() {
+        let mut buffer = CryptoVec::new_zeroed(MAX_RETAINED_BUFFER_LEN);
+        let allocation = buffer.as_ptr();
+        super::release_buffer(&mut buffer);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), allocation);
+
+        let mut buffer = CryptoVec::new_zeroed(MAX_RETAINED_BUFFER_LEN + 1);
+        let allocation = buffer.as_ptr();
+        super::release_buffer(&mut buffer);
+        assert!(buffer.is_empty());
+        assert_ne!(buffer.as_ptr(), allocation);
+    }
+}
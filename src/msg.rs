@@ -23,6 +23,8 @@ pub const DEBUG: u8 = 4;
 
 pub const SERVICE_REQUEST: u8 = 5;
 pub const SERVICE_ACCEPT: u8 = 6;
+// https://tools.ietf.org/html/rfc8308#section-2.3
+pub const EXT_INFO: u8 = 7;
 pub const KEXINIT: u8 = 20;
 pub const NEWKEYS: u8 = 21;
 
@@ -36,6 +38,7 @@ pub const USERAUTH_FAILURE: u8 = 51;
 pub const USERAUTH_SUCCESS: u8 = 52;
 pub const USERAUTH_BANNER: u8 = 53;
 pub const USERAUTH_PK_OK: u8 = 60;
+pub const USERAUTH_PASSWD_CHANGEREQ: u8 = 60;
 
 // https://tools.ietf.org/html/rfc4256#section-5
 pub const USERAUTH_INFO_REQUEST: u8 = 60;
@@ -57,3 +60,19 @@ pub const CHANNEL_CLOSE: u8 = 97;
 pub const CHANNEL_REQUEST: u8 = 98;
 pub const CHANNEL_SUCCESS: u8 = 99;
 pub const CHANNEL_FAILURE: u8 = 100;
+
+/// Whether `m` is a message number assigned by the SSH
+/// specifications, including the ranges reserved for key exchange
+/// and authentication methods. Other numbers are answered with
+/// `SSH_MSG_UNIMPLEMENTED`.
+pub fn is_assigned(m: u8) -> bool {
+    match m {
+        DISCONNECT..=EXT_INFO | KEXINIT | NEWKEYS => true,
+        // Key exchange method specific.
+        30..=49 => true,
+        // User authentication, including method specific messages.
+        USERAUTH_REQUEST..=USERAUTH_BANNER | 60..=79 => true,
+        GLOBAL_REQUEST..=REQUEST_FAILURE | CHANNEL_OPEN..=CHANNEL_FAILURE => true,
+        _ => false,
+    }
+}
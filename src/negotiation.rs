@@ -13,6 +13,7 @@
 // limitations under the License.
 //
 use crate::{cipher, kex, msg, Error};
+use std::borrow::Cow;
 use std::str::from_utf8;
 use thrussh_keys::key;
 // use super::mac; // unimplemented
@@ -29,31 +30,139 @@ pub struct Names {
     pub cipher: cipher::Name,
     pub mac: Option<&'static str>,
     pub ignore_guessed: bool,
+    /// The language lists of the remote side's KEXINIT
+    /// (client-to-server, then server-to-client).
+    pub languages: (Vec<String>, Vec<String>),
+    /// Whether the remote side accepts `SSH_MSG_EXT_INFO`.
+    pub ext_info: bool,
+    /// Whether the remote side supports strict key exchange.
+    pub strict_kex: bool,
 }
 
 /// Lists of preferred algorithms. This is normally hard-coded into implementations.
-#[derive(Debug)]
+///
+/// Since version 0.27, the lists are `Cow`s, so that
+/// [`from_names`](#method.from_names) can build them: lists written
+/// as literals must be wrapped in `Cow::Borrowed`.
+#[derive(Debug, Clone)]
 pub struct Preferred {
     /// Preferred key exchange algorithms.
-    pub kex: &'static [kex::Name],
+    pub kex: Cow<'static, [kex::Name]>,
     /// Preferred public key algorithms.
-    pub key: &'static [key::Name],
+    pub key: Cow<'static, [key::Name]>,
     /// Preferred symmetric ciphers.
-    pub cipher: &'static [cipher::Name],
+    pub cipher: Cow<'static, [cipher::Name]>,
     /// Preferred MAC algorithms.
-    pub mac: &'static [&'static str],
+    pub mac: Cow<'static, [&'static str]>,
     /// Preferred compression algorithms.
-    pub compression: &'static [&'static str],
+    pub compression: Cow<'static, [&'static str]>,
 }
 
 pub const DEFAULT: Preferred = Preferred {
-    kex: &[kex::CURVE25519],
-    key: &[key::ED25519, key::RSA_SHA2_256, key::RSA_SHA2_512],
-    cipher: &[cipher::chacha20poly1305::NAME],
-    mac: &["none"],
-    compression: &["none"],
+    kex: Cow::Borrowed(&[kex::CURVE25519]),
+    key: Cow::Borrowed(&[key::ED25519, key::RSA_SHA2_256, key::RSA_SHA2_512]),
+    cipher: Cow::Borrowed(&[cipher::chacha20poly1305::NAME]),
+    mac: Cow::Borrowed(&["none"]),
+    compression: Cow::Borrowed(&["none"]),
 };
 
+/// Pseudo key exchange algorithm sent by clients accepting
+/// `SSH_MSG_EXT_INFO`, see [RFC8308](https://tools.ietf.org/html/rfc8308).
+pub(crate) const EXT_INFO_C: &str = "ext-info-c";
+/// Pseudo key exchange algorithm sent by servers accepting
+/// `SSH_MSG_EXT_INFO`.
+pub(crate) const EXT_INFO_S: &str = "ext-info-s";
+/// Pseudo key exchange algorithm sent by clients supporting strict
+/// key exchange, see OpenSSH's
+/// [PROTOCOL](https://cvsweb.openbsd.org/src/usr.bin/ssh/PROTOCOL)
+/// (mitigation of CVE-2023-48795, "Terrapin").
+pub(crate) const STRICT_KEX_C: &str = "kex-strict-c-v00@openssh.com";
+/// Pseudo key exchange algorithm sent by servers supporting strict
+/// key exchange.
+pub(crate) const STRICT_KEX_S: &str = "kex-strict-s-v00@openssh.com";
+/// Extension listing the public key signature algorithms accepted
+/// by the server for user authentication.
+pub(crate) const SERVER_SIG_ALGS: &str = "server-sig-algs";
+
+/// OpenSSH host certificate algorithm for Ed25519 keys.
+pub const ED25519_CERT: key::Name = key::Name("ssh-ed25519-cert-v01@openssh.com");
+/// OpenSSH host certificate algorithm for RSA keys, signing with SHA-256.
+pub const RSA_SHA2_256_CERT: key::Name = key::Name("rsa-sha2-256-cert-v01@openssh.com");
+/// OpenSSH host certificate algorithm for RSA keys, signing with SHA-512.
+pub const RSA_SHA2_512_CERT: key::Name = key::Name("rsa-sha2-512-cert-v01@openssh.com");
+
+/// The key algorithm used to sign with a certificate algorithm, or
+/// `None` if `name` is not a certificate algorithm.
+pub(crate) fn certificate_key(name: &str) -> Option<key::Name> {
+    if name == ED25519_CERT.0 {
+        Some(key::ED25519)
+    } else if name == RSA_SHA2_256_CERT.0 {
+        Some(key::RSA_SHA2_256)
+    } else if name == RSA_SHA2_512_CERT.0 {
+        Some(key::RSA_SHA2_512)
+    } else {
+        None
+    }
+}
+
+const SUPPORTED: Preferred = Preferred {
+    kex: Cow::Borrowed(&[kex::CURVE25519]),
+    key: Cow::Borrowed(&[
+        key::ED25519,
+        key::RSA_SHA2_256,
+        key::RSA_SHA2_512,
+        ED25519_CERT,
+        RSA_SHA2_256_CERT,
+        RSA_SHA2_512_CERT,
+    ]),
+    cipher: Cow::Borrowed(&[cipher::chacha20poly1305::NAME]),
+    mac: Cow::Borrowed(&["none"]),
+    compression: Cow::Borrowed(&["none"]),
+};
+
+impl Preferred {
+    /// Build lists of preferred algorithms from the given names, in
+    /// this order of preference, with no defaults. An error is
+    /// returned if one of the lists is empty, or if one of the names
+    /// is not implemented by Thrussh.
+    pub fn from_names(
+        kex: &[&str],
+        key: &[&str],
+        cipher: &[&str],
+        mac: &[&str],
+        compression: &[&str],
+    ) -> Result<Preferred, Error> {
+        Ok(Preferred {
+            kex: select_names("key exchange", &SUPPORTED.kex, kex)?,
+            key: select_names("public key", &SUPPORTED.key, key)?,
+            cipher: select_names("cipher", &SUPPORTED.cipher, cipher)?,
+            mac: select_names("MAC", &SUPPORTED.mac, mac)?,
+            compression: select_names("compression", &SUPPORTED.compression, compression)?,
+        })
+    }
+}
+
+fn select_names<N: AsRef<str> + Copy>(
+    category: &'static str,
+    supported: &[N],
+    names: &[&str],
+) -> Result<Cow<'static, [N]>, Error> {
+    if names.is_empty() {
+        return Err(Error::EmptyAlgorithmList { category });
+    }
+    let mut selected = Vec::with_capacity(names.len());
+    for name in names {
+        if let Some(n) = supported.iter().find(|n| n.as_ref() == *name) {
+            selected.push(*n)
+        } else {
+            return Err(Error::UnknownAlgorithm {
+                name: name.to_string(),
+            });
+        }
+    }
+    Ok(Cow::Owned(selected))
+}
+
 impl Default for Preferred {
     fn default() -> Preferred {
         DEFAULT
@@ -93,12 +202,29 @@ impl Named for KeyPair {
 }
 
 pub trait Select {
+    /// The pseudo-algorithm the remote side sends to signal it
+    /// accepts `SSH_MSG_EXT_INFO`.
+    const PEER_EXT_INFO: &'static str;
+    /// The pseudo-algorithm the remote side sends to signal it
+    /// supports strict key exchange.
+    const PEER_STRICT_KEX: &'static str;
+
     fn select<S: AsRef<str> + Copy>(a: &[S], b: &[u8]) -> Option<(bool, S)>;
 
     fn read_kex(buffer: &[u8], pref: &Preferred) -> Result<Names, anyhow::Error> {
+        Self::read_kex_keys(buffer, pref, &pref.key)
+    }
+
+    /// Same as `read_kex`, but choosing the host key algorithm among
+    /// `keys` instead of `pref.key`.
+    fn read_kex_keys(
+        buffer: &[u8],
+        pref: &Preferred,
+        keys: &[key::Name],
+    ) -> Result<Names, anyhow::Error> {
         let mut r = buffer.reader(17);
         let kex_string = r.read_string()?;
-        let (kex_both_first, kex_algorithm) = if let Some(x) = Self::select(pref.kex, kex_string) {
+        let (kex_both_first, kex_algorithm) = if let Some(x) = Self::select(&pref.kex, kex_string) {
             x
         } else {
             debug!(
@@ -108,21 +234,27 @@ pub trait Select {
             );
             return Err(Error::NoCommonKexAlgo.into());
         };
+        let ext_info = kex_string
+            .split(|&x| x == b',')
+            .any(|x| x == Self::PEER_EXT_INFO.as_bytes());
+        let strict_kex = kex_string
+            .split(|&x| x == b',')
+            .any(|x| x == Self::PEER_STRICT_KEX.as_bytes());
 
         let key_string = r.read_string()?;
-        let (key_both_first, key_algorithm) = if let Some(x) = Self::select(pref.key, key_string) {
+        let (key_both_first, key_algorithm) = if let Some(x) = Self::select(keys, key_string) {
             x
         } else {
             debug!(
                 "Could not find common key algorithm, other side only supports {:?}, we only support {:?}",
                 from_utf8(key_string),
-                pref.key
+                keys
             );
             return Err(Error::NoCommonKeyAlgo.into());
         };
 
         let cipher_string = r.read_string()?;
-        let cipher = Self::select(pref.cipher, cipher_string);
+        let cipher = Self::select(&pref.cipher, cipher_string);
         if cipher.is_none() {
             debug!(
                 "Could not find common cipher, other side only supports {:?}, we only support {:?}",
@@ -132,12 +264,15 @@ pub trait Select {
             return Err(Error::NoCommonCipher.into());
         }
         r.read_string()?; // SERVER_TO_CLIENT
-        let mac = Self::select(pref.mac, r.read_string()?);
+        let mac = Self::select(&pref.mac, r.read_string()?);
         let mac = mac.and_then(|(_, x)| Some(x));
         r.read_string()?; // SERVER_TO_CLIENT
-        r.read_string()?; //
-        r.read_string()?; //
-        r.read_string()?; //
+        r.read_string()?; // compression, CLIENT_TO_SERVER
+        r.read_string()?; // compression, SERVER_TO_CLIENT
+        let languages = (
+            read_name_list(r.read_string()?),
+            read_name_list(r.read_string()?),
+        );
 
         let follows = r.read_byte()? != 0;
         match (cipher, mac, follows) {
@@ -149,6 +284,9 @@ pub trait Select {
                     mac: mac,
                     // Ignore the next packet if (1) it follows and (2) it's not the correct guess.
                     ignore_guessed: fol && !(kex_both_first && key_both_first),
+                    languages,
+                    ext_info,
+                    strict_kex,
                 })
             }
             _ => Err(Error::KexInit.into()),
@@ -156,10 +294,21 @@ pub trait Select {
     }
 }
 
+/// Split a comma-separated name-list, skipping empty names.
+fn read_name_list(list: &[u8]) -> Vec<String> {
+    list.split(|&x| x == b',')
+        .filter(|x| !x.is_empty())
+        .map(|x| String::from_utf8_lossy(x).into_owned())
+        .collect()
+}
+
 pub struct Server;
 pub struct Client;
 
 impl Select for Server {
+    const PEER_EXT_INFO: &'static str = EXT_INFO_C;
+    const PEER_STRICT_KEX: &'static str = STRICT_KEX_C;
+
     fn select<S: AsRef<str> + Copy>(server_list: &[S], client_list: &[u8]) -> Option<(bool, S)> {
         let mut both_first_choice = true;
         for c in client_list.split(|&x| x == b',') {
@@ -175,6 +324,9 @@ impl Select for Server {
 }
 
 impl Select for Client {
+    const PEER_EXT_INFO: &'static str = EXT_INFO_S;
+    const PEER_STRICT_KEX: &'static str = STRICT_KEX_S;
+
     fn select<S: AsRef<str> + Copy>(client_list: &[S], server_list: &[u8]) -> Option<(bool, S)> {
         let mut both_first_choice = true;
         for &c in client_list {
@@ -189,7 +341,25 @@ impl Select for Client {
     }
 }
 
-pub fn write_kex(prefs: &Preferred, buf: &mut CryptoVec) -> Result<(), anyhow::Error> {
+/// Write a KEXINIT packet. The pseudo-algorithms in `extensions`
+/// (such as `ext-info-c`) are appended to the key exchange
+/// algorithms, to signal support for protocol extensions.
+pub fn write_kex(
+    prefs: &Preferred,
+    extensions: &[&str],
+    buf: &mut CryptoVec,
+) -> Result<(), anyhow::Error> {
+    write_kex_keys(prefs, prefs.key.iter().map(|k| k.0), extensions, buf)
+}
+
+/// Same as `write_kex`, but advertising only the host key algorithms
+/// in `keys` instead of `prefs.key`.
+pub fn write_kex_keys<'a, I: Iterator<Item = &'a str>>(
+    prefs: &Preferred,
+    keys: I,
+    extensions: &[&str],
+    buf: &mut CryptoVec,
+) -> Result<(), anyhow::Error> {
     // buf.clear();
     buf.push(msg::KEXINIT);
 
@@ -197,9 +367,16 @@ pub fn write_kex(prefs: &Preferred, buf: &mut CryptoVec) -> Result<(), anyhow::E
     rand::rand_bytes(&mut cookie)?;
 
     buf.extend(&cookie); // cookie
-    buf.extend_list(prefs.kex.iter()); // kex algo
+    // kex algo
+    buf.extend_list(
+        prefs
+            .kex
+            .iter()
+            .map(|k| k.as_ref())
+            .chain(extensions.iter().cloned()),
+    );
 
-    buf.extend_list(prefs.key.iter());
+    buf.extend_list(keys);
 
     buf.extend_list(prefs.cipher.iter()); // cipher client to server
     buf.extend_list(prefs.cipher.iter()); // cipher server to client
@@ -216,3 +393,120 @@ pub fn write_kex(prefs: &Preferred, buf: &mut CryptoVec) -> Result<(), anyhow::E
     buf.extend(&[0, 0, 0, 0]); // reserved
     Ok(())
 }
+
+/// Write an `SSH_MSG_EXT_INFO` packet, see
+/// [RFC8308](https://tools.ietf.org/html/rfc8308#section-2.3).
+pub(crate) fn write_ext_info(extensions: &[(String, Vec<u8>)], buf: &mut CryptoVec) {
+    buf.push(msg::EXT_INFO);
+    buf.push_u32_be(extensions.len() as u32);
+    for (name, value) in extensions {
+        buf.extend_ssh_string(name.as_bytes());
+        buf.extend_ssh_string(value);
+    }
+}
+
+/// The value of the `server-sig-algs` extension: the comma-separated
+/// signature algorithms of `prefs`, without the certificate
+/// algorithms.
+pub(crate) fn server_sig_algs(prefs: &Preferred) -> Vec<u8> {
+    let mut algs = Vec::new();
+    for key in prefs.key.iter() {
+        if crate::cert::key_algorithm(key.0.as_bytes()).is_some() {
+            continue;
+        }
+        if !algs.is_empty() {
+            algs.push(b',')
+        }
+        algs.extend(key.0.as_bytes())
+    }
+    algs
+}
+
+/// Read an `SSH_MSG_EXT_INFO` packet into `extensions`, replacing the
+/// values of extensions already received.
+pub(crate) fn read_ext_info(
+    buf: &[u8],
+    extensions: &mut Vec<(String, Vec<u8>)>,
+) -> Result<(), anyhow::Error> {
+    let mut r = buf.reader(1);
+    let n = r.read_u32()?;
+    for _ in 0..n {
+        let name = from_utf8(r.read_string()?)?;
+        let value = r.read_string()?.to_vec();
+        if let Some(ext) = extensions.iter_mut().find(|ext| ext.0 == name) {
+            ext.1 = value
+        } else {
+            extensions.push((name.to_string(), value))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_names() {
+        let preferred = Preferred::from_names(
+            &["curve25519-sha256@libssh.org"],
+            &["rsa-sha2-512", "ssh-ed25519"],
+            &["chacha20-poly1305@openssh.com"],
+            &["none"],
+            &["none"],
+        )
+        .unwrap();
+        assert_eq!(&preferred.kex[..], &[kex::CURVE25519]);
+        assert_eq!(&preferred.key[..], &[key::RSA_SHA2_512, key::ED25519]);
+        assert_eq!(&preferred.cipher[..], &[cipher::chacha20poly1305::NAME]);
+        assert_eq!(&preferred.mac[..], &["none"]);
+        assert_eq!(&preferred.compression[..], &["none"]);
+
+        let unknown = Preferred::from_names(
+            &["curve25519-sha256@libssh.org"],
+            &["ssh-ed25519"],
+            &["aes128-cbc"],
+            &["none"],
+            &["none"],
+        );
+        match unknown {
+            Err(Error::UnknownAlgorithm { name }) => assert_eq!(name, "aes128-cbc"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let empty = Preferred::from_names(
+            &["curve25519-sha256@libssh.org"],
+            &["ssh-ed25519"],
+            &["chacha20-poly1305@openssh.com"],
+            &[],
+            &["none"],
+        );
+        match empty {
+            Err(Error::EmptyAlgorithmList { category }) => assert_eq!(category, "MAC"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn peer_languages() {
+        let mut kexinit = CryptoVec::new();
+        write_kex(&DEFAULT, &[], &mut kexinit).unwrap();
+        // Replace the empty language lists, the "first kex packet
+        // follows" flag and the reserved field.
+        let len = kexinit.len();
+        kexinit.resize(len - 13);
+        kexinit.extend_ssh_string(b"en-US,fr");
+        kexinit.extend_ssh_string(b"de,");
+        kexinit.push(0);
+        kexinit.push_u32_be(0);
+        let names = Server::read_kex(&kexinit, &DEFAULT).unwrap();
+        let client_to_server = vec!["en-US".to_string(), "fr".to_string()];
+        assert_eq!(names.languages, (client_to_server, vec!["de".to_string()]));
+
+        // Our own KEXINIT has empty lists.
+        kexinit.clear();
+        write_kex(&DEFAULT, &[], &mut kexinit).unwrap();
+        let names = Server::read_kex(&kexinit, &DEFAULT).unwrap();
+        assert_eq!(names.languages, (Vec::new(), Vec::new()));
+    }
+}
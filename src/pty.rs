@@ -1,3 +1,6 @@
+use byteorder::{BigEndian, ByteOrder};
+use cryptovec::CryptoVec;
+
 #[allow(non_camel_case_types, missing_docs)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 /// Standard pseudo-terminal codes.
@@ -130,3 +133,364 @@ impl Pty {
         }
     }
 }
+
+/// Terminal modes of a pseudo-terminal request, as pairs of a mode
+/// and its value, in the order sent by the client. On Linux and the
+/// BSDs, they can be applied to, or read from, a `libc::termios`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PtyModes(Vec<(Pty, u32)>);
+
+impl PtyModes {
+    /// An empty list of modes.
+    pub fn new() -> Self {
+        PtyModes(Vec::new())
+    }
+
+    /// The value of `mode`, if it was set. If a mode is set several
+    /// times, the last value wins.
+    pub fn get(&self, mode: Pty) -> Option<u32> {
+        self.0
+            .iter()
+            .rev()
+            .find(|&&(m, _)| m == mode)
+            .map(|&(_, value)| value)
+    }
+
+    /// Set `mode` to `value`, replacing the value returned by `get`.
+    pub fn set(&mut self, mode: Pty, value: u32) {
+        if let Some(m) = self.0.iter_mut().rev().find(|(m, _)| *m == mode) {
+            m.1 = value
+        } else {
+            self.0.push((mode, value))
+        }
+    }
+
+    /// Iterate over the modes and their values.
+    pub fn iter(&self) -> std::slice::Iter<'_, (Pty, u32)> {
+        self.0.iter()
+    }
+
+    /// The modes and their values, as a slice.
+    pub fn as_slice(&self) -> &[(Pty, u32)] {
+        &self.0
+    }
+
+    /// Number of modes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether no mode is set.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Parse the encoded terminal modes of a `pty-req`
+    /// ([RFC4254](https://tools.ietf.org/html/rfc4254#section-8)).
+    /// Unknown modes are skipped, and parsing stops at `TTY_OP_END`,
+    /// at opcodes 160 to 255, or at a truncated mode.
+    pub fn parse(mut buf: &[u8]) -> PtyModes {
+        let mut modes = Vec::new();
+        while buf.len() >= 5 {
+            let code = buf[0];
+            if code == 0 || code >= 160 {
+                break;
+            }
+            let value = BigEndian::read_u32(&buf[1..]);
+            if let Some(mode) = Pty::from_u8(code) {
+                modes.push((mode, value))
+            } else {
+                info!("pty-req: unknown pty code {:?}", code);
+            }
+            buf = &buf[5..]
+        }
+        PtyModes(modes)
+    }
+
+    /// Encode these modes as in a `pty-req`, including the final
+    /// `TTY_OP_END`, but not the length of the string.
+    pub fn encode(&self, buf: &mut CryptoVec) {
+        for &(code, value) in self.0.iter() {
+            buf.push(code as u8);
+            buf.push_u32_be(value)
+        }
+        buf.push(Pty::TTY_OP_END as u8)
+    }
+}
+
+impl From<Vec<(Pty, u32)>> for PtyModes {
+    fn from(modes: Vec<(Pty, u32)>) -> Self {
+        PtyModes(modes)
+    }
+}
+
+impl std::iter::FromIterator<(Pty, u32)> for PtyModes {
+    fn from_iter<I: IntoIterator<Item = (Pty, u32)>>(iter: I) -> Self {
+        PtyModes(iter.into_iter().collect())
+    }
+}
+
+impl<'a> IntoIterator for &'a PtyModes {
+    type Item = &'a (Pty, u32);
+    type IntoIter = std::slice::Iter<'a, (Pty, u32)>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+/// Conversions between `PtyModes` and the `termios` of the C library,
+/// following OpenSSH. Modes without an equivalent on this system are
+/// ignored.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+mod termios {
+    use super::{Pty, PtyModes};
+    use libc::{speed_t, tcflag_t, termios};
+
+    const CHARS: &[(Pty, usize)] = &[
+        (Pty::VINTR, libc::VINTR),
+        (Pty::VQUIT, libc::VQUIT),
+        (Pty::VERASE, libc::VERASE),
+        (Pty::VKILL, libc::VKILL),
+        (Pty::VEOF, libc::VEOF),
+        (Pty::VEOL, libc::VEOL),
+        (Pty::VEOL2, libc::VEOL2),
+        (Pty::VSTART, libc::VSTART),
+        (Pty::VSTOP, libc::VSTOP),
+        (Pty::VSUSP, libc::VSUSP),
+        (Pty::VREPRINT, libc::VREPRINT),
+        (Pty::VWERASE, libc::VWERASE),
+        (Pty::VLNEXT, libc::VLNEXT),
+        (Pty::VDISCARD, libc::VDISCARD),
+    ];
+
+    const INPUT: &[(Pty, tcflag_t)] = &[
+        (Pty::IGNPAR, libc::IGNPAR),
+        (Pty::PARMRK, libc::PARMRK),
+        (Pty::INPCK, libc::INPCK),
+        (Pty::ISTRIP, libc::ISTRIP),
+        (Pty::INLCR, libc::INLCR),
+        (Pty::IGNCR, libc::IGNCR),
+        (Pty::ICRNL, libc::ICRNL),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (Pty::IUCLC, libc::IUCLC),
+        (Pty::IXON, libc::IXON),
+        (Pty::IXANY, libc::IXANY),
+        (Pty::IXOFF, libc::IXOFF),
+        (Pty::IMAXBEL, libc::IMAXBEL),
+    ];
+
+    const LOCAL: &[(Pty, tcflag_t)] = &[
+        (Pty::ISIG, libc::ISIG),
+        (Pty::ICANON, libc::ICANON),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (Pty::XCASE, libc::XCASE),
+        (Pty::ECHO, libc::ECHO),
+        (Pty::ECHOE, libc::ECHOE),
+        (Pty::ECHOK, libc::ECHOK),
+        (Pty::ECHONL, libc::ECHONL),
+        (Pty::NOFLSH, libc::NOFLSH),
+        (Pty::TOSTOP, libc::TOSTOP),
+        (Pty::IEXTEN, libc::IEXTEN),
+        (Pty::ECHOCTL, libc::ECHOCTL),
+        (Pty::ECHOKE, libc::ECHOKE),
+        (Pty::PENDIN, libc::PENDIN),
+    ];
+
+    const OUTPUT: &[(Pty, tcflag_t)] = &[
+        (Pty::OPOST, libc::OPOST),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (Pty::OLCUC, libc::OLCUC),
+        (Pty::ONLCR, libc::ONLCR),
+        (Pty::OCRNL, libc::OCRNL),
+        (Pty::ONOCR, libc::ONOCR),
+        (Pty::ONLRET, libc::ONLRET),
+    ];
+
+    const CONTROL: &[(Pty, tcflag_t)] = &[
+        (Pty::CS7, libc::CS7),
+        (Pty::CS8, libc::CS8),
+        (Pty::PARENB, libc::PARENB),
+        (Pty::PARODD, libc::PARODD),
+    ];
+
+    /// The baud rates of `TTY_OP_ISPEED` and `TTY_OP_OSPEED`.
+    const SPEEDS: &[(u32, speed_t)] = &[
+        (0, libc::B0),
+        (50, libc::B50),
+        (75, libc::B75),
+        (110, libc::B110),
+        (134, libc::B134),
+        (150, libc::B150),
+        (200, libc::B200),
+        (300, libc::B300),
+        (600, libc::B600),
+        (1200, libc::B1200),
+        (1800, libc::B1800),
+        (2400, libc::B2400),
+        (4800, libc::B4800),
+        (9600, libc::B9600),
+        (19200, libc::B19200),
+        (38400, libc::B38400),
+        (57600, libc::B57600),
+        (115200, libc::B115200),
+        (230400, libc::B230400),
+    ];
+
+    fn flags(termios: &termios) -> [(tcflag_t, &'static [(Pty, tcflag_t)]); 4] {
+        [
+            (termios.c_iflag, INPUT),
+            (termios.c_lflag, LOCAL),
+            (termios.c_oflag, OUTPUT),
+            (termios.c_cflag, CONTROL),
+        ]
+    }
+
+    fn flags_mut(termios: &mut termios) -> [(&mut tcflag_t, &'static [(Pty, tcflag_t)]); 4] {
+        [
+            (&mut termios.c_iflag, INPUT),
+            (&mut termios.c_lflag, LOCAL),
+            (&mut termios.c_oflag, OUTPUT),
+            (&mut termios.c_cflag, CONTROL),
+        ]
+    }
+
+    impl PtyModes {
+        /// Set the modes of `termios` present in `self`, leaving the
+        /// others unchanged. Unknown baud rates are ignored.
+        pub fn apply_to_termios(&self, termios: &mut termios) {
+            for &(mode, value) in self.iter() {
+                if let Some(&(_, i)) = CHARS.iter().find(|&&(m, _)| m == mode) {
+                    termios.c_cc[i] = value as libc::cc_t;
+                } else if mode == Pty::TTY_OP_ISPEED || mode == Pty::TTY_OP_OSPEED {
+                    if let Some(&(_, speed)) = SPEEDS.iter().find(|&&(rate, _)| rate == value) {
+                        unsafe {
+                            if mode == Pty::TTY_OP_ISPEED {
+                                libc::cfsetispeed(termios, speed);
+                            } else {
+                                libc::cfsetospeed(termios, speed);
+                            }
+                        }
+                    }
+                } else {
+                    for (field, table) in flags_mut(termios).iter_mut() {
+                        if let Some(&(_, flag)) = table.iter().find(|&&(m, _)| m == mode) {
+                            if value != 0 {
+                                **field |= flag
+                            } else {
+                                **field &= !flag
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        /// The modes of `termios`, for instance to send the local
+        /// terminal's modes in a `pty-req`.
+        pub fn from_termios(termios: &termios) -> PtyModes {
+            let mut modes = PtyModes::new();
+            for &(mode, i) in CHARS {
+                modes.0.push((mode, termios.c_cc[i] as u32))
+            }
+            for &(field, table) in flags(termios).iter() {
+                for &(mode, flag) in table {
+                    modes.0.push((mode, (field & flag != 0) as u32))
+                }
+            }
+            let speeds = unsafe {
+                [
+                    (Pty::TTY_OP_ISPEED, libc::cfgetispeed(termios)),
+                    (Pty::TTY_OP_OSPEED, libc::cfgetospeed(termios)),
+                ]
+            };
+            for &(mode, speed) in speeds.iter() {
+                if let Some(&(rate, _)) = SPEEDS.iter().find(|&&(_, s)| s == speed) {
+                    modes.0.push((mode, rate))
+                }
+            }
+            modes
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_parse() {
+        // Duplicate modes are kept, in order.
+        let modes: PtyModes = vec![
+            (Pty::VINTR, 3),
+            (Pty::ECHO, 0),
+            (Pty::TTY_OP_ISPEED, 38400),
+            (Pty::ECHO, 1),
+        ]
+        .into();
+        let mut buf = CryptoVec::new();
+        modes.encode(&mut buf);
+        assert_eq!(buf.len(), 4 * 5 + 1);
+        assert_eq!(buf[buf.len() - 1], Pty::TTY_OP_END as u8);
+        let parsed = PtyModes::parse(&buf);
+        assert_eq!(parsed, modes);
+        assert_eq!(parsed.get(Pty::ECHO), Some(1));
+    }
+
+    #[test]
+    fn set_duplicate() {
+        // `set` changes the last occurrence of a mode, which `get` returns.
+        let mut modes: PtyModes = vec![(Pty::ECHO, 1), (Pty::VINTR, 3), (Pty::ECHO, 0)].into();
+        assert_eq!(modes.get(Pty::ECHO), Some(0));
+        modes.set(Pty::ECHO, 1);
+        assert_eq!(modes.get(Pty::ECHO), Some(1));
+        assert_eq!(
+            modes.as_slice(),
+            &[(Pty::ECHO, 1), (Pty::VINTR, 3), (Pty::ECHO, 1)]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn termios() {
+        let modes: PtyModes = vec![
+            (Pty::VINTR, 3),
+            (Pty::ECHO, 1),
+            (Pty::ICANON, 1),
+            (Pty::ONLCR, 0),
+            (Pty::TTY_OP_ISPEED, 38400),
+            (Pty::TTY_OP_OSPEED, 38400),
+        ]
+        .into();
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        termios.c_oflag = libc::ONLCR | libc::OPOST;
+        modes.apply_to_termios(&mut termios);
+        assert_eq!(termios.c_cc[libc::VINTR], 3);
+        assert_eq!(termios.c_lflag, libc::ECHO | libc::ICANON);
+        assert_eq!(termios.c_oflag, libc::OPOST);
+        let back = PtyModes::from_termios(&termios);
+        for &(mode, value) in modes.iter() {
+            assert_eq!(back.get(mode), Some(value), "{:?}", mode);
+        }
+        assert_eq!(back.get(Pty::OPOST), Some(1));
+        assert_eq!(back.get(Pty::ECHOE), Some(0));
+    }
+
+    #[test]
+    fn parse_unknown_and_truncated() {
+        // An unknown mode (99) is skipped, and the last mode is truncated.
+        let buf = [1, 0, 0, 0, 3, 99, 0, 0, 0, 1, 53, 0, 0, 0, 1, 128, 0, 0];
+        let modes = PtyModes::parse(&buf);
+        assert_eq!(modes.as_slice(), &[(Pty::VINTR, 3), (Pty::ECHO, 1)]);
+        assert_eq!(modes.get(Pty::ECHO), Some(1));
+        assert_eq!(modes.get(Pty::TTY_OP_ISPEED), None);
+    }
+}
@@ -15,10 +15,10 @@
 use super::super::*;
 use super::*;
 use auth::*;
+use crate::key::PubKey;
 use byteorder::{BigEndian, ByteOrder};
 use msg;
 use negotiation;
-use negotiation::Select;
 use std::cell::RefCell;
 use thrussh_keys::encoding::{Encoding, Position, Reader};
 use thrussh_keys::key;
@@ -32,7 +32,8 @@ impl Session {
         handler: &mut Option<H>,
         buf: &[u8],
     ) -> Result<Self, anyhow::Error> {
-        let instant = tokio::time::Instant::now() + self.common.config.auth_rejection_time;
+        let now = tokio::time::Instant::now();
+        let instant = now + self.common.config.auth_rejection_time;
         debug!("read_encrypted");
         // Either this packet is a KEXINIT, in which case we start a key re-exchange.
 
@@ -40,11 +41,7 @@ impl Session {
         if buf[0] == msg::KEXINIT {
             // If we're not currently rekeying, but buf is a rekey request
             if let Some(exchange) = enc.exchange.take() {
-                let kexinit = KexInit::received_rekey(
-                    exchange,
-                    negotiation::Server::read_kex(buf, &self.common.config.as_ref().preferred)?,
-                    &enc.session_id,
-                );
+                let kexinit = KexInit::received_rekey(exchange, &enc.session_id);
                 self.common.kex = Some(kexinit.server_parse(
                     self.common.config.as_ref(),
                     &mut self.common.cipher,
@@ -54,6 +51,10 @@ impl Session {
             }
             return Ok(self);
         }
+        if buf[0] == msg::EXT_INFO {
+            negotiation::read_ext_info(buf, &mut enc.peer_extensions)?;
+            return Ok(self);
+        }
         // If we've successfully read a packet.
         // debug!("state = {:?}, buf = {:?}", self.0.state, buf);
         debug!(
@@ -63,36 +64,102 @@ impl Session {
             msg::SERVICE_REQUEST
         );
         match enc.state {
-            EncryptedState::WaitingServiceRequest { ref mut accepted }
-                if buf[0] == msg::SERVICE_REQUEST =>
-            {
+            EncryptedState::WaitingServiceRequest { .. } if buf[0] == msg::SERVICE_REQUEST => {
                 let mut r = buf.reader(1);
                 let request = r.read_string()?;
                 debug!("request: {:?}", std::str::from_utf8(request));
                 if request == b"ssh-userauth" {
-                    let auth_request = server_accept_service(
-                        self.common.config.as_ref().auth_banner,
-                        self.common.config.as_ref().methods,
-                        &mut enc.write,
-                    );
-                    *accepted = true;
-                    enc.state = EncryptedState::WaitingAuthRequest(auth_request);
+                    let config = self.common.config.clone();
+                    if let Some(ref message) = config.maintenance_mode {
+                        server_accept_service(
+                            Some(message.as_str()),
+                            MethodSet::empty(),
+                            &mut enc.write,
+                        );
+                        self.common.disconnect(
+                            Disconnect::ServiceNotAvailable,
+                            message,
+                            &config.language_tag,
+                        );
+                        return Ok(self);
+                    }
+                    let h = handler.take().unwrap();
+                    let (h, mut s, accept) = h
+                        .service_request(std::str::from_utf8(request)?, self)
+                        .await?;
+                    *handler = Some(h);
+                    if !accept {
+                        s.common.disconnect(
+                            Disconnect::ServiceNotAvailable,
+                            "Service not available",
+                            &config.language_tag,
+                        );
+                        return Ok(s);
+                    }
+                    if let Some(ref mut enc) = s.common.encrypted {
+                        let auth_request = server_accept_service(
+                            config.auth_banner,
+                            config.methods,
+                            &mut enc.write,
+                        );
+                        enc.state = EncryptedState::WaitingAuthRequest(auth_request);
+                    }
+                    return Ok(s);
                 }
+                debug!("unknown service: {:?}", request);
+                let config = self.common.config.clone();
+                self.common.disconnect(
+                    Disconnect::ServiceNotAvailable,
+                    "Service not available",
+                    &config.language_tag,
+                );
                 Ok(self)
             }
             EncryptedState::WaitingAuthRequest(_) if buf[0] == msg::USERAUTH_REQUEST => {
-                enc.server_read_auth_request(instant, handler, buf, &mut self.common.auth_user)
-                    .await?;
+                // The same jitter delays rejections and acceptances.
+                let jitter = rejection_jitter(&self.common.config);
+                let until = instant + jitter;
+                let accepted_until =
+                    self.common.config.auth_success_time.map(|t| now + t + jitter);
+                let mut r = buf.reader(1);
+                let user = std::str::from_utf8(r.read_string()?)?;
+                if self.banner_user.as_deref() != Some(user) {
+                    self.banner_user = Some(user.to_string());
+                    let h = handler.take().unwrap();
+                    let (h, s) = h.auth_banner_for(user, self).await?;
+                    *handler = Some(h);
+                    self = s;
+                    enc = self.common.encrypted.as_mut().unwrap();
+                }
+                enc.server_read_auth_request(
+                    until,
+                    instant,
+                    handler,
+                    &self.common.config,
+                    buf,
+                    &mut self.common.auth_user,
+                )
+                .await?;
+                if self.after_auth_request() {
+                    return self.auth_succeeded(accepted_until, handler).await;
+                }
+                self.delay_step_accepted(accepted_until).await;
                 Ok(self)
             }
             EncryptedState::WaitingAuthRequest(ref mut auth)
                 if buf[0] == msg::USERAUTH_INFO_RESPONSE =>
             {
+                let jitter = rejection_jitter(&self.common.config);
+                let until = instant + jitter;
+                let accepted_until =
+                    self.common.config.auth_success_time.map(|t| now + t + jitter);
                 if read_userauth_info_response(
+                    until,
                     instant,
                     handler,
                     &mut enc.write,
                     auth,
+                    self.common.config.max_interactive_rounds,
                     &mut self.common.auth_user,
                     buf,
                 )
@@ -100,17 +167,152 @@ impl Session {
                 {
                     enc.state = EncryptedState::Authenticated
                 }
+                if self.after_auth_request() {
+                    return self.auth_succeeded(accepted_until, handler).await;
+                }
+                self.delay_step_accepted(accepted_until).await;
                 Ok(self)
             }
             EncryptedState::Authenticated => {
                 enc.state = EncryptedState::Authenticated;
                 self.server_read_authenticated(handler, buf).await
             }
+            _ if buf[0] >= msg::GLOBAL_REQUEST && buf[0] <= 127 => {
+                // Connection protocol messages (channels and global
+                // requests) are only allowed after authentication.
+                // https://tools.ietf.org/html/rfc4252#section-6
+                debug!("connection protocol message before authentication: {:?}", buf[0]);
+                let config = self.common.config.clone();
+                self.common.disconnect(
+                    Disconnect::ProtocolError,
+                    "Connection protocol message before authentication",
+                    &config.language_tag,
+                );
+                Ok(self)
+            }
             _ => Ok(self),
         }
     }
 }
 
+impl Session {
+    /// Called after each authentication request. If the client was
+    /// authenticated, start a key re-exchange if the configuration
+    /// asks for it. Else, disconnect if the client has reached the
+    /// maximal number of authentication attempts or of
+    /// keyboard-interactive rounds. Rejections are delayed until
+    /// `config.auth_rejection_time`, so this happens after that delay
+    /// too.
+    ///
+    /// Returns `true` if the client was authenticated.
+    fn after_auth_request(&mut self) -> bool {
+        let (rejection_count, interactive_rounds) = match self.common.encrypted {
+            Some(ref mut enc) => match enc.state {
+                EncryptedState::Authenticated => {
+                    server_send_hostkeys(&self.common.config, &mut enc.write);
+                    if self.common.config.rekey_after_auth {
+                        debug!("rekey after authentication");
+                        enc.rekey_wanted = true;
+                    }
+                    return true;
+                }
+                EncryptedState::WaitingAuthRequest(ref auth_request) => (
+                    auth_request.rejection_count,
+                    auth_request.interactive_rounds,
+                ),
+                _ => return false,
+            },
+            None => return false,
+        };
+        let config = self.common.config.clone();
+        if rejection_count >= config.max_auth_attempts {
+            debug!("too many authentication attempts");
+            self.common.disconnect(
+                Disconnect::NoMoreAuthMethodsAvailable,
+                "Too many authentication failures",
+                &config.language_tag,
+            );
+        } else if interactive_rounds > config.max_interactive_rounds {
+            debug!("too many keyboard-interactive rounds");
+            self.common.disconnect(
+                Disconnect::NoMoreAuthMethodsAvailable,
+                "Too many keyboard-interactive rounds",
+                &config.language_tag,
+            );
+        }
+        false
+    }
+
+    /// If the last request was accepted as one step of the
+    /// authentication (partial success or `SSH_MSG_USERAUTH_PK_OK`),
+    /// delay the reply until `until` like full successes.
+    async fn delay_step_accepted(&mut self, until: Option<Instant>) {
+        let step_accepted = match self.common.encrypted {
+            Some(Encrypted {
+                state: EncryptedState::WaitingAuthRequest(ref mut auth_request),
+                ..
+            }) => std::mem::replace(&mut auth_request.step_accepted, false),
+            _ => false,
+        };
+        if let (true, Some(until)) = (step_accepted, until) {
+            tokio::time::delay_until(until).await
+        }
+    }
+
+    /// Take a token from the global request bucket, refilled at the
+    /// rate of `config.max_global_requests_per_minute`. Returns
+    /// `false` if the bucket is empty.
+    fn global_request_allowed(&mut self) -> bool {
+        let max = if let Some(max) = self.common.config.max_global_requests_per_minute {
+            max as f64
+        } else {
+            return true;
+        };
+        let now = tokio::time::Instant::now();
+        let (ref mut tokens, ref mut last) = self.global_request_tokens;
+        let elapsed = now.duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * max / 60.).min(max);
+        *last = now;
+        if *tokens >= 1. {
+            *tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Called when a request authenticated the client. The success
+    /// is delayed until `until`, if set (see
+    /// `Config::auth_success_time`).
+    async fn auth_succeeded<H: Handler>(
+        self,
+        until: Option<Instant>,
+        handler: &mut Option<H>,
+    ) -> Result<Self, anyhow::Error> {
+        // USERAUTH_SUCCESS is only sent after this returns.
+        if let Some(until) = until {
+            tokio::time::delay_until(until).await
+        }
+        let h = handler.take().unwrap();
+        let (h, s) = h.auth_succeeded(self).await?;
+        *handler = Some(h);
+        Ok(s)
+    }
+}
+
+/// Advertise all our host keys to the client, so that it can learn
+/// about new keys before they are used (`hostkeys-00@openssh.com`).
+fn server_send_hostkeys(config: &Config, buffer: &mut CryptoVec) {
+    push_packet!(buffer, {
+        buffer.push(msg::GLOBAL_REQUEST);
+        buffer.extend_ssh_string(b"hostkeys-00@openssh.com");
+        buffer.push(0);
+        for key in config.keys.iter() {
+            key.push_to(buffer);
+        }
+    })
+}
+
 fn server_accept_service(
     banner: Option<&str>,
     methods: MethodSet,
@@ -132,8 +334,12 @@ fn server_accept_service(
     AuthRequest {
         methods: methods,
         partial_success: false, // not used immediately anway.
+        partial_user: None,
+        completed: MethodSet::empty(),
+        step_accepted: false,
         current: None,
         rejection_count: 0,
+        interactive_rounds: 0,
     }
 }
 
@@ -142,7 +348,9 @@ impl Encrypted {
     async fn server_read_auth_request<H: Handler>(
         &mut self,
         until: Instant,
+        deadline: Instant,
         handler: &mut Option<H>,
+        config: &Config,
         buf: &[u8],
         auth_user: &mut String,
     ) -> Result<(), anyhow::Error> {
@@ -159,6 +367,30 @@ impl Encrypted {
             std::str::from_utf8(method)
         );
 
+        if let EncryptedState::WaitingAuthRequest(ref mut auth_request) = self.state {
+            if let Some(ref partial_user) = auth_request.partial_user {
+                if partial_user != user {
+                    // After a partial success, refuse to change users,
+                    // else the steps could be for different users.
+                    debug!("user changed after partial success: {:?}", user);
+                    reject_auth_request(until, &mut self.write, auth_request).await;
+                    return Ok(());
+                }
+            }
+            if !auth_request.completed.is_empty() {
+                // After a partial success, only the remaining methods
+                // can be used.
+                let allowed = MethodSet::from_bytes(method)
+                    .map(|m| auth_request.methods.contains(m))
+                    .unwrap_or(false);
+                if !allowed {
+                    debug!("method not allowed after partial success: {:?}", method);
+                    reject_auth_request(until, &mut self.write, auth_request).await;
+                    return Ok(());
+                }
+            }
+        }
+
         if service_name == b"ssh-connection" {
             if method == b"password" {
                 let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state
@@ -169,15 +401,37 @@ impl Encrypted {
                 };
                 auth_user.clear();
                 auth_user.push_str(user);
-                r.read_byte()?;
+                let change = r.read_byte()? != 0;
                 let password = r.read_string()?;
                 let password = std::str::from_utf8(password)?;
                 let handler_ = handler.take().unwrap();
-                let (handler_, auth) = handler_.auth_password(user, password).await?;
+                let (handler_, auth) = if change {
+                    let new_password = std::str::from_utf8(r.read_string()?)?;
+                    handler_
+                        .auth_password_change(user, password, new_password)
+                        .await?
+                } else {
+                    handler_.auth_password(user, password).await?
+                };
+                report_slow_auth(&handler_, "password", deadline);
                 *handler = Some(handler_);
-                if let Auth::Accept = auth {
-                    server_auth_request_success(&mut self.write);
-                    self.state = EncryptedState::Authenticated;
+                if let Some(remaining) = accepted(&auth) {
+                    if server_auth_request_success(
+                        handler,
+                        &mut self.write,
+                        auth_request,
+                        auth_user,
+                        MethodSet::PASSWORD,
+                        remaining,
+                    ) {
+                        self.state = EncryptedState::Authenticated;
+                    }
+                } else if let Auth::PasswordChangeRequired { prompt, language } = auth {
+                    push_packet!(self.write, {
+                        self.write.push(msg::USERAUTH_PASSWD_CHANGEREQ);
+                        self.write.extend_ssh_string(prompt.as_bytes());
+                        self.write.extend_ssh_string(language.as_bytes());
+                    })
                 } else {
                     auth_user.clear();
                     auth_request.methods = auth_request.methods - MethodSet::PASSWORD;
@@ -186,9 +440,18 @@ impl Encrypted {
                 }
                 Ok(())
             } else if method == b"publickey" {
-                self.server_read_auth_request_pk(until, handler, buf, auth_user, user, r)
-                    .await
+                self.server_read_auth_request_pk(
+                    until, deadline, handler, buf, auth_user, user, r,
+                )
+                .await
+            } else if method == b"hostbased" {
+                self.server_read_auth_request_hostbased(
+                    until, deadline, handler, buf, auth_user, user, r,
+                )
+                .await
             } else if method == b"keyboard-interactive" {
+                let language_tag = r.read_string()?; // deprecated.
+                self.capture_language_tag(config.capture_language_tags, language_tag);
                 let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state
                 {
                     a
@@ -197,7 +460,6 @@ impl Encrypted {
                 };
                 auth_user.clear();
                 auth_user.push_str(user);
-                let _ = r.read_string()?; // language_tag, deprecated.
                 let submethods = std::str::from_utf8(r.read_string()?)?;
                 debug!("{:?}", submethods);
                 auth_request.current = Some(CurrentRequest::KeyboardInteractive {
@@ -207,8 +469,19 @@ impl Encrypted {
                 let (h, auth) = h
                     .auth_keyboard_interactive(user, submethods, None)
                     .await?;
+                report_slow_auth(&h, "keyboard-interactive", deadline);
                 *handler = Some(h);
-                if reply_userauth_info_response(until, auth_request, &mut self.write, auth).await? {
+                if reply_userauth_info_response(
+                    until,
+                    handler,
+                    auth_request,
+                    config.max_interactive_rounds,
+                    &mut self.write,
+                    auth_user,
+                    auth,
+                )
+                .await?
+                {
                     self.state = EncryptedState::Authenticated
                 }
                 Ok(())
@@ -234,10 +507,21 @@ thread_local! {
     static SIGNATURE_BUFFER: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
 }
 
+/// The key of a "publickey" authentication request.
+struct PublicKeyAuth<'a> {
+    algo: &'a [u8],
+    blob: &'a [u8],
+    /// The signature and its position in the request, which is the
+    /// end of the signed data, or `None` if the client is only asking
+    /// whether the key would be accepted.
+    signature: Option<(usize, &'a [u8])>,
+}
+
 impl Encrypted {
     async fn server_read_auth_request_pk<'a, H: Handler>(
         &mut self,
         until: Instant,
+        deadline: Instant,
         handler: &mut Option<H>,
         buf: &[u8],
         auth_user: &mut String,
@@ -253,18 +537,37 @@ impl Encrypted {
         let pubkey_algo = r.read_string()?;
         let pubkey_key = r.read_string()?;
         debug!("algo: {:?}, key: {:?}", pubkey_algo, pubkey_key);
+        if crate::cert::key_algorithm(pubkey_algo).is_some() {
+            let signature = if is_real != 0 {
+                let pos0 = r.position;
+                Some((pos0, r.read_string()?))
+            } else {
+                None
+            };
+            let key = PublicKeyAuth {
+                algo: pubkey_algo,
+                blob: pubkey_key,
+                signature,
+            };
+            return self
+                .server_read_auth_request_cert(until, deadline, handler, buf, auth_user, user, key)
+                .await;
+        }
         match key::PublicKey::parse(pubkey_algo, pubkey_key) {
             Ok(pubkey) => {
                 debug!("is_real = {:?}", is_real);
 
                 if is_real != 0 {
                     let pos0 = r.position;
-                    let sent_pk_ok = if let Some(CurrentRequest::PublicKey { sent_pk_ok, .. }) =
-                        auth_request.current
+                    let (sent_pk_ok, remaining) = if let Some(CurrentRequest::PublicKey {
+                        sent_pk_ok,
+                        remaining,
+                        ..
+                    }) = auth_request.current
                     {
-                        sent_pk_ok
+                        (sent_pk_ok, remaining)
                     } else {
-                        false
+                        (false, None)
                     };
 
                     let signature = r.read_string()?;
@@ -275,19 +578,31 @@ impl Encrypted {
                     let sig = s.read_string()?;
                     let init = &buf[0..pos0];
 
+                    // The signature must use the algorithm of the
+                    // request, which also determines the hash of RSA
+                    // signatures (`ssh-rsa`, `rsa-sha2-256` or
+                    // `rsa-sha2-512`), see
+                    // [RFC8332](https://tools.ietf.org/html/rfc8332#section-3.2).
+                    if algo_ != pubkey_algo {
+                        debug!("signature algorithm mismatch");
+                        reject_auth_request(until, &mut self.write, auth_request).await;
+                        return Ok(());
+                    }
+
                     let is_valid = if sent_pk_ok && user == auth_user {
-                        true
+                        Some(remaining)
                     } else if auth_user.len() == 0 {
                         auth_user.clear();
                         auth_user.push_str(user);
                         let h = handler.take().unwrap();
                         let (h, auth) = h.auth_publickey(user, &pubkey).await?;
+                        report_slow_auth(&h, "publickey", deadline);
                         *handler = Some(h);
-                        auth == Auth::Accept
+                        accepted(&auth)
                     } else {
-                        false
+                        None
                     };
-                    if is_valid {
+                    if let Some(remaining) = is_valid {
                         let session_id = self.session_id.as_ref();
                         if SIGNATURE_BUFFER.with(|buf| {
                             let mut buf = buf.borrow_mut();
@@ -295,11 +610,21 @@ impl Encrypted {
                             buf.extend_ssh_string(session_id);
                             buf.extend(init);
                             // Verify signature.
-                            pubkey.verify_client_auth(&buf, sig)
+                            let is_valid = pubkey.verify_client_auth(&buf, sig);
+                            release_buffer(&mut buf);
+                            is_valid
                         }) {
                             debug!("signature verified");
-                            server_auth_request_success(&mut self.write);
-                            self.state = EncryptedState::Authenticated;
+                            if server_auth_request_success(
+                                handler,
+                                &mut self.write,
+                                auth_request,
+                                auth_user,
+                                MethodSet::PUBLICKEY,
+                                remaining,
+                            ) {
+                                self.state = EncryptedState::Authenticated;
+                            }
                         } else {
                             debug!("signature wrong");
                             reject_auth_request(until, &mut self.write, auth_request).await;
@@ -313,8 +638,9 @@ impl Encrypted {
                     auth_user.push_str(user);
                     let h = handler.take().unwrap();
                     let (h, auth) = h.auth_publickey(user, &pubkey).await?;
+                    report_slow_auth(&h, "publickey", deadline);
                     *handler = Some(h);
-                    if auth == Auth::Accept {
+                    if let Some(remaining) = accepted(&auth) {
                         let mut public_key = CryptoVec::new();
                         public_key.extend(pubkey_key);
 
@@ -326,11 +652,13 @@ impl Encrypted {
                             self.write.extend_ssh_string(&pubkey_algo);
                             self.write.extend_ssh_string(&pubkey_key);
                         });
+                        auth_request.step_accepted = true;
 
                         auth_request.current = Some(CurrentRequest::PublicKey {
                             key: public_key,
                             algo: algo,
                             sent_pk_ok: true,
+                            remaining,
                         });
                     } else {
                         debug!("signature wrong");
@@ -351,6 +679,222 @@ impl Encrypted {
             }
         }
     }
+
+    /// Public key authentication with an OpenSSH certificate.
+    /// Certificates that are badly signed, expired, not user
+    /// certificates, not valid for `user` or with unknown critical
+    /// options are rejected without calling the handler.
+    async fn server_read_auth_request_cert<'a, H: Handler>(
+        &mut self,
+        until: Instant,
+        deadline: Instant,
+        handler: &mut Option<H>,
+        buf: &[u8],
+        auth_user: &mut String,
+        user: &str,
+        key: PublicKeyAuth<'a>,
+    ) -> Result<(), anyhow::Error> {
+        let cert_algo = key.algo;
+        let cert_blob = key.blob;
+        let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state {
+            a
+        } else {
+            unreachable!()
+        };
+        let cert = match crate::Certificate::parse(cert_algo, cert_blob) {
+            Ok(cert) => cert,
+            Err(e) => {
+                debug!("invalid certificate: {:?}", e);
+                reject_auth_request(until, &mut self.write, auth_request).await;
+                return Ok(());
+            }
+        };
+        if cert.cert_type != crate::SSH_CERT_TYPE_USER
+            || !cert.is_valid_now()
+            || !cert.allows_principal(user)
+        {
+            debug!("certificate refused: {:?}", cert.key_id);
+            reject_auth_request(until, &mut self.write, auth_request).await;
+            return Ok(());
+        }
+        if let Some(option) = cert.unknown_critical_option() {
+            debug!("unknown critical option: {:?}", option);
+            reject_auth_request(until, &mut self.write, auth_request).await;
+            return Ok(());
+        }
+        if let Some((pos0, signature)) = key.signature {
+            let mut s = signature.reader(0);
+            let algo = s.read_string()?;
+            let sig = s.read_string()?;
+            let init = &buf[0..pos0];
+            if Some(algo) != crate::cert::key_algorithm(cert_algo) {
+                debug!("signature algorithm mismatch");
+                reject_auth_request(until, &mut self.write, auth_request).await;
+                return Ok(());
+            }
+            let session_id = self.session_id.as_ref();
+            let signature_valid = SIGNATURE_BUFFER.with(|buf| {
+                let mut buf = buf.borrow_mut();
+                buf.clear();
+                buf.extend_ssh_string(session_id);
+                buf.extend(init);
+                let is_valid = cert.key.verify_client_auth(&buf, sig);
+                release_buffer(&mut buf);
+                is_valid
+            });
+            if !signature_valid {
+                debug!("signature wrong");
+                reject_auth_request(until, &mut self.write, auth_request).await;
+                return Ok(());
+            }
+        }
+        let h = handler.take().unwrap();
+        let (h, auth) = h.auth_publickey_cert(user, &cert).await?;
+        report_slow_auth(&h, "publickey", deadline);
+        *handler = Some(h);
+        let remaining = if let Some(remaining) = accepted(&auth) {
+            remaining
+        } else {
+            auth_request.partial_success = false;
+            reject_auth_request(until, &mut self.write, auth_request).await;
+            return Ok(());
+        };
+        if key.signature.is_some() {
+            auth_user.clear();
+            auth_user.push_str(user);
+            if server_auth_request_success(
+                handler,
+                &mut self.write,
+                auth_request,
+                auth_user,
+                MethodSet::PUBLICKEY,
+                remaining,
+            ) {
+                self.state = EncryptedState::Authenticated;
+            }
+        } else {
+            push_packet!(self.write, {
+                self.write.push(msg::USERAUTH_PK_OK);
+                self.write.extend_ssh_string(cert_algo);
+                self.write.extend_ssh_string(cert_blob);
+            });
+            auth_request.step_accepted = true;
+        }
+        Ok(())
+    }
+
+    /// Host-based authentication, see
+    /// [RFC4252](https://tools.ietf.org/html/rfc4252#section-9). The
+    /// signature is checked before the handler is called.
+    async fn server_read_auth_request_hostbased<'a, H: Handler>(
+        &mut self,
+        until: Instant,
+        deadline: Instant,
+        handler: &mut Option<H>,
+        buf: &[u8],
+        auth_user: &mut String,
+        user: &str,
+        mut r: Position<'a>,
+    ) -> Result<(), anyhow::Error> {
+        let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state {
+            a
+        } else {
+            unreachable!()
+        };
+        let pubkey_algo = r.read_string()?;
+        let pubkey_key = r.read_string()?;
+        let client_host = std::str::from_utf8(r.read_string()?)?;
+        let client_user = std::str::from_utf8(r.read_string()?)?;
+        let pos0 = r.position;
+        let signature = r.read_string()?;
+        let mut s = signature.reader(0);
+        let algo = s.read_string()?;
+        let sig = s.read_string()?;
+        let init = &buf[0..pos0];
+        debug!("hostbased: {:?} {:?}", client_host, client_user);
+        if algo != pubkey_algo {
+            debug!("signature algorithm mismatch");
+            reject_auth_request(until, &mut self.write, auth_request).await;
+            return Ok(());
+        }
+
+        let host_key = match key::PublicKey::parse(pubkey_algo, pubkey_key) {
+            Ok(host_key) => host_key,
+            Err(e) => {
+                if let Some(thrussh_keys::Error::CouldNotReadKey) = e.downcast_ref() {
+                    reject_auth_request(until, &mut self.write, auth_request).await;
+                    return Ok(());
+                } else {
+                    return Err(e);
+                }
+            }
+        };
+        let session_id = self.session_id.as_ref();
+        let signature_valid = SIGNATURE_BUFFER.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            buf.clear();
+            buf.extend_ssh_string(session_id);
+            buf.extend(init);
+            let is_valid = host_key.verify_client_auth(&buf, sig);
+            release_buffer(&mut buf);
+            is_valid
+        });
+        if !signature_valid {
+            debug!("signature wrong");
+            reject_auth_request(until, &mut self.write, auth_request).await;
+            return Ok(());
+        }
+        let h = handler.take().unwrap();
+        let (h, auth) = h
+            .auth_hostbased(user, &host_key, client_host, client_user)
+            .await?;
+        report_slow_auth(&h, "hostbased", deadline);
+        *handler = Some(h);
+        if let Some(remaining) = accepted(&auth) {
+            auth_user.clear();
+            auth_user.push_str(user);
+            if server_auth_request_success(
+                handler,
+                &mut self.write,
+                auth_request,
+                auth_user,
+                MethodSet::HOSTBASED,
+                remaining,
+            ) {
+                self.state = EncryptedState::Authenticated;
+            }
+        } else {
+            auth_request.partial_success = false;
+            reject_auth_request(until, &mut self.write, auth_request).await;
+        }
+        Ok(())
+    }
+}
+
+/// A random duration between zero and `config.auth_rejection_jitter`,
+/// drawn for each authentication request.
+fn rejection_jitter(config: &Config) -> std::time::Duration {
+    if let Some(jitter) = config.auth_rejection_jitter {
+        let max = jitter.as_nanos() as u64;
+        let mut bytes = [0; 8];
+        if max > 0 && openssl::rand::rand_bytes(&mut bytes).is_ok() {
+            let nanos = BigEndian::read_u64(&bytes) % (max + 1);
+            return std::time::Duration::from_nanos(nanos);
+        }
+    }
+    std::time::Duration::from_secs(0)
+}
+
+/// Authentication rejections are delayed until `config.auth_rejection_time`
+/// after the request (plus some jitter), so that they all take the
+/// same time. If the handler answered after `deadline`, the end of
+/// that delay without the jitter, the timing of rejections leaks
+/// information: report it to the handler.
+fn report_slow_auth<H: Handler>(handler: &H, method: &str, deadline: Instant) {
+    let now = Instant::now();
+    if now > deadline {
+        handler.auth_slow(method, now - deadline)
+    }
 }
 
 async fn reject_auth_request(
@@ -370,17 +914,63 @@ async fn reject_auth_request(
     tokio::time::delay_until(until).await
 }
 
-fn server_auth_request_success(buffer: &mut CryptoVec) {
-    push_packet!(buffer, {
-        buffer.push(msg::USERAUTH_SUCCESS);
-    })
+/// `Some(remaining)` if `auth` accepts the request, where `remaining`
+/// is `None` if the client is authenticated, or the methods still
+/// required after an `Auth::AcceptPartial`.
+fn accepted(auth: &Auth) -> Option<Option<MethodSet>> {
+    match *auth {
+        Auth::Accept => Some(None),
+        Auth::AcceptPartial { remaining } => Some(Some(remaining)),
+        _ => None,
+    }
+}
+
+/// Reply to an accepted request for `method`. Returns `true` if the
+/// client is authenticated. Else, if `remaining` is `Some`, record the
+/// step, tell the handler, and send a failure with the partial
+/// success flag and the methods still allowed.
+fn server_auth_request_success<H: Handler>(
+    handler: &mut Option<H>,
+    buffer: &mut CryptoVec,
+    auth_request: &mut AuthRequest,
+    auth_user: &mut String,
+    method: MethodSet,
+    remaining: Option<MethodSet>,
+) -> bool {
+    if let Some(remaining) = remaining {
+        debug!("partial success, remaining {:?}", remaining);
+        auth_request.methods = remaining;
+        auth_request.partial_success = true;
+        auth_request.partial_user = Some(auth_user.clone());
+        auth_request.completed |= method;
+        if let Some(ref mut h) = *handler {
+            h.auth_partial_success(auth_user, method, remaining)
+        }
+        auth_request.step_accepted = true;
+        auth_request.current = None;
+        // The next method may be for another key.
+        auth_user.clear();
+        push_packet!(buffer, {
+            buffer.push(msg::USERAUTH_FAILURE);
+            buffer.extend_list(auth_request.methods);
+            buffer.push(1);
+        });
+        false
+    } else {
+        push_packet!(buffer, {
+            buffer.push(msg::USERAUTH_SUCCESS);
+        });
+        true
+    }
 }
 
 async fn read_userauth_info_response<H: Handler>(
     until: Instant,
+    deadline: Instant,
     handler: &mut Option<H>,
     write: &mut CryptoVec,
     auth_request: &mut AuthRequest,
+    max_rounds: usize,
     user: &mut String,
     b: &[u8],
 ) -> Result<bool, anyhow::Error> {
@@ -392,26 +982,43 @@ async fn read_userauth_info_response<H: Handler>(
         let (h, auth) = h
             .auth_keyboard_interactive(user, submethods, Some(response))
             .await?;
+        report_slow_auth(&h, "keyboard-interactive", deadline);
         *handler = Some(h);
-        reply_userauth_info_response(until, auth_request, write, auth).await
+        reply_userauth_info_response(until, handler, auth_request, max_rounds, write, user, auth)
+            .await
     } else {
         reject_auth_request(until, write, auth_request).await;
         Ok(false)
     }
 }
 
-async fn reply_userauth_info_response(
+async fn reply_userauth_info_response<H: Handler>(
     until: Instant,
+    handler: &mut Option<H>,
     auth_request: &mut AuthRequest,
+    max_rounds: usize,
     write: &mut CryptoVec,
+    auth_user: &mut String,
     auth: Auth,
 ) -> Result<bool, anyhow::Error> {
     match auth {
-        Auth::Accept => {
-            server_auth_request_success(write);
-            Ok(true)
-        }
-        Auth::Reject => {
+        Auth::Accept => Ok(server_auth_request_success(
+            handler,
+            write,
+            auth_request,
+            auth_user,
+            MethodSet::KEYBOARD_INTERACTIVE,
+            None,
+        )),
+        Auth::AcceptPartial { remaining } => Ok(server_auth_request_success(
+            handler,
+            write,
+            auth_request,
+            auth_user,
+            MethodSet::KEYBOARD_INTERACTIVE,
+            Some(remaining),
+        )),
+        Auth::Reject | Auth::PasswordChangeRequired { .. } => {
             auth_request.partial_success = false;
             reject_auth_request(until, write, auth_request).await;
             Ok(false)
@@ -421,6 +1028,12 @@ async fn reply_userauth_info_response(
             instructions,
             prompts,
         } => {
+            auth_request.interactive_rounds += 1;
+            if auth_request.interactive_rounds > max_rounds {
+                // Don't send a prompt the client will never be able
+                // to answer: `after_auth_request` disconnects it.
+                return Ok(false);
+            }
             push_packet!(write, {
                 write.push(msg::USERAUTH_INFO_REQUEST);
                 write.extend_ssh_string(name.as_bytes());
@@ -449,12 +1062,35 @@ impl Session {
             &buf[..std::cmp::min(buf.len(), 100)]
         );
         match buf[0] {
+            msg::SERVICE_REQUEST => {
+                // Some clients explicitly request the connection
+                // service after authentication.
+                let mut r = buf.reader(1);
+                if r.read_string()? == b"ssh-connection" {
+                    if let Some(ref mut enc) = self.common.encrypted {
+                        push_packet!(enc.write, {
+                            enc.write.push(msg::SERVICE_ACCEPT);
+                            enc.write.extend_ssh_string(b"ssh-connection");
+                        })
+                    }
+                }
+                Ok(self)
+            }
             msg::CHANNEL_OPEN => self.server_handle_channel_open(handler, buf).await,
             msg::CHANNEL_CLOSE => {
                 let mut r = buf.reader(1);
                 let channel_num = ChannelId(r.read_u32()?);
-                if let Some(ref mut enc) = self.common.encrypted {
-                    enc.channels.remove(&channel_num);
+                let removed = if let Some(ref mut enc) = self.common.encrypted {
+                    enc.channels.remove(&channel_num).is_some()
+                } else {
+                    false
+                };
+                if !removed {
+                    // Both sides closed the channel at the same time,
+                    // or the client repeated itself: the handler has
+                    // already been notified.
+                    debug!("channel_close: channel {:?} already closed", channel_num);
+                    return Ok(self);
                 }
                 debug!("handler.channel_close {:?}", channel_num);
                 let h = handler.take().unwrap();
@@ -465,6 +1101,11 @@ impl Session {
             msg::CHANNEL_EOF => {
                 let mut r = buf.reader(1);
                 let channel_num = ChannelId(r.read_u32()?);
+                if let Some(ref mut enc) = self.common.encrypted {
+                    if let Some(channel) = enc.channels.get_mut(&channel_num) {
+                        channel.eof_received = true;
+                    }
+                }
                 debug!("handler.channel_eof {:?}", channel_num);
                 let h = handler.take().unwrap();
                 let (h, s) = h.channel_eof(channel_num, self).await?;
@@ -484,9 +1125,15 @@ impl Session {
                 let data = r.read_string()?;
                 let target = self.common.config.window_size;
                 if let Some(ref mut enc) = self.common.encrypted {
-                    enc.adjust_window_size(channel_num, data, target);
+                    enc.adjust_window_size(channel_num, data, target)?;
                 }
                 self.flush()?;
+                if let Some(ext) = ext {
+                    if ext != 1 && self.common.config.drop_unknown_extended_data {
+                        debug!("dropping extended data with unknown code {:?}", ext);
+                        return Ok(self);
+                    }
+                }
                 let h = handler.take().unwrap();
                 let (h, s) = if let Some(ext) = ext {
                     h.extended_data(channel_num, ext, &data, self).await?
@@ -504,11 +1151,22 @@ impl Session {
                 let mut new_value = 0;
                 if let Some(ref mut enc) = self.common.encrypted {
                     if let Some(channel) = enc.channels.get_mut(&channel_num) {
-                        channel.recipient_window_size += amount;
+                        // The window may not exceed 2^32 - 1 bytes.
+                        // https://tools.ietf.org/html/rfc4254#section-5.2
+                        channel.recipient_window_size = channel
+                            .recipient_window_size
+                            .checked_add(amount)
+                            .ok_or(Error::WindowOverflow)?;
                         new_value = channel.recipient_window_size;
                     } else {
                         return Err(Error::WrongChannel.into());
                     }
+                    // Send the data that was waiting for this window.
+                    if enc.flush_pending(channel_num) > 0 {
+                        if let Some(channel) = enc.channels.get(&channel_num) {
+                            new_value = channel.recipient_window_size;
+                        }
+                    }
                 }
                 debug!("handler.window_adjusted {:?}", channel_num);
                 let h = handler.take().unwrap();
@@ -535,23 +1193,12 @@ impl Session {
                         let row_height = r.read_u32()?;
                         let pix_width = r.read_u32()?;
                         let pix_height = r.read_u32()?;
-                        let mut modes = [(Pty::TTY_OP_END, 0); 130];
-                        let mut i = 0;
-                        {
-                            let mode_string = r.read_string()?;
-                            while 5 * i < mode_string.len() {
-                                let code = mode_string[5 * i];
-                                if code == 0 {
-                                    break;
-                                }
-                                let num = BigEndian::read_u32(&mode_string[5 * i + 1..]);
-                                debug!("code = {:?}", code);
-                                if let Some(code) = Pty::from_u8(code) {
-                                    modes[i] = (code, num);
-                                } else {
-                                    info!("pty-req: unknown pty code {:?}", code);
-                                }
-                                i += 1
+                        let modes = PtyModes::parse(r.read_string()?);
+                        if let Some(ref allowed_terms) = self.common.config.allowed_terms {
+                            if !allowed_terms.contains(term) {
+                                debug!("pty-req: terminal type not allowed: {:?}", term);
+                                self.channel_anyhow(channel_num);
+                                return Ok(self);
                             }
                         }
                         debug!("handler.pty_request {:?}", channel_num);
@@ -563,7 +1210,7 @@ impl Session {
                             row_height,
                             pix_width,
                             pix_height,
-                            &modes[0..i],
+                            &modes,
                             self,
                         ).await?;
                         *handler = Some(h);
@@ -571,16 +1218,16 @@ impl Session {
                     }
                     b"x11-req" => {
                         let single_connection = r.read_byte()? != 0;
-                        let x11_auth_protocol = std::str::from_utf8(r.read_string()?)?;
-                        let x11_auth_cookie = std::str::from_utf8(r.read_string()?)?;
+                        let x11_auth_protocol = String::from_utf8_lossy(r.read_string()?);
+                        let x11_auth_cookie = String::from_utf8_lossy(r.read_string()?);
                         let x11_screen_number = r.read_u32()?;
                         debug!("handler.x11_request {:?}", channel_num);
                         let h = handler.take().unwrap();
                         let (h, s) = h.x11_request(
                             channel_num,
                             single_connection,
-                            x11_auth_protocol,
-                            x11_auth_cookie,
+                            &x11_auth_protocol,
+                            &x11_auth_cookie,
                             x11_screen_number,
                             self,
                         )
@@ -589,13 +1236,17 @@ impl Session {
                         Ok(s)
                     }
                     b"env" => {
-                        let env_variable = std::str::from_utf8(r.read_string()?)?;
-                        let env_value = std::str::from_utf8(r.read_string()?)?;
+                        let env_variable = String::from_utf8_lossy(r.read_string()?);
+                        let env_value = String::from_utf8_lossy(r.read_string()?);
                         debug!("handler.env_request {:?}", channel_num);
                         let h = handler.take().unwrap();
-                        let (h, s) = h.env_request(channel_num, env_variable, env_value, self)
+                        let (h, mut s) = h.env_request(channel_num, &env_variable, &env_value, self)
                             .await?;
                         *handler = Some(h);
+                        // If the client wants a reply and the handler
+                        // did not accept the variable with
+                        // `channel_success`, reject it.
+                        s.channel_anyhow(channel_num);
                         Ok(s)
                     }
                     b"shell" => {
@@ -615,10 +1266,10 @@ impl Session {
                         Ok(s)
                     }
                     b"subsystem" => {
-                        let name = std::str::from_utf8(r.read_string()?)?;
+                        let name = String::from_utf8_lossy(r.read_string()?);
                         debug!("handler.subsystem_request {:?}", channel_num);
                         let h = handler.take().unwrap();
-                        let (h, s) = h.subsystem_request(channel_num, name, self).await?;
+                        let (h, s) = h.subsystem_request(channel_num, &name, self).await?;
                         *handler = Some(h);
                         Ok(s)
                     }
@@ -642,7 +1293,6 @@ impl Session {
                         Ok(s)
                     }
                     b"signal" => {
-                        r.read_byte()?; // should be 0.
                         let signal_name = Sig::from_name(r.read_string()?)?;
                         debug!("handler.signal {:?} {:?}", channel_num, signal_name);
                         let h = handler.take().unwrap();
@@ -651,22 +1301,39 @@ impl Session {
                         Ok(s)
                     }
                     x => {
-                        debug!(
-                            "{:?}, line {:?} req_type = {:?}",
-                            file!(),
-                            line!(),
-                            std::str::from_utf8(x)
-                        );
-                        if let Some(ref mut enc) = self.common.encrypted {
-                            push_packet!(enc.write, {
-                                enc.write.push(msg::CHANNEL_FAILURE);
-                            });
+                        let req_type = String::from_utf8_lossy(x);
+                        debug!("handler.channel_request {:?} {:?}", channel_num, req_type);
+                        let h = handler.take().unwrap();
+                        let (h, mut s, result) = h
+                            .channel_request(
+                                channel_num,
+                                &req_type,
+                                wants_reply != 0,
+                                &buf[r.position..],
+                                self,
+                            )
+                            .await?;
+                        *handler = Some(h);
+                        if result {
+                            s.channel_success(channel_num)
+                        } else {
+                            s.channel_anyhow(channel_num)
                         }
-                        Ok(self)
+                        Ok(s)
                     }
                 }
             }
             msg::GLOBAL_REQUEST => {
+                if !self.global_request_allowed() {
+                    debug!("too many global requests");
+                    let config = self.common.config.clone();
+                    self.common.disconnect(
+                        Disconnect::ByApplication,
+                        "Too many global requests",
+                        &config.language_tag,
+                    );
+                    return Ok(self);
+                }
                 let mut r = buf.reader(1);
                 let req_type = r.read_string()?;
                 self.common.wants_reply = r.read_byte()? != 0;
@@ -675,18 +1342,42 @@ impl Session {
                         let address = std::str::from_utf8(r.read_string()?)?;
                         let port = r.read_u32()?;
                         debug!("handler.tcpip_forward {:?} {:?}", address, port);
+                        self.bound_port = None;
                         let h = handler.take().unwrap();
                         let (h, mut s, result) = h.tcpip_forward(address, port, self).await?;
                         *handler = Some(h);
-                        if let Some(ref mut enc) = s.common.encrypted {
-                            if result {
-                                push_packet!(enc.write, enc.write.push(msg::REQUEST_SUCCESS))
-                            } else {
-                                push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+                        // For port 0, the reply must contain the
+                        // port actually bound.
+                        let bound_port = if port == 0 { s.bound_port } else { Some(port) };
+                        if result && bound_port.is_none() {
+                            debug!("tcpip-forward to port 0 accepted without a bound port");
+                        }
+                        if s.common.wants_reply {
+                            if let Some(ref mut enc) = s.common.encrypted {
+                                if let (true, Some(bound_port)) = (result, bound_port) {
+                                    push_packet!(enc.write, {
+                                        enc.write.push(msg::REQUEST_SUCCESS);
+                                        if port == 0 {
+                                            enc.write.push_u32_be(bound_port)
+                                        }
+                                    })
+                                } else {
+                                    push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+                                }
                             }
                         }
                         Ok(s)
                     }
+                    b"no-more-sessions@openssh.com" => {
+                        debug!("no more sessions");
+                        if let Some(ref mut enc) = self.common.encrypted {
+                            enc.no_more_sessions = true;
+                            if self.common.wants_reply {
+                                push_packet!(enc.write, enc.write.push(msg::REQUEST_SUCCESS))
+                            }
+                        }
+                        Ok(self)
+                    }
                     b"cancel-tcpip-forward" => {
                         let address = std::str::from_utf8(r.read_string()?)?;
                         let port = r.read_u32()?;
@@ -703,18 +1394,78 @@ impl Session {
                         }
                         Ok(s)
                     }
-                    _ => {
-                        if let Some(ref mut enc) = self.common.encrypted {
-                            push_packet!(enc.write, {
-                                enc.write.push(msg::REQUEST_FAILURE);
-                            });
+                    req_type => {
+                        let req_type = String::from_utf8_lossy(req_type);
+                        let want_reply = self.common.wants_reply;
+                        debug!("handler.global_request {:?}", req_type);
+                        let h = handler.take().unwrap();
+                        let (h, mut s, result) = h
+                            .global_request(&req_type, want_reply, &buf[r.position..], self)
+                            .await?;
+                        *handler = Some(h);
+                        if want_reply {
+                            if let Some(ref mut enc) = s.common.encrypted {
+                                if result {
+                                    push_packet!(enc.write, enc.write.push(msg::REQUEST_SUCCESS))
+                                } else {
+                                    push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+                                }
+                            }
                         }
-                        Ok(self)
+                        Ok(s)
+                    }
+                }
+            }
+            msg::REQUEST_SUCCESS | msg::REQUEST_FAILURE => {
+                // Replies to our keepalive requests.
+                self.common.alive_timeouts = 0;
+                Ok(self)
+            }
+            msg::CHANNEL_OPEN_CONFIRMATION => {
+                // The client accepted a channel we opened.
+                let mut r = buf.reader(1);
+                let channel_num = ChannelId(r.read_u32()?);
+                let recipient_channel = r.read_u32()?;
+                let window = r.read_u32()?;
+                let max_packet = r.read_u32()?;
+                if let Some(ref mut enc) = self.common.encrypted {
+                    if let Some(channel) = enc.channels.get_mut(&channel_num) {
+                        channel.recipient_channel = recipient_channel;
+                        channel.recipient_window_size = window;
+                        channel.recipient_maximum_packet_size = max_packet;
+                        channel.confirmed = true;
+                    } else {
+                        // We've not opened this channel.
+                        return Err(Error::Inconsistent.into());
                     }
                 }
+                Ok(self)
+            }
+            msg::CHANNEL_OPEN_FAILURE => {
+                let mut r = buf.reader(1);
+                let channel_num = ChannelId(r.read_u32()?);
+                r.read_u32()?; // reason code
+                r.read_string()?; // description
+                let language = r.read_string()?;
+                debug!("channel open failure: {:?}", channel_num);
+                if let Some(ref mut enc) = self.common.encrypted {
+                    enc.channels.remove(&channel_num);
+                    enc.capture_language_tag(self.common.config.capture_language_tags, language);
+                }
+                Ok(self)
+            }
+            msg::CHANNEL_SUCCESS | msg::CHANNEL_FAILURE => {
+                // Replies to our channel requests.
+                debug!("channel request reply: {:?}", buf[0]);
+                Ok(self)
+            }
+            m if msg::is_assigned(m) => {
+                debug!("unexpected message received: {:?}", m);
+                Ok(self)
             }
             m => {
                 debug!("unknown message received: {:?}", m);
+                self.common.unimplemented();
                 Ok(self)
             }
         }
@@ -732,6 +1483,20 @@ impl Session {
         let window = r.read_u32()?;
         let maxpacket = r.read_u32()?;
 
+        let open_channels = self.common.encrypted.as_ref().map_or(0, |enc| {
+            enc.channels.values().filter(|c| c.confirmed).count()
+        });
+        if open_channels >= self.common.config.max_channels {
+            debug!("too many channels: {:?}", open_channels);
+            self.channel_open_anyhow(
+                ChannelId(sender),
+                ChannelOpenFailure::ResourceShortage,
+                "Too many channels",
+                "",
+            );
+            return Ok(self);
+        }
+
         let sender_channel = if let Some(ref mut enc) = self.common.encrypted {
             enc.new_channel_id()
         } else {
@@ -749,12 +1514,55 @@ impl Session {
             sender_maximum_packet_size: self.common.config.maximum_packet_size,
             confirmed: true,
             wants_reply: false,
+            eof_received: false,
+            pending_data: std::collections::VecDeque::new(),
+            pending_eof: false,
+            pending_close: false,
         };
+        let no_more_sessions = self
+            .common
+            .encrypted
+            .as_ref()
+            .map(|enc| enc.no_more_sessions)
+            .unwrap_or(false);
+        if typ == b"session" && no_more_sessions {
+            debug!("session channel refused after no-more-sessions");
+            self.channel_open_anyhow(
+                ChannelId(sender),
+                ChannelOpenFailure::AdministrativelyProhibited,
+                "No more sessions",
+                "",
+            );
+            return Ok(self);
+        }
+
+        self.channel_open_failure = None;
+        let h = handler.take().unwrap();
+        let (h, s, accept) = h
+            .check_channel_open(
+                ChannelId(sender),
+                &String::from_utf8_lossy(typ),
+                &buf[r.position..],
+                self,
+            )
+            .await?;
+        *handler = Some(h);
+        self = s;
+        if !accept {
+            debug!("channel open refused by the handler");
+            let (reason, description) = self.channel_open_failure.take().unwrap_or((
+                ChannelOpenFailure::AdministrativelyProhibited,
+                "Channel refused".to_string(),
+            ));
+            self.channel_open_anyhow(ChannelId(sender), reason, &description, "");
+            return Ok(self);
+        }
         match typ {
             b"session" => {
                 self.confirm_channel_open(channel);
                 let h = handler.take().unwrap();
-                let (h, s) = h.channel_open_session(sender_channel, self).await?;
+                let (h, s) = h.channel_opened(sender_channel, "session", self).await?;
+                let (h, s) = h.channel_open_session(sender_channel, s).await?;
                 *handler = Some(h);
                 Ok(s)
             }
@@ -763,7 +1571,8 @@ impl Session {
                 let a = std::str::from_utf8(r.read_string()?)?;
                 let b = r.read_u32()?;
                 let h = handler.take().unwrap();
-                let (h, s) = h.channel_open_x11(sender_channel, a, b, self).await?;
+                let (h, s) = h.channel_opened(sender_channel, "x11", self).await?;
+                let (h, s) = h.channel_open_x11(sender_channel, a, b, s).await?;
                 *handler = Some(h);
                 Ok(s)
             }
@@ -774,11 +1583,36 @@ impl Session {
                 let c = std::str::from_utf8(r.read_string()?)?;
                 let d = r.read_u32()?;
                 let h = handler.take().unwrap();
-                let (h, s) = h.channel_open_direct_tcpip(sender_channel, a, b, c, d, self)
+                let (h, s) = h.channel_opened(sender_channel, "direct-tcpip", self).await?;
+                let (h, s) = h.channel_open_direct_tcpip(sender_channel, a, b, c, d, s)
                     .await?;
                 *handler = Some(h);
                 Ok(s)
             }
+            b"tun@openssh.com" => {
+                // https://github.com/openssh/openssh-portable/blob/master/PROTOCOL
+                let mode = r.read_u32()?;
+                let remote_unit = r.read_u32()?;
+                debug!("handler.channel_open_tun {:?} {:?}", mode, remote_unit);
+                let h = handler.take().unwrap();
+                let (h, mut s, accept) = h
+                    .channel_open_tun(sender_channel, mode, remote_unit, self)
+                    .await?;
+                if !accept {
+                    *handler = Some(h);
+                    s.channel_open_anyhow(
+                        ChannelId(sender),
+                        ChannelOpenFailure::AdministrativelyProhibited,
+                        "Tunnel forwarding refused",
+                        "",
+                    );
+                    return Ok(s);
+                }
+                s.confirm_channel_open(channel);
+                let (h, s) = h.channel_opened(sender_channel, "tun@openssh.com", s).await?;
+                *handler = Some(h);
+                Ok(s)
+            }
             t => {
                 debug!("unknown channel type: {:?}", t);
                 if let Some(ref mut enc) = self.common.encrypted {
@@ -787,7 +1621,8 @@ impl Session {
                         enc.write.push_u32_be(sender);
                         enc.write.push_u32_be(3); // SSH_OPEN_UNKNOWN_CHANNEL_TYPE
                         enc.write.extend_ssh_string(b"Unknown channel type");
-                        enc.write.extend_ssh_string(b"en");
+                        enc.write
+                            .extend_ssh_string(self.common.config.language_tag.as_bytes());
                     });
                 }
                 Ok(self)
@@ -811,3 +1646,707 @@ fn server_confirm_channel_open(buffer: &mut CryptoVec, channel: &Channel, config
         buffer.push_u32_be(config.maximum_packet_size);
     });
 }
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use cryptovec::CryptoVec;
+    use futures::Future;
+    use thrussh_keys::encoding::{Encoding, Reader};
+    use thrussh_keys::key;
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    use crate::client;
+    use crate::key::PubKey;
+    use crate::server::{self, Handler, Prompt, Response};
+    use crate::{auth, msg, test_util, ChannelId};
+
+    #[tokio::test]
+    async fn max_auth_attempts() {
+        let mut config = test_util::server_config();
+        config.max_auth_attempts = 3;
+        let (mut client, server) = test_util::connect(
+            config,
+            test_util::Server,
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        for _ in 0..3 {
+            assert!(!client.authenticate_password("user", "wrong").await.unwrap());
+        }
+        // The server hung up after the third failure, so the right
+        // password comes too late.
+        let late = client.authenticate_password("user", test_util::PASSWORD);
+        assert!(!late.await.unwrap_or(false));
+        assert!(server.await.is_ok());
+    }
+
+    /// A server prompting forever in keyboard-interactive
+    /// authentication, counting its calls.
+    struct Prompting(Arc<AtomicUsize>);
+
+    impl Handler for Prompting {
+        server_futures!();
+
+        fn auth_keyboard_interactive(
+            self,
+            _: &str,
+            _: &str,
+            _: Option<Response>,
+        ) -> Self::FutureAuth {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            self.finished_auth(server::Auth::Partial {
+                name: "".into(),
+                instructions: "".into(),
+                prompts: vec![Prompt::hidden("Code: ")].into(),
+            })
+        }
+    }
+
+    /// A client answering all prompts, and counting the rounds.
+    struct Answering(Arc<AtomicUsize>);
+
+    impl client::Handler for Answering {
+        client_futures!();
+
+        fn keyboard_interactive_prompts(
+            self,
+            _: &str,
+            _: &str,
+            prompts: &[(&str, bool)],
+            mut session: client::Session,
+        ) -> Self::FutureUnit {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            session.keyboard_interactive_responses(&vec!["0000"; prompts.len()]);
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn max_interactive_rounds() {
+        let mut config = test_util::server_config();
+        config.max_interactive_rounds = 3;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let rounds = Arc::new(AtomicUsize::new(0));
+        let handler = Prompting(calls.clone());
+        let client_handler = Answering(rounds.clone());
+        let (mut client, server) =
+            test_util::connect(config, handler, Default::default(), client_handler).await;
+        let auth = client.authenticate_keyboard_interactive("user", "");
+        assert!(!auth.await.unwrap_or(false));
+        assert!(server.await.is_ok());
+        // The client got exactly 3 prompts. The handler was called
+        // for the request and for each answer, but its 4th prompt
+        // was never sent.
+        assert_eq!(rounds.load(Ordering::SeqCst), 3);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    /// A server accepting host-based authentication from
+    /// `client.example.com` with the host key `0`. Public key probes
+    /// are accepted, so that `HostbasedSigner` can answer them.
+    struct Hostbased(key::PublicKey);
+
+    impl Handler for Hostbased {
+        server_futures!();
+
+        fn auth_publickey(self, _: &str, _: &key::PublicKey) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn auth_hostbased(
+            self,
+            _: &str,
+            host_key: &key::PublicKey,
+            client_host: &str,
+            _: &str,
+        ) -> Self::FutureAuth {
+            let auth = if *host_key == self.0 && client_host == "client.example.com" {
+                server::Auth::Accept
+            } else {
+                server::Auth::Reject
+            };
+            self.finished_auth(auth)
+        }
+    }
+
+    /// A server requiring the key `key`, and then `PASSWORD`. Keyboard
+    /// interactive authentication is accepted too once the key is
+    /// verified, but it is not among the remaining methods.
+    struct Mfa {
+        key: key::PublicKey,
+        key_verified: bool,
+    }
+
+    impl Handler for Mfa {
+        server_futures!();
+
+        fn auth_publickey(self, _: &str, public_key: &key::PublicKey) -> Self::FutureAuth {
+            let auth = if *public_key == self.key {
+                server::Auth::AcceptPartial {
+                    remaining: auth::MethodSet::PASSWORD,
+                }
+            } else {
+                server::Auth::Reject
+            };
+            self.finished_auth(auth)
+        }
+
+        fn auth_partial_success(
+            &mut self,
+            _: &str,
+            method: auth::MethodSet,
+            _: auth::MethodSet,
+        ) {
+            self.key_verified = method == auth::MethodSet::PUBLICKEY
+        }
+
+        fn auth_password(self, _: &str, password: &str) -> Self::FutureAuth {
+            let auth = if self.key_verified && password == test_util::PASSWORD {
+                server::Auth::Accept
+            } else {
+                server::Auth::Reject
+            };
+            self.finished_auth(auth)
+        }
+
+        fn auth_keyboard_interactive(
+            self,
+            _: &str,
+            _: &str,
+            _: Option<Response>,
+        ) -> Self::FutureAuth {
+            let auth = if self.key_verified {
+                server::Auth::Accept
+            } else {
+                server::Auth::Reject
+            };
+            self.finished_auth(auth)
+        }
+    }
+
+    /// A signer leaving the request unsigned, so that the client only
+    /// sends an unsigned query for its key. The sender is notified
+    /// when the client asks for a signature.
+    struct QueryOnly(Option<tokio::sync::oneshot::Sender<()>>);
+
+    impl auth::Signer for QueryOnly {
+        fn auth_publickey_sign(
+            mut self,
+            _: &key::PublicKey,
+            to_sign: CryptoVec,
+        ) -> Pin<Box<dyn Future<Output = (Self, Result<CryptoVec, anyhow::Error>)> + Send>>
+        {
+            if let Some(asked) = self.0.take() {
+                asked.send(()).unwrap_or(())
+            }
+            Box::pin(futures::future::ready((self, Ok(to_sign))))
+        }
+    }
+
+    /// A server accepting public keys only with the signature
+    /// algorithm `0`.
+    struct SignatureAlgorithm(&'static str);
+
+    impl Handler for SignatureAlgorithm {
+        server_futures!();
+
+        fn auth_publickey(self, _: &str, public_key: &key::PublicKey) -> Self::FutureAuth {
+            let auth = if public_key.name() == self.0 {
+                server::Auth::Accept
+            } else {
+                server::Auth::Reject
+            };
+            self.finished_auth(auth)
+        }
+    }
+
+    /// A server configuration advertising no RSA algorithm in
+    /// `server-sig-algs`, so that clients sign with the algorithm of
+    /// their key.
+    fn ed25519_only_config() -> server::Config {
+        let mut config = test_util::server_config();
+        config.preferred = crate::Preferred {
+            key: Cow::Borrowed(&[key::ED25519]),
+            ..Default::default()
+        };
+        config
+    }
+
+    /// A server trusting the user certificates signed by a CA. Public
+    /// key probes are accepted, so that `RequestSigner` can answer
+    /// them.
+    struct TrustCa(key::PublicKey);
+
+    impl Handler for TrustCa {
+        server_futures!();
+
+        fn auth_publickey(self, _: &str, _: &key::PublicKey) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn auth_publickey_cert(self, _: &str, cert: &crate::Certificate) -> Self::FutureAuth {
+            let auth = if cert.signature_key == self.0 {
+                server::Auth::Accept
+            } else {
+                server::Auth::Reject
+            };
+            self.finished_auth(auth)
+        }
+    }
+
+    /// Since the client cannot send some requests, this replaces
+    /// the signed public key request of the client with a request for
+    /// `method`, with `fields` after the method name, signed by
+    /// `key`. If `tamper` is set, the signature is made over a wrong
+    /// session identifier.
+    struct RequestSigner {
+        key: key::KeyPair,
+        method: &'static [u8],
+        fields: Vec<u8>,
+        tamper: bool,
+    }
+
+    impl auth::Signer for RequestSigner {
+        fn auth_publickey_sign(
+            self,
+            _: &key::PublicKey,
+            to_sign: CryptoVec,
+        ) -> Pin<Box<dyn Future<Output = (Self, Result<CryptoVec, anyhow::Error>)> + Send>>
+        {
+            // `to_sign` starts with the session identifier, which is
+            // not sent.
+            let mut session_id = to_sign.reader(0).read_string().unwrap().to_vec();
+            if self.tamper {
+                session_id[0] ^= 1
+            }
+            let mut buf = CryptoVec::new();
+            buf.extend_ssh_string(&session_id);
+            buf.push(msg::USERAUTH_REQUEST);
+            buf.extend_ssh_string(b"user");
+            buf.extend_ssh_string(b"ssh-connection");
+            buf.extend_ssh_string(self.method);
+            buf.extend(&self.fields);
+            let signed = self.key.add_self_signature(&mut buf).map(|()| buf);
+            Box::pin(futures::future::ready((self, signed)))
+        }
+    }
+
+    #[tokio::test]
+    async fn hostbased() {
+        for &tamper in &[false, true] {
+            let host_key = key::KeyPair::generate_ed25519().unwrap();
+            let public_key = host_key.clone_public_key();
+            let (mut client, _) = test_util::connect(
+                test_util::server_config(),
+                Hostbased(host_key.clone_public_key()),
+                Default::default(),
+                test_util::Client,
+            )
+            .await;
+            let mut fields = CryptoVec::new();
+            fields.extend_ssh_string(host_key.name().as_bytes());
+            host_key.push_to(&mut fields);
+            fields.extend_ssh_string(b"client.example.com");
+            fields.extend_ssh_string(b"user");
+            let signer = RequestSigner {
+                key: host_key,
+                method: b"hostbased",
+                fields: fields.to_vec(),
+                tamper,
+            };
+            let auth = client.authenticate_future("user", public_key, signer);
+            let (_, authenticated) = auth.await.unwrap();
+            assert_eq!(authenticated, !tamper);
+        }
+    }
+
+    /// Log in with a certificate signed by `signer`, valid until
+    /// `valid_before`, on a server trusting `ca`.
+    async fn certificate_login(
+        ca: &key::KeyPair,
+        signer: &key::KeyPair,
+        valid_before: u64,
+    ) -> bool {
+        let user_key = key::KeyPair::generate_ed25519().unwrap();
+        let public_key = user_key.clone_public_key();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            TrustCa(ca.clone_public_key()),
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        let cert = test_util::certificate(
+            &user_key,
+            signer,
+            crate::SSH_CERT_TYPE_USER,
+            &["user"],
+            0,
+            valid_before,
+        );
+        let mut fields = CryptoVec::new();
+        fields.push(1);
+        fields.extend_ssh_string(crate::ED25519_CERT.0.as_bytes());
+        fields.extend_ssh_string(&cert);
+        let signer = RequestSigner {
+            key: user_key,
+            method: b"publickey",
+            fields: fields.to_vec(),
+            tamper: false,
+        };
+        let auth = client.authenticate_future("user", public_key, signer);
+        auth.await.unwrap().1
+    }
+
+    #[tokio::test]
+    async fn certificates() {
+        let ca = key::KeyPair::generate_ed25519().unwrap();
+        let other_ca = key::KeyPair::generate_ed25519().unwrap();
+        assert!(certificate_login(&ca, &ca, u64::MAX).await);
+        // Expired.
+        assert!(!certificate_login(&ca, &ca, 1).await);
+        assert!(!certificate_login(&ca, &other_ca, u64::MAX).await);
+    }
+
+    #[tokio::test]
+    async fn rsa_signature_algorithms() {
+        let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+        let hashes = [
+            key::SignatureHash::SHA1,
+            key::SignatureHash::SHA2_256,
+            key::SignatureHash::SHA2_512,
+        ];
+        for &hash in &hashes {
+            let (mut client, _) = test_util::connect(
+                ed25519_only_config(),
+                SignatureAlgorithm(hash.name().0),
+                Default::default(),
+                test_util::Client,
+            )
+            .await;
+            let key = key::KeyPair::RSA {
+                key: rsa.clone(),
+                hash,
+            };
+            let auth = client.authenticate_publickey("user", Arc::new(key));
+            assert!(auth.await.unwrap(), "{:?}", hash);
+        }
+    }
+
+    /// An `rsa-sha2-512` request with an `rsa-sha2-256` signature is
+    /// rejected.
+    #[tokio::test]
+    async fn rsa_signature_algorithm_mismatch() {
+        let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+        let (mut client, _) = test_util::connect(
+            ed25519_only_config(),
+            SignatureAlgorithm(key::RSA_SHA2_512.0),
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        let requested = key::KeyPair::RSA {
+            key: rsa.clone(),
+            hash: key::SignatureHash::SHA2_512,
+        };
+        let mut fields = CryptoVec::new();
+        fields.push(1);
+        fields.extend_ssh_string(key::RSA_SHA2_512.0.as_bytes());
+        requested.push_to(&mut fields);
+        let signer = RequestSigner {
+            key: key::KeyPair::RSA {
+                key: rsa,
+                hash: key::SignatureHash::SHA2_256,
+            },
+            method: b"publickey",
+            fields: fields.to_vec(),
+            tamper: false,
+        };
+        let auth = client.authenticate_future("user", requested.clone_public_key(), signer);
+        assert!(!auth.await.unwrap().1);
+    }
+
+    /// An `ssh-rsa` key rejected by a server that doesn't advertise
+    /// RSA in `server-sig-algs` is retried with `rsa-sha2-512`, then
+    /// `rsa-sha2-256`.
+    #[tokio::test]
+    async fn rsa_sha2_retry() {
+        for &accepted in &[key::RSA_SHA2_512, key::RSA_SHA2_256] {
+            let (mut client, _) = test_util::connect(
+                ed25519_only_config(),
+                SignatureAlgorithm(accepted.0),
+                Default::default(),
+                test_util::Client,
+            )
+            .await;
+            let key = key::KeyPair::generate_rsa(2048, key::SignatureHash::SHA1).unwrap();
+            let auth = client.authenticate_publickey("user", Arc::new(key));
+            assert!(auth.await.unwrap(), "{:?}", accepted);
+        }
+    }
+
+    #[tokio::test]
+    async fn publickey_then_password() {
+        let key = key::KeyPair::generate_ed25519().unwrap();
+        let handler = Mfa {
+            key: key.clone_public_key(),
+            key_verified: false,
+        };
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            handler,
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        let password = client.authenticate_password("user", test_util::PASSWORD);
+        assert!(!password.await.unwrap());
+        // Querying the key without signing it is not a first factor.
+        // The client sends no signature, so it gets no answer.
+        let (asked, signature_asked) = tokio::sync::oneshot::channel();
+        let unanswered = {
+            let signer = QueryOnly(Some(asked));
+            let query = client.authenticate_future("user", key.clone_public_key(), signer);
+            let query = futures::future::select(Box::pin(query), signature_asked).await;
+            matches!(query, futures::future::Either::Right(_))
+        };
+        assert!(unanswered);
+        let password = client.authenticate_password("user", test_util::PASSWORD);
+        assert!(!password.await.unwrap());
+        // The key is only the first factor.
+        let publickey = client.authenticate_publickey("user", Arc::new(key));
+        assert!(!publickey.await.unwrap());
+        // Methods that are not among the remaining ones are refused.
+        let interactive = client.authenticate_keyboard_interactive("user", "");
+        assert!(!interactive.await.unwrap());
+        let password = client.authenticate_password("user", test_util::PASSWORD);
+        assert!(password.await.unwrap());
+    }
+
+    /// Successes and rejections both take `auth_rejection_time`.
+    #[tokio::test]
+    async fn auth_success_time() {
+        let delay = std::time::Duration::from_millis(200);
+        let mut config = test_util::server_config();
+        config.auth_rejection_time = delay;
+        config.auth_success_time = Some(delay);
+        let (mut client, _) = test_util::connect(
+            config,
+            test_util::Server,
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        let start = std::time::Instant::now();
+        assert!(!client.authenticate_password("user", "wrong").await.unwrap());
+        let rejection = start.elapsed();
+        let start = std::time::Instant::now();
+        let auth = client.authenticate_password("user", test_util::PASSWORD);
+        assert!(auth.await.unwrap());
+        let success = start.elapsed();
+        for &elapsed in &[rejection, success] {
+            assert!(elapsed >= delay && elapsed < delay * 2, "{:?}", elapsed);
+        }
+    }
+
+    #[test]
+    fn rejection_jitter() {
+        let mut config = server::Config::default();
+        let zero = std::time::Duration::from_secs(0);
+        assert_eq!(super::rejection_jitter(&config), zero);
+        let jitter = std::time::Duration::from_millis(100);
+        config.auth_rejection_jitter = Some(jitter);
+        let draws: Vec<_> = (0..100).map(|_| super::rejection_jitter(&config)).collect();
+        assert!(draws.iter().all(|&d| d <= jitter), "{:?}", draws);
+        // A new delay is drawn for each rejection.
+        assert!(draws.iter().any(|&d| d != draws[0]), "{:?}", draws);
+    }
+
+    /// Rejections take at least `auth_rejection_time` with jitter
+    /// enabled (the bounds of the jitter itself are checked by
+    /// `rejection_jitter`).
+    #[tokio::test]
+    async fn rejection_delay_with_jitter() {
+        let base = std::time::Duration::from_millis(100);
+        let jitter = std::time::Duration::from_millis(100);
+        let mut config = test_util::server_config();
+        config.auth_rejection_time = base;
+        config.auth_rejection_jitter = Some(jitter);
+        let (mut client, _) = test_util::connect(
+            config,
+            test_util::Server,
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        for _ in 0..3 {
+            let start = std::time::Instant::now();
+            assert!(!client.authenticate_password("user", "wrong").await.unwrap());
+            let elapsed = start.elapsed();
+            assert!(elapsed >= base, "{:?}", elapsed);
+        }
+    }
+
+    /// A server taking 200ms to check the password "slow", and
+    /// reporting slow handlers.
+    struct SlowAuth(UnboundedSender<(String, std::time::Duration)>);
+
+    impl Handler for SlowAuth {
+        type FutureAuth =
+            Pin<Box<dyn Future<Output = Result<(Self, server::Auth), anyhow::Error>> + Send>>;
+        type FutureUnit =
+            futures::future::Ready<Result<(Self, server::Session), anyhow::Error>>;
+        type FutureBool =
+            futures::future::Ready<Result<(Self, server::Session, bool), anyhow::Error>>;
+
+        fn finished_auth(self, auth: server::Auth) -> Self::FutureAuth {
+            Box::pin(futures::future::ready(Ok((self, auth))))
+        }
+        fn finished_bool(self, b: bool, session: server::Session) -> Self::FutureBool {
+            futures::future::ready(Ok((self, session, b)))
+        }
+        fn finished(self, session: server::Session) -> Self::FutureUnit {
+            futures::future::ready(Ok((self, session)))
+        }
+
+        fn auth_password(self, _: &str, password: &str) -> Self::FutureAuth {
+            if password != "slow" {
+                return self.finished_auth(server::Auth::Reject);
+            }
+            Box::pin(async move {
+                tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
+                Ok((self, server::Auth::Reject))
+            })
+        }
+
+        fn auth_slow(&self, method: &str, late: std::time::Duration) {
+            self.0.send((method.to_string(), late)).unwrap();
+        }
+    }
+
+    /// Handlers answering after `auth_rejection_time` are reported,
+    /// whatever the jitter drawn for the request.
+    #[tokio::test]
+    async fn slow_auth() {
+        let mut config = test_util::server_config();
+        config.auth_rejection_time = std::time::Duration::from_millis(50);
+        config.auth_rejection_jitter = Some(std::time::Duration::from_millis(500));
+        let (sender, mut reports) = unbounded_channel();
+        let (mut client, _server) = test_util::connect(
+            config,
+            SlowAuth(sender),
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(!client.authenticate_password("user", "fast").await.unwrap());
+        assert!(reports.try_recv().is_err());
+        assert!(!client.authenticate_password("user", "slow").await.unwrap());
+        let (method, late) = reports.try_recv().unwrap();
+        assert_eq!(method, "password");
+        // The handler took 200ms, 150ms more than allowed.
+        assert!(late >= std::time::Duration::from_millis(140), "{:?}", late);
+    }
+
+    #[tokio::test]
+    async fn max_channels() {
+        let mut config = test_util::server_config();
+        config.max_channels = 2;
+        let (mut client, _server) = test_util::authenticated(config).await;
+        let mut channels = Vec::new();
+        for _ in 0..2 {
+            channels.push(client.channel_open_session().await.unwrap());
+        }
+        assert!(client.channel_open_session().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn global_request_flood() {
+        let mut config = test_util::server_config();
+        config.max_global_requests_per_minute = Some(5);
+        let (mut client, server) = test_util::authenticated(config).await;
+        let mut channel = client.channel_open_session().await.unwrap();
+        for _ in 0..10 {
+            // The client may already be gone by the last requests.
+            channel.tcpip_forward(false, "127.0.0.1", 0).await.ok();
+        }
+        // The server hung up during the flood.
+        assert!(client.channel_open_session().await.is_err());
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(tokio::time::timeout(timeout, server).await.is_ok());
+    }
+
+    /// A server accepting the environment variable `LANG` only.
+    struct Lang;
+
+    impl Handler for Lang {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn env_request(
+            self,
+            channel: ChannelId,
+            variable_name: &str,
+            _: &str,
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            if variable_name == "LANG" {
+                session.channel_success(channel)
+            }
+            self.finished(session)
+        }
+    }
+
+    /// A client reporting the replies to its channel requests.
+    struct Replies(UnboundedSender<bool>);
+
+    impl client::Handler for Replies {
+        client_futures!();
+
+        fn channel_success(self, _: ChannelId, session: client::Session) -> Self::FutureUnit {
+            self.0.send(true).unwrap();
+            self.finished(session)
+        }
+
+        fn channel_failure(self, _: ChannelId, session: client::Session) -> Self::FutureUnit {
+            self.0.send(false).unwrap();
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn env_want_reply() {
+        let (sender, mut replies) = unbounded_channel();
+        let (mut client, _server) = test_util::connect(
+            test_util::server_config(),
+            Lang,
+            Default::default(),
+            Replies(sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.set_env(true, "LANG", "C").await.unwrap();
+        assert_eq!(replies.recv().await, Some(true));
+        channel.set_env(true, "LD_PRELOAD", "x").await.unwrap();
+        assert_eq!(replies.recv().await, Some(false));
+        // Requests without want_reply get no answer, so the next
+        // reply is always the one to the following request.
+        channel.set_env(false, "LD_PRELOAD", "x").await.unwrap();
+        channel.set_env(true, "LANG", "C").await.unwrap();
+        assert_eq!(replies.recv().await, Some(true));
+        channel.set_env(false, "LANG", "C").await.unwrap();
+        channel.set_env(true, "LD_PRELOAD", "x").await.unwrap();
+        assert_eq!(replies.recv().await, Some(false));
+    }
+}
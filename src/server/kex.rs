@@ -1,6 +1,6 @@
 use super::*;
 use crate::cipher::CipherPair;
-use crate::key::PubKey;
+use crate::key::{HostCertificate, PubKey};
 use crate::negotiation::Select;
 use crate::{kex, msg, negotiation};
 use std::cell::RefCell;
@@ -23,7 +23,11 @@ impl KexInit {
             let algo = if self.algo.is_none() {
                 // read algorithms from packet.
                 self.exchange.client_kex_init.extend(buf);
-                super::negotiation::Server::read_kex(buf, &config.preferred)?
+                super::negotiation::Server::read_kex_keys(
+                    buf,
+                    &config.preferred,
+                    &advertised_keys(config),
+                )?
             } else {
                 return Err(Error::Kex.into());
             };
@@ -32,7 +36,13 @@ impl KexInit {
             }
             let mut key = 0;
             debug!("config {:?} algo {:?}", config.keys, algo.key);
-            while key < config.keys.len() && config.keys[key].name() != algo.key.as_ref() {
+            let certificate = negotiation::certificate_key(algo.key.0);
+            let key_name = certificate.map(|k| k.0).unwrap_or(algo.key.0);
+            while key < config.keys.len()
+                && (config.keys[key].name() != key_name
+                    || (certificate.is_some()
+                        && host_certificate(config, &config.keys[key]).is_none()))
+            {
                 key += 1
             }
             let next_kex = if key < config.keys.len() {
@@ -59,7 +69,12 @@ impl KexInit {
         write_buffer: &mut SSHBuffer,
     ) -> Result<(), anyhow::Error> {
         self.exchange.server_kex_init.clear();
-        negotiation::write_kex(&config.preferred, &mut self.exchange.server_kex_init)?;
+        negotiation::write_kex_keys(
+            &config.preferred,
+            advertised_keys(config).iter().map(|k| k.0),
+            &[negotiation::EXT_INFO_S, negotiation::STRICT_KEX_S],
+            &mut self.exchange.server_kex_init,
+        )?;
         debug!("server kex init: {:?}", &self.exchange.server_kex_init[..]);
         self.sent = true;
         cipher.write(&self.exchange.server_kex_init, write_buffer);
@@ -77,13 +92,18 @@ impl KexDh {
     ) -> Result<Kex, anyhow::Error> {
         debug!("KexDh: parse {:?}", self.names.ignore_guessed);
         if self.names.ignore_guessed {
-            // If we need to ignore this packet.
+            // The client sent a key exchange packet for the
+            // algorithms it guessed, and the guess was wrong
+            // (RFC 4253, section 7.1): ignore that packet.
+            debug!("ignoring guessed kex packet {:?}", buf.first());
             self.names.ignore_guessed = false;
             Ok(Kex::KexDh(self))
+        } else if buf[0] != msg::KEX_ECDH_INIT {
+            error!("Wrong packet received");
+            Err(Error::Inconsistent.into())
         } else {
             // Else, process it.
             debug!("buf = {:?}", buf);
-            assert!(buf[0] == msg::KEX_ECDH_INIT);
             let mut r = buf.reader(1);
             self.exchange.client_ephemeral.extend(r.read_string()?);
             let kex = kex::Algorithm::server_dh(self.names.kex, &mut self.exchange, buf)?;
@@ -101,15 +121,31 @@ impl KexDh {
                     let mut buffer = buffer.borrow_mut();
                     buffer.clear();
                     debug!("server kexdhdone.exchange = {:?}", kexdhdone.exchange);
-                    let hash = kexdhdone.kex.compute_exchange_hash(
-                        &config.keys[kexdhdone.key],
-                        &kexdhdone.exchange,
-                        &mut buffer,
-                    )?;
+                    let host_key = &config.keys[kexdhdone.key];
+                    let certificate = negotiation::certificate_key(kexdhdone.names.key.0)
+                        .and_then(|_| host_certificate(config, host_key))
+                        .map(HostCertificate);
+                    let hash = if let Some(ref certificate) = certificate {
+                        kexdhdone.kex.compute_exchange_hash(
+                            certificate,
+                            &kexdhdone.exchange,
+                            &mut buffer,
+                        )?
+                    } else {
+                        kexdhdone.kex.compute_exchange_hash(
+                            host_key,
+                            &kexdhdone.exchange,
+                            &mut buffer,
+                        )?
+                    };
                     debug!("exchange hash: {:?}", hash);
                     buffer.clear();
                     buffer.push(msg::KEX_ECDH_REPLY);
-                    config.keys[kexdhdone.key].push_to(&mut buffer);
+                    if let Some(ref certificate) = certificate {
+                        certificate.push_to(&mut buffer)
+                    } else {
+                        host_key.push_to(&mut buffer)
+                    }
                     // Server ephemeral
                     buffer.extend_ssh_string(&kexdhdone.exchange.server_ephemeral);
                     // Hash signature
@@ -119,6 +155,7 @@ impl KexDh {
                     config.keys[kexdhdone.key].add_signature(&mut buffer, &hash)?;
                     cipher.write(&buffer, write_buffer);
                     cipher.write(&[msg::NEWKEYS], write_buffer);
+                    crate::release_buffer(&mut buffer);
                     Ok(hash)
                 });
 
@@ -126,3 +163,53 @@ impl KexDh {
         }
     }
 }
+
+/// The host key algorithms of `config.preferred`, without the
+/// certificate algorithms we have no certificate for. These are the
+/// algorithms we advertise, and therefore the only ones we may
+/// negotiate.
+fn advertised_keys(config: &Config) -> Vec<thrussh_keys::key::Name> {
+    config
+        .preferred
+        .key
+        .iter()
+        .cloned()
+        .filter(|name| {
+            if let Some(key_name) = negotiation::certificate_key(name.0) {
+                config
+                    .keys
+                    .iter()
+                    .any(|k| k.name() == key_name.0 && host_certificate(config, k).is_some())
+            } else {
+                true
+            }
+        })
+        .collect()
+}
+
+/// Find the certificate of `key` in `config.host_certificates`, by
+/// comparing the public key fields of each certificate (after its
+/// type and nonce) with the fields of the public key (after its
+/// type).
+fn host_certificate<'a>(
+    config: &'a Config,
+    key: &thrussh_keys::key::KeyPair,
+) -> Option<&'a [u8]> {
+    let mut buffer = CryptoVec::new();
+    key.push_to(&mut buffer);
+    let mut r = buffer.reader(0);
+    let blob = r.read_string().ok()?;
+    let mut r = blob.reader(0);
+    r.read_string().ok()?;
+    let fields = &blob[r.position..];
+    config
+        .host_certificates
+        .iter()
+        .map(|cert| &cert[..])
+        .find(|cert| {
+            let mut r = cert.reader(0);
+            r.read_string().is_ok()
+                && r.read_string().is_ok()
+                && cert[r.position..].starts_with(fields)
+        })
+}
@@ -14,22 +14,24 @@
 //
 
 use std;
+use std::collections::{HashMap, VecDeque};
 use std::net::ToSocketAddrs;
 use std::sync::Arc;
 
 use futures::future::Future;
-use futures::stream::TryStreamExt;
+use futures::stream::StreamExt;
+use thrussh_keys::encoding::Reader;
 use thrussh_keys::key;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
 
-use crate::negotiation::Select;
 use crate::session::*;
 use crate::ssh_read::*;
 use crate::sshbuffer::*;
 use crate::*;
 
 mod kex;
+mod proxy_protocol;
 mod session;
 pub use self::kex::*;
 pub use self::session::*;
@@ -44,23 +46,142 @@ pub struct Config {
     pub methods: auth::MethodSet,
     /// The authentication banner, usually a warning message shown to the client.
     pub auth_banner: Option<&'static str>,
+    /// The language tag of the messages sent by the server, such as
+    /// channel open failures and disconnection messages. Empty by
+    /// default, as recommended by
+    /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-5.1).
+    pub language_tag: String,
+    /// Keep the language tags sent by the client (in
+    /// keyboard-interactive requests, channel open failures, debug
+    /// and disconnect messages), see
+    /// `Session::peer_language_tag`. They are ignored by default.
+    pub capture_language_tags: bool,
     /// Authentication rejections must happen in constant time for
     /// security reasons. Thrussh does not handle this by default.
     pub auth_rejection_time: std::time::Duration,
+    /// If set, a random delay between zero and this duration is
+    /// added to `auth_rejection_time` for each request, so that the
+    /// fixed delay can't be measured and subtracted from the time
+    /// taken by rejections. The same delay is added to
+    /// `auth_success_time`.
+    pub auth_rejection_jitter: Option<std::time::Duration>,
+    /// If set, successful authentications, partial successes and
+    /// `SSH_MSG_USERAUTH_PK_OK` replies are also delayed until this
+    /// long after the request was received (plus the jitter of
+    /// `auth_rejection_jitter`), so that the time taken to answer
+    /// doesn't tell whether the credentials were valid. This slows
+    /// down every login: setting it to `auth_rejection_time` makes
+    /// the delays of acceptances and rejections follow the same
+    /// distribution, as long as the handler answers within that
+    /// time.
+    pub auth_success_time: Option<std::time::Duration>,
     /// The server's keys. The first key pair in the client's preference order will be chosen.
     pub keys: Vec<key::KeyPair>,
+    /// OpenSSH host certificates for the keys in `keys`, in wire
+    /// format (the base64-decoded second field of a `-cert.pub`
+    /// file). They are offered to the client if the corresponding
+    /// algorithms, such as
+    /// [`ED25519_CERT`](../constant.ED25519_CERT.html), are
+    /// listed in `preferred.key`.
+    pub host_certificates: Vec<Vec<u8>>,
     /// The bytes and time limits before key re-exchange.
     pub limits: Limits,
     /// The initial size of a channel (used for flow control).
     pub window_size: u32,
-    /// The maximal size of a single packet.
+    /// The maximal size of a single packet. Once the user is
+    /// authenticated, larger channel data packets close the
+    /// connection.
     pub maximum_packet_size: u32,
+    /// The maximal size of the packets received before the user is
+    /// authenticated. Larger packets close the connection.
+    pub max_handshake_packet_size: usize,
+    /// The maximal number of channels open at the same time on a
+    /// connection. Further channel open requests from the client are
+    /// refused with `ChannelOpenFailure::ResourceShortage`.
+    pub max_channels: usize,
+    /// The maximal rate of global requests (such as `tcpip-forward`
+    /// or keepalives) from the client, with bursts of up to this
+    /// many requests. Clients going over it are disconnected.
+    pub max_global_requests_per_minute: Option<u32>,
+    /// Drop extended data whose type code is not
+    /// `SSH_EXTENDED_DATA_STDERR` (1), instead of passing it to
+    /// [`Handler::extended_data`](trait.Handler.html#method.extended_data).
+    /// The channel window is adjusted in both cases.
+    pub drop_unknown_extended_data: bool,
+    /// If set, only pseudo-terminal requests for these terminal
+    /// types (the `TERM` variable, such as `xterm-256color`) are
+    /// passed to the handler, others are refused.
+    pub allowed_terms: Option<std::collections::HashSet<String>>,
     /// Lists of preferred algorithms.
     pub preferred: Preferred,
     /// Maximal number of allowed authentication attempts.
     pub max_auth_attempts: usize,
+    /// Maximal number of prompt rounds (`Auth::Partial`) in
+    /// keyboard-interactive authentication, after which the client
+    /// is disconnected, to stop clients or handlers looping forever.
+    pub max_interactive_rounds: usize,
+    /// Start a key re-exchange as soon as the client is
+    /// authenticated, so that the rest of the session uses fresh keys.
+    pub rekey_after_auth: bool,
+    /// If set, all authentication is refused: this message is sent
+    /// to the client as a banner, and the connection is closed with
+    /// `SERVICE_NOT_AVAILABLE` right after the service request.
+    pub maintenance_mode: Option<String>,
     /// Time after which the connection is garbage-collected.
     pub connection_timeout: Option<std::time::Duration>,
+    /// Maximal time between the connection and the end of the
+    /// authentication, after which the client is disconnected. This
+    /// also bounds the time to read the client's SSH id.
+    pub handshake_timeout: Option<std::time::Duration>,
+    /// Send a `keepalive@openssh.com` request to authenticated
+    /// clients after this much time without receiving anything.
+    pub keepalive_interval: Option<std::time::Duration>,
+    /// Disconnect clients that left this many keepalive requests
+    /// unanswered.
+    pub keepalive_max: usize,
+    /// Send an `SSH_MSG_IGNORE` packet of random length and contents
+    /// after this much time without sending or receiving anything,
+    /// to make traffic analysis harder. These packets don't reset
+    /// `idle_timeout`.
+    pub cover_traffic_interval: Option<std::time::Duration>,
+    /// Maximal time to wait for the client to read the data we send.
+    /// When a write takes longer than this, for instance because the
+    /// client stopped reading, the connection is closed with
+    /// `Error::SendTimeout` instead of blocking forever.
+    pub send_timeout: Option<std::time::Duration>,
+    /// Disconnect clients after this much time without any packet
+    /// read from or written to the connection.
+    pub idle_timeout: Option<std::time::Duration>,
+    /// Maximal time [`run_with_shutdown`](fn.run_with_shutdown.html)
+    /// waits for the connections to close after the shutdown, before
+    /// returning anyway. If `None`, it waits for all of them.
+    pub shutdown_timeout: Option<std::time::Duration>,
+    /// Maximal number of simultaneous connections from a single IP
+    /// address. Further connections from that address are closed
+    /// immediately by [`run`](fn.run.html).
+    pub per_ip_connection_limit: Option<usize>,
+    /// Set `SO_REUSEADDR` on the listening socket, allowing the
+    /// server to be restarted while old connections are still in
+    /// `TIME_WAIT`.
+    pub reuse_address: bool,
+    /// Set `SO_REUSEPORT` on the listening socket (Unix only),
+    /// allowing several processes to share the same port.
+    pub reuse_port: bool,
+    /// Expect a [PROXY protocol](https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt)
+    /// header (version 1 or 2) at the beginning of each connection,
+    /// as sent by load balancers, and use the client address it
+    /// contains instead of the address of the balancer. Connections
+    /// without a valid header are closed.
+    pub expect_proxy_protocol: bool,
+    /// Extensions (name and value) sent to the client in
+    /// `SSH_MSG_EXT_INFO` ([RFC8308](https://tools.ietf.org/html/rfc8308))
+    /// after the first key exchange, if the client supports it.
+    /// Applications can use custom extension names (such as
+    /// `name@example.com`) to negotiate their own capabilities.
+    ///
+    /// `server-sig-algs` is always sent, with the signature
+    /// algorithms of `preferred.key`, unless it is in this list.
+    pub extensions: Vec<(String, Vec<u8>)>,
 }
 
 impl Default for Config {
@@ -73,14 +194,39 @@ impl Default for Config {
             ),
             methods: auth::MethodSet::all(),
             auth_banner: None,
+            language_tag: String::new(),
+            capture_language_tags: false,
             auth_rejection_time: std::time::Duration::from_secs(1),
+            auth_success_time: None,
+            auth_rejection_jitter: None,
             keys: Vec::new(),
+            host_certificates: Vec::new(),
             window_size: 200000,
             maximum_packet_size: 200000,
+            max_handshake_packet_size: 256 * 1024,
+            max_channels: 64,
+            max_global_requests_per_minute: None,
+            drop_unknown_extended_data: false,
+            allowed_terms: None,
             limits: Limits::default(),
             preferred: Default::default(),
             max_auth_attempts: 10,
+            max_interactive_rounds: 10,
+            rekey_after_auth: false,
+            maintenance_mode: None,
             connection_timeout: Some(std::time::Duration::from_secs(600)),
+            handshake_timeout: Some(std::time::Duration::from_secs(30)),
+            keepalive_interval: None,
+            keepalive_max: 3,
+            cover_traffic_interval: None,
+            send_timeout: None,
+            idle_timeout: None,
+            shutdown_timeout: Some(std::time::Duration::from_secs(30)),
+            per_ip_connection_limit: None,
+            reuse_address: true,
+            reuse_port: false,
+            expect_proxy_protocol: false,
+            extensions: Vec::new(),
         }
     }
 }
@@ -116,6 +262,21 @@ pub enum Auth {
     /// Method was not accepted, but no other check was performed.
     UnsupportedMethod,
 
+    /// Accept this method as one step of the authentication, and ask
+    /// the client to continue with one of the `remaining` methods,
+    /// for instance to require a public key *and* a password. The
+    /// client is told of its partial success, see
+    /// [RFC4252](https://tools.ietf.org/html/rfc4252#section-5.1).
+    ///
+    /// Only the `remaining` methods are allowed afterwards. The
+    /// handler is responsible for remembering the steps already taken,
+    /// as reported by `Handler::auth_partial_success`, and for
+    /// returning `Auth::Accept` only once all of them are done.
+    AcceptPartial {
+        /// Methods the client may use for the next step.
+        remaining: auth::MethodSet,
+    },
+
     /// Partially accept the challenge-response authentication
     /// request, providing more instructions for the client to follow.
     Partial {
@@ -128,6 +289,42 @@ pub enum Auth {
         /// typed by the user.
         prompts: Cow<'static, [(Cow<'static, str>, bool)]>,
     },
+
+    /// The password is correct but expired: ask the client to
+    /// change it, see
+    /// [RFC4252](https://tools.ietf.org/html/rfc4252#section-8). The
+    /// client then sends its old and new passwords, passed to
+    /// `Handler::auth_password_change`. Only valid as a reply to the
+    /// "password" method, other methods reject the request instead.
+    PasswordChangeRequired {
+        /// Message shown to the user.
+        prompt: Cow<'static, str>,
+        /// Language tag of the prompt, usually empty.
+        language: Cow<'static, str>,
+    },
+}
+
+/// Constructors for the prompts of [`Auth::Partial`](enum.Auth.html).
+///
+/// ```
+/// use thrussh::server::Prompt;
+/// let prompts = vec![Prompt::visible("Login: "), Prompt::hidden("Password: ")];
+/// assert_eq!(prompts[1], ("Password: ".into(), false));
+/// ```
+#[derive(Debug)]
+pub struct Prompt;
+
+impl Prompt {
+    /// A prompt whose answer is echoed by the client's terminal, such
+    /// as a one-time token.
+    pub fn visible<T: Into<Cow<'static, str>>>(text: T) -> (Cow<'static, str>, bool) {
+        (text.into(), true)
+    }
+
+    /// A prompt whose answer is not echoed, such as a password.
+    pub fn hidden<T: Into<Cow<'static, str>>>(text: T) -> (Cow<'static, str>, bool) {
+        (text.into(), false)
+    }
 }
 
 /// Server handler. Each client will have their own handler.
@@ -154,6 +351,25 @@ pub trait Handler: Sized {
     /// default handlers.
     fn finished(self, session: Session) -> Self::FutureUnit;
 
+    /// Called when the client requests the `ssh-userauth` service,
+    /// before any authentication takes place. Returning `false`
+    /// refuses the service and disconnects the client. Requests for
+    /// any other service are refused without calling this method.
+    #[allow(unused_variables)]
+    fn service_request(self, name: &str, session: Session) -> Self::FutureBool {
+        self.finished_bool(true, session)
+    }
+
+    /// Called before the first authentication request for `user`
+    /// (and again if the client changes user names), for instance
+    /// to show a notice specific to a user or to the client address
+    /// with `session.send_auth_banner`. This comes in addition to
+    /// `config.auth_banner`.
+    #[allow(unused_variables)]
+    fn auth_banner_for(self, user: &str, session: Session) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
     /// Check authentication using the "none" method. Thrussh makes
     /// sure rejection happens in time `config.auth_rejection_time`,
     /// except if this method takes more than that.
@@ -171,6 +387,21 @@ pub trait Handler: Sized {
         self.finished_auth(Auth::Reject)
     }
 
+    /// Check a password change, sent by the client after
+    /// `Auth::PasswordChangeRequired`. Accepting the request both
+    /// authenticates the user and is expected to set the new
+    /// password; rejection happens in time
+    /// `config.auth_rejection_time`, like for `auth_password`.
+    #[allow(unused_variables)]
+    fn auth_password_change(
+        self,
+        user: &str,
+        old_password: &str,
+        new_password: &str,
+    ) -> Self::FutureAuth {
+        self.finished_auth(Auth::Reject)
+    }
+
     /// Check authentication using the "publickey" method. This method
     /// should just check whether the public key matches the
     /// authorized ones. Thrussh then checks the signature. If the key
@@ -197,6 +428,70 @@ pub trait Handler: Sized {
         self.finished_auth(Auth::Reject)
     }
 
+    /// Check authentication using an OpenSSH user certificate. This
+    /// is only called for certificates signed by their certificate
+    /// authority, currently valid, valid for `user`, and without
+    /// critical options other than `force-command` and
+    /// `source-address`: the handler must still check that it trusts
+    /// `cert.signature_key`, and enforce these critical options.
+    #[allow(unused_variables)]
+    fn auth_publickey_cert(self, user: &str, cert: &crate::Certificate) -> Self::FutureAuth {
+        self.finished_auth(Auth::Reject)
+    }
+
+    /// Check authentication using the "hostbased" method, see
+    /// [RFC4252](https://tools.ietf.org/html/rfc4252#section-9):
+    /// `client_user` on host `client_host` wants to log in as `user`.
+    /// This is only called once the request has been verified to be
+    /// signed with `host_key`; the handler must still check that
+    /// `host_key` is the key of `client_host`, and that
+    /// `client_user` may log in as `user`.
+    #[allow(unused_variables)]
+    fn auth_hostbased(
+        self,
+        user: &str,
+        host_key: &key::PublicKey,
+        client_host: &str,
+        client_user: &str,
+    ) -> Self::FutureAuth {
+        self.finished_auth(Auth::Reject)
+    }
+
+    /// Called when one of the `auth_*` methods answered later than
+    /// `config.auth_rejection_time` after the request was received,
+    /// `late` being the excess. Rejections are then delayed by the
+    /// handler rather than by Thrussh, and their timing can leak
+    /// information. The default implementation logs a warning.
+    fn auth_slow(&self, method: &str, late: std::time::Duration) {
+        warn!(
+            "{} authentication handler exceeded auth_rejection_time by {:?}",
+            method, late
+        );
+    }
+
+    /// Called when a step of a multi-step authentication is accepted
+    /// (an `auth_*` method returned `Auth::AcceptPartial`), once
+    /// Thrussh has checked it. In particular, `auth_publickey` is also
+    /// called for unsigned queries, whereas this is only called after
+    /// the signature is verified. Only the `remaining` methods are
+    /// allowed after this, so handlers should record the completed
+    /// steps here rather than in the `auth_*` methods.
+    #[allow(unused_variables)]
+    fn auth_partial_success(
+        &mut self,
+        user: &str,
+        method: auth::MethodSet,
+        remaining: auth::MethodSet,
+    ) {
+    }
+
+    /// Called once, when the client is authenticated, whatever the
+    /// method. This is not called again on key re-exchanges.
+    #[allow(unused_variables)]
+    fn auth_succeeded(self, session: Session) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
     /// Called when the client closes a channel.
     #[allow(unused_variables)]
     fn channel_close(self, channel: ChannelId, session: Session) -> Self::FutureUnit {
@@ -209,6 +504,57 @@ pub trait Handler: Sized {
         self.finished(session)
     }
 
+    /// Called when the client asks to open a channel of any type,
+    /// before the channel is confirmed and before the type-specific
+    /// method such as `channel_open_direct_tcpip` is called. `data`
+    /// contains the type-specific fields of the request (for instance
+    /// the host and port to connect to, for `direct-tcpip`), and
+    /// `channel` is the client's identifier of the channel.
+    ///
+    /// Returning `false` refuses the channel, with the reason set by
+    /// [`Session::set_channel_open_failure`](struct.Session.html#method.set_channel_open_failure),
+    /// or else `ChannelOpenFailure::AdministrativelyProhibited`. The
+    /// default implementation accepts all channels.
+    #[allow(unused_variables)]
+    fn check_channel_open(
+        self,
+        channel: ChannelId,
+        channel_type: &str,
+        data: &[u8],
+        session: Session,
+    ) -> Self::FutureBool {
+        self.finished_bool(true, session)
+    }
+
+    /// Called whenever a channel is opened and confirmed, whatever its
+    /// type (`session`, `x11`, `direct-tcpip`), before the
+    /// type-specific method such as `channel_open_session`.
+    #[allow(unused_variables)]
+    fn channel_opened(
+        self,
+        channel: ChannelId,
+        channel_type: &str,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the client asks to open a `tun@openssh.com`
+    /// channel, forwarding packets (`mode` 1) or Ethernet frames
+    /// (`mode` 2) to the tunnel device `remote_unit` (`0x7fffffff`
+    /// means any device). The channel is opened only if this returns
+    /// `true`, which it does not by default.
+    #[allow(unused_variables)]
+    fn channel_open_tun(
+        self,
+        channel: ChannelId,
+        mode: u32,
+        remote_unit: u32,
+        session: Session,
+    ) -> Self::FutureBool {
+        self.finished_bool(false, session)
+    }
+
     /// Called when a new session channel is created.
     #[allow(unused_variables)]
     fn channel_open_session(self, channel: ChannelId, session: Session) -> Self::FutureUnit {
@@ -276,7 +622,10 @@ pub trait Handler: Sized {
     }
 
     /// The client requests a pseudo-terminal with the given
-    /// specifications.
+    /// specifications. Requests for a terminal type not in
+    /// `Config::allowed_terms` are refused without calling this
+    /// method. The handler can refuse the request with
+    /// `session.channel_anyhow(channel)`.
     #[allow(unused_variables)]
     fn pty_request(
         self,
@@ -286,7 +635,7 @@ pub trait Handler: Sized {
         row_height: u32,
         pix_width: u32,
         pix_height: u32,
-        modes: &[(Pty, u32)],
+        modes: &PtyModes,
         session: Session,
     ) -> Self::FutureUnit {
         self.finished(session)
@@ -308,7 +657,10 @@ pub trait Handler: Sized {
 
     /// The client wants to set the given environment variable. Check
     /// these carefully, as it is dangerous to allow any variable
-    /// environment to be set.
+    /// environment to be set. Accept the variable by calling
+    /// `session.channel_success(channel)`, else it is rejected if
+    /// the client asked for a reply. Invalid UTF-8 in the name or
+    /// value is replaced with U+FFFD.
     #[allow(unused_variables)]
     fn env_request(
         self,
@@ -366,8 +718,105 @@ pub trait Handler: Sized {
         self.finished(session)
     }
 
+    /// Called when the client disconnects, with the reason code,
+    /// description and language tag of its disconnect message.
+    #[allow(unused_variables)]
+    fn disconnected(
+        self,
+        reason: Disconnect,
+        description: &str,
+        language: &str,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the client sends a debug message
+    /// (`SSH_MSG_DEBUG`, see
+    /// [RFC4253](https://tools.ietf.org/html/rfc4253#section-11.3)).
+    /// `always_display` asks for the message to be shown even when
+    /// debugging is not enabled.
+    #[allow(unused_variables)]
+    fn debug(
+        self,
+        always_display: bool,
+        message: &str,
+        language: &str,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the client sends an ignore message
+    /// (`SSH_MSG_IGNORE`, see
+    /// [RFC4253](https://tools.ietf.org/html/rfc4253#section-11.2)),
+    /// which is meant to be discarded, for instance cover traffic.
+    #[allow(unused_variables)]
+    fn ignore(self, data: &[u8], session: Session) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the client did not implement one of our packets
+    /// (`SSH_MSG_UNIMPLEMENTED`, see
+    /// [RFC4253](https://tools.ietf.org/html/rfc4253#section-11.4)),
+    /// with the sequence number of that packet.
+    #[allow(unused_variables)]
+    fn unimplemented(self, seqn: u32, session: Session) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when a key re-exchange has completed (but not after the
+    /// initial key exchange), with the newly negotiated algorithms.
+    #[allow(unused_variables)]
+    fn rekey_completed(
+        self,
+        algorithms: &NegotiatedAlgorithms,
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.finished(session)
+    }
+
+    /// Called when the client sends a channel request of a type not
+    /// handled by the other methods of this trait, such as `break` or
+    /// `eow@openssh.com`. `data` contains the request-specific fields,
+    /// after the `want_reply` flag. If `want_reply` is set, the client
+    /// is answered with `CHANNEL_SUCCESS` or `CHANNEL_FAILURE`,
+    /// according to the returned boolean. The default implementation
+    /// refuses all requests.
+    #[allow(unused_variables)]
+    fn channel_request(
+        self,
+        channel: ChannelId,
+        request_type: &str,
+        want_reply: bool,
+        data: &[u8],
+        session: Session,
+    ) -> Self::FutureBool {
+        self.finished_bool(false, session)
+    }
+
+    /// Called when the client sends a global request of an unknown
+    /// type, such as a vendor extension. `data` contains the
+    /// request-specific fields, after the request name and the
+    /// `want_reply` flag. If `want_reply` is set, the client is
+    /// answered with success or failure, according to the returned
+    /// boolean. The default implementation refuses all requests.
+    #[allow(unused_variables)]
+    fn global_request(
+        self,
+        name: &str,
+        want_reply: bool,
+        data: &[u8],
+        session: Session,
+    ) -> Self::FutureBool {
+        self.finished_bool(false, session)
+    }
+
     /// Used for reverse-forwarding ports, see
-    /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-7).
+    /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-7). If
+    /// `port` is 0, the handler chooses a port, and must report it with
+    /// [`Session::set_bound_port`](struct.Session.html#method.set_bound_port),
+    /// or the request fails.
     #[allow(unused_variables)]
     fn tcpip_forward(self, address: &str, port: u32, session: Session) -> Self::FutureBool {
         self.finished_bool(false, session)
@@ -394,24 +843,233 @@ pub trait Server {
 pub async fn run<H: Server + Send + 'static>(
     config: Arc<Config>,
     addr: &str,
+    server: H,
+) -> Result<(), std::io::Error> {
+    run_with_shutdown(config, addr, server, futures::future::pending()).await
+}
+
+/// Run a server until `shutdown` resolves. The server then stops
+/// accepting new connections, and asks the existing ones to
+/// disconnect: each connection flushes its pending data (including
+/// the data queued on its channels) before sending an
+/// `SSH_MSG_DISCONNECT` message and closing. This returns once all
+/// the connections are closed, or after `config.shutdown_timeout`.
+pub async fn run_with_shutdown<H: Server + Send + 'static, S: Future<Output = ()>>(
+    config: Arc<Config>,
+    addr: &str,
+    server: H,
+    shutdown: S,
+) -> Result<(), std::io::Error> {
+    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to bind to")
+    })?;
+    let socket = bind(&config, &addr)?;
+    run_on_socket_with_shutdown(config, socket, server, shutdown).await
+}
+
+/// Run a server on an already bound listener, for instance a socket
+/// bound with custom options, inherited from systemd, or bound to
+/// port 0 (use `listener.local_addr()` to read the port back).
+pub async fn run_on_socket<H: Server + Send + 'static>(
+    config: Arc<Config>,
+    socket: TcpListener,
+    server: H,
+) -> Result<(), std::io::Error> {
+    run_on_socket_with_shutdown(config, socket, server, futures::future::pending()).await
+}
+
+/// Run a server on an already bound listener, until `shutdown`
+/// resolves. See [`run_with_shutdown`](fn.run_with_shutdown.html).
+pub async fn run_on_socket_with_shutdown<H: Server + Send + 'static, S: Future<Output = ()>>(
+    config: Arc<Config>,
+    mut socket: TcpListener,
+    server: H,
+    shutdown: S,
+) -> Result<(), std::io::Error> {
+    let server = Arc::new(std::sync::Mutex::new(server));
+    let connections = Arc::new(std::sync::Mutex::new(HashMap::new()));
+    let (shutdown_sender, _) = tokio::sync::broadcast::channel(1);
+    let (live, finished) = tokio::sync::mpsc::channel(1);
+    let mut incoming = socket.incoming();
+    futures::pin_mut!(shutdown);
+    loop {
+        let socket = tokio::select! {
+            _ = &mut shutdown => break,
+            socket = incoming.next() => match socket {
+                Some(socket) => socket?,
+                None => break,
+            },
+        };
+        let config = config.clone();
+        let server = server.clone();
+        let connections = connections.clone();
+        let shutdown = shutdown_sender.subscribe();
+        let live = live.clone();
+        tokio::spawn(async move {
+            let _live = live;
+            let mut socket = socket;
+            let mut peer_addr = socket.peer_addr().ok();
+            if config.expect_proxy_protocol {
+                if let Some(addr) = read_proxy_header(&config, &mut socket).await? {
+                    peer_addr = Some(addr)
+                }
+            }
+            let ip = if let Some(limit) = config.per_ip_connection_limit {
+                let ip = peer_addr.map(|addr| addr.ip());
+                if !acquire_connection(&connections, ip, limit) {
+                    debug!("too many connections from {:?}", ip);
+                    return Ok(());
+                }
+                ip
+            } else {
+                None
+            };
+            let handler = server.lock().unwrap().new(peer_addr);
+            let result =
+                run_stream_until(config, socket, handler, Some(shutdown), peer_addr).await;
+            if let Some(ip) = ip {
+                release_connection(&connections, ip)
+            }
+            result
+        });
+    }
+    debug!("shutting down");
+    shutdown_sender.send(()).unwrap_or(0);
+    wait_for_connections(&config, live, finished).await;
+    Ok(())
+}
+
+/// Run a server on a Unix domain socket listener. Since there is no
+/// peer address for these connections, `Server::new` is called with
+/// `None`, and `per_ip_connection_limit` does not apply.
+#[cfg(unix)]
+pub async fn run_on_unix<H: Server + Send + 'static>(
+    config: Arc<Config>,
+    listener: tokio::net::UnixListener,
+    server: H,
+) -> Result<(), std::io::Error> {
+    run_on_unix_with_shutdown(config, listener, server, futures::future::pending()).await
+}
+
+/// Run a server on a Unix domain socket listener, until `shutdown`
+/// resolves. See [`run_with_shutdown`](fn.run_with_shutdown.html)
+/// and [`run_on_unix`](fn.run_on_unix.html).
+#[cfg(unix)]
+pub async fn run_on_unix_with_shutdown<H: Server + Send + 'static, S: Future<Output = ()>>(
+    config: Arc<Config>,
+    mut listener: tokio::net::UnixListener,
     mut server: H,
+    shutdown: S,
 ) -> Result<(), std::io::Error> {
-    let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-    let mut socket = TcpListener::bind(&addr).await?;
-    socket
-        .incoming()
-        .try_for_each(move |socket| {
-            let config = config.clone();
-            let server = server.new(socket.peer_addr().ok());
-            async move {
-                tokio::spawn(run_stream(config, socket, server));
-                Ok(())
+    let (shutdown_sender, _) = tokio::sync::broadcast::channel(1);
+    let (live, finished) = tokio::sync::mpsc::channel(1);
+    let mut incoming = listener.incoming();
+    futures::pin_mut!(shutdown);
+    loop {
+        let socket = tokio::select! {
+            _ = &mut shutdown => break,
+            socket = incoming.next() => match socket {
+                Some(socket) => socket?,
+                None => break,
+            },
+        };
+        let config = config.clone();
+        let handler = server.new(None);
+        let shutdown = shutdown_sender.subscribe();
+        let live = live.clone();
+        tokio::spawn(async move {
+            let _live = live;
+            let mut socket = socket;
+            let mut peer_addr = None;
+            if config.expect_proxy_protocol {
+                peer_addr = read_proxy_header(&config, &mut socket).await?
             }
-        })
-        .await?;
+            run_stream_until(config, socket, handler, Some(shutdown), peer_addr).await
+        });
+    }
+    debug!("shutting down");
+    shutdown_sender.send(()).unwrap_or(0);
+    wait_for_connections(&config, live, finished).await;
     Ok(())
 }
 
+/// Wait until the connection tasks, which each hold a clone of
+/// `live`, have all returned, or until `config.shutdown_timeout`.
+async fn wait_for_connections(
+    config: &Config,
+    live: tokio::sync::mpsc::Sender<()>,
+    mut finished: tokio::sync::mpsc::Receiver<()>,
+) {
+    drop(live);
+    // Nothing is ever sent on this channel: `recv` returns `None`
+    // once all the senders are dropped.
+    let closed = finished.recv();
+    if let Some(t) = config.shutdown_timeout {
+        if tokio::time::timeout(t, closed).await.is_err() {
+            debug!("connections still open after the shutdown timeout");
+        }
+    } else {
+        closed.await;
+    }
+}
+
+type ConnectionCount = Arc<std::sync::Mutex<HashMap<std::net::IpAddr, usize>>>;
+
+/// Count a new connection from `ip`, returning `false` if that
+/// address has already reached `limit`.
+fn acquire_connection(
+    connections: &ConnectionCount,
+    ip: Option<std::net::IpAddr>,
+    limit: usize,
+) -> bool {
+    if let Some(ip) = ip {
+        let mut connections = connections.lock().unwrap();
+        let n = connections.entry(ip).or_insert(0);
+        if *n >= limit {
+            return false;
+        }
+        *n += 1;
+    }
+    true
+}
+
+/// Forget about a closed connection from `ip`, removing the entry
+/// once no connection from that address remains.
+fn release_connection(connections: &ConnectionCount, ip: std::net::IpAddr) {
+    let mut connections = connections.lock().unwrap();
+    if let std::collections::hash_map::Entry::Occupied(mut e) = connections.entry(ip) {
+        *e.get_mut() -= 1;
+        if *e.get() == 0 {
+            e.remove();
+        }
+    }
+}
+
+/// Create the listening socket, setting the socket options
+/// requested in the configuration.
+fn bind(config: &Config, addr: &std::net::SocketAddr) -> Result<TcpListener, std::io::Error> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    if config.reuse_address {
+        socket.set_reuse_address(true)?;
+    }
+    #[cfg(unix)]
+    {
+        if config.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+    }
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into_tcp_listener())
+}
+
 use std::cell::RefCell;
 thread_local! {
     static B1: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
@@ -426,63 +1084,242 @@ pub async fn timeout(delay: Option<std::time::Duration>) {
     };
 }
 
+/// Resolves at `deadline`, or never if `deadline` is `None`.
+async fn timeout_at(deadline: Option<tokio::time::Instant>) {
+    if let Some(deadline) = deadline {
+        tokio::time::delay_until(deadline).await
+    } else {
+        futures::future::pending().await
+    };
+}
+
 pub async fn run_stream<H: Handler, R>(
+    config: Arc<Config>,
+    stream: R,
+    handler: H,
+) -> Result<(), anyhow::Error>
+where
+    R: AsyncRead + AsyncWrite + Tcp + Unpin,
+{
+    let mut stream = stream;
+    let mut peer_addr = stream.peer_addr();
+    if config.expect_proxy_protocol {
+        if let Some(addr) = read_proxy_header(&config, &mut stream).await? {
+            peer_addr = Some(addr)
+        }
+    }
+    run_stream_until(config, stream, handler, None, peer_addr).await
+}
+
+/// Read the PROXY protocol header at the beginning of `stream`,
+/// within the connection timeout.
+async fn read_proxy_header<R: AsyncRead + Unpin>(
+    config: &Config,
+    stream: &mut R,
+) -> Result<Option<std::net::SocketAddr>, anyhow::Error> {
+    if let Some(t) = config.connection_timeout {
+        tokio::time::timeout(t, proxy_protocol::read_header(stream)).await?
+    } else {
+        proxy_protocol::read_header(stream).await
+    }
+}
+
+/// Send the queued buffers in order with `send`, which returns how
+/// many bytes it could send, stopping at the first one that isn't
+/// sent entirely. Buffers are sent from borrowed slices, keeping
+/// track of the offset of what was already sent instead of copying
+/// the rest.
+fn send_queued<T, F: FnMut(&T, &[u8]) -> usize>(
+    queue: &mut VecDeque<(T, CryptoVec, usize)>,
+    mut send: F,
+) {
+    while let Some((id, data, offset)) = queue.front_mut() {
+        *offset += send(id, &data[*offset..]);
+
+        if *offset == data.len() {
+            queue.pop_front();
+        } else {
+            break;
+        }
+    }
+}
+
+/// Resolves when a shutdown of the server is requested.
+async fn shutdown_requested(shutdown: &mut Option<tokio::sync::broadcast::Receiver<()>>) {
+    if let Some(ref mut shutdown) = shutdown {
+        if shutdown.recv().await.is_ok() {
+            return;
+        }
+    }
+    futures::future::pending().await
+}
+
+async fn run_stream_until<H: Handler, R>(
     config: Arc<Config>,
     mut stream: R,
     handler: H,
+    mut shutdown: Option<tokio::sync::broadcast::Receiver<()>>,
+    peer_addr: Option<std::net::SocketAddr>,
 ) -> Result<(), anyhow::Error>
 where
     R: AsyncRead + AsyncWrite + Tcp + Unpin,
 {
     let mut handler = Some(handler);
     let delay = config.connection_timeout;
+    let idle = config.idle_timeout;
+    let keepalive = config.keepalive_interval;
+    let keepalive_max = config.keepalive_max;
+    let cover = config.cover_traffic_interval;
+    let send_timeout = config.send_timeout;
+    let mut handshake_deadline = config
+        .handshake_timeout
+        .map(|t| tokio::time::Instant::now() + t);
     // Writing SSH id.
     let mut write_buffer = SSHBuffer::new();
     write_buffer.send_ssh_id(config.as_ref().server_id.as_bytes());
-    stream.write_all(&write_buffer.buffer[..]).await?;
+    stream
+        .write_all(&write_buffer.buffer[..])
+        .await
+        .map_err(|e| handshake_error(e.into()))?;
 
     // Reading SSH id and allocating a session.
     let mut stream = SshRead::new(&mut stream);
-    let common = read_ssh_id(config, &mut stream).await?;
+    let mut common = read_ssh_id(config.clone(), &mut stream)
+        .await
+        .map_err(handshake_error)?;
+    common.peer_addr = peer_addr;
+    common.local_addr = stream.local_addr();
     let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let global_request_tokens = common.config.max_global_requests_per_minute.unwrap_or(0) as f64;
     let mut session = Session {
         common,
         receiver,
         sender: server::session::Handle { sender },
+        bound_port: None,
+        banner_user: None,
+        channel_open_failure: None,
+        global_request_tokens: (global_request_tokens, tokio::time::Instant::now()),
     };
     session.flush()?;
     stream
         .write_all(&session.common.write_buffer.buffer)
-        .await?;
+        .await
+        .map_err(|e| handshake_error(e.into()))?;
+    session.common.bytes_written += session.common.write_buffer.buffer.len() as u64;
     session.common.write_buffer.buffer.clear();
     let mut buffer = SSHBuffer::new();
 
-    let mut data_queue = vec![];
-    let mut extended_data_queue = vec![];
+    let mut data_queue = VecDeque::new();
+    let mut extended_data_queue = VecDeque::new();
+    let mut idle_deadline = idle.map(|t| tokio::time::Instant::now() + t);
+    let mut keepalive_deadline = keepalive.map(|t| tokio::time::Instant::now() + t);
+    let mut cover_deadline = cover.map(|t| tokio::time::Instant::now() + t);
 
     while !session.common.disconnected {
+        let max_len = session.common.max_read_len(
+            session.common.config.max_handshake_packet_size,
+            session.common.config.maximum_packet_size,
+        );
+        let mut cover_sent = false;
         tokio::select! {
-            _ = cipher::read(&mut stream, &mut buffer, &session.common.cipher) => {
-                if buffer.buffer.len() < 5 || buffer.buffer[5] == crate::msg::DISCONNECT {
+            r = cipher::read(&mut stream, &mut buffer, &session.common.cipher, max_len) => {
+                if let Err(e) = r {
+                    if session.common.encrypted.is_none() {
+                        return Err(handshake_error(e));
+                    } else if !peer_closed(&e) {
+                        // Invalid or oversized packets.
+                        return Err(e);
+                    }
+                    debug!("read error: {:?}", e);
+                    break;
+                }
+                session.common.bytes_read = buffer.bytes as u64;
+                session.common.received_seqn = buffer.seqn.0.wrapping_sub(1);
+                idle_deadline = idle.map(|t| tokio::time::Instant::now() + t);
+                keepalive_deadline = keepalive.map(|t| tokio::time::Instant::now() + t);
+                cover_deadline = cover.map(|t| tokio::time::Instant::now() + t);
+                if buffer.buffer.len() < 5 {
+                    debug!("break");
+                    break;
+                } else if buffer.buffer.get(5) == Some(&crate::msg::DISCONNECT) {
                     debug!("break");
+                    session = read_disconnect(session, &mut handler, &buffer.buffer[5..]).await?;
                     break;
-                } else if buffer.buffer[5] <= 4 {
+                } else if buffer.buffer.len() > 5 && session.common.strict_kex_forbids(buffer.buffer[5]) {
+                    return Err(Error::StrictKex.into());
+                } else if buffer.buffer.get(5) == Some(&crate::msg::DEBUG) {
+                    session = read_debug(session, &mut handler, &buffer.buffer[5..]).await?;
+                } else if buffer.buffer.get(5) == Some(&crate::msg::IGNORE) {
+                    session = read_ignore(session, &mut handler, &buffer.buffer[5..]).await?;
+                } else if buffer.buffer.get(5) == Some(&crate::msg::UNIMPLEMENTED) {
+                    session = read_unimplemented(session, &mut handler, &buffer.buffer[5..]).await?;
+                } else if buffer.buffer.len() > 5 && buffer.buffer[5] <= 4 {
                     continue;
+                } else {
+                    debug!("buffer = {:?}", &buffer.buffer[..]);
+                    session = reply(session, &mut handler, &buffer.buffer[5..]).await?;
+                    if buffer.buffer[5] == crate::msg::NEWKEYS && session.common.strict_kex {
+                        buffer.seqn = std::num::Wrapping(0);
+                    }
+                    if let Some(EncryptedState::Authenticated) =
+                        session.common.encrypted.as_ref().map(|enc| &enc.state)
+                    {
+                        handshake_deadline = None
+                    }
                 }
-                debug!("buffer = {:?}", &buffer.buffer[..]);
-                session = reply(session, &mut handler, &buffer.buffer[5..]).await?;
             }
             _ = timeout(delay) => {
                 debug!("timeout");
                 break
             },
+            _ = timeout_at(idle_deadline) => {
+                debug!("idle timeout");
+                break
+            },
+            _ = timeout_at(keepalive_deadline) => {
+                keepalive_deadline = keepalive.map(|t| tokio::time::Instant::now() + t);
+                if session.common.alive_timeouts >= keepalive_max {
+                    debug!("keepalive timeout");
+                    session.common.disconnect(
+                        Disconnect::ByApplication,
+                        "Keepalive timeout",
+                        &config.language_tag,
+                    );
+                } else {
+                    session.keepalive_request();
+                }
+            },
+            _ = timeout_at(cover_deadline) => {
+                cover_deadline = cover.map(|t| tokio::time::Instant::now() + t);
+                let mut len = [0; 1];
+                openssl::rand::rand_bytes(&mut len)?;
+                let max = session.common.config.maximum_packet_size as usize;
+                session.send_ignore(std::cmp::min(len[0] as usize, max));
+                cover_sent = true;
+            },
+            _ = timeout_at(handshake_deadline) => {
+                debug!("handshake timeout");
+                session.common.disconnect(
+                    Disconnect::ByApplication,
+                    "Authentication timeout",
+                    &config.language_tag,
+                );
+            },
+            _ = shutdown_requested(&mut shutdown) => {
+                debug!("shutdown");
+                session.common.disconnect(
+                    Disconnect::ByApplication,
+                    "Server shutdown",
+                    &config.language_tag,
+                );
+            },
             msg = session.receiver.recv() => {
                 match msg {
                     Some((id, ChannelMsg::Data { data })) => {
-                        data_queue.push((id, data));
+                        data_queue.push_back((id, data, 0));
                     }
                     Some((id, ChannelMsg::ExtendedData { ext, data })) => {
-                        extended_data_queue.push((id, ext, data));
+                        extended_data_queue.push_back(((id, ext), data, 0));
                     }
                     Some((id, ChannelMsg::Eof)) => {
                         session.eof(id);
@@ -503,33 +1340,46 @@ where
             }
         }
 
-        while let Some((id, data)) = data_queue.first() {
-            let wrote = session.data(*id, data);
+        send_queued(&mut data_queue, |id, data| session.data(*id, data));
+        send_queued(&mut extended_data_queue, |(id, ext), data| {
+            session.extended_data(*id, *ext, data)
+        });
 
-            if wrote == data.len() {
-                data_queue.remove(0);
-            } else {
-                data_queue[0] = (*id, CryptoVec::from_slice(&data[wrote..]));
-                break;
+        session.flush()?;
+        debug!("writing {:?}", &session.common.write_buffer.buffer[..]);
+        if !session.common.write_buffer.buffer.is_empty() {
+            if !cover_sent {
+                idle_deadline = idle.map(|t| tokio::time::Instant::now() + t);
             }
+            cover_deadline = cover.map(|t| tokio::time::Instant::now() + t);
         }
-
-        while let Some((id, ext, data)) = extended_data_queue.first() {
-            let wrote = session.extended_data(*id, *ext, data);
-
-            if wrote == data.len() {
-                extended_data_queue.remove(0);
-            } else {
-                extended_data_queue[0] = (*id, *ext, CryptoVec::from_slice(&data[wrote..]));
-                break;
+        let write = stream.write_all(&session.common.write_buffer.buffer);
+        let written = if let Some(t) = send_timeout {
+            tokio::time::timeout(t, write)
+                .await
+                .map_err(|_| Error::SendTimeout)
+        } else {
+            Ok(write.await)
+        };
+        let lost = match written {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => {
+                debug!("write error: {:?}", e);
+                Some((Error::PeerWriteClosed, "Connection closed by peer"))
             }
+            Err(e) => {
+                debug!("send timeout");
+                Some((e, "Send timeout"))
+            }
+        };
+        if let Some((error, description)) = lost {
+            // The client is gone or stopped reading, but the handler
+            // still needs to clean up.
+            let h = handler.take().unwrap();
+            h.disconnected(Disconnect::ConnectionLost, description, "", session).await?;
+            return Err(error.into());
         }
-
-        session.flush()?;
-        debug!("writing {:?}", &session.common.write_buffer.buffer[..]);
-        stream
-            .write_all(&session.common.write_buffer.buffer)
-            .await?;
+        session.common.bytes_written += session.common.write_buffer.buffer.len() as u64;
         buffer.buffer.clear();
         session.common.write_buffer.buffer.clear();
     }
@@ -537,17 +1387,49 @@ where
     // Shutdown
     stream.tcp_shutdown()?;
     buffer.buffer.clear();
-    while cipher::read(&mut stream, &mut buffer, &session.common.cipher).await? != 0 {
+    let max_len = session.common.max_read_len(
+        session.common.config.max_handshake_packet_size,
+        session.common.config.maximum_packet_size,
+    );
+    while cipher::read(&mut stream, &mut buffer, &session.common.cipher, max_len).await? != 0 {
         buffer.buffer.clear();
     }
     Ok(())
 }
 
+/// Classify the errors caused by the client closing the connection
+/// before the end of the key exchange (which port scanners do all the
+/// time) as `Error::PeerClosedDuringHandshake`.
+fn handshake_error(e: anyhow::Error) -> anyhow::Error {
+    if let Some(Error::Disconnect) = e.downcast_ref::<Error>() {
+        Error::PeerClosedDuringHandshake.into()
+    } else if peer_closed(&e) {
+        Error::PeerClosedDuringHandshake.into()
+    } else {
+        e
+    }
+}
+
+/// Whether `e` is an IO error caused by the client closing the
+/// connection.
+fn peer_closed(e: &anyhow::Error) -> bool {
+    if let Some(io) = e.downcast_ref::<std::io::Error>() {
+        match io.kind() {
+            std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
 async fn read_ssh_id<R: AsyncRead + Unpin>(
     config: Arc<Config>,
     read: &mut SshRead<R>,
 ) -> Result<CommonSession<Arc<Config>>, anyhow::Error> {
-    let sshid = if let Some(t) = config.connection_timeout {
+    let sshid = if let Some(t) = config.handshake_timeout.or(config.connection_timeout) {
         tokio::time::timeout(t, read.read_ssh_id()).await??
     } else {
         read.read_ssh_id().await?
@@ -567,6 +1449,7 @@ async fn read_ssh_id<R: AsyncRead + Unpin>(
     let cipher = Arc::new(cipher::CLEAR_PAIR);
     let mut write_buffer = SSHBuffer::new();
     kexinit.server_write(config.as_ref(), cipher.as_ref(), &mut write_buffer)?;
+    let limits = config.limits.clone();
     Ok(CommonSession {
         write_buffer,
         kex: Some(Kex::KexInit(kexinit)),
@@ -578,14 +1461,100 @@ async fn read_ssh_id<R: AsyncRead + Unpin>(
         wants_reply: false,
         disconnected: false,
         buffer: CryptoVec::new(),
+        peer_addr: None,
+        local_addr: None,
+        alive_timeouts: 0,
+        received_seqn: 0,
+        bytes_read: 0,
+        bytes_written: 0,
+        connected_at: std::time::Instant::now(),
+        limits,
+        strict_kex: false,
     })
 }
 
+/// Parse a disconnect message from the client, and pass it to the
+/// handler.
+async fn read_disconnect<H: Handler>(
+    mut session: Session,
+    handler: &mut Option<H>,
+    buf: &[u8],
+) -> Result<Session, anyhow::Error> {
+    let mut r = buf.reader(1);
+    let reason = r.read_u32()?;
+    let description = String::from_utf8_lossy(r.read_string()?);
+    let language = String::from_utf8_lossy(r.read_string()?);
+    debug!("disconnected: {:?} {:?}", reason, description);
+    if let Some(ref mut enc) = session.common.encrypted {
+        enc.capture_language_tag(session.common.config.capture_language_tags, language.as_bytes());
+    }
+    // Unknown reason codes are reported as application disconnections.
+    let reason = Disconnect::from_u32(reason).unwrap_or(Disconnect::ByApplication);
+    let h = handler.take().unwrap();
+    let (h, session) = h.disconnected(reason, &description, &language, session).await?;
+    *handler = Some(h);
+    Ok(session)
+}
+
+/// Parse a debug message from the client, and pass it to the
+/// handler.
+async fn read_debug<H: Handler>(
+    mut session: Session,
+    handler: &mut Option<H>,
+    buf: &[u8],
+) -> Result<Session, anyhow::Error> {
+    let mut r = buf.reader(1);
+    let always_display = r.read_byte()? != 0;
+    let message = String::from_utf8_lossy(r.read_string()?);
+    let language = String::from_utf8_lossy(r.read_string()?);
+    if let Some(ref mut enc) = session.common.encrypted {
+        enc.capture_language_tag(
+            session.common.config.capture_language_tags,
+            language.as_bytes(),
+        );
+    }
+    let h = handler.take().unwrap();
+    let (h, session) = h.debug(always_display, &message, &language, session).await?;
+    *handler = Some(h);
+    Ok(session)
+}
+
+/// Pass the data of an ignore message from the client to the handler.
+/// A missing data string is read as empty.
+async fn read_ignore<H: Handler>(
+    session: Session,
+    handler: &mut Option<H>,
+    buf: &[u8],
+) -> Result<Session, anyhow::Error> {
+    let data = buf.reader(1).read_string().unwrap_or(&[]);
+    let h = handler.take().unwrap();
+    let (h, session) = h.ignore(data, session).await?;
+    *handler = Some(h);
+    Ok(session)
+}
+
+/// Pass the sequence number of an unimplemented message from the
+/// client to the handler.
+async fn read_unimplemented<H: Handler>(
+    session: Session,
+    handler: &mut Option<H>,
+    buf: &[u8],
+) -> Result<Session, anyhow::Error> {
+    let seqn = buf.reader(1).read_u32()?;
+    let h = handler.take().unwrap();
+    let (h, session) = h.unimplemented(seqn, session).await?;
+    *handler = Some(h);
+    Ok(session)
+}
+
 async fn reply<H: Handler>(
     mut session: Session,
     handler: &mut Option<H>,
     buf: &[u8],
 ) -> Result<Session, anyhow::Error> {
+    if buf.is_empty() {
+        return Err(Error::IndexOutOfBounds.into());
+    }
     // Handle key exchange/re-exchange.
     debug!("kex = {:?}", session.common.kex);
     match session.common.kex.take() {
@@ -594,25 +1563,34 @@ async fn reply<H: Handler>(
                 || buf[0] == msg::KEXINIT
                 || session.common.encrypted.is_none()
             {
-                session.common.kex = Some(kexinit.server_parse(
+                let kex = kexinit.server_parse(
                     session.common.config.as_ref(),
                     &session.common.cipher,
                     &buf,
                     &mut session.common.write_buffer,
-                )?);
+                )?;
+                if let Kex::KexDh(ref kexdh) = kex {
+                    session.common.start_strict_kex(&kexdh.names)?;
+                }
+                session.common.kex = Some(kex);
                 return Ok(session);
             }
             // Else, i.e. if the other side has not started
             // the key exchange, process its packets by simple
             // not returning.
+            session.common.kex = Some(Kex::KexInit(kexinit));
         }
         Some(Kex::KexDh(kexdh)) => {
-            session.common.kex = Some(kexdh.parse(
+            let kex = kexdh.parse(
                 session.common.config.as_ref(),
                 &session.common.cipher,
                 buf,
                 &mut session.common.write_buffer,
-            )?);
+            )?;
+            if let Kex::NewKeys(_) = kex {
+                session.common.newkeys_sent();
+            }
+            session.common.kex = Some(kex);
             return Ok(session);
         }
         Some(Kex::NewKeys(newkeys)) => {
@@ -620,10 +1598,40 @@ async fn reply<H: Handler>(
                 return Err(Error::Kex.into());
             }
             // Ok, NEWKEYS received, now encrypted.
+            let rekey = session.common.encrypted.is_some();
+            let send_ext_info = newkeys.names.ext_info && !rekey;
             session.common.encrypted(
                 EncryptedState::WaitingServiceRequest { accepted: false },
                 newkeys,
             );
+            // EXT_INFO is the first packet after our first NEWKEYS.
+            if send_ext_info {
+                let config = &session.common.config;
+                let mut extensions = config.extensions.clone();
+                if !extensions
+                    .iter()
+                    .any(|(name, _)| name == negotiation::SERVER_SIG_ALGS)
+                {
+                    extensions.push((
+                        negotiation::SERVER_SIG_ALGS.to_string(),
+                        negotiation::server_sig_algs(&config.preferred),
+                    ))
+                }
+                let mut ext_info = CryptoVec::new();
+                negotiation::write_ext_info(&extensions, &mut ext_info);
+                session
+                    .common
+                    .cipher
+                    .write(&ext_info, &mut session.common.write_buffer);
+            }
+            if rekey {
+                if let Some(algorithms) = session.common.algorithms() {
+                    let h = handler.take().unwrap();
+                    let (h, s) = h.rekey_completed(&algorithms, session).await?;
+                    *handler = Some(h);
+                    session = s;
+                }
+            }
             return Ok(session);
         }
         Some(kex) => {
@@ -639,12 +1647,7 @@ async fn reply<H: Handler>(
         if let Some(ref mut enc) = session.common.encrypted {
             // If we're not currently rekeying, but buf is a rekey request
             if let Some(exchange) = enc.exchange.take() {
-                let pref = &session.common.config.as_ref().preferred;
-                let kexinit = KexInit::received_rekey(
-                    exchange,
-                    negotiation::Server::read_kex(buf, pref)?,
-                    &enc.session_id,
-                );
+                let kexinit = KexInit::received_rekey(exchange, &enc.session_id);
                 session.common.kex = Some(kexinit.server_parse(
                     session.common.config.as_ref(),
                     &mut session.common.cipher,
@@ -658,3 +1661,1078 @@ async fn reply<H: Handler>(
     // No kex going on, and the version id is done.
     Ok(session.server_read_encrypted(handler, buf).await?)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client;
+    use crate::test_util;
+    use byteorder::{BigEndian, ByteOrder};
+    use thrussh_keys::encoding::Encoding;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    /// Send our SSH id followed by `packets` to a server, and return
+    /// the outcome of the connection on the server side.
+    async fn send_raw(packets: &[u8]) -> Result<(), anyhow::Error> {
+        let (server, mut stream) =
+            test_util::accept(test_util::server_config(), test_util::Server).await;
+        stream.write_all(b"SSH-2.0-test\r\n").await?;
+        stream.write_all(packets).await?;
+        // Only close our side, so that the server still reads
+        // everything we sent.
+        stream.shutdown(std::net::Shutdown::Write)?;
+        server.await.expect("the server panicked")
+    }
+
+    /// The error returned by the server, if it is one of ours.
+    fn error(result: &Result<(), anyhow::Error>) -> Option<&Error> {
+        result.as_ref().err()?.downcast_ref()
+    }
+
+    /// A client KEXINIT listing `kex`, and announcing a guessed key
+    /// exchange packet if `follows` is set.
+    fn kexinit(kex: &str, follows: bool) -> CryptoVec {
+        let mut kexinit = CryptoVec::new();
+        kexinit.push(msg::KEXINIT);
+        kexinit.extend(&[0; 16]);
+        for list in &[
+            kex,
+            "ssh-ed25519",
+            "chacha20-poly1305@openssh.com",
+            "chacha20-poly1305@openssh.com",
+            "none",
+            "none",
+            "none",
+            "none",
+            "",
+            "",
+        ] {
+            kexinit.extend_ssh_string(list.as_bytes());
+        }
+        kexinit.push(if follows { 1 } else { 0 });
+        kexinit.push_u32_be(0);
+        kexinit
+    }
+
+    /// Start a key exchange with a KEXINIT listing `kex`, immediately
+    /// followed by an `SSH_MSG_IGNORE`.
+    async fn ignore_during_kex(kex: &str) -> Result<(), anyhow::Error> {
+        let mut buffer = SSHBuffer::new();
+        cipher::CLEAR_PAIR.write(&kexinit(kex, false), &mut buffer);
+        cipher::CLEAR_PAIR.write(&[msg::IGNORE, 0, 0, 0, 0], &mut buffer);
+        send_raw(&buffer.buffer).await
+    }
+
+    #[tokio::test]
+    async fn strict_kex_rejects_ignore() {
+        let result =
+            ignore_during_kex("curve25519-sha256@libssh.org,kex-strict-c-v00@openssh.com").await;
+        assert!(
+            matches!(error(&result), Some(Error::StrictKex)),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn ignore_allowed_without_strict_kex() {
+        let result = ignore_during_kex("curve25519-sha256@libssh.org").await;
+        assert!(
+            !matches!(error(&result), Some(Error::StrictKex)),
+            "{:?}",
+            result
+        );
+    }
+
+    /// Start a key exchange with a KEXINIT listing `kex` and
+    /// announcing a guessed key exchange packet, followed by `inits`
+    /// `SSH_MSG_KEX_ECDH_INIT`. Return the types of the messages the
+    /// server sent in clear.
+    async fn guessed_kex(kex: &str, inits: usize) -> Vec<u8> {
+        use tokio::io::AsyncReadExt;
+        let mut buffer = SSHBuffer::new();
+        cipher::CLEAR_PAIR.write(&kexinit(kex, true), &mut buffer);
+        let mut init = CryptoVec::new();
+        init.push(msg::KEX_ECDH_INIT);
+        init.extend_ssh_string(&[9; 32]);
+        for _ in 0..inits {
+            cipher::CLEAR_PAIR.write(&init, &mut buffer);
+        }
+        let (server, mut stream) =
+            test_util::accept(test_util::server_config(), test_util::Server).await;
+        stream.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+        stream.write_all(&buffer.buffer).await.unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap_or(0);
+        server.await.expect("the server panicked").unwrap_or(());
+        // Skip the server's id, then read the packets.
+        let id_len = output.iter().position(|&c| c == b'\n').unwrap() + 1;
+        let mut packets = &output[id_len..];
+        let mut types = Vec::new();
+        while packets.len() > 5 {
+            let len = BigEndian::read_u32(packets) as usize;
+            types.push(packets[5]);
+            packets = &packets[4 + len..];
+        }
+        types
+    }
+
+    /// A packet following a wrong guess is ignored, and the key
+    /// exchange goes on with the next one. A right guess is used.
+    #[tokio::test]
+    async fn first_kex_packet_follows() {
+        let wrong = "unknown@example.com,curve25519-sha256@libssh.org";
+        assert_eq!(guessed_kex(wrong, 1).await, vec![msg::KEXINIT]);
+        let reply = vec![msg::KEXINIT, msg::KEX_ECDH_REPLY, msg::NEWKEYS];
+        assert_eq!(guessed_kex(wrong, 2).await, reply);
+        let right = "curve25519-sha256@libssh.org";
+        assert_eq!(guessed_kex(right, 1).await, reply);
+    }
+
+    #[tokio::test]
+    async fn short_packets() {
+        // Packet length 0, without even a padding length.
+        let result = send_raw(&[0, 0, 0, 0]).await;
+        assert!(
+            matches!(error(&result), Some(Error::IndexOutOfBounds)),
+            "{:?}",
+            result
+        );
+        // Packet length 1, with an empty payload.
+        let result = send_raw(&[0, 0, 0, 1, 0]).await;
+        assert!(
+            matches!(error(&result), Some(Error::IndexOutOfBounds)),
+            "{:?}",
+            result
+        );
+        // Packet length 1, and a padding length eating the packet
+        // length itself (4 bytes left): the server hangs up.
+        let result = send_raw(&[0, 0, 0, 1, 1]).await;
+        assert!(
+            !matches!(error(&result), Some(Error::IndexOutOfBounds)),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_handshake_packet() {
+        // A packet length of 4 GiB, without the packet: the server
+        // gives up instead of waiting for it.
+        let result = send_raw(&[0xff, 0xff, 0xff, 0xff]).await;
+        assert!(
+            matches!(error(&result), Some(Error::PacketTooLarge)),
+            "{:?}",
+            result
+        );
+    }
+
+    /// A client speaking the protocol by hand over a connection to a
+    /// server, after the key exchange.
+    struct RawClient {
+        stream: tokio::net::TcpStream,
+        cipher: cipher::CipherPair,
+        write: SSHBuffer,
+        read: SSHBuffer,
+    }
+
+    impl RawClient {
+        /// Exchange keys with a connection of `handler`, and log in
+        /// with `PASSWORD`. Return the task running the server along
+        /// with the client.
+        async fn authenticated<H: Handler + Send + 'static>(
+            handler: H,
+        ) -> (tokio::task::JoinHandle<Result<(), anyhow::Error>>, Self) {
+            use tokio::io::AsyncReadExt;
+            let (server, mut stream) = test_util::accept(test_util::server_config(), handler).await;
+            stream.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+            let mut server_id = Vec::new();
+            while !server_id.ends_with(b"\r\n") {
+                server_id.push(stream.read_u8().await.unwrap());
+            }
+            let mut exchange = Exchange::new();
+            exchange.client_id.extend(b"SSH-2.0-test");
+            exchange.server_id.extend(&server_id[..server_id.len() - 2]);
+            let mut client = RawClient {
+                stream,
+                cipher: cipher::CLEAR_PAIR,
+                write: SSHBuffer::new(),
+                read: SSHBuffer::new(),
+            };
+
+            // Key exchange, without checking the server's signature.
+            exchange
+                .client_kex_init
+                .extend(&kexinit("curve25519-sha256@libssh.org", false));
+            let mut init = CryptoVec::new();
+            let mut kex = crate::kex::Algorithm::client_dh(
+                crate::kex::CURVE25519,
+                &mut exchange.client_ephemeral,
+                &mut init,
+            )
+            .unwrap();
+            client.send(&exchange.client_kex_init).await;
+            client.send(&init).await;
+            exchange.server_kex_init.extend(&client.recv().await);
+            let reply = client.recv().await;
+            assert_eq!(reply[0], msg::KEX_ECDH_REPLY);
+            let mut r = reply.reader(1);
+            let host_key = key::parse_public_key(r.read_string().unwrap()).unwrap();
+            exchange.server_ephemeral.extend(r.read_string().unwrap());
+            kex.compute_shared_secret(&exchange.server_ephemeral).unwrap();
+            let hash = kex
+                .compute_exchange_hash(&host_key, &exchange, &mut CryptoVec::new())
+                .unwrap();
+            assert_eq!(client.recv().await[0], msg::NEWKEYS);
+            client.send(&[msg::NEWKEYS]).await;
+            client.cipher = kex
+                .compute_keys(&hash, &hash, cipher::chacha20poly1305::NAME, false)
+                .unwrap();
+
+            let mut request = CryptoVec::new();
+            request.push(msg::SERVICE_REQUEST);
+            request.extend_ssh_string(b"ssh-userauth");
+            client.send(&request).await;
+            assert_eq!(client.recv().await[0], msg::SERVICE_ACCEPT);
+            request.clear();
+            request.push(msg::USERAUTH_REQUEST);
+            request.extend_ssh_string(b"user");
+            request.extend_ssh_string(b"ssh-connection");
+            request.extend_ssh_string(b"password");
+            request.push(0);
+            request.extend_ssh_string(test_util::PASSWORD.as_bytes());
+            client.send(&request).await;
+            assert_eq!(client.recv().await[0], msg::USERAUTH_SUCCESS);
+            (server, client)
+        }
+
+        /// Send a packet carrying `payload`.
+        async fn send(&mut self, payload: &[u8]) {
+            self.cipher.write(payload, &mut self.write);
+            self.stream.write_all(&self.write.buffer).await.unwrap();
+            self.write.buffer.clear();
+        }
+
+        /// Read the payload of the next packet, skipping the server's
+        /// global requests (such as its host keys announcement).
+        async fn recv(&mut self) -> CryptoVec {
+            loop {
+                cipher::read(&mut self.stream, &mut self.read, &self.cipher, 1 << 20)
+                    .await
+                    .unwrap();
+                if self.read.buffer[5] != msg::GLOBAL_REQUEST {
+                    break;
+                }
+            }
+            let mut payload = CryptoVec::new();
+            payload.extend(&self.read.buffer[5..]);
+            payload
+        }
+    }
+
+    /// A server forwarding a channel to the client as soon as it asks
+    /// for a port to be forwarded.
+    struct Forwarding;
+
+    impl Handler for Forwarding {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(Auth::Accept)
+        }
+
+        fn tcpip_forward(self, address: &str, _: u32, mut session: Session) -> Self::FutureBool {
+            session
+                .channel_open_forwarded_tcpip(address, 2222, "10.0.0.1", 4321)
+                .unwrap();
+            self.finished_bool(true, session)
+        }
+    }
+
+    /// Requests on channels the client has not confirmed yet are read
+    /// without a reply.
+    #[tokio::test]
+    async fn request_on_unconfirmed_channel() {
+        let (server, mut client) = RawClient::authenticated(Forwarding).await;
+        let mut request = CryptoVec::new();
+        request.push(msg::GLOBAL_REQUEST);
+        request.extend_ssh_string(b"tcpip-forward");
+        request.push(0);
+        request.extend_ssh_string(b"127.0.0.1");
+        request.push_u32_be(0);
+        client.send(&request).await;
+        let open = client.recv().await;
+        assert_eq!(open[0], msg::CHANNEL_OPEN);
+        let mut r = open.reader(1);
+        assert_eq!(r.read_string().unwrap(), b"forwarded-tcpip");
+        let channel = r.read_u32().unwrap();
+
+        // A request on that channel instead of a confirmation.
+        request.clear();
+        request.push(msg::CHANNEL_REQUEST);
+        request.push_u32_be(channel);
+        request.extend_ssh_string(b"env");
+        request.push(1);
+        request.extend_ssh_string(b"LANG");
+        request.extend_ssh_string(b"C");
+        client.send(&request).await;
+        // The reply to this global request comes right after the
+        // reply to the channel request, if any.
+        request.clear();
+        request.push(msg::GLOBAL_REQUEST);
+        request.extend_ssh_string(b"keepalive@openssh.com");
+        request.push(1);
+        client.send(&request).await;
+        assert_eq!(client.recv().await[0], msg::REQUEST_FAILURE);
+
+        // The server did not panic.
+        drop(client);
+        assert!(server.await.is_ok());
+    }
+
+    /// A server starting a key re-exchange on the `rekey` command,
+    /// and reporting the completed re-exchanges.
+    struct Rekeying(UnboundedSender<&'static str>);
+
+    impl Handler for Rekeying {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            command: &[u8],
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            if command == b"rekey" {
+                session.request_rekey()
+            }
+            session.exit_status_request(channel, 0);
+            session.close(channel);
+            self.finished(session)
+        }
+
+        fn rekey_completed(
+            self,
+            algorithms: &NegotiatedAlgorithms,
+            session: Session,
+        ) -> Self::FutureUnit {
+            self.0.send(algorithms.kex).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A client reporting the completed key re-exchanges.
+    struct RekeyReporter(UnboundedSender<&'static str>);
+
+    impl client::Handler for RekeyReporter {
+        client_futures!();
+
+        fn rekey_completed(
+            self,
+            algorithms: &NegotiatedAlgorithms,
+            session: client::Session,
+        ) -> Self::FutureUnit {
+            self.0.send(algorithms.kex).unwrap();
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn rekey_completed() {
+        let (server_sender, mut server_rekeys) = unbounded_channel();
+        let (client_sender, mut client_rekeys) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            Rekeying(server_sender),
+            client::Config::default(),
+            RekeyReporter(client_sender),
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        // Nothing is reported after the initial key exchange.
+        assert!(server_rekeys.try_recv().is_err());
+        assert!(client_rekeys.try_recv().is_err());
+        for command in &["rekey", "true"] {
+            let mut channel = client.channel_open_session().await.unwrap();
+            channel.exec(false, *command).await.unwrap();
+            while channel.wait().await.is_some() {}
+        }
+        let timeout = std::time::Duration::from_secs(5);
+        let server_kex = tokio::time::timeout(timeout, server_rekeys.recv())
+            .await
+            .unwrap();
+        let client_kex = tokio::time::timeout(timeout, client_rekeys.recv())
+            .await
+            .unwrap();
+        assert!(server_kex.is_some());
+        assert_eq!(server_kex, client_kex);
+        // Exactly once on each side.
+        assert!(server_rekeys.try_recv().is_err());
+        assert!(client_rekeys.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn rekey_after_auth() {
+        let mut config = test_util::server_config();
+        config.rekey_after_auth = true;
+        let (server_sender, mut server_rekeys) = unbounded_channel();
+        let (client_sender, mut client_rekeys) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            config,
+            Rekeying(server_sender),
+            client::Config::default(),
+            RekeyReporter(client_sender),
+        )
+        .await;
+        assert!(server_rekeys.try_recv().is_err());
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        // The key re-exchange happens without any further request.
+        let timeout = std::time::Duration::from_secs(5);
+        let server_kex = tokio::time::timeout(timeout, server_rekeys.recv())
+            .await
+            .unwrap();
+        let client_kex = tokio::time::timeout(timeout, client_rekeys.recv())
+            .await
+            .unwrap();
+        assert!(server_kex.is_some());
+        assert_eq!(server_kex, client_kex);
+        // The session goes on with the new keys.
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "true").await.unwrap();
+        while channel.wait().await.is_some() {}
+        assert!(server_rekeys.try_recv().is_err());
+        assert!(client_rekeys.try_recv().is_err());
+    }
+
+    /// Traffic keeps connections alive past `idle_timeout`, which
+    /// only closes them once the traffic stops.
+    #[tokio::test]
+    async fn idle_timeout() {
+        let idle = std::time::Duration::from_millis(300);
+        let mut config = test_util::server_config();
+        config.idle_timeout = Some(idle);
+        let (mut client, mut server) = test_util::authenticated(config).await;
+        let mut channel = client.channel_open_session().await.unwrap();
+        let start = tokio::time::Instant::now();
+        while start.elapsed() < idle * 4 {
+            channel.data(&b"busy"[..]).await.unwrap();
+            tokio::time::delay_for(idle / 3).await;
+            assert!(futures::poll!(&mut server).is_pending());
+        }
+        client.channel_open_session().await.unwrap();
+        let timeout = idle * 10;
+        // Idle, the connection is closed.
+        assert!(tokio::time::timeout(timeout, server).await.is_ok());
+        assert!(client.channel_open_session().await.is_err());
+    }
+
+    /// The key exchange algorithms of the last KEXINIT of the client.
+    fn client_kex_algorithms(session: &Session) -> Vec<String> {
+        let enc = session.common.encrypted.as_ref().unwrap();
+        let kexinit = &enc.exchange.as_ref().unwrap().client_kex_init;
+        // After the message number and the cookie.
+        let list = kexinit.reader(17).read_string().unwrap();
+        let list = std::str::from_utf8(list).unwrap();
+        list.split(',').map(|a| a.to_string()).collect()
+    }
+
+    /// A server starting a key re-exchange on the `rekey` command,
+    /// and reporting the key exchange algorithms of the client after
+    /// the first key exchange and after the re-exchange.
+    struct ClientKexInit(UnboundedSender<Vec<String>>);
+
+    impl Handler for ClientKexInit {
+        server_futures!();
+
+        fn auth_banner_for(self, _: &str, session: Session) -> Self::FutureUnit {
+            self.0.send(client_kex_algorithms(&session)).unwrap();
+            self.finished(session)
+        }
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            session.request_rekey();
+            session.close(channel);
+            self.finished(session)
+        }
+
+        fn rekey_completed(self, _: &NegotiatedAlgorithms, session: Session) -> Self::FutureUnit {
+            self.0.send(client_kex_algorithms(&session)).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// The client only offers extension negotiation and strict key
+    /// exchange in its first KEXINIT.
+    #[tokio::test]
+    async fn client_rekey_kexinit() {
+        let (sender, mut kexinits) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            ClientKexInit(sender),
+            client::Config::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let markers = [negotiation::EXT_INFO_C, negotiation::STRICT_KEX_C];
+        let first = kexinits.recv().await.unwrap();
+        assert!(markers.iter().all(|m| first.iter().any(|a| a == m)), "{:?}", first);
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "rekey").await.unwrap();
+        while channel.wait().await.is_some() {}
+        let timeout = std::time::Duration::from_secs(5);
+        let rekey = tokio::time::timeout(timeout, kexinits.recv()).await.unwrap().unwrap();
+        assert!(!rekey.is_empty());
+        assert!(markers.iter().all(|m| rekey.iter().all(|a| a != m)), "{:?}", rekey);
+    }
+
+    /// A server sending 4096 bytes on each command, after lowering
+    /// its write limit before key re-exchange to 1024 bytes on the
+    /// `limit` command, and reporting the completed re-exchanges.
+    struct Limited(UnboundedSender<&'static str>);
+
+    impl Handler for Limited {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            command: &[u8],
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            if command == b"limit" {
+                session.set_limits(Limits::new(
+                    1024,
+                    1 << 30,
+                    std::time::Duration::from_secs(3600),
+                ))
+            }
+            session.data(channel, &[0; 4096]);
+            session.exit_status_request(channel, 0);
+            session.close(channel);
+            self.finished(session)
+        }
+
+        fn rekey_completed(
+            self,
+            algorithms: &NegotiatedAlgorithms,
+            session: Session,
+        ) -> Self::FutureUnit {
+            self.0.send(algorithms.kex).unwrap();
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn set_limits() {
+        let (sender, mut rekeys) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            Limited(sender),
+            client::Config::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        for command in &["data", "limit"] {
+            let mut channel = client.channel_open_session().await.unwrap();
+            channel.exec(false, *command).await.unwrap();
+            let mut received = 0;
+            while let Some(msg) = channel.wait().await {
+                if let ChannelMsg::Data { data } = msg {
+                    received += data.len()
+                }
+            }
+            assert_eq!(received, 4096);
+            if *command == "data" {
+                // The default limits are far from reached.
+                assert!(rekeys.try_recv().is_err());
+            }
+        }
+        let timeout = std::time::Duration::from_secs(5);
+        let rekey = tokio::time::timeout(timeout, rekeys.recv()).await.unwrap();
+        assert!(rekey.is_some());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reuse_port() {
+        let mut config = Config {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let first = bind(&config, &"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind(&config, &addr);
+        assert!(second.is_ok());
+        config.reuse_port = false;
+        assert!(bind(&config, &addr).is_err());
+    }
+
+    /// Servers running `test_util::Server` on each connection.
+    struct Servers;
+
+    impl Server for Servers {
+        type Handler = test_util::Server;
+        fn new(&mut self, _: Option<std::net::SocketAddr>) -> test_util::Server {
+            test_util::Server
+        }
+    }
+
+    /// Connect to `addr` from `source`, returning the stream if the
+    /// server sends its SSH id.
+    #[cfg(target_os = "linux")]
+    async fn connect_from(
+        source: &str,
+        addr: std::net::SocketAddr,
+    ) -> Option<tokio::net::TcpStream> {
+        use socket2::{Domain, Socket, Type};
+        use tokio::io::AsyncReadExt;
+        let socket = Socket::new(Domain::ipv4(), Type::stream(), None).unwrap();
+        let source: std::net::SocketAddr = source.parse().unwrap();
+        socket.bind(&source.into()).unwrap();
+        socket.connect(&addr.into()).unwrap();
+        let mut stream = tokio::net::TcpStream::from_std(socket.into_tcp_stream()).unwrap();
+        let mut id = [0; 8];
+        match stream.read_exact(&mut id).await {
+            Ok(_) if &id == b"SSH-2.0-" => Some(stream),
+            _ => None,
+        }
+    }
+
+    /// Connections over the limit are closed, and other addresses are
+    /// not affected. This needs the whole 127.0.0.0/8 range on the
+    /// loopback interface.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn per_ip_connection_limit() {
+        let mut config = test_util::server_config();
+        config.per_ip_connection_limit = Some(1);
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_on_socket(Arc::new(config), listener, Servers));
+
+        let first = connect_from("127.0.0.1:0", addr).await;
+        assert!(first.is_some());
+        assert!(connect_from("127.0.0.1:0", addr).await.is_none());
+        // Other addresses are not affected.
+        assert!(connect_from("127.0.0.2:0", addr).await.is_some());
+
+        // Closed connections are not counted anymore.
+        drop(first);
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+        while connect_from("127.0.0.1:0", addr).await.is_none() {
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await
+        }
+    }
+
+    /// A client reporting disconnections.
+    struct DisconnectReporter(UnboundedSender<(Disconnect, String)>);
+
+    impl client::Handler for DisconnectReporter {
+        client_futures!();
+
+        fn disconnected(
+            self,
+            reason: Disconnect,
+            description: &str,
+            _: &str,
+            session: client::Session,
+        ) -> Self::FutureUnit {
+            self.0.send((reason, description.to_string())).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// A handler accepting all passwords, counting the live handlers.
+    struct Live(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Drop for Live {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    impl Handler for Live {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(Auth::Accept)
+        }
+    }
+
+    /// Servers running `Live` on each connection.
+    struct LiveServers(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Server for LiveServers {
+        type Handler = Live;
+        fn new(&mut self, _: Option<std::net::SocketAddr>) -> Live {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Live(self.0.clone())
+        }
+    }
+
+    /// Once the shutdown future resolves, the server stops accepting
+    /// connections, the connected clients are disconnected, and the
+    /// server returns after their connections are closed.
+    #[tokio::test]
+    async fn run_with_shutdown() {
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, shutdown_requested) = tokio::sync::oneshot::channel::<()>();
+        let config = Arc::new(test_util::server_config());
+        let live = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = tokio::spawn(run_on_socket_with_shutdown(
+            config,
+            listener,
+            LiveServers(live.clone()),
+            async move {
+                shutdown_requested.await.unwrap_or(());
+            },
+        ));
+
+        let (sender, mut disconnections) = unbounded_channel();
+        let config = Arc::new(client::Config::default());
+        let mut client = client::connect(config, addr, DisconnectReporter(sender))
+            .await
+            .unwrap();
+        let password = test_util::PASSWORD;
+        assert!(client.authenticate_password("user", password).await.unwrap());
+
+        shutdown.send(()).unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        let result = tokio::time::timeout(timeout, server).await.unwrap();
+        assert!(result.unwrap().is_ok());
+        assert_eq!(live.load(std::sync::atomic::Ordering::SeqCst), 0);
+        let disconnection = tokio::time::timeout(timeout, disconnections.recv())
+            .await
+            .unwrap();
+        let expected = (Disconnect::ByApplication, "Server shutdown".to_string());
+        assert_eq!(disconnection, Some(expected));
+        // No new connections are accepted.
+        assert!(tokio::net::TcpStream::connect(&addr).await.is_err());
+    }
+
+    /// Same as `run_with_shutdown`, on a Unix domain socket.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn run_on_unix_with_shutdown() {
+        let path = std::env::temp_dir().join(format!("thrussh-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).unwrap_or(());
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let (shutdown, shutdown_requested) = tokio::sync::oneshot::channel::<()>();
+        let config = Arc::new(test_util::server_config());
+        let live = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = tokio::spawn(super::run_on_unix_with_shutdown(
+            config,
+            listener,
+            LiveServers(live.clone()),
+            async move {
+                shutdown_requested.await.unwrap_or(());
+            },
+        ));
+
+        let (sender, mut disconnections) = unbounded_channel();
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let config = Arc::new(client::Config::default());
+        let mut client = client::connect_stream(config, stream, DisconnectReporter(sender))
+            .await
+            .unwrap();
+        assert!(client.authenticate_password("user", "").await.unwrap());
+
+        shutdown.send(()).unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        let result = tokio::time::timeout(timeout, server).await.unwrap();
+        assert!(result.unwrap().is_ok());
+        assert_eq!(live.load(std::sync::atomic::Ordering::SeqCst), 0);
+        let disconnection = tokio::time::timeout(timeout, disconnections.recv())
+            .await
+            .unwrap();
+        let expected = (Disconnect::ByApplication, "Server shutdown".to_string());
+        assert_eq!(disconnection, Some(expected));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Relay the bytes between `client` and `server`, dropping those
+    /// sent by the client once `blackhole` is set, as a dead client
+    /// behind a NAT would, and no longer reading from the server once
+    /// `stall` is set, as a client that stopped reading would.
+    async fn relay(
+        client: tokio::net::TcpStream,
+        server: tokio::net::TcpStream,
+        blackhole: Arc<std::sync::atomic::AtomicBool>,
+        stall: Arc<std::sync::atomic::AtomicBool>,
+    ) {
+        use std::sync::atomic::Ordering;
+        use tokio::io::AsyncReadExt;
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let (mut server_read, mut server_write) = tokio::io::split(server);
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            while let Ok(n) = server_read.read(&mut buf).await {
+                if n == 0 || client_write.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+                if stall.load(Ordering::SeqCst) {
+                    futures::future::pending::<()>().await
+                }
+            }
+        });
+        let mut buf = [0; 4096];
+        while let Ok(n) = client_read.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+            if !blackhole.load(Ordering::SeqCst) {
+                server_write.write_all(&buf[..n]).await.unwrap_or(());
+            }
+        }
+    }
+
+    /// Connect a client running `client` to a server running
+    /// `server` with `config` through `relay`, and log in with
+    /// `test_util::PASSWORD`. Return the client handle, the task
+    /// running the server, and the `blackhole` and `stall` flags of
+    /// the relay.
+    async fn relayed<S, C>(
+        config: Config,
+        server: S,
+        client: C,
+    ) -> (
+        client::Handle,
+        tokio::task::JoinHandle<Result<(), anyhow::Error>>,
+        Arc<std::sync::atomic::AtomicBool>,
+        Arc<std::sync::atomic::AtomicBool>,
+    )
+    where
+        S: Handler + Send + 'static,
+        C: client::Handler + Send + 'static,
+    {
+        let (server_task, server) = test_util::accept(config, server).await;
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let (relayed, _) = listener.accept().await.unwrap();
+        let blackhole = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let stall = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        tokio::spawn(relay(relayed, server, blackhole.clone(), stall.clone()));
+        let config = Arc::new(client::Config {
+            window_size: 1 << 30,
+            ..Default::default()
+        });
+        let mut client = client::connect_stream(config, stream, client).await.unwrap();
+        let password = test_util::PASSWORD;
+        assert!(client.authenticate_password("user", password).await.unwrap());
+        (client, server_task, blackhole, stall)
+    }
+
+    /// Clients answering keepalive requests stay connected; silent
+    /// ones are disconnected after `keepalive_max` missed replies.
+    #[tokio::test]
+    async fn keepalive_timeout() {
+        let interval = std::time::Duration::from_millis(100);
+        let mut config = test_util::server_config();
+        config.keepalive_interval = Some(interval);
+        config.keepalive_max = 3;
+        let (sender, mut disconnections) = unbounded_channel();
+        let client = DisconnectReporter(sender);
+        let (_client, _, blackhole, _) = relayed(config, test_util::Server, client).await;
+        // Keepalive requests are answered.
+        tokio::time::delay_for(interval * 10).await;
+        assert!(disconnections.try_recv().is_err());
+
+        blackhole.store(true, std::sync::atomic::Ordering::SeqCst);
+        let start = tokio::time::Instant::now();
+        let timeout = std::time::Duration::from_secs(5);
+        let disconnection = tokio::time::timeout(timeout, disconnections.recv())
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= interval * 3);
+        let expected = (Disconnect::ByApplication, "Keepalive timeout".to_string());
+        assert_eq!(disconnection, Some(expected));
+    }
+
+    /// Writes to a client that stopped reading fail with
+    /// `Error::SendTimeout` after `send_timeout`, and the handler is
+    /// told the connection was lost.
+    #[tokio::test]
+    async fn send_timeout() {
+        let send_timeout = std::time::Duration::from_millis(200);
+        let mut config = test_util::server_config();
+        config.send_timeout = Some(send_timeout);
+        let (sender, mut disconnections) = unbounded_channel();
+        let (mut client, server, _, stall) =
+            relayed(config, FloodReporter(sender), test_util::Client).await;
+        let mut channel = client.channel_open_session().await.unwrap();
+        stall.store(true, std::sync::atomic::Ordering::SeqCst);
+        channel.exec(false, "flood").await.unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        let error = tokio::time::timeout(timeout, server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert!(
+            matches!(error.downcast_ref(), Some(Error::SendTimeout)),
+            "{:?}",
+            error
+        );
+        let expected = (Disconnect::ConnectionLost, "Send timeout".to_string());
+        assert_eq!(disconnections.recv().await, Some(expected));
+    }
+
+    /// A server sending 32 MB on exec requests, and reporting its
+    /// disconnection.
+    struct FloodReporter(UnboundedSender<(Disconnect, String)>);
+
+    impl Handler for FloodReporter {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: Session,
+        ) -> Self::FutureUnit {
+            session.data(channel, &vec![0; 32 << 20]);
+            self.finished(session)
+        }
+
+        fn disconnected(
+            self,
+            reason: Disconnect,
+            description: &str,
+            _: &str,
+            session: Session,
+        ) -> Self::FutureUnit {
+            self.0.send((reason, description.to_string())).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// Clients resetting the connection while the server writes to
+    /// them are reported to the handler, and the server stops with
+    /// `Error::PeerWriteClosed`.
+    #[tokio::test]
+    async fn peer_write_closed() {
+        let (sender, mut disconnections) = unbounded_channel();
+        let (server, mut stream) =
+            test_util::accept(test_util::server_config(), FloodReporter(sender)).await;
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client_stream = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let (mut relayed, _) = listener.accept().await.unwrap();
+        let (reset, reset_received) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            // Closing without lingering resets the connection.
+            stream.set_linger(Some(std::time::Duration::from_secs(0))).unwrap();
+            let (mut server_read, mut server_write) = stream.split();
+            let (mut client_read, mut client_write) = relayed.split();
+            let down = tokio::io::copy(&mut server_read, &mut client_write);
+            let up = tokio::io::copy(&mut client_read, &mut server_write);
+            tokio::select! {
+                _ = futures::future::join(down, up) => {}
+                _ = reset_received => {}
+            }
+        });
+        let config = Arc::new(client::Config {
+            window_size: 1 << 30,
+            ..Default::default()
+        });
+        let mut client = client::connect_stream(config, client_stream, test_util::Client)
+            .await
+            .unwrap();
+        let password = test_util::PASSWORD;
+        assert!(client.authenticate_password("user", password).await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "flood").await.unwrap();
+        loop {
+            if let Some(ChannelMsg::Data { .. }) = channel.wait().await {
+                break;
+            }
+        }
+        reset.send(()).unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+        let error = tokio::time::timeout(timeout, server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert!(
+            matches!(error.downcast_ref(), Some(Error::PeerWriteClosed)),
+            "{:?}",
+            error
+        );
+        let expected = (Disconnect::ConnectionLost, "Connection closed by peer".to_string());
+        assert_eq!(disconnections.recv().await, Some(expected));
+    }
+
+    /// Clients closing the connection after sending their SSH id are
+    /// reported as such.
+    #[tokio::test]
+    async fn peer_closed_during_handshake() {
+        let (server, mut stream) =
+            test_util::accept(test_util::server_config(), test_util::Server).await;
+        stream.write_all(b"SSH-2.0-scanner\r\n").await.unwrap();
+        drop(stream);
+        let error = server.await.unwrap().unwrap_err();
+        assert!(
+            matches!(error.downcast_ref(), Some(Error::PeerClosedDuringHandshake)),
+            "{:?}",
+            error
+        );
+    }
+
+    /// Clients stalling after their SSH id are disconnected once
+    /// `handshake_timeout` is over, long before `connection_timeout`.
+    #[tokio::test]
+    async fn handshake_timeout() {
+        use tokio::io::AsyncReadExt;
+        let handshake = std::time::Duration::from_millis(300);
+        let mut config = test_util::server_config();
+        config.handshake_timeout = Some(handshake);
+        let (server, mut stream) = test_util::accept(config, test_util::Server).await;
+        let start = tokio::time::Instant::now();
+        stream.write_all(b"SSH-2.0-stalling\r\n").await.unwrap();
+        // The server sends its SSH id and KEXINIT, and then closes the
+        // connection.
+        let mut received = Vec::new();
+        let timeout = std::time::Duration::from_secs(5);
+        tokio::time::timeout(timeout, stream.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(start.elapsed() >= handshake);
+        assert!(received.starts_with(b"SSH-2.0-"));
+        drop(stream);
+        assert!(tokio::time::timeout(timeout, server).await.is_ok());
+    }
+}
@@ -0,0 +1,172 @@
+//! Parsing of the [PROXY protocol](https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt)
+//! header, sent by load balancers before the SSH identification
+//! string to forward the address of the actual client.
+
+use crate::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Maximal length of a version 1 header, including the final CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Read a PROXY protocol header (version 1 or 2) from the beginning
+/// of `stream`, without consuming anything after it. Returns the
+/// source address of the proxied connection, or `None` if the proxy
+/// did not provide one (`UNKNOWN` and `LOCAL` connections).
+pub(crate) async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<SocketAddr>, anyhow::Error> {
+    // The shortest valid header (`PROXY UNKNOWN\r\n`) is longer than
+    // the version 2 signature.
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut addresses = vec![0; len];
+        stream.read_exact(&mut addresses).await?;
+        Ok(parse_v2(header[0], header[1], &addresses)?)
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(Error::InvalidProxyHeader.into());
+            }
+            let mut b = [0];
+            stream.read_exact(&mut b).await?;
+            line.push(b[0]);
+        }
+        Ok(parse_v1(&line[..line.len() - 2])?)
+    } else {
+        Err(Error::InvalidProxyHeader.into())
+    }
+}
+
+/// Parse a version 1 header line, without its final CRLF, such as
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 22`.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let line = std::str::from_utf8(line).map_err(|_| Error::InvalidProxyHeader)?;
+    let mut words = line.split(' ').skip(1);
+    let mut next = || words.next().ok_or(Error::InvalidProxyHeader);
+    let ipv4 = match next()? {
+        "UNKNOWN" => return Ok(None),
+        "TCP4" => true,
+        "TCP6" => false,
+        _ => return Err(Error::InvalidProxyHeader),
+    };
+    let source: IpAddr = next()?.parse().map_err(|_| Error::InvalidProxyHeader)?;
+    let destination: IpAddr = next()?.parse().map_err(|_| Error::InvalidProxyHeader)?;
+    let source_port: u16 = next()?.parse().map_err(|_| Error::InvalidProxyHeader)?;
+    let _: u16 = next()?.parse().map_err(|_| Error::InvalidProxyHeader)?;
+    if next().is_ok() || source.is_ipv4() != ipv4 || destination.is_ipv4() != ipv4 {
+        return Err(Error::InvalidProxyHeader);
+    }
+    Ok(Some(SocketAddr::new(source, source_port)))
+}
+
+/// Parse the version/command byte, the family byte and the address
+/// block of a version 2 header.
+fn parse_v2(ver_cmd: u8, family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    if ver_cmd >> 4 != 2 {
+        return Err(Error::InvalidProxyHeader);
+    }
+    match ver_cmd & 0xf {
+        // LOCAL: health checks from the proxy itself.
+        0 => Ok(None),
+        // PROXY
+        1 => match family >> 4 {
+            1 => {
+                if addresses.len() < 12 {
+                    return Err(Error::InvalidProxyHeader);
+                }
+                let mut ip = [0; 4];
+                ip.clone_from_slice(&addresses[..4]);
+                let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+                Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+            }
+            2 => {
+                if addresses.len() < 36 {
+                    return Err(Error::InvalidProxyHeader);
+                }
+                let mut ip = [0; 16];
+                ip.clone_from_slice(&addresses[..16]);
+                let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+                Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+            }
+            // AF_UNSPEC and AF_UNIX carry no IP address.
+            _ => Ok(None),
+        },
+        _ => Err(Error::InvalidProxyHeader),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SSH_ID: &[u8] = b"SSH-2.0-test\r\n";
+
+    /// Read the header at the beginning of `header` followed by an SSH
+    /// id, checking that the SSH id is left unread.
+    async fn read(header: &[u8]) -> Result<Option<SocketAddr>, anyhow::Error> {
+        let input = [header, SSH_ID].concat();
+        let mut stream = &input[..];
+        let addr = read_header(&mut stream).await?;
+        assert_eq!(stream, SSH_ID);
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let addr = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 22\r\n").await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        let addr = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 22\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert!(read(b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 22\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n")
+            .await
+            .is_err());
+    }
+
+    /// A version 2 header, with the given version and command byte,
+    /// family byte and address block.
+    fn v2_header(ver_cmd: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(ver_cmd);
+        header.push(family);
+        header.extend(&(addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0, 22];
+        let addr = read(&v2_header(0x21, 0x11, &ipv4)).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        let mut ipv6 = vec![0x20, 0x01, 0x0d, 0xb8];
+        ipv6.extend(&[0; 11]);
+        ipv6.push(1);
+        ipv6.extend(&[0; 16]);
+        ipv6.extend(&[0xdc, 0x04, 0, 22]);
+        let addr = read(&v2_header(0x21, 0x21, &ipv6)).await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        // LOCAL connections, such as health checks from the proxy.
+        assert_eq!(read(&v2_header(0x20, 0, &[])).await.unwrap(), None);
+        // Truncated address block.
+        assert!(read(&v2_header(0x21, 0x11, &ipv4[..8])).await.is_err());
+        // Wrong version.
+        assert!(read(&v2_header(0x11, 0x11, &ipv4)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn no_header() {
+        assert!(read(b"").await.is_err());
+    }
+}
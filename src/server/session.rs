@@ -9,6 +9,17 @@ pub struct Session {
     pub(crate) common: CommonSession<Arc<Config>>,
     pub(crate) sender: Handle,
     pub(crate) receiver: UnboundedReceiver<(ChannelId, ChannelMsg)>,
+    /// Port bound by the handler for a `tcpip-forward` request.
+    pub(crate) bound_port: Option<u32>,
+    /// The user name `Handler::auth_banner_for` was last called with.
+    pub(crate) banner_user: Option<String>,
+    /// Reason for refusing the channel `Handler::check_channel_open`
+    /// is called for, set by the handler.
+    pub(crate) channel_open_failure: Option<(ChannelOpenFailure, String)>,
+    /// Token bucket for `config.max_global_requests_per_minute`: the
+    /// number of requests currently allowed, and the time it was
+    /// last updated.
+    pub(crate) global_request_tokens: (f64, tokio::time::Instant),
 }
 
 #[derive(Clone)]
@@ -99,19 +110,26 @@ impl Session {
     /// Flush the session, i.e. encrypt the pending buffer.
     pub fn flush(&mut self) -> Result<(), anyhow::Error> {
         if let Some(ref mut enc) = self.common.encrypted {
-            if enc.flush(
-                &self.common.config.as_ref().limits,
+            // Packets queued during a key exchange are sent with the
+            // new keys, once it is over.
+            if self.common.kex.is_some() {
+                return Ok(());
+            }
+            let limits_reached = enc.flush(
+                &self.common.limits,
                 &self.common.cipher,
                 &mut self.common.write_buffer,
-            ) {
+            );
+            if limits_reached || (enc.rekey_wanted && self.common.kex.is_none()) {
                 if let Some(exchange) = enc.exchange.take() {
+                    enc.rekey_wanted = false;
                     let mut kexinit = KexInit::initiate_rekey(exchange, &enc.session_id);
                     kexinit.server_write(
                         &self.common.config.as_ref(),
                         &mut self.common.cipher,
                         &mut self.common.write_buffer,
                     )?;
-                    enc.rekey = Some(Kex::KexInit(kexinit))
+                    self.common.kex = Some(Kex::KexInit(kexinit))
                 }
             }
         }
@@ -123,7 +141,159 @@ impl Session {
         &self.common.config
     }
 
-    /// Sends a disconnect message.
+    /// The address of the client, if the connection is a TCP
+    /// connection (or a proxied one, see
+    /// `Config::expect_proxy_protocol`).
+    pub fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.common.peer_addr
+    }
+
+    /// The local address the client connected to, if the connection
+    /// is a TCP connection.
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.common.local_addr
+    }
+
+    /// The host key algorithm negotiated with the client (for
+    /// instance `ssh-ed25519` or `rsa-sha2-512`), or `None` if the
+    /// key exchange is not finished yet.
+    pub fn host_key_algorithm(&self) -> Option<&str> {
+        self.common.encrypted.as_ref().map(|enc| enc.key_algorithm)
+    }
+
+    /// The maximum packet sizes of a channel, as a pair `(sender,
+    /// recipient)`: the first one is the maximum size of the packets
+    /// we accept on this channel, the second one the maximum size of
+    /// the packets the client accepts.
+    pub fn channel_max_packet(&self, channel: ChannelId) -> Option<(u32, u32)> {
+        let enc = self.common.encrypted.as_ref()?;
+        let channel = enc.channels.get(&channel)?;
+        Some((
+            channel.sender_maximum_packet_size,
+            channel.recipient_maximum_packet_size,
+        ))
+    }
+
+    /// The identifiers of the channels currently open (or waiting
+    /// for confirmation) on this session, in no particular order.
+    pub fn channels(&self) -> impl Iterator<Item = ChannelId> + '_ {
+        self.common
+            .encrypted
+            .iter()
+            .flat_map(|enc| enc.channels.keys().cloned())
+    }
+
+    /// A snapshot of the window and packet size parameters of a
+    /// channel, or `None` if there is no such channel.
+    pub fn channel_info(&self, channel: ChannelId) -> Option<ChannelInfo> {
+        let enc = self.common.encrypted.as_ref()?;
+        Some(enc.channels.get(&channel)?.info())
+    }
+
+    /// A snapshot of the counters and negotiated parameters of this
+    /// connection, for instance to report on a status page.
+    pub fn stats(&self) -> ConnectionStats {
+        self.common.stats()
+    }
+
+    /// The session identifier, i.e. the exchange hash of the first
+    /// key exchange, or `None` before that exchange has
+    /// completed. It does not change when keys are re-exchanged.
+    pub fn session_id(&self) -> Option<&[u8]> {
+        self.common.encrypted.as_ref().map(|enc| &enc.session_id[..])
+    }
+
+    /// Replace the bytes and time limits before key re-exchange for
+    /// this connection only, for instance to re-exchange keys more
+    /// often with a weaker cipher. The bytes and time already
+    /// elapsed since the last key exchange count towards the new
+    /// limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.common.limits = limits
+    }
+
+    /// Start a new key exchange with the client, for instance after a
+    /// privilege change. This does nothing before the first key
+    /// exchange has completed, or if a key exchange is already in
+    /// progress.
+    pub fn request_rekey(&mut self) {
+        if self.common.kex.is_some() {
+            return;
+        }
+        if let Some(ref mut enc) = self.common.encrypted {
+            if enc.exchange.is_some() {
+                enc.rekey_wanted = true
+            }
+        }
+    }
+
+    /// The language tags advertised by the client in its key
+    /// exchange, as a pair `(client-to-server, server-to-client)`, or
+    /// `None` if the key exchange is not finished yet. Both lists are
+    /// usually empty.
+    pub fn peer_languages(&self) -> Option<(&[String], &[String])> {
+        let enc = self.common.encrypted.as_ref()?;
+        Some((&enc.peer_languages.0, &enc.peer_languages.1))
+    }
+
+    /// The last language tag sent by the client, if
+    /// `Config::capture_language_tags` is set and the client sent
+    /// one.
+    pub fn peer_language_tag(&self) -> Option<&str> {
+        self.common
+            .encrypted
+            .as_ref()?
+            .peer_language_tag
+            .as_deref()
+    }
+
+    /// The extensions sent by the client in `SSH_MSG_EXT_INFO`, as
+    /// `(name, value)` pairs. Empty if the client did not send any.
+    pub fn peer_extensions(&self) -> &[(String, Vec<u8>)] {
+        if let Some(ref enc) = self.common.encrypted {
+            &enc.peer_extensions
+        } else {
+            &[]
+        }
+    }
+
+    /// Send an `SSH_MSG_IGNORE` packet with `len` random bytes, which
+    /// the client discards, for instance to hide the timing or size
+    /// of the actual traffic. `len` is capped at
+    /// `config.maximum_packet_size`.
+    pub fn send_ignore(&mut self, len: usize) {
+        let len = std::cmp::min(len, self.common.config.maximum_packet_size as usize);
+        if let Some(ref mut enc) = self.common.encrypted {
+            let mut data = vec![0; len];
+            if openssl::rand::rand_bytes(&mut data).is_err() {
+                return;
+            }
+            push_packet!(enc.write, {
+                enc.write.push(msg::IGNORE);
+                enc.write.extend_ssh_string(&data);
+            })
+        }
+    }
+
+    /// Send a banner to the client (`SSH_MSG_USERAUTH_BANNER`), to
+    /// be shown to the user. Banners can only be sent before
+    /// authentication succeeds; this does nothing afterwards.
+    pub fn send_auth_banner(&mut self, banner: &str, language_tag: &str) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            if let EncryptedState::Authenticated = enc.state {
+                return;
+            }
+            push_packet!(enc.write, {
+                enc.write.push(msg::USERAUTH_BANNER);
+                enc.write.extend_ssh_string(banner.as_bytes());
+                enc.write.extend_ssh_string(language_tag.as_bytes());
+            })
+        }
+    }
+
+    /// Sends a disconnect message and closes the connection once it
+    /// has been flushed. This can be used to kick a client, for
+    /// instance after a policy violation.
     pub fn disconnect(&mut self, reason: Disconnect, description: &str, language_tag: &str) {
         self.common.disconnect(reason, description, language_tag);
     }
@@ -149,14 +319,47 @@ impl Session {
         }
     }
 
+    /// When accepting a `tcpip-forward` request for port 0 (meaning
+    /// "any port"), set the port that was actually bound. It is sent
+    /// back to the client in the reply, as required by
+    /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-7.1).
+    pub fn set_bound_port(&mut self, port: u32) {
+        self.bound_port = Some(port)
+    }
+
+    /// When refusing a channel in `Handler::check_channel_open`, set
+    /// the reason and description sent to the client. Without this,
+    /// channels are refused with
+    /// `ChannelOpenFailure::AdministrativelyProhibited`.
+    pub fn set_channel_open_failure(&mut self, reason: ChannelOpenFailure, description: &str) {
+        self.channel_open_failure = Some((reason, description.to_string()))
+    }
+
+    /// Send a `keepalive@openssh.com` global request, if the client
+    /// is authenticated, and count it as unanswered until a reply
+    /// comes back.
+    pub(crate) fn keepalive_request(&mut self) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            if let EncryptedState::Authenticated = enc.state {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::GLOBAL_REQUEST);
+                    enc.write.extend_ssh_string(b"keepalive@openssh.com");
+                    enc.write.push(1);
+                });
+                self.common.alive_timeouts += 1;
+            }
+        }
+    }
+
     /// Send a "success" reply to a channel request. Always call this
     /// function if the request was successful (it checks whether the
     /// client expects an answer).
     pub fn channel_success(&mut self, channel: ChannelId) {
         if let Some(ref mut enc) = self.common.encrypted {
             if let Some(channel) = enc.channels.get_mut(&channel) {
-                assert!(channel.confirmed);
-                if channel.wants_reply {
+                // Requests on channels the client has not confirmed
+                // yet get no reply.
+                if channel.confirmed && channel.wants_reply {
                     channel.wants_reply = false;
                     push_packet!(enc.write, {
                         enc.write.push(msg::CHANNEL_SUCCESS);
@@ -171,8 +374,9 @@ impl Session {
     pub fn channel_anyhow(&mut self, channel: ChannelId) {
         if let Some(ref mut enc) = self.common.encrypted {
             if let Some(channel) = enc.channels.get_mut(&channel) {
-                assert!(channel.confirmed);
-                if channel.wants_reply {
+                // Requests on channels the client has not confirmed
+                // yet get no reply.
+                if channel.confirmed && channel.wants_reply {
                     channel.wants_reply = false;
                     push_packet!(enc.write, {
                         enc.write.push(msg::CHANNEL_FAILURE);
@@ -183,7 +387,8 @@ impl Session {
         }
     }
 
-    /// Send a "anyhow" reply to a request to open a channel open.
+    /// Send a "anyhow" reply to a request to open a channel open. The
+    /// configured `language_tag` is used if `language` is empty.
     pub fn channel_open_anyhow(
         &mut self,
         channel: ChannelId,
@@ -197,19 +402,30 @@ impl Session {
                 enc.write.push_u32_be(channel.0);
                 enc.write.push_u32_be(reason as u32);
                 enc.write.extend_ssh_string(description.as_bytes());
-                enc.write.extend_ssh_string(language.as_bytes());
+                if language.is_empty() {
+                    enc.write
+                        .extend_ssh_string(self.common.config.language_tag.as_bytes());
+                } else {
+                    enc.write.extend_ssh_string(language.as_bytes());
+                }
             })
         }
     }
 
-    /// Close a channel.
+    /// Close a channel, after the data sent with
+    /// `data_flow_controlled` is out.
     pub fn close(&mut self, channel: ChannelId) {
-        self.common.byte(channel, msg::CHANNEL_CLOSE);
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.close(channel)
+        }
     }
 
-    /// Send EOF to a channel
+    /// Send EOF to a channel, after the data sent with
+    /// `data_flow_controlled` is out.
     pub fn eof(&mut self, channel: ChannelId) {
-        self.common.byte(channel, msg::CHANNEL_EOF);
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.eof(channel)
+        }
     }
 
     /// Send data to a channel. On session channels, `extended` can be
@@ -226,6 +442,38 @@ impl Session {
         }
     }
 
+    /// Send as much of `data` as the window of the channel allows,
+    /// straight from the borrowed slice, and return the part that was
+    /// not sent, to be sent once the window is adjusted (see
+    /// `Handler::window_adjusted`). Unlike `data_flow_controlled`,
+    /// nothing is copied out of `data`.
+    pub fn data_from_slice<'a>(&mut self, channel: ChannelId, data: &'a [u8]) -> &'a [u8] {
+        let sent = self.data(channel, data);
+        &data[sent..]
+    }
+
+    /// Send data to a channel, respecting flow control: the bytes
+    /// that do not fit in the window of the channel are kept and
+    /// sent automatically as the other side adjusts the window.
+    pub fn data_flow_controlled(&mut self, channel: ChannelId, data: &[u8]) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.data_flow_controlled(channel, data)
+        }
+    }
+
+    /// Same as `data_flow_controlled`, for extended data (for
+    /// instance standard error, with `extended` set to 1).
+    pub fn extended_data_flow_controlled(
+        &mut self,
+        channel: ChannelId,
+        extended: u32,
+        data: &[u8],
+    ) {
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.extended_data_flow_controlled(channel, extended, data)
+        }
+    }
+
     /// Send data to a channel. On session channels, `extended` can be
     /// used to encode standard error by passing `Some(1)`, and stdout
     /// by passing `None`.
@@ -240,6 +488,20 @@ impl Session {
         }
     }
 
+    /// Make a command fail: send `stderr_message` on the standard
+    /// error of the channel and the exit status `exit_code`, then
+    /// EOF and close the channel. The message is flow-controlled like
+    /// `data_flow_controlled`, and EOF and close are only sent after
+    /// all the data waiting for the window of the channel.
+    pub fn fail_command(&mut self, channel: ChannelId, stderr_message: &str, exit_code: u32) {
+        self.extended_data_flow_controlled(channel, 1, stderr_message.as_bytes());
+        self.exit_status_request(channel, exit_code);
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.eof(channel);
+            enc.close(channel);
+        }
+    }
+
     /// Inform the client of whether they may perform
     /// control-S/control-Q flow control. See
     /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-6.8).
@@ -354,3 +616,308 @@ impl Session {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::server::{self, Handler};
+    use crate::{client, test_util, ChannelId, ChannelMsg};
+    use std::borrow::Cow;
+    use std::time::Duration;
+    use thrussh_keys::key;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    /// A server failing all commands.
+    struct Failing;
+
+    impl Handler for Failing {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            _: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            session.fail_command(channel, "command not found\n", 127);
+            self.finished(session)
+        }
+    }
+
+    #[tokio::test]
+    async fn fail_command() {
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            Failing,
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "missing").await.unwrap();
+        // Stderr, the exit status and EOF, and then the channel is
+        // closed.
+        let mut messages = Vec::new();
+        while let Some(msg) = channel.wait().await {
+            messages.push(msg)
+        }
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        match messages[0] {
+            ChannelMsg::ExtendedData { ref data, ext: 1 } => {
+                assert_eq!(&data[..], b"command not found\n")
+            }
+            ref msg => panic!("unexpected message: {:?}", msg),
+        }
+        assert!(matches!(
+            messages[1],
+            ChannelMsg::ExitStatus { exit_status: 127 }
+        ));
+        assert!(matches!(messages[2], ChannelMsg::Eof));
+    }
+
+    /// A server starting a key re-exchange on the `rekey` command,
+    /// and reporting the number of re-exchanges on each command.
+    struct Rekeying(UnboundedSender<usize>);
+
+    impl Handler for Rekeying {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn exec_request(
+            self,
+            channel: ChannelId,
+            command: &[u8],
+            mut session: server::Session,
+        ) -> Self::FutureUnit {
+            self.0.send(session.stats().rekeys).unwrap();
+            if command == b"rekey" {
+                session.request_rekey()
+            }
+            session.data(channel, b"ok");
+            session.exit_status_request(channel, 0);
+            session.eof(channel);
+            session.close(channel);
+            self.finished(session)
+        }
+    }
+
+    /// Run `command` on a new channel, and check its output.
+    async fn run(client: &mut client::Handle, command: &str) {
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, command).await.unwrap();
+        let mut messages = Vec::new();
+        while let Some(msg) = channel.wait().await {
+            messages.push(msg)
+        }
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        match messages[0] {
+            ChannelMsg::Data { ref data } => assert_eq!(&data[..], b"ok"),
+            ref msg => panic!("unexpected message: {:?}", msg),
+        }
+        assert!(matches!(
+            messages[1],
+            ChannelMsg::ExitStatus { exit_status: 0 }
+        ));
+    }
+
+    #[tokio::test]
+    async fn request_rekey() {
+        let (sender, mut rekeys) = unbounded_channel();
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            Rekeying(sender),
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        run(&mut client, "rekey").await;
+        assert_eq!(rekeys.recv().await, Some(0));
+        // The connection is still usable, and the keys are eventually
+        // re-exchanged exactly once.
+        let mut n = 0;
+        for _ in 0..20 {
+            run(&mut client, "true").await;
+            n = rekeys.recv().await.unwrap();
+            if n > 0 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await
+        }
+        assert_eq!(n, 1);
+        run(&mut client, "true").await;
+        assert_eq!(rekeys.recv().await, Some(1));
+    }
+
+    type Addresses = (Option<std::net::SocketAddr>, Option<std::net::SocketAddr>);
+
+    /// A server reporting the peer and local addresses of the
+    /// connection when a session channel is opened, and when it
+    /// receives data.
+    struct PeerAddr(UnboundedSender<Addresses>);
+
+    impl Handler for PeerAddr {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn channel_open_session(self, _: ChannelId, session: server::Session) -> Self::FutureUnit {
+            self.0.send((session.peer_addr(), session.local_addr())).unwrap();
+            self.finished(session)
+        }
+
+        fn data(self, _: ChannelId, _: &[u8], session: server::Session) -> Self::FutureUnit {
+            self.0.send((session.peer_addr(), session.local_addr())).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// The handler sees the addresses of both ends of the TCP
+    /// connection.
+    #[tokio::test]
+    async fn peer_addr() {
+        let (sender, mut addresses) = unbounded_channel();
+        let (_, stream) = test_util::accept(test_util::server_config(), PeerAddr(sender)).await;
+        let expected = (stream.local_addr().ok(), stream.peer_addr().ok());
+        let config = std::sync::Arc::new(client::Config::default());
+        let mut client = client::connect_stream(config, stream, test_util::Client)
+            .await
+            .unwrap();
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        let opened = addresses.recv().await.unwrap();
+        assert!(opened.0.is_some());
+        assert_eq!(opened, expected);
+        channel.data(&b"data"[..]).await.unwrap();
+        assert_eq!(addresses.recv().await, Some(expected));
+    }
+
+    /// A server reporting the host key algorithm when the client
+    /// starts authenticating.
+    struct HostKeyAlgorithm(UnboundedSender<Option<String>>);
+
+    impl Handler for HostKeyAlgorithm {
+        server_futures!();
+
+        fn auth_banner_for(self, _: &str, session: server::Session) -> Self::FutureUnit {
+            let algorithm = session.host_key_algorithm().map(|a| a.to_string());
+            self.0.send(algorithm).unwrap();
+            self.finished(session)
+        }
+    }
+
+    /// The algorithm reported is the one the client preferred among
+    /// the keys of the server.
+    #[tokio::test]
+    async fn host_key_algorithm() {
+        let algorithms = [
+            (key::ED25519, "ssh-ed25519"),
+            (key::RSA_SHA2_256, "rsa-sha2-256"),
+        ];
+        for &(name, algorithm) in &algorithms {
+            let mut server_config = test_util::server_config();
+            let rsa = key::KeyPair::generate_rsa(2048, key::SignatureHash::SHA2_256).unwrap();
+            server_config.keys.push(rsa);
+            let client_config = client::Config {
+                preferred: crate::Preferred {
+                    key: Cow::Owned(vec![name]),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let (sender, mut received) = unbounded_channel();
+            let (mut client, _) = test_util::connect(
+                server_config,
+                HostKeyAlgorithm(sender),
+                client_config,
+                test_util::Client,
+            )
+            .await;
+            assert!(!client.authenticate_password("user", "").await.unwrap());
+            let negotiated = received.recv().await.unwrap();
+            assert_eq!(negotiated.as_deref(), Some(algorithm));
+        }
+    }
+
+    /// A server reporting connection statistics when a session
+    /// channel is opened, and once it received `expected` bytes of
+    /// data.
+    struct Stats {
+        received: usize,
+        expected: usize,
+        stats: UnboundedSender<crate::ConnectionStats>,
+    }
+
+    impl Handler for Stats {
+        server_futures!();
+
+        fn auth_password(self, _: &str, _: &str) -> Self::FutureAuth {
+            self.finished_auth(server::Auth::Accept)
+        }
+
+        fn channel_open_session(
+            self,
+            _: ChannelId,
+            session: server::Session,
+        ) -> Self::FutureUnit {
+            self.stats.send(session.stats()).unwrap();
+            self.finished(session)
+        }
+
+        fn data(mut self, _: ChannelId, data: &[u8], session: server::Session) -> Self::FutureUnit {
+            self.received += data.len();
+            if self.received == self.expected {
+                self.stats.send(session.stats()).unwrap();
+            }
+            self.finished(session)
+        }
+    }
+
+    /// The statistics of a connection count the channels and the
+    /// data sent on them.
+    #[tokio::test]
+    async fn stats() {
+        let (sender, mut stats) = unbounded_channel();
+        let handler = Stats {
+            received: 0,
+            expected: 3000,
+            stats: sender,
+        };
+        let (mut client, _) = test_util::connect(
+            test_util::server_config(),
+            handler,
+            Default::default(),
+            test_util::Client,
+        )
+        .await;
+        assert!(client.authenticate_password("user", "").await.unwrap());
+        let mut first = client.channel_open_session().await.unwrap();
+        let mut second = client.channel_open_session().await.unwrap();
+        let before = stats.recv().await.unwrap();
+        assert_eq!(before.channels, 1);
+        let opened = stats.recv().await.unwrap();
+        assert_eq!(opened.channels, 2);
+        first.data(&[0; 1000][..]).await.unwrap();
+        second.data(&[0; 2000][..]).await.unwrap();
+        let after = stats.recv().await.unwrap();
+
+        assert_eq!(after.channels, 2);
+        assert_eq!(after.rekeys, 0);
+        assert_eq!(after.user.as_deref(), Some("user"));
+        assert!(after.algorithms.is_some());
+        assert!(after.uptime >= opened.uptime);
+        // Two packets of data, with their headers, padding and MACs.
+        let read = after.bytes_read - opened.bytes_read;
+        assert!((3000..3200).contains(&read), "{}", read);
+        assert!(after.bytes_written >= opened.bytes_written);
+    }
+}
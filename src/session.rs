@@ -14,8 +14,8 @@
 //
 
 use crate::sshbuffer::SSHBuffer;
-use crate::{auth, cipher, kex, msg, negotiation};
-use crate::{Channel, ChannelId, Disconnect, Limits};
+use crate::{auth, cipher, kex, msg, negotiation, Named};
+use crate::{Channel, ChannelId, ConnectionStats, Disconnect, Error, Limits, NegotiatedAlgorithms};
 use byteorder::{BigEndian, ByteOrder};
 use cryptovec::CryptoVec;
 use openssl::hash;
@@ -32,15 +32,33 @@ pub(crate) struct Encrypted {
     pub exchange: Option<Exchange>,
     pub kex: kex::Algorithm,
     pub key: usize,
+    pub key_algorithm: &'static str,
+    pub kex_algorithm: &'static str,
+    pub cipher_algorithm: &'static str,
+    /// The languages advertised by the remote side during the last
+    /// key exchange (client-to-server, then server-to-client).
+    pub peer_languages: (Vec<String>, Vec<String>),
+    /// The extensions received from the remote side in
+    /// `SSH_MSG_EXT_INFO`.
+    pub peer_extensions: Vec<(String, Vec<u8>)>,
+    /// The last language tag received from the remote side, if
+    /// language tags are captured.
+    pub peer_language_tag: Option<String>,
     pub mac: Option<&'static str>,
     pub session_id: hash::DigestBytes,
-    pub rekey: Option<Kex>,
+    /// Start a key re-exchange at the next flush.
+    pub rekey_wanted: bool,
     pub channels: HashMap<ChannelId, Channel>,
+    /// The client sent `no-more-sessions@openssh.com`: refuse any
+    /// further session channel.
+    pub no_more_sessions: bool,
     pub last_channel_id: Wrapping<u32>,
     pub wants_reply: bool,
     pub write: CryptoVec,
     pub write_cursor: usize,
     pub last_rekey: std::time::Instant,
+    /// Number of completed key re-exchanges.
+    pub rekeys: usize,
 }
 
 pub(crate) struct CommonSession<Config> {
@@ -54,65 +72,199 @@ pub(crate) struct CommonSession<Config> {
     pub wants_reply: bool,
     pub disconnected: bool,
     pub buffer: CryptoVec,
+    /// Address of the remote side, if known.
+    pub peer_addr: Option<std::net::SocketAddr>,
+    /// Local address of the connection, if known.
+    pub local_addr: Option<std::net::SocketAddr>,
+    /// Number of keepalive requests sent without a reply.
+    pub alive_timeouts: usize,
+    /// Sequence number of the last packet received.
+    pub received_seqn: u32,
+    /// Bytes received and sent on the connection, not counting the
+    /// identification strings.
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub connected_at: std::time::Instant,
+    /// The bytes and time limits before key re-exchange, initially
+    /// `config.limits`.
+    pub limits: Limits,
+    /// Both sides advertised strict key exchange in their first
+    /// KEXINIT: sequence numbers are reset on each NEWKEYS.
+    pub strict_kex: bool,
 }
 
+/// Bytes of a channel data packet besides the data: packet and
+/// padding lengths, message header, padding and MAC.
+const DATA_PACKET_OVERHEAD: usize = 4 + 1 + 13 + 255 + 64;
+
 impl<C> CommonSession<C> {
+    /// The maximal length of the next packet read: `handshake_limit`
+    /// until the user is authenticated, then the length of a channel
+    /// data packet of `maximum_packet_size` bytes.
+    pub fn max_read_len(&self, handshake_limit: usize, maximum_packet_size: u32) -> usize {
+        if let Some(Encrypted {
+            state: EncryptedState::Authenticated,
+            ..
+        }) = self.encrypted
+        {
+            maximum_packet_size as usize + DATA_PACKET_OVERHEAD
+        } else {
+            handshake_limit
+        }
+    }
+
     pub fn encrypted(&mut self, state: EncryptedState, newkeys: NewKeys) {
         if let Some(ref mut enc) = self.encrypted {
             enc.exchange = Some(newkeys.exchange);
             enc.kex = newkeys.kex;
             enc.key = newkeys.key;
+            enc.key_algorithm = newkeys.names.key.0;
+            enc.kex_algorithm = newkeys.names.kex.name();
+            enc.cipher_algorithm = newkeys.names.cipher.name();
             enc.mac = newkeys.names.mac;
+            enc.peer_languages = newkeys.names.languages;
+            enc.rekeys += 1;
             self.cipher = Arc::new(newkeys.cipher);
         } else {
             self.encrypted = Some(Encrypted {
                 exchange: Some(newkeys.exchange),
                 kex: newkeys.kex,
                 key: newkeys.key,
+                key_algorithm: newkeys.names.key.0,
+                kex_algorithm: newkeys.names.kex.name(),
+                cipher_algorithm: newkeys.names.cipher.name(),
+                peer_languages: newkeys.names.languages,
+                peer_extensions: Vec::new(),
+                peer_language_tag: None,
                 mac: newkeys.names.mac,
                 session_id: newkeys.session_id,
                 state,
-                rekey: None,
+                rekey_wanted: false,
                 channels: HashMap::new(),
+                no_more_sessions: false,
                 last_channel_id: Wrapping(1),
                 wants_reply: false,
                 write: CryptoVec::new(),
                 write_cursor: 0,
                 last_rekey: std::time::Instant::now(),
+                rekeys: 0,
             });
             self.cipher = Arc::new(newkeys.cipher);
         }
     }
 
+    /// Enable strict key exchange if the peer supports it and this is
+    /// the initial key exchange, in which case the peer's KEXINIT
+    /// must have been its first packet.
+    pub fn start_strict_kex(&mut self, names: &negotiation::Names) -> Result<(), Error> {
+        if self.encrypted.is_none() && names.strict_kex {
+            if self.received_seqn != 0 {
+                return Err(Error::StrictKex);
+            }
+            self.strict_kex = true
+        }
+        Ok(())
+    }
+
+    /// Whether message `msg` is forbidden because we are in the
+    /// initial strict key exchange, which only allows key exchange
+    /// messages.
+    pub fn strict_kex_forbids(&self, msg: u8) -> bool {
+        self.strict_kex
+            && self.encrypted.is_none()
+            && !(msg == msg::KEXINIT || msg == msg::NEWKEYS || (30..=49).contains(&msg))
+    }
+
+    /// Called after sending NEWKEYS.
+    pub fn newkeys_sent(&mut self) {
+        if self.strict_kex {
+            self.write_buffer.seqn = Wrapping(0)
+        }
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let enc = self.encrypted.as_ref();
+        let user = match enc.map(|enc| &enc.state) {
+            Some(EncryptedState::Authenticated) => Some(self.auth_user.clone()),
+            _ => None,
+        };
+        ConnectionStats {
+            bytes_read: self.bytes_read,
+            bytes_written: self.bytes_written,
+            channels: enc.map(|enc| enc.channels.len()).unwrap_or(0),
+            rekeys: enc.map(|enc| enc.rekeys).unwrap_or(0),
+            user,
+            uptime: self.connected_at.elapsed(),
+            algorithms: self.algorithms(),
+        }
+    }
+
+    /// The algorithms of the last key exchange, if any.
+    pub fn algorithms(&self) -> Option<NegotiatedAlgorithms> {
+        self.encrypted.as_ref().map(|enc| NegotiatedAlgorithms {
+            kex: enc.kex_algorithm,
+            key: enc.key_algorithm,
+            cipher: enc.cipher_algorithm,
+            mac: enc.mac,
+        })
+    }
+
+    /// Reply `SSH_MSG_UNIMPLEMENTED` to the last packet received, see
+    /// [RFC4253](https://tools.ietf.org/html/rfc4253#section-11.4).
+    pub fn unimplemented(&mut self) {
+        if let Some(ref mut enc) = self.encrypted {
+            let seqn = self.received_seqn;
+            push_packet!(enc.write, {
+                enc.write.push(msg::UNIMPLEMENTED);
+                enc.write.push_u32_be(seqn);
+            })
+        }
+    }
+
     /// Send a disconnect message.
     pub fn disconnect(&mut self, reason: Disconnect, description: &str, language_tag: &str) {
         let disconnect = |buf: &mut CryptoVec| {
-            push_packet!(buf, {
-                buf.push(msg::DISCONNECT);
-                buf.push_u32_be(reason as u32);
-                buf.extend_ssh_string(description.as_bytes());
-                buf.extend_ssh_string(language_tag.as_bytes());
-            });
+            buf.push(msg::DISCONNECT);
+            buf.push_u32_be(reason as u32);
+            buf.extend_ssh_string(description.as_bytes());
+            buf.extend_ssh_string(language_tag.as_bytes());
         };
         if !self.disconnected {
             self.disconnected = true;
             if let Some(ref mut enc) = self.encrypted {
-                disconnect(&mut enc.write)
+                push_packet!(enc.write, disconnect(&mut enc.write))
             } else {
-                disconnect(&mut self.write_buffer.buffer)
+                // Before the first key exchange, the packet is sent
+                // in the clear.
+                self.buffer.clear();
+                disconnect(&mut self.buffer);
+                self.cipher.write(&self.buffer, &mut self.write_buffer);
+                self.buffer.clear();
             }
         }
     }
+}
 
-    /// Send a single byte message onto the channel.
-    pub fn byte(&mut self, channel: ChannelId, msg: u8) {
-        if let Some(ref mut enc) = self.encrypted {
-            enc.byte(channel, msg)
+impl Encrypted {
+    /// Keep `tag` as the last language tag sent by the peer, if
+    /// `capture` (the `capture_language_tags` setting) is set.
+    pub(crate) fn capture_language_tag(&mut self, capture: bool, tag: &[u8]) {
+        if capture {
+            self.peer_language_tag = Some(String::from_utf8_lossy(tag).into_owned())
         }
     }
-}
 
-impl Encrypted {
+    /// The signature algorithms listed in the peer's `server-sig-algs`
+    /// extension, or `None` if it did not send that extension.
+    pub(crate) fn server_sig_algs(&self) -> Option<Vec<&str>> {
+        let (_, value) = self
+            .peer_extensions
+            .iter()
+            .find(|(name, _)| name == negotiation::SERVER_SIG_ALGS)?;
+        let value = std::str::from_utf8(value).ok()?;
+        Some(value.split(',').filter(|alg| !alg.is_empty()).collect())
+    }
+
     pub fn byte(&mut self, channel: ChannelId, msg: u8) {
         if let Some(channel) = self.channels.get(&channel) {
             push_packet!(self.write, {
@@ -123,13 +275,45 @@ impl Encrypted {
     }
 
     pub fn eof(&mut self, channel: ChannelId) {
+        // Don't let EOF overtake the data waiting for the window.
+        if let Some(c) = self.channels.get_mut(&channel) {
+            if !c.pending_data.is_empty() {
+                c.pending_eof = true;
+                return;
+            }
+        }
         self.byte(channel, msg::CHANNEL_EOF);
     }
 
-    pub fn adjust_window_size(&mut self, channel: ChannelId, data: &[u8], target: u32) {
+    /// Close a channel, once the data (and EOF) waiting for its
+    /// window are sent.
+    pub fn close(&mut self, channel: ChannelId) {
+        if let Some(c) = self.channels.get_mut(&channel) {
+            if !c.pending_data.is_empty() {
+                c.pending_close = true;
+                return;
+            }
+        }
+        self.byte(channel, msg::CHANNEL_CLOSE);
+    }
+
+    pub fn adjust_window_size(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        target: u32,
+    ) -> Result<(), Error> {
         debug!("adjust_window_size");
         if let Some(ref mut channel) = self.channels.get_mut(&channel) {
             debug!("channel {:?}", channel);
+            // No data may be sent after EOF.
+            // https://tools.ietf.org/html/rfc4254#section-5.3
+            if channel.eof_received {
+                return Err(Error::DataAfterEof);
+            }
+            if data.len() > channel.sender_maximum_packet_size as usize {
+                return Err(Error::PacketTooLarge);
+            }
             // Ignore extra data.
             // https://tools.ietf.org/html/rfc4254#section-5.2
             if data.len() as u32 <= channel.sender_window_size {
@@ -148,40 +332,91 @@ impl Encrypted {
                 channel.sender_window_size = target;
             }
         }
+        Ok(())
     }
 
     pub fn data(&mut self, channel: ChannelId, buf: &[u8]) -> usize {
-        use std::ops::Deref;
-        if let Some(channel) = self.channels.get_mut(&channel) {
-            assert!(channel.confirmed);
-            let mut buf = if buf.len() as u32 > channel.recipient_window_size {
-                &buf[0..channel.recipient_window_size as usize]
-            } else {
-                buf
-            };
-            let buf_len = buf.len();
+        self.send_channel_data(channel, None, buf)
+    }
 
-            while buf.len() > 0 {
-                // Compute the length we're allowed to send.
-                let off = std::cmp::min(buf.len(), channel.recipient_maximum_packet_size as usize);
-                let off = std::cmp::min(off, channel.recipient_window_size as usize);
-                push_packet!(self.write, {
-                    self.write.push(msg::CHANNEL_DATA);
-                    self.write.push_u32_be(channel.recipient_channel);
-                    self.write.extend_ssh_string(&buf[..off]);
-                });
-                debug!("buffer: {:?}", self.write.deref().len());
-                channel.recipient_window_size -= off as u32;
-                buf = &buf[off..]
-            }
-            debug!("buf.len() = {:?}, buf_len = {:?}", buf.len(), buf_len);
-            buf_len
+    pub fn extended_data(&mut self, channel: ChannelId, ext: u32, buf: &[u8]) -> usize {
+        self.send_channel_data(channel, Some(ext), buf)
+    }
+
+    /// Push as much of `buf` as the channel window allows, and keep
+    /// the rest in the channel until the window is adjusted. Data
+    /// already waiting on that channel is sent first.
+    pub fn data_flow_controlled(&mut self, channel: ChannelId, buf: &[u8]) {
+        self.send_flow_controlled(channel, None, buf)
+    }
+
+    /// Same as `data_flow_controlled`, for extended data.
+    pub fn extended_data_flow_controlled(&mut self, channel: ChannelId, ext: u32, buf: &[u8]) {
+        self.send_flow_controlled(channel, Some(ext), buf)
+    }
+
+    fn send_flow_controlled(&mut self, channel: ChannelId, ext: Option<u32>, buf: &[u8]) {
+        let has_pending = if let Some(c) = self.channels.get(&channel) {
+            !c.pending_data.is_empty()
         } else {
+            return;
+        };
+        let sent = if has_pending {
             0
+        } else {
+            self.send_channel_data(channel, ext, buf)
+        };
+        if sent < buf.len() {
+            if let Some(c) = self.channels.get_mut(&channel) {
+                let mut rest = CryptoVec::new();
+                rest.extend(&buf[sent..]);
+                c.pending_data.push_back((ext, rest));
+            }
         }
     }
 
-    pub fn extended_data(&mut self, channel: ChannelId, ext: u32, buf: &[u8]) -> usize {
+    /// Send the data waiting for the window of this channel, as much
+    /// as the window allows, then the pending EOF and close if all
+    /// the data was sent. Called when the window is adjusted.
+    pub fn flush_pending(&mut self, channel: ChannelId) -> usize {
+        let mut sent = 0;
+        while let Some((ext, pending)) = self
+            .channels
+            .get_mut(&channel)
+            .and_then(|c| c.pending_data.pop_front())
+        {
+            let n = self.send_channel_data(channel, ext, &pending);
+            sent += n;
+            if n < pending.len() {
+                if let Some(c) = self.channels.get_mut(&channel) {
+                    let mut rest = CryptoVec::new();
+                    rest.extend(&pending[n..]);
+                    c.pending_data.push_front((ext, rest));
+                }
+                return sent;
+            }
+        }
+        let (send_eof, send_close) = if let Some(c) = self.channels.get_mut(&channel) {
+            (
+                std::mem::replace(&mut c.pending_eof, false),
+                std::mem::replace(&mut c.pending_close, false),
+            )
+        } else {
+            return sent;
+        };
+        if send_eof {
+            self.eof(channel)
+        }
+        if send_close {
+            self.close(channel)
+        }
+        sent
+    }
+
+    /// Push as much of `buf` as the channel window allows, split into
+    /// packets no larger than the maximum packet size of the peer,
+    /// and return the number of bytes pushed.
+    fn send_channel_data(&mut self, channel: ChannelId, ext: Option<u32>, buf: &[u8]) -> usize {
         use std::ops::Deref;
         if let Some(channel) = self.channels.get_mut(&channel) {
             assert!(channel.confirmed);
@@ -191,15 +426,21 @@ impl Encrypted {
                 buf
             };
             let buf_len = buf.len();
+            // https://tools.ietf.org/html/rfc4254#section-5.2
+            let max_packet = std::cmp::max(channel.recipient_maximum_packet_size, 1) as usize;
 
             while buf.len() > 0 {
                 // Compute the length we're allowed to send.
-                let off = std::cmp::min(buf.len(), channel.recipient_maximum_packet_size as usize);
-                let off = std::cmp::min(off, channel.recipient_window_size as usize);
+                let off = std::cmp::min(buf.len(), max_packet);
                 push_packet!(self.write, {
-                    self.write.push(msg::CHANNEL_EXTENDED_DATA);
-                    self.write.push_u32_be(channel.recipient_channel);
-                    self.write.push_u32_be(ext);
+                    if let Some(ext) = ext {
+                        self.write.push(msg::CHANNEL_EXTENDED_DATA);
+                        self.write.push_u32_be(channel.recipient_channel);
+                        self.write.push_u32_be(ext);
+                    } else {
+                        self.write.push(msg::CHANNEL_DATA);
+                        self.write.push_u32_be(channel.recipient_channel);
+                    }
                     self.write.extend_ssh_string(&buf[..off]);
                 });
                 debug!("buffer: {:?}", self.write.deref().len());
@@ -278,6 +519,10 @@ impl Encrypted {
                     recipient_maximum_packet_size: 0,
                     confirmed: false,
                     wants_reply: false,
+                    eof_received: false,
+                    pending_data: std::collections::VecDeque::new(),
+                    pending_eof: false,
+                    pending_close: false,
                 });
                 return ChannelId(self.last_channel_id.0);
             }
@@ -287,6 +532,9 @@ impl Encrypted {
 
 #[derive(Debug)]
 pub enum EncryptedState {
+    /// Waiting for the `ssh-userauth` service request. `accepted` is
+    /// only used by clients, once the server has accepted it: servers
+    /// go to `WaitingAuthRequest` when they accept the service.
     WaitingServiceRequest { accepted: bool },
     WaitingAuthRequest(auth::AuthRequest),
     Authenticated,
@@ -341,14 +589,10 @@ pub struct KexInit {
 }
 
 impl KexInit {
-    pub fn received_rekey(
-        ex: Exchange,
-        algo: negotiation::Names,
-        session_id: &hash::DigestBytes,
-    ) -> Self {
+    pub fn received_rekey(ex: Exchange, session_id: &hash::DigestBytes) -> Self {
         let mut kexinit = KexInit {
             exchange: ex,
-            algo: Some(algo),
+            algo: None,
             sent: false,
             session_id: Some(session_id.clone()),
         };
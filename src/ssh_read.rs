@@ -103,6 +103,12 @@ impl<R: Tcp> Tcp for SshRead<R> {
     fn tcp_shutdown(&mut self) -> Result<(), std::io::Error> {
         self.r.tcp_shutdown()
     }
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.r.peer_addr()
+    }
+    fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.r.local_addr()
+    }
 }
 
 impl<R: AsyncRead + Unpin> SshRead<R> {
@@ -4,6 +4,14 @@ pub trait Tcp {
     fn tcp_shutdown(&mut self) -> Result<(), std::io::Error> {
         Ok(())
     }
+    /// The address of the remote side, if this is a TCP connection.
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        None
+    }
+    /// The local address of the connection, if this is a TCP connection.
+    fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        None
+    }
 }
 
 impl Tcp for tokio::net::TcpStream {
@@ -11,17 +19,39 @@ impl Tcp for tokio::net::TcpStream {
         debug!("tcp shutdown for tcpstream");
         self.shutdown(std::net::Shutdown::Both)
     }
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        tokio::net::TcpStream::peer_addr(self).ok()
+    }
+    fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        tokio::net::TcpStream::local_addr(self).ok()
+    }
 }
 
+/// Unix domain sockets have no TCP shutdown, closing them is enough.
+#[cfg(unix)]
+impl Tcp for tokio::net::UnixStream {}
+
 impl<T: ?Sized + Tcp> Tcp for Box<T> {
     fn tcp_shutdown(&mut self) -> Result<(), std::io::Error> {
         self.as_mut().tcp_shutdown()
     }
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.as_ref().peer_addr()
+    }
+    fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.as_ref().local_addr()
+    }
 }
 impl<'a, T: ?Sized + Tcp> Tcp for &'a mut T {
     fn tcp_shutdown(&mut self) -> Result<(), std::io::Error> {
         (*self).tcp_shutdown()
     }
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        (**self).peer_addr()
+    }
+    fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        (**self).local_addr()
+    }
 }
 impl<'a> Tcp for std::io::Cursor<&'a mut [u8]> {}
 impl Tcp for std::io::Cursor<Vec<u8>> {}
@@ -0,0 +1,218 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Helpers for the tests: servers and clients connected over local
+//! TCP sockets.
+
+use std::sync::Arc;
+
+use cryptovec::CryptoVec;
+use thrussh_keys::encoding::Encoding;
+use thrussh_keys::key;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::key::PubKey;
+use crate::{client, server};
+
+/// The associated types and constructors of a server handler whose
+/// futures are all immediately ready.
+macro_rules! server_futures {
+    () => {
+        type FutureAuth =
+            futures::future::Ready<Result<(Self, crate::server::Auth), anyhow::Error>>;
+        type FutureUnit =
+            futures::future::Ready<Result<(Self, crate::server::Session), anyhow::Error>>;
+        type FutureBool =
+            futures::future::Ready<Result<(Self, crate::server::Session, bool), anyhow::Error>>;
+
+        fn finished_auth(self, auth: crate::server::Auth) -> Self::FutureAuth {
+            futures::future::ready(Ok((self, auth)))
+        }
+        fn finished_bool(self, b: bool, session: crate::server::Session) -> Self::FutureBool {
+            futures::future::ready(Ok((self, session, b)))
+        }
+        fn finished(self, session: crate::server::Session) -> Self::FutureUnit {
+            futures::future::ready(Ok((self, session)))
+        }
+    };
+}
+
+/// The associated types and constructors of a client handler whose
+/// futures are all immediately ready. Server keys are accepted
+/// without checking them.
+macro_rules! client_futures {
+    () => {
+        type FutureBool = futures::future::Ready<Result<(Self, bool), anyhow::Error>>;
+        type FutureUnit =
+            futures::future::Ready<Result<(Self, crate::client::Session), anyhow::Error>>;
+
+        fn finished_bool(self, b: bool) -> Self::FutureBool {
+            futures::future::ready(Ok((self, b)))
+        }
+        fn finished(self, session: crate::client::Session) -> Self::FutureUnit {
+            futures::future::ready(Ok((self, session)))
+        }
+        fn check_server_key(self, _: &thrussh_keys::key::PublicKey) -> Self::FutureBool {
+            futures::future::ready(Ok((self, true)))
+        }
+    };
+}
+
+/// The password accepted by [`Server`](struct.Server.html).
+pub(crate) const PASSWORD: &str = "password";
+
+/// A server accepting `PASSWORD` for any user, and any channel.
+pub(crate) struct Server;
+
+impl server::Handler for Server {
+    server_futures!();
+
+    fn auth_password(self, _: &str, password: &str) -> Self::FutureAuth {
+        self.finished_auth(if password == PASSWORD {
+            server::Auth::Accept
+        } else {
+            server::Auth::Reject
+        })
+    }
+}
+
+/// A client doing nothing but accepting the server's key.
+pub(crate) struct Client;
+
+impl client::Handler for Client {
+    client_futures!();
+}
+
+/// A server configuration with a fresh Ed25519 host key, which
+/// rejects authentications immediately.
+pub(crate) fn server_config() -> server::Config {
+    server::Config {
+        auth_rejection_time: std::time::Duration::from_secs(0),
+        keys: vec![key::KeyPair::generate_ed25519().unwrap()],
+        ..Default::default()
+    }
+}
+
+/// Run a connection of `handler` on a local socket, and return the
+/// task running it along with a raw stream connected to it.
+pub(crate) async fn accept<H: server::Handler + Send + 'static>(
+    config: server::Config,
+    handler: H,
+) -> (JoinHandle<Result<(), anyhow::Error>>, TcpStream) {
+    let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut listener = TcpListener::bind(&addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = Arc::new(config);
+    let server = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await?;
+        server::run_stream(config, socket, handler).await
+    });
+    (server, TcpStream::connect(addr).await.unwrap())
+}
+
+/// Connect a client running `client` to a server running `server`,
+/// returning the client's handle and the task running the server.
+pub(crate) async fn connect<S, C>(
+    server_config: server::Config,
+    server: S,
+    client_config: client::Config,
+    client: C,
+) -> (client::Handle, JoinHandle<Result<(), anyhow::Error>>)
+where
+    S: server::Handler + Send + 'static,
+    C: client::Handler + Send + 'static,
+{
+    let (server, stream) = accept(server_config, server).await;
+    let client = client::connect_stream(Arc::new(client_config), stream, client)
+        .await
+        .unwrap();
+    (client, server)
+}
+
+/// Connect `Client` to `Server`, and log in with `PASSWORD`.
+pub(crate) async fn authenticated(
+    server_config: server::Config,
+) -> (client::Handle, JoinHandle<Result<(), anyhow::Error>>) {
+    let (mut client, server) =
+        connect(server_config, Server, client::Config::default(), Client).await;
+    assert!(client
+        .authenticate_password("user", PASSWORD)
+        .await
+        .unwrap());
+    (client, server)
+}
+
+/// Queue a debug message to be displayed, with a message that is not
+/// valid UTF-8 and a language tag, followed by an ignore message
+/// carrying `cover`.
+pub(crate) fn push_debug_and_ignore(write: &mut CryptoVec) {
+    let mut debug = CryptoVec::new();
+    debug.push(crate::msg::DEBUG);
+    debug.push(1);
+    debug.extend_ssh_string(b"caf\xe9");
+    debug.extend_ssh_string(b"fr");
+    let mut ignore = CryptoVec::new();
+    ignore.push(crate::msg::IGNORE);
+    ignore.extend_ssh_string(b"cover");
+    for packet in &[debug, ignore] {
+        write.push_u32_be(packet.len() as u32);
+        write.extend(packet);
+    }
+}
+
+/// The messages reported by `Handler::debug` and `Handler::ignore`
+/// for the packets of `push_debug_and_ignore`.
+pub(crate) const DEBUG_AND_IGNORE: [&str; 2] = ["debug true caf\u{fffd} fr", "ignore cover"];
+
+/// An OpenSSH certificate of type `cert_type` for the Ed25519 key
+/// `key`, signed by `ca`, for `principals` and valid between the UNIX
+/// times `valid_after` and `valid_before`, in wire format.
+pub(crate) fn certificate(
+    key: &key::KeyPair,
+    ca: &key::KeyPair,
+    cert_type: u32,
+    principals: &[&str],
+    valid_after: u64,
+    valid_before: u64,
+) -> Vec<u8> {
+    let mut public = CryptoVec::new();
+    key.push_to(&mut public);
+    let mut cert = CryptoVec::new();
+    cert.extend_ssh_string(b"ssh-ed25519-cert-v01@openssh.com");
+    // A zero nonce, followed by the fields of the public key (after
+    // its length and type) and the serial number.
+    cert.extend_ssh_string(&[0; 32]);
+    cert.extend(&public[8 + key::ED25519.0.len()..]);
+    cert.push_u32_be(0);
+    cert.push_u32_be(1);
+    cert.push_u32_be(cert_type);
+    cert.extend_ssh_string(b"test");
+    let mut p = CryptoVec::new();
+    for principal in principals {
+        p.extend_ssh_string(principal.as_bytes());
+    }
+    cert.extend_ssh_string(&p);
+    for &t in &[valid_after, valid_before] {
+        cert.push_u32_be((t >> 32) as u32);
+        cert.push_u32_be(t as u32);
+    }
+    cert.extend_ssh_string(b""); // critical options
+    cert.extend_ssh_string(b""); // extensions
+    cert.extend_ssh_string(b""); // reserved
+    ca.push_to(&mut cert);
+    ca.add_self_signature(&mut cert).unwrap();
+    cert.to_vec()
+}